@@ -0,0 +1,66 @@
+// Database updates and DPF output checks over 1M slots, one element at
+// a time as the servers used to, and with the batch operations.
+//
+// Run with `cargo bench --bench batch`.
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use payapp::batch;
+use payapp::{FieldElm, Group, MAX_GROUP_SIZE};
+
+const SLOTS: usize = 1 << 20;
+
+fn slots(seed: u32) -> Vec<FieldElm> {
+    (0..SLOTS as u32).map(|i| FieldElm::from(i.wrapping_mul(seed))).collect()
+}
+
+fn batch_ops(c: &mut Criterion) {
+    let mut db = slots(1);
+    let src = slots(3);
+    let dest = slots(7);
+
+    let mut group = c.benchmark_group("batch_1m");
+    group.sample_size(10);
+
+    group.bench_function("transact/loop", |b| {
+        b.iter(|| {
+            for i in 0..SLOTS {
+                db[i].add(&src[i]);
+                db[i].sub(&dest[i]);
+            }
+        })
+    });
+    group.bench_function("transact/batch", |b| b.iter(|| batch::add_sub_assign(&mut db, &src, &dest)));
+
+    group.bench_function("index_product/loop", |b| {
+        b.iter(|| {
+            let mut total = FieldElm::zero();
+            for (i, x) in src.iter().enumerate() {
+                let mut term = x.clone();
+                term.mul(&FieldElm::from(i as u32));
+                total.add(&term);
+            }
+            total
+        })
+    });
+    group.bench_function("index_product/batch", |b| b.iter(|| batch::index_inner_product(&src)));
+
+    group.bench_function("group_sums/loop", |b| {
+        b.iter(|| {
+            let mut sums = Vec::with_capacity(SLOTS / MAX_GROUP_SIZE);
+            for group in src.chunks(MAX_GROUP_SIZE) {
+                let mut total = FieldElm::zero();
+                for x in group {
+                    total.add(x);
+                }
+                sums.push(total);
+            }
+            sums
+        })
+    });
+    group.bench_function("group_sums/batch", |b| b.iter(|| batch::group_sums(&src, MAX_GROUP_SIZE)));
+
+    group.finish();
+}
+
+criterion_group!(benches, batch_ops);
+criterion_main!(benches);
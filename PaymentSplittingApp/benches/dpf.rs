@@ -0,0 +1,44 @@
+// Full-domain DPF evaluation over a database of 100k slots, comparing
+// the level-by-level parallel evaluator with the old depth-first walk,
+// and all-prefix keys with standard and early-termination ones.
+//
+// Run with `cargo bench --bench dpf`.
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use payapp::dpf::{DPFKey, PackedDPFKey, PointDPFKey};
+use payapp::my_u32_to_bits;
+use payapp::FieldElm;
+use payapp::Group;
+
+// 2^17 = 131072 leaves, enough for 100k slots
+const LEVELS: usize = 17;
+
+fn eval_all(c: &mut Criterion) {
+    let alpha_bits = my_u32_to_bits(LEVELS as u8 + 1, 12345);
+    let values = vec![FieldElm::zero(); LEVELS];
+    let (key, _) = DPFKey::<FieldElm, FieldElm>::gen(&alpha_bits, &values, &FieldElm::zero());
+
+    let mut group = c.benchmark_group("eval_all_100k");
+    group.sample_size(10);
+    group.bench_function("recursive", |b| b.iter(|| key.eval_all_recursive()));
+    let mut out = vec![FieldElm::zero(); 1 << LEVELS];
+    group.bench_function("parallel", |b| b.iter(|| key.eval_first_into(&mut out)));
+    let (point, _) = PointDPFKey::<FieldElm>::gen(&alpha_bits[..LEVELS], &FieldElm::one());
+    group.bench_function("point", |b| b.iter(|| point.eval_first_into(&mut out)));
+    group.finish();
+
+    // Eight slots a leaf, in place of the last three levels, against a
+    // standard key over the same field. Packing needs outputs of at most
+    // half a block.
+    let mut group = c.benchmark_group("eval_all_100k_u64");
+    group.sample_size(10);
+    let mut out = vec![0u64; 1 << LEVELS];
+    let (point, _) = PointDPFKey::<u64>::gen(&alpha_bits[..LEVELS], &1);
+    group.bench_function("point", |b| b.iter(|| point.eval_first_into(&mut out)));
+    let (packed, _) = PackedDPFKey::<u64>::gen(&alpha_bits[..LEVELS - 3], 8, 1, &1);
+    group.bench_function("packed", |b| b.iter(|| packed.eval_first_into(&mut out)));
+    group.finish();
+}
+
+criterion_group!(benches, eval_all);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+// DPF payloads compared: the scalar field that commitments are over
+// (FieldElm), u64 modulo 2^63 - 25, u128 modulo 2^127 - 1 and the ring
+// of wrapping u64s.
+// Times full-domain evaluation of a transaction key and a server's
+// database update for one transaction.
+//
+// Run with `cargo bench --bench fields`.
+use std::num::Wrapping;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use payapp::dpf::PointDPFKey;
+use payapp::prg::FromRng;
+use payapp::ps::ServerData;
+use payapp::{my_u32_to_bits, FieldElm, Group, DPF_DOMAIN, MAX_GROUP_NUM, MAX_GROUP_SIZE};
+
+// 2^17 = 131072 leaves, enough for 100k slots
+const LEVELS: usize = 17;
+
+fn eval_all_in<T>(c: &mut Criterion, name: &str, value: T)
+where
+    T: FromRng + Clone + Group + std::fmt::Debug + Send + Sync,
+{
+    let alpha_bits = my_u32_to_bits(LEVELS as u8 + 1, 12345);
+    let (key, _) = PointDPFKey::<T>::gen(&alpha_bits[..LEVELS], &value);
+    let mut out = vec![T::zero(); 1 << LEVELS];
+
+    let mut group = c.benchmark_group("field_eval_all_100k");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(1 << LEVELS));
+    group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(|| key.eval_first_into(&mut out)));
+    group.finish();
+}
+
+fn transact_in<T>(c: &mut Criterion, name: &str, value: T)
+where
+    T: FromRng + Clone + Group + std::fmt::Debug + Send + Sync,
+{
+    // The source and destination keys of one transaction, as a server
+    // evaluates them
+    let n = MAX_GROUP_NUM * MAX_GROUP_SIZE;
+    let slot = |i| my_u32_to_bits(DPF_DOMAIN as u8, i)[..DPF_DOMAIN - 1].to_vec();
+    let (src, _) = PointDPFKey::<T>::gen(&slot(12), &value);
+    let (dest, _) = PointDPFKey::<T>::gen(&slot(345), &value);
+    let mut db = vec![T::zero(); n];
+
+    let mut group = c.benchmark_group("field_transact");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function(BenchmarkId::from_parameter(name), |b| {
+        b.iter(|| {
+            let src_vec = src.eval_first(n);
+            let dest_vec = dest.eval_first(n);
+            ServerData::transact(&mut db, &src_vec, &dest_vec);
+        })
+    });
+    group.finish();
+}
+
+fn fields(c: &mut Criterion) {
+    eval_all_in(c, "scalar", FieldElm::from(20u32));
+    eval_all_in(c, "u64", 20u64);
+    eval_all_in(c, "mersenne127", 20u128);
+    eval_all_in(c, "ring64", Wrapping(20u64));
+    transact_in(c, "scalar", FieldElm::from(20u32));
+    transact_in(c, "u64", 20u64);
+    transact_in(c, "mersenne127", 20u128);
+    transact_in(c, "ring64", Wrapping(20u64));
+}
+
+criterion_group!(benches, fields);
+criterion_main!(benches);
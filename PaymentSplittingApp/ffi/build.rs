@@ -0,0 +1,22 @@
+// Generate payapp.h from the extern "C" functions in src/lib.rs into
+// OUT_DIR, where the C harness test compiles against it. The copy the
+// apps use, include/payapp.h, is only rewritten on request:
+//
+//     PSA_UPDATE_HEADER=1 cargo build
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap();
+    let bindings = cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("unable to generate C bindings");
+    bindings.write_to_file(format!("{}/payapp.h", out_dir));
+    if std::env::var_os("PSA_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(format!("{}/include/payapp.h", crate_dir));
+    }
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=PSA_UPDATE_HEADER");
+}
@@ -0,0 +1,206 @@
+#ifndef PAYAPP_H
+#define PAYAPP_H
+
+/* Generated by cbindgen from src/lib.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Number of slots in a group, and so of balances psa_decrypt_balances
+ * writes.
+ */
+#define PSA_GROUP_SIZE 10
+
+typedef enum PsaStatus {
+  PSA_STATUS_OK = 0,
+  /**
+   * A required pointer argument was null
+   */
+  PSA_STATUS_NULL_POINTER,
+  /**
+   * Bytes from the app or the servers could not be decoded
+   */
+  PSA_STATUS_MALFORMED,
+  /**
+   * The wallet's token is not among the group's tokens
+   */
+  PSA_STATUS_NOT_IN_GROUP,
+  /**
+   * The servers' receipts are for another transaction
+   */
+  PSA_STATUS_WRONG_TRANSACTION,
+  /**
+   * A receipt is not signed by the pinned server, or a server
+   * rejected the transaction
+   */
+  PSA_STATUS_BAD_RECEIPT,
+  /**
+   * The output array is shorter than PSA_GROUP_SIZE
+   */
+  PSA_STATUS_BUFFER_TOO_SMALL,
+  /**
+   * A server refused to settle in the settlement's epoch; prepare a
+   * new settlement and try again
+   */
+  PSA_STATUS_SETTLE_REFUSED,
+} PsaStatus;
+
+/**
+ * One member's group token.
+ */
+typedef struct PsaGroupToken PsaGroupToken;
+
+/**
+ * A request for a group's balances in a fresh epoch.
+ */
+typedef struct PsaSettlement PsaSettlement;
+
+/**
+ * A payment, prepared for both servers.
+ */
+typedef struct PsaTransaction PsaTransaction;
+
+/**
+ * A member's slot of a group: their group token and the keys that go
+ * with it.
+ */
+typedef struct PsaWallet PsaWallet;
+
+/**
+ * Bytes owned by the caller; release with psa_buffer_free.
+ */
+typedef struct PsaBuffer {
+  uint8_t *data;
+  size_t len;
+} PsaBuffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Release a buffer returned by this library.
+ */
+void psa_buffer_free(struct PsaBuffer buffer);
+
+/**
+ * Load a wallet stored with psa_wallet_to_bytes (or by the wasm
+ * library's Wallet.to_bytes).
+ */
+enum PsaStatus psa_wallet_from_bytes(const uint8_t *data, size_t len, struct PsaWallet **out);
+
+enum PsaStatus psa_wallet_to_bytes(const struct PsaWallet *wallet, struct PsaBuffer *out);
+
+/**
+ * The wallet's database slot, which other members pay to.
+ */
+enum PsaStatus psa_wallet_account(const struct PsaWallet *wallet, uint32_t *out);
+
+enum PsaStatus psa_wallet_group(const struct PsaWallet *wallet, uint32_t *out);
+
+/**
+ * The wallet's own group token, to share with the other members.
+ */
+enum PsaStatus psa_wallet_group_token(const struct PsaWallet *wallet, struct PsaGroupToken **out);
+
+void psa_wallet_free(struct PsaWallet *wallet);
+
+/**
+ * Load another member's group token. Tokens whose points are not valid
+ * group elements are Malformed.
+ */
+enum PsaStatus psa_group_token_from_bytes(const uint8_t *data,
+                                          size_t len,
+                                          struct PsaGroupToken **out);
+
+enum PsaStatus psa_group_token_to_bytes(const struct PsaGroupToken *token, struct PsaBuffer *out);
+
+void psa_group_token_free(struct PsaGroupToken *token);
+
+/**
+ * Pay amount from the wallet's slot to slot dest. tokens holds the
+ * group's n_tokens group tokens, the wallet's own among them; the
+ * servers learn only that the payer is one of them. The servers refuse
+ * a session_id they have seen before, so pick a fresh random one.
+ */
+enum PsaStatus psa_prepare_transaction(const struct PsaWallet *wallet,
+                                       uint32_t session_id,
+                                       const struct PsaGroupToken *const *tokens,
+                                       size_t n_tokens,
+                                       uint32_t dest,
+                                       uint32_t amount,
+                                       struct PsaTransaction **out);
+
+/**
+ * The payload for S1 (opcode 4).
+ */
+enum PsaStatus psa_transaction_s1_payload(const struct PsaTransaction *transaction,
+                                          struct PsaBuffer *out);
+
+/**
+ * The payload for S2 (opcode 4).
+ */
+enum PsaStatus psa_transaction_s2_payload(const struct PsaTransaction *transaction,
+                                          struct PsaBuffer *out);
+
+/**
+ * Check that both servers accepted the transaction, from their
+ * replies, under the server identities the wallet pinned.
+ */
+enum PsaStatus psa_transaction_check_receipts(const struct PsaTransaction *transaction,
+                                              const struct PsaWallet *wallet,
+                                              const uint8_t *receipt1,
+                                              size_t receipt1_len,
+                                              const uint8_t *receipt2,
+                                              size_t receipt2_len);
+
+void psa_transaction_free(struct PsaTransaction *transaction);
+
+/**
+ * Ask for the balances of the wallet's group in a fresh epoch.
+ */
+enum PsaStatus psa_prepare_settle(const struct PsaWallet *wallet, struct PsaSettlement **out);
+
+/**
+ * The payload for S1 (opcode 5).
+ */
+enum PsaStatus psa_settlement_s1_payload(const struct PsaSettlement *settlement,
+                                         struct PsaBuffer *out);
+
+/**
+ * The payload for S2 (opcode 5).
+ */
+enum PsaStatus psa_settlement_s2_payload(const struct PsaSettlement *settlement,
+                                         struct PsaBuffer *out);
+
+/**
+ * The epoch the servers mask their answers for, to pass to
+ * psa_decrypt_balances.
+ */
+enum PsaStatus psa_settlement_epoch(const struct PsaSettlement *settlement, uint64_t *out);
+
+void psa_settlement_free(struct PsaSettlement *settlement);
+
+/**
+ * Add up the servers' answers to a settlement in the given epoch and
+ * strip the masks, writing the balance of every slot of the wallet's
+ * group to balances, which must hold PSA_GROUP_SIZE values.
+ */
+enum PsaStatus psa_decrypt_balances(const struct PsaWallet *wallet,
+                                    uint64_t epoch,
+                                    const uint8_t *reply1,
+                                    size_t reply1_len,
+                                    const uint8_t *reply2,
+                                    size_t reply2_len,
+                                    int64_t *balances,
+                                    size_t balances_len);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* PAYAPP_H */
@@ -0,0 +1,351 @@
+// C API for the client protocol, for the Kotlin and Swift apps.
+//
+// Like the wasm library, nothing here does I/O: the app sends the
+// payloads to the servers in frames over its own connections and passes
+// the servers' replies back in. Wallets, group tokens, prepared
+// transactions and settlements are opaque handles, released with the
+// matching psa_*_free. Payloads come back as PsaBuffers owned by the
+// caller and released with psa_buffer_free. Every call returns a
+// PsaStatus and writes its outputs only on PSA_STATUS_OK.
+//
+// The header, include/payapp.h, is generated from this file by
+// cbindgen; rebuild with PSA_UPDATE_HEADER=1 after changing the API. The
+// doc comments below end up in it.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ptr;
+use std::slice;
+
+use payapp::client::{
+    account_index, combine_balances, prepare_settle, prepare_transaction, receipts_match,
+    signed_balance, TransactionError,
+};
+use payapp::keys::SettleError;
+use payapp::ps::{GroupToken, GroupTokenPriv, SettleData, TransactionData, TransactionDataS2};
+use payapp::receipt::ReceiptPair;
+use payapp::ringproof::decompress_ring;
+use payapp::{settle_epoch, FieldElm, MAX_GROUP_SIZE};
+
+/// Number of slots in a group, and so of balances psa_decrypt_balances
+/// writes.
+pub const PSA_GROUP_SIZE: usize = 10;
+const _: () = assert!(PSA_GROUP_SIZE == MAX_GROUP_SIZE);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PsaStatus {
+    Ok = 0,
+    /// A required pointer argument was null
+    NullPointer,
+    /// Bytes from the app or the servers could not be decoded
+    Malformed,
+    /// The wallet's token is not among the group's tokens
+    NotInGroup,
+    /// The servers' receipts are for another transaction
+    WrongTransaction,
+    /// A receipt is not signed by the pinned server, or a server
+    /// rejected the transaction
+    BadReceipt,
+    /// The output array is shorter than PSA_GROUP_SIZE
+    BufferTooSmall,
+    /// A server refused to settle in the settlement's epoch; prepare a
+    /// new settlement and try again
+    SettleRefused,
+}
+
+/// Bytes owned by the caller; release with psa_buffer_free.
+#[repr(C)]
+pub struct PsaBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl PsaBuffer {
+    fn new(bytes: Vec<u8>) -> PsaBuffer {
+        let len = bytes.len();
+        let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        PsaBuffer { data, len }
+    }
+}
+
+/// A member's slot of a group: their group token and the keys that go
+/// with it.
+pub struct PsaWallet(GroupTokenPriv);
+
+/// One member's group token.
+pub struct PsaGroupToken(GroupToken);
+
+/// A payment, prepared for both servers.
+pub struct PsaTransaction {
+    s1: TransactionData,
+    s2: TransactionDataS2,
+}
+
+/// A request for a group's balances in a fresh epoch.
+pub struct PsaSettlement {
+    s1: SettleData,
+    s2: SettleData,
+}
+
+macro_rules! check_ptr {
+    ($($p:expr),+) => {
+        if $($p.is_null())||+ {
+            return PsaStatus::NullPointer;
+        }
+    };
+}
+
+fn group_of(wallet: &GroupTokenPriv) -> u32 {
+    account_index(&wallet.aid) / MAX_GROUP_SIZE as u32
+}
+
+unsafe fn input<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return if len == 0 { Some(&[]) } else { None };
+    }
+    Some(slice::from_raw_parts(data, len))
+}
+
+unsafe fn decode<T: serde::de::DeserializeOwned>(data: *const u8, len: usize) -> Result<T, PsaStatus> {
+    let bytes = input(data, len).ok_or(PsaStatus::NullPointer)?;
+    bincode::deserialize(bytes).map_err(|_| PsaStatus::Malformed)
+}
+
+unsafe fn boxed<T>(value: T, out: *mut *mut T) -> PsaStatus {
+    *out = Box::into_raw(Box::new(value));
+    PsaStatus::Ok
+}
+
+unsafe fn release<T>(handle: *mut T) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Release a buffer returned by this library.
+#[no_mangle]
+pub unsafe extern "C" fn psa_buffer_free(buffer: PsaBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)));
+    }
+}
+
+/// Load a wallet stored with psa_wallet_to_bytes (or by the wasm
+/// library's Wallet.to_bytes).
+#[no_mangle]
+pub unsafe extern "C" fn psa_wallet_from_bytes(data: *const u8, len: usize, out: *mut *mut PsaWallet) -> PsaStatus {
+    check_ptr!(out);
+    match decode(data, len) {
+        Ok(token) => boxed(PsaWallet(token), out),
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_wallet_to_bytes(wallet: *const PsaWallet, out: *mut PsaBuffer) -> PsaStatus {
+    check_ptr!(wallet, out);
+    *out = PsaBuffer::new(bincode::serialize(&(*wallet).0).unwrap());
+    PsaStatus::Ok
+}
+
+/// The wallet's database slot, which other members pay to.
+#[no_mangle]
+pub unsafe extern "C" fn psa_wallet_account(wallet: *const PsaWallet, out: *mut u32) -> PsaStatus {
+    check_ptr!(wallet, out);
+    *out = account_index(&(*wallet).0.aid);
+    PsaStatus::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_wallet_group(wallet: *const PsaWallet, out: *mut u32) -> PsaStatus {
+    check_ptr!(wallet, out);
+    *out = group_of(&(*wallet).0);
+    PsaStatus::Ok
+}
+
+/// The wallet's own group token, to share with the other members.
+#[no_mangle]
+pub unsafe extern "C" fn psa_wallet_group_token(wallet: *const PsaWallet, out: *mut *mut PsaGroupToken) -> PsaStatus {
+    check_ptr!(wallet, out);
+    boxed(PsaGroupToken((*wallet).0.token.clone()), out)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_wallet_free(wallet: *mut PsaWallet) {
+    release(wallet);
+}
+
+/// Load another member's group token. Tokens whose points are not valid
+/// group elements are Malformed.
+#[no_mangle]
+pub unsafe extern "C" fn psa_group_token_from_bytes(data: *const u8, len: usize, out: *mut *mut PsaGroupToken) -> PsaStatus {
+    check_ptr!(out);
+    match decode::<GroupToken>(data, len) {
+        Ok(token) if decompress_ring(&[(token.P, token.cm_aid)]).is_none() => PsaStatus::Malformed,
+        Ok(token) => boxed(PsaGroupToken(token), out),
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_group_token_to_bytes(token: *const PsaGroupToken, out: *mut PsaBuffer) -> PsaStatus {
+    check_ptr!(token, out);
+    *out = PsaBuffer::new(bincode::serialize(&(*token).0).unwrap());
+    PsaStatus::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_group_token_free(token: *mut PsaGroupToken) {
+    release(token);
+}
+
+/// Pay amount from the wallet's slot to slot dest. tokens holds the
+/// group's n_tokens group tokens, the wallet's own among them; the
+/// servers learn only that the payer is one of them. The servers refuse
+/// a session_id they have seen before, so pick a fresh random one.
+#[no_mangle]
+pub unsafe extern "C" fn psa_prepare_transaction(
+    wallet: *const PsaWallet,
+    session_id: u32,
+    tokens: *const *const PsaGroupToken,
+    n_tokens: usize,
+    dest: u32,
+    amount: u32,
+    out: *mut *mut PsaTransaction,
+) -> PsaStatus {
+    check_ptr!(wallet, tokens, out);
+    let handles = slice::from_raw_parts(tokens, n_tokens);
+    if handles.iter().any(|t| t.is_null()) {
+        return PsaStatus::NullPointer;
+    }
+    let group_tokens: Vec<GroupToken> = handles.iter().map(|t| (**t).0.clone()).collect();
+    match prepare_transaction(session_id, &(*wallet).0, &group_tokens, dest, amount) {
+        Ok((s1, s2)) => boxed(PsaTransaction { s1, s2 }, out),
+        Err(TransactionError::NotInGroup) => PsaStatus::NotInGroup,
+        Err(TransactionError::BadToken) => PsaStatus::Malformed,
+    }
+}
+
+/// The payload for S1 (opcode 4).
+#[no_mangle]
+pub unsafe extern "C" fn psa_transaction_s1_payload(transaction: *const PsaTransaction, out: *mut PsaBuffer) -> PsaStatus {
+    check_ptr!(transaction, out);
+    *out = PsaBuffer::new(bincode::serialize(&(*transaction).s1).unwrap());
+    PsaStatus::Ok
+}
+
+/// The payload for S2 (opcode 4).
+#[no_mangle]
+pub unsafe extern "C" fn psa_transaction_s2_payload(transaction: *const PsaTransaction, out: *mut PsaBuffer) -> PsaStatus {
+    check_ptr!(transaction, out);
+    *out = PsaBuffer::new(bincode::serialize(&(*transaction).s2).unwrap());
+    PsaStatus::Ok
+}
+
+/// Check that both servers accepted the transaction, from their
+/// replies, under the server identities the wallet pinned.
+#[no_mangle]
+pub unsafe extern "C" fn psa_transaction_check_receipts(
+    transaction: *const PsaTransaction,
+    wallet: *const PsaWallet,
+    receipt1: *const u8,
+    receipt1_len: usize,
+    receipt2: *const u8,
+    receipt2_len: usize,
+) -> PsaStatus {
+    check_ptr!(transaction, wallet);
+    let receipts = match (decode(receipt1, receipt1_len), decode(receipt2, receipt2_len)) {
+        (Ok(s1), Ok(s2)) => ReceiptPair { s1, s2 },
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+    let transaction = &*transaction;
+    if !receipts_match(&transaction.s1, &transaction.s2, &receipts) {
+        return PsaStatus::WrongTransaction;
+    }
+    match receipts.verify(&(*wallet).0.issuer_key) {
+        Ok(()) => PsaStatus::Ok,
+        Err(_) => PsaStatus::BadReceipt,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_transaction_free(transaction: *mut PsaTransaction) {
+    release(transaction);
+}
+
+/// Ask for the balances of the wallet's group in a fresh epoch.
+#[no_mangle]
+pub unsafe extern "C" fn psa_prepare_settle(wallet: *const PsaWallet, out: *mut *mut PsaSettlement) -> PsaStatus {
+    check_ptr!(wallet, out);
+    let (s1, s2) = prepare_settle(group_of(&(*wallet).0), settle_epoch());
+    boxed(PsaSettlement { s1, s2 }, out)
+}
+
+/// The payload for S1 (opcode 5).
+#[no_mangle]
+pub unsafe extern "C" fn psa_settlement_s1_payload(settlement: *const PsaSettlement, out: *mut PsaBuffer) -> PsaStatus {
+    check_ptr!(settlement, out);
+    *out = PsaBuffer::new(bincode::serialize(&(*settlement).s1).unwrap());
+    PsaStatus::Ok
+}
+
+/// The payload for S2 (opcode 5).
+#[no_mangle]
+pub unsafe extern "C" fn psa_settlement_s2_payload(settlement: *const PsaSettlement, out: *mut PsaBuffer) -> PsaStatus {
+    check_ptr!(settlement, out);
+    *out = PsaBuffer::new(bincode::serialize(&(*settlement).s2).unwrap());
+    PsaStatus::Ok
+}
+
+/// The epoch the servers mask their answers for, to pass to
+/// psa_decrypt_balances.
+#[no_mangle]
+pub unsafe extern "C" fn psa_settlement_epoch(settlement: *const PsaSettlement, out: *mut u64) -> PsaStatus {
+    check_ptr!(settlement, out);
+    *out = (*settlement).s1.epoch;
+    PsaStatus::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psa_settlement_free(settlement: *mut PsaSettlement) {
+    release(settlement);
+}
+
+/// Add up the servers' answers to a settlement in the given epoch and
+/// strip the masks, writing the balance of every slot of the wallet's
+/// group to balances, which must hold PSA_GROUP_SIZE values.
+#[no_mangle]
+pub unsafe extern "C" fn psa_decrypt_balances(
+    wallet: *const PsaWallet,
+    epoch: u64,
+    reply1: *const u8,
+    reply1_len: usize,
+    reply2: *const u8,
+    reply2_len: usize,
+    balances: *mut i64,
+    balances_len: usize,
+) -> PsaStatus {
+    check_ptr!(wallet, balances);
+    if balances_len < PSA_GROUP_SIZE {
+        return PsaStatus::BufferTooSmall;
+    }
+    type Reply = Result<Vec<FieldElm>, SettleError>;
+    let (bv1, bv2) = match (decode::<Reply>(reply1, reply1_len), decode::<Reply>(reply2, reply2_len)) {
+        (Ok(Ok(bv1)), Ok(Ok(bv2))) => (bv1, bv2),
+        (Err(e), _) | (_, Err(e)) => return e,
+        _ => return PsaStatus::SettleRefused,
+    };
+    if bv1.len() < MAX_GROUP_SIZE || bv2.len() < MAX_GROUP_SIZE {
+        return PsaStatus::Malformed;
+    }
+    let wallet = &(*wallet).0;
+    let values = combine_balances(&bv1, &bv2, &wallet.group_secret, group_of(wallet), epoch);
+    let values: Option<Vec<i64>> = values.iter().map(signed_balance).collect();
+    match values {
+        Some(values) => {
+            slice::from_raw_parts_mut(balances, PSA_GROUP_SIZE).copy_from_slice(&values);
+            PsaStatus::Ok
+        }
+        None => PsaStatus::Malformed,
+    }
+}
@@ -0,0 +1,158 @@
+// Builds tests/harness.c against the library and the generated header,
+// and answers it as both servers. The servers are simulated in-process;
+// only the payloads of the frames pass between them and the harness.
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::ptr;
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+
+use payapp::coms::{verify_group_tokens, SessionContext};
+use payapp::keys::{GroupMasterSecret, KeyRegistry, SettleError};
+use payapp::membership::RevocationList;
+use payapp::ps::{GroupTokenPriv, ServerData, SettleData, TransactionData, TransactionDataS2};
+use payapp::test_util::TestServers;
+use payapp::tokens::validate_tokens;
+use payapp::{my_u32_to_bits, FieldElm, Group, DPF_DOMAIN, MAX_GROUP_NUM, MAX_GROUP_SIZE};
+use payapp_ffi::{psa_group_token_free, psa_group_token_from_bytes, PsaStatus};
+
+const GROUP: usize = 3;
+
+// The harness talks in the servers' framing: a big-endian u32 length,
+// then the payload
+fn send(w: &mut impl Write, payload: &[u8]) {
+    w.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+    w.write_all(payload).unwrap();
+    w.flush().unwrap();
+}
+
+fn recv(r: &mut impl Read) -> Vec<u8> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut payload).unwrap();
+    payload
+}
+
+// Compile the harness, linking the cdylib cargo built next to this test.
+// That's the copy in deps: cargo test doesn't refresh the one in the
+// directory above.
+fn build_harness() -> PathBuf {
+    let crate_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = std::env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let exe = lib_dir.join("psa_c_harness");
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(crate_dir.join("tests/harness.c"))
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(env!("OUT_DIR"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lpayapp_ffi")
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("a C compiler is installed");
+    assert!(status.success(), "harness.c failed to compile");
+    exe
+}
+
+#[test]
+fn c_harness() {
+    let mut servers = TestServers::new();
+    let group_secret = GroupMasterSecret::random();
+    let members: Vec<GroupTokenPriv> = (0..3).map(|slot| servers.member((GROUP * MAX_GROUP_SIZE) as u64 + slot, &group_secret)).collect();
+    let tokens: Vec<_> = members.iter().map(|m| m.token.clone()).collect();
+    let payee = (GROUP * MAX_GROUP_SIZE + 2) as u32;
+
+    let mut harness = Command::new(build_harness())
+        .args(&[payee.to_string(), "25".to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut to_c = harness.stdin.take().unwrap();
+    let mut from_c = harness.stdout.take().unwrap();
+
+    send(&mut to_c, &bincode::serialize(&members[1]).unwrap());
+    for token in &tokens {
+        send(&mut to_c, &bincode::serialize(token).unwrap());
+    }
+    send(&mut to_c, &[]);
+
+    // S1 accepts the proof of group membership, and the key shares move
+    // the amount from the payer's slot to the payee's
+    let td1: TransactionData = bincode::deserialize(&recv(&mut from_c)).unwrap();
+    let td2: TransactionDataS2 = bincode::deserialize(&recv(&mut from_c)).unwrap();
+    assert_eq!(td1.id, 7);
+    let session = SessionContext::new(7, &servers.s1.issuer_shares(), &td1.dpf_src, &td1.dpf_dest, &tokens);
+    assert!(verify_group_tokens(td1.token_proof.clone(), tokens.clone(), td1.com_i, &session, &servers.keyrings[0], &RevocationList::default()));
+    assert!(validate_tokens(&tokens, &servers.keyrings[1]).is_ok());
+    let slot = |i: u32| my_u32_to_bits(DPF_DOMAIN as u8, i)[..DPF_DOMAIN - 1].to_vec();
+    let mut paid = td1.dpf_src.eval(&slot(payee - 1));
+    paid.add(&td2.dpf_src.eval(&slot(payee - 1)));
+    assert_eq!(paid, FieldElm::from(25u32));
+    let mut received = td1.dpf_dest.eval(&slot(payee));
+    received.add(&td2.dpf_dest.eval(&slot(payee)));
+    assert_eq!(received, FieldElm::from(25u32));
+
+    let (receipt1, receipt2) = servers.receipts(&td1, &td2);
+    send(&mut to_c, &receipt1);
+    send(&mut to_c, &receipt2);
+
+    // Opcode 5, over a database with the payer's slot overdrawn
+    let mut reg1 = KeyRegistry::new();
+    let mut reg2 = KeyRegistry::new();
+    reg1.register(GROUP, group_secret.server_key(1));
+    reg2.register(GROUP, group_secret.server_key(2));
+    let mut db: Vec<FieldElm> = (0..MAX_GROUP_NUM * MAX_GROUP_SIZE).map(|i| FieldElm::from(i as u32)).collect();
+    db[payee as usize - 1].sub(&FieldElm::from(100u32));
+    let zero = vec![FieldElm::zero(); db.len()];
+
+    let d1: SettleData = bincode::deserialize(&recv(&mut from_c)).unwrap();
+    let d2: SettleData = bincode::deserialize(&recv(&mut from_c)).unwrap();
+    let enc1 = ServerData::encrypt_db(&db, &reg1, d1.epoch);
+    let enc2 = ServerData::encrypt_db(&zero, &reg2, d2.epoch);
+    send(&mut to_c, &bincode::serialize(&Ok::<_, SettleError>(ServerData::settle(&enc1, &enc2, &d1.dpf_key))).unwrap());
+    send(&mut to_c, &bincode::serialize(&Ok::<_, SettleError>(ServerData::settle(&enc2, &enc1, &d2.dpf_key))).unwrap());
+
+    let balances: Vec<i64> = recv(&mut from_c)
+        .chunks(8)
+        .map(|b| i64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        .collect();
+    let mut expected: Vec<i64> = (0..MAX_GROUP_SIZE).map(|i| (GROUP * MAX_GROUP_SIZE + i) as i64).collect();
+    expected[1] -= 100;
+    assert_eq!(balances, expected);
+
+    assert!(harness.wait().unwrap().success());
+}
+
+// The apps build against the checked-in header
+#[test]
+fn header_is_current() {
+    let generated = std::fs::read_to_string(concat!(env!("OUT_DIR"), "/payapp.h")).unwrap();
+    let checked_in = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/include/payapp.h")).unwrap();
+    assert!(generated == checked_in, "include/payapp.h is stale; rebuild with PSA_UPDATE_HEADER=1");
+}
+
+#[test]
+fn group_token_points() {
+    let mut servers = TestServers::new();
+    let token = servers.member((GROUP * MAX_GROUP_SIZE) as u64, &GroupMasterSecret::random()).token;
+    let load = |bytes: &[u8]| unsafe {
+        let mut handle = ptr::null_mut();
+        let status = psa_group_token_from_bytes(bytes.as_ptr(), bytes.len(), &mut handle);
+        psa_group_token_free(handle);
+        status
+    };
+    assert_eq!(load(&bincode::serialize(&token).unwrap()), PsaStatus::Ok);
+
+    // A token whose commitment is not a group element is refused
+    let mut broken = token;
+    broken.cm_aid = CompressedRistretto([0xff; 32]);
+    assert_eq!(load(&bincode::serialize(&broken).unwrap()), PsaStatus::Malformed);
+}
@@ -0,0 +1,136 @@
+/*
+ * Test harness for the C API, driven by tests/c_harness.rs.
+ *
+ * The test plays both servers. Messages go over stdin and stdout in
+ * the servers' framing (a 4-byte big-endian length, then the payload):
+ *
+ *   in:  wallet, the group's tokens (one frame each, then an empty frame)
+ *   out: S1 and S2 payloads of a payment     in: both receipts
+ *   out: S1 and S2 payloads of a settlement  in: both replies
+ *   out: the decrypted balances, as PSA_GROUP_SIZE little-endian int64s
+ *
+ * usage: harness DEST AMOUNT
+ */
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "payapp.h"
+
+#define MAX_TOKENS 16
+
+static void check(PsaStatus got, PsaStatus want, const char *what) {
+    if (got != want) {
+        fprintf(stderr, "%s: status %d, expected %d\n", what, (int)got, (int)want);
+        exit(1);
+    }
+}
+
+static void send_frame(const uint8_t *data, size_t len) {
+    uint8_t hdr[4] = {len >> 24, len >> 16, len >> 8, len};
+    if (fwrite(hdr, 1, 4, stdout) != 4 || fwrite(data, 1, len, stdout) != len) {
+        exit(1);
+    }
+    fflush(stdout);
+}
+
+static void send_buffer(PsaBuffer buf) {
+    send_frame(buf.data, buf.len);
+    psa_buffer_free(buf);
+}
+
+/* The caller frees the frame */
+static uint8_t *recv_frame(size_t *len) {
+    uint8_t hdr[4];
+    if (fread(hdr, 1, 4, stdin) != 4) {
+        exit(1);
+    }
+    *len = (size_t)hdr[0] << 24 | (size_t)hdr[1] << 16 | (size_t)hdr[2] << 8 | hdr[3];
+    uint8_t *data = malloc(*len + 1);
+    if (fread(data, 1, *len, stdin) != *len) {
+        exit(1);
+    }
+    return data;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: harness DEST AMOUNT\n");
+        return 2;
+    }
+    uint32_t dest = (uint32_t)strtoul(argv[1], NULL, 10);
+    uint32_t amount = (uint32_t)strtoul(argv[2], NULL, 10);
+    size_t len;
+    uint8_t *data;
+
+    PsaWallet *wallet;
+    data = recv_frame(&len);
+    check(psa_wallet_from_bytes(data, len, &wallet), PSA_STATUS_OK, "wallet");
+    free(data);
+
+    const PsaGroupToken *tokens[MAX_TOKENS];
+    size_t n_tokens = 0;
+    while ((data = recv_frame(&len)), len > 0 && n_tokens < MAX_TOKENS) {
+        PsaGroupToken *token;
+        check(psa_group_token_from_bytes(data, len, &token), PSA_STATUS_OK, "group token");
+        tokens[n_tokens++] = token;
+        free(data);
+    }
+    free(data);
+
+    /* Bad arguments are refused rather than crashing */
+    PsaTransaction *tx;
+    check(psa_prepare_transaction(NULL, 7, tokens, n_tokens, dest, amount, &tx), PSA_STATUS_NULL_POINTER, "null wallet");
+    check(psa_prepare_transaction(wallet, 7, tokens, 0, dest, amount, &tx), PSA_STATUS_NOT_IN_GROUP, "no tokens");
+    uint8_t junk[3] = {1, 2, 3};
+    PsaWallet *bad;
+    check(psa_wallet_from_bytes(junk, sizeof junk, &bad), PSA_STATUS_MALFORMED, "junk wallet");
+
+    /* Pay, and check both servers' receipts */
+    PsaBuffer buf;
+    check(psa_prepare_transaction(wallet, 7, tokens, n_tokens, dest, amount, &tx), PSA_STATUS_OK, "prepare transaction");
+    check(psa_transaction_s1_payload(tx, &buf), PSA_STATUS_OK, "S1 payload");
+    send_buffer(buf);
+    check(psa_transaction_s2_payload(tx, &buf), PSA_STATUS_OK, "S2 payload");
+    send_buffer(buf);
+    size_t len1, len2;
+    uint8_t *receipt1 = recv_frame(&len1);
+    uint8_t *receipt2 = recv_frame(&len2);
+    check(psa_transaction_check_receipts(tx, wallet, receipt1, len1, receipt2, len2), PSA_STATUS_OK, "receipts");
+    check(psa_transaction_check_receipts(tx, wallet, receipt2, len2, receipt1, len1), PSA_STATUS_WRONG_TRANSACTION, "swapped receipts");
+    free(receipt1);
+    free(receipt2);
+    psa_transaction_free(tx);
+
+    /* Settle, and decrypt the group's balances */
+    PsaSettlement *settlement;
+    uint64_t epoch;
+    check(psa_prepare_settle(wallet, &settlement), PSA_STATUS_OK, "prepare settle");
+    check(psa_settlement_s1_payload(settlement, &buf), PSA_STATUS_OK, "S1 settle payload");
+    send_buffer(buf);
+    check(psa_settlement_s2_payload(settlement, &buf), PSA_STATUS_OK, "S2 settle payload");
+    send_buffer(buf);
+    check(psa_settlement_epoch(settlement, &epoch), PSA_STATUS_OK, "epoch");
+    psa_settlement_free(settlement);
+
+    int64_t balances[PSA_GROUP_SIZE];
+    uint8_t *reply1 = recv_frame(&len1);
+    uint8_t *reply2 = recv_frame(&len2);
+    check(psa_decrypt_balances(wallet, epoch, reply1, len1, reply2, len2, balances, 3), PSA_STATUS_BUFFER_TOO_SMALL, "short output");
+    check(psa_decrypt_balances(wallet, epoch, reply1, len1, reply2, len2, balances, PSA_GROUP_SIZE), PSA_STATUS_OK, "decrypt balances");
+    free(reply1);
+    free(reply2);
+
+    uint8_t out[8 * PSA_GROUP_SIZE];
+    for (size_t i = 0; i < PSA_GROUP_SIZE; i++) {
+        for (size_t j = 0; j < 8; j++) {
+            out[8 * i + j] = (uint8_t)((uint64_t)balances[i] >> (8 * j));
+        }
+    }
+    send_frame(out, sizeof out);
+
+    for (size_t i = 0; i < n_tokens; i++) {
+        psa_group_token_free((PsaGroupToken *)tokens[i]);
+    }
+    psa_wallet_free(wallet);
+    return 0;
+}
@@ -0,0 +1,171 @@
+// Operations on whole vectors of group elements, for the servers'
+// database updates and the checks on a transaction's DPF outputs.
+//
+// Slices are split into chunks of BATCH_CHUNK elements that run on the
+// rayon pool, so short vectors like the payment database stay on one
+// thread. Long sums accumulate with the lazy operations and reduce once
+// a chunk; element-wise updates reduce every element, as each one is
+// stored.
+use rayon::prelude::*;
+
+use crate::Group;
+
+// The most elements one thread handles at a time
+const BATCH_CHUNK: usize = 1 << 14;
+
+// xs[i] += ys[i]
+pub fn add_assign<T: Group + Send + Sync>(xs: &mut [T], ys: &[T]) {
+    assert_eq!(xs.len(), ys.len());
+    xs.par_chunks_mut(BATCH_CHUNK)
+        .zip(ys.par_chunks(BATCH_CHUNK))
+        .for_each(|(xs, ys)| {
+            for (x, y) in xs.iter_mut().zip(ys) {
+                x.add(y);
+            }
+        });
+}
+
+// xs[i] -= ys[i]
+pub fn sub_assign<T: Group + Send + Sync>(xs: &mut [T], ys: &[T]) {
+    assert_eq!(xs.len(), ys.len());
+    xs.par_chunks_mut(BATCH_CHUNK)
+        .zip(ys.par_chunks(BATCH_CHUNK))
+        .for_each(|(xs, ys)| {
+            for (x, y) in xs.iter_mut().zip(ys) {
+                x.sub(y);
+            }
+        });
+}
+
+// xs[i] += ys[i] - zs[i], in one pass over xs
+pub fn add_sub_assign<T: Group + Send + Sync>(xs: &mut [T], ys: &[T], zs: &[T]) {
+    assert!(xs.len() == ys.len() && xs.len() == zs.len());
+    xs.par_chunks_mut(BATCH_CHUNK)
+        .zip(ys.par_chunks(BATCH_CHUNK))
+        .zip(zs.par_chunks(BATCH_CHUNK))
+        .for_each(|((xs, ys), zs)| {
+            for ((x, y), z) in xs.iter_mut().zip(ys).zip(zs) {
+                x.add(y);
+                x.sub(z);
+            }
+        });
+}
+
+fn sum_chunk<T: Group>(xs: &[T]) -> T {
+    let mut total = T::zero();
+    for x in xs {
+        total.add_lazy(x);
+    }
+    total.reduce();
+    total
+}
+
+fn add_reduced<T: Group>(mut x: T, y: T) -> T {
+    x.add(&y);
+    x
+}
+
+// The sum of xs
+pub fn sum<T: Group + Send + Sync>(xs: &[T]) -> T {
+    xs.par_chunks(BATCH_CHUNK)
+        .map(sum_chunk)
+        .reduce(T::zero, add_reduced)
+}
+
+// <xs, (0, 1, 2, ...)>, the sum of each element times its index
+pub fn index_inner_product<T>(xs: &[T]) -> T
+where
+    T: Group + From<u32> + Clone + Send + Sync,
+{
+    // Every index fits in a u32. Compared in u64, as u32::MAX + 1
+    // overflows a 32-bit usize.
+    assert!(xs.len() as u64 <= 1 << 32);
+    xs.par_chunks(BATCH_CHUNK)
+        .enumerate()
+        .map(|(c, chunk)| {
+            let mut total = T::zero();
+            for (j, x) in chunk.iter().enumerate() {
+                let mut term = x.clone();
+                term.mul_lazy(&T::from((c * BATCH_CHUNK + j) as u32));
+                total.add_lazy(&term);
+            }
+            total.reduce();
+            total
+        })
+        .reduce(T::zero, add_reduced)
+}
+
+// The sums of consecutive groups of `width` elements, such as the slots
+// of each group of the database. A short last group is summed as is.
+// Groups are short, so they are summed without lazy reduction.
+pub fn group_sums<T: Group + Send + Sync>(xs: &[T], width: usize) -> Vec<T> {
+    assert!(width > 0);
+    xs.par_chunks(width)
+        .with_min_len((BATCH_CHUNK / width).max(1))
+        .map(|group| group.iter().fold(T::zero(), |mut total, x| {
+            total.add(x);
+            total
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{FieldElm, Share};
+
+    // Long enough to take several chunks, and not a multiple of them
+    const LEN: usize = 2 * BATCH_CHUNK + 37;
+
+    #[test]
+    fn element_wise() {
+        let xs: Vec<u64> = (0..LEN).map(|_| u64::random()).collect();
+        let ys: Vec<u64> = (0..LEN).map(|_| u64::random()).collect();
+        let zs: Vec<u64> = (0..LEN).map(|_| u64::random()).collect();
+
+        let mut out = xs.clone();
+        add_sub_assign(&mut out, &ys, &zs);
+        for i in 0..LEN {
+            let mut expected = xs[i];
+            expected.add(&ys[i]);
+            expected.sub(&zs[i]);
+            assert_eq!(out[i], expected);
+        }
+
+        add_assign(&mut out, &zs);
+        sub_assign(&mut out, &ys);
+        assert_eq!(out, xs);
+    }
+
+    #[test]
+    fn sums() {
+        let xs: Vec<u64> = (0..LEN).map(|_| u64::random()).collect();
+        let mut total = 0u64;
+        let mut weighted = 0u64;
+        for (i, x) in xs.iter().enumerate() {
+            total.add(x);
+            let mut term = *x;
+            term.mul(&(i as u64));
+            weighted.add(&term);
+        }
+        assert_eq!(sum(&xs), total);
+        assert_eq!(index_inner_product(&xs), weighted);
+
+        let groups = group_sums(&xs, 10);
+        assert_eq!(groups.len(), LEN.div_ceil(10));
+        assert_eq!(groups[3], sum(&xs[30..40]));
+        assert_eq!(groups[groups.len() - 1], sum(&xs[LEN - LEN % 10..]));
+        assert_eq!(sum(&groups), total);
+    }
+
+    #[test]
+    fn scalar_field() {
+        let xs: Vec<FieldElm> = (0..500u32).map(FieldElm::from).collect();
+        // 0 + 1 + ... + 499, and 0^2 + 1^2 + ... + 499^2
+        assert_eq!(sum(&xs), FieldElm::from(124750u32));
+        assert_eq!(index_inner_product(&xs), FieldElm::from(41541750u32));
+        assert_eq!(group_sums(&xs, 10)[1], FieldElm::from(145u32));
+        assert_eq!(sum::<FieldElm>(&[]), FieldElm::zero());
+    }
+}
@@ -15,7 +15,7 @@ use curve25519_dalek::ristretto::CompressedRistretto;
 use curve25519_dalek::constants as dalek_constants;
 use curve25519_dalek::traits::Identity;
 use sha2::Sha512;
-use payapp::sketch::SketchDPFKey;
+use payapp::sketch::SketchPointDPFKey;
 use std::convert::TryInto;
 use std::ops::Neg;
 use std::time::{Duration, SystemTime};
@@ -28,11 +28,21 @@ use sha2::Digest;
 use payapp::ps::*;
 use payapp::ggm::*;
 use payapp::coms::*;
+use payapp::keys::{GroupMasterSecret, SettleError};
+use payapp::invite::{invite, accept_invitation, register_credential, Invitation, MemberKeyPair};
+use payapp::transparency::{fetch_issuer_key, identity_from_text, AttestedIssuerKey, KeyPin, TransparencyError};
+use payapp::client::{account_index, combine_balances, prepare_settle, prepare_transaction, receipts_match};
+use payapp::receipt::ReceiptPair;
+use payapp::creation::{CreationError, CreationPass, GroupCreateRequest, PeerGroupRequest};
+use payapp::ringproof::{decompress_ring, RingProof, RingWitness};
 use payapp::Group;
 use payapp::u32_to_bits;
+use payapp::{read_frame, write_frame};
+use payapp::GROUP_TOKEN_SIZE;
 use payapp::my_u32_to_bits;
+use payapp::settle_epoch;
 use payapp::FieldElm;
-use payapp::dpf::DPFKey;
+use payapp::dpf::PointDPFKey;
 use payapp::MAX_GROUP_SIZE;
 use payapp::MAX_GROUP_NUM;
 use payapp::DPF_DOMAIN;
@@ -43,6 +53,10 @@ pub const SERVER2: &str = "127.0.0.1:7879";
 // pub const SERVER1: &str = "35.197.55.229:7878";
 // pub const SERVER2: &str = "34.173.21.162:7879";
 pub const TRIALS: usize = 50;
+// The servers' identity keys, as the operator hands them out (see
+// transparency.rs)
+pub const SERVER1_PUB: &str = "server1.pub";
+pub const SERVER2_PUB: &str = "server2.pub";
 
 lazy_static! {
     pub static ref GEN_G: RistrettoPoint =
@@ -50,31 +64,62 @@ lazy_static! {
     pub static ref GEN_H: RistrettoPoint = dalek_constants::RISTRETTO_BASEPOINT_POINT;
 }
 
-fn setup_group(group_size: usize) -> Result<Vec<GroupTokenPriv>, std::io::Error> {
+// Pin the attested issuer key against the servers' identity keys, if
+// we were given them, and on first use otherwise
+fn pin_issuer_key(issuer_key: &AttestedIssuerKey) -> Result<KeyPin, TransparencyError> {
+    let read = |path| std::fs::read_to_string(path).ok().and_then(|text| identity_from_text(&text));
+    match (read(SERVER1_PUB), read(SERVER2_PUB)) {
+        (Some(s1), Some(s2)) => KeyPin::with_identities(&[s1, s2], issuer_key),
+        _ => {
+            eprintln!("no {} and {}: trusting the servers' identities on first use", SERVER1_PUB, SERVER2_PUB);
+            KeyPin::new(issuer_key)
+        }
+    }
+}
+
+fn setup_group(group_size: usize, pass: &CreationPass) -> Result<Vec<GroupTokenPriv>, std::io::Error> {
 
-    let mut leader = GpLeaderData::new(MAX_GROUP_SIZE);
+    let mut leader = GpLeaderData::new(group_size);
     let mut stream1 = TcpStream::connect(SERVER1)?;
+    let mut stream2 = TcpStream::connect(SERVER2)?;
 
     // GROUP SETUP
     let now = SystemTime::now();
-    // Send group creation request to the server
-    let mut encoded: Vec<u8> = Vec::new();
-    let key_bytes1 = rand::thread_rng().gen::<[u8; 16]>();
-    let key_bytes2 = rand::thread_rng().gen::<[u8; 16]>();
-    let prf_keys = (key_bytes1.to_vec(), key_bytes2.to_vec());
-    let mut rng = rand::thread_rng();
-    encoded.push(1u8);
-    encoded.extend(bincode::serialize(&prf_keys).unwrap());
-    stream1.write(&encoded).expect("failed to write");
+    // Send group creation request to the server, with the operator's
+    // pass for creating groups
+    let group_secret = GroupMasterSecret::random();
+    let request = GroupCreateRequest {
+        s1_key: group_secret.server_key(1),
+        leader_pk: leader.leader.pk.compress(),
+        pass: pass.clone(),
+    };
+    stream1.write_all(&[1u8])?;
+    write_frame(&mut stream1, &bincode::serialize(&request).unwrap())?;
 
-    // The server responds with a list of account IDs and a public key
-    let mut buf = [0;40192];
-    let mut bytes_read = 0;
-    while bytes_read == 0 {
-        bytes_read = stream1.read(&mut buf)?;
-    }
-    let (aids, pubkey): (Vec<u64>, IssuerPubKey) = bincode::deserialize(&buf[0..bytes_read]).unwrap();
-    let creds = leader.group_setup(aids, &stream1, pubkey.clone())?;
+    // The server responds with a list of account IDs
+    let response: Result<Vec<u64>, CreationError> = bincode::deserialize(&read_frame(&mut stream1)?).unwrap();
+    let aids = response.map_err(|e| Error::new(io::ErrorKind::PermissionDenied, format!("{:?}", e)))?;
+    let group_num = (aids[0] as usize / MAX_GROUP_SIZE) as u32;
+
+    // Register the group, S2's key and our public key with S2 directly;
+    // S2 checks our slot requests itself
+    let request = PeerGroupRequest {
+        group: group_num,
+        s2_key: group_secret.server_key(2),
+        leader_pk: leader.leader.pk.compress(),
+        pass: pass.clone(),
+    };
+    stream2.write_all(&[1u8])?;
+    write_frame(&mut stream2, &bincode::serialize(&request).unwrap())?;
+    let response: Result<(), CreationError> = bincode::deserialize(&read_frame(&mut stream2)?).unwrap();
+    response.map_err(|e| Error::new(io::ErrorKind::PermissionDenied, format!("{:?}", e)))?;
+
+    // Credentials carry a MAC under each of S1's and S2's issuer keys,
+    // which both servers attest to
+    let issuer_key = fetch_issuer_key(&stream1, &stream2)?;
+    let pin = pin_issuer_key(&issuer_key).map_err(|e| Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+    let shares = pin.shares().to_vec();
+    let creds = leader.group_setup(aids, &stream1, &stream2, &shares)?;
     match now.elapsed() {
         Ok(elapsed) => {
             println!("Setup Time: {:?}", (elapsed.as_nanos() as f64) / (1000000000 as f64));
@@ -90,29 +135,8 @@ fn setup_group(group_size: usize) -> Result<Vec<GroupTokenPriv>, std::io::Error>
     let mut tokens = Vec::<GroupTokenPriv>::new();
     let now = SystemTime::now(); 
     for i in 0..1 {   
-        let now = SystemTime::now(); 
-        let (z3, showmsg) = show_blind345_5::show(&creds[i], &pubkey);
-        match now.elapsed() {
-            Ok(elapsed) => {
-                // it prints '2'
-                println!("Reg time {}", elapsed.as_nanos() as f64 / (1000000000 as f64));
-            }
-            Err(e) => {
-                // an error occurred!
-                println!("Error: {e:?}");
-            }
-        }
-        let mut encoded: Vec<u8> = Vec::new();
-        encoded.push(3u8);
-        encoded.extend(bincode::serialize(&showmsg).unwrap());
-        stream1.write(&encoded).expect("failed to write");
         let now = SystemTime::now();
-        let mut buf = [0;136];
-        let mut bytes_read = 136;
-        let mut res = stream1.read_exact(&mut buf);
-        while !res.is_ok() {
-            res = stream1.read_exact(&mut buf);
-        }
+        let (zs, group_token) = register_credential(&creds[i], &shares, &stream1, &stream2)?;
         match now.elapsed() {
             Ok(elapsed) => {
                 // it prints '2'
@@ -124,12 +148,12 @@ fn setup_group(group_size: usize) -> Result<Vec<GroupTokenPriv>, std::io::Error>
             }
         }
         let now = SystemTime::now();
-        let group_token: GroupToken = bincode::deserialize(&buf[0..bytes_read]).unwrap();
         let priv_token = GroupTokenPriv {
-            prf_keys: prf_keys.clone(),
+            group_secret: group_secret.clone(),
             token: group_token.clone(), 
-            z3: z3, 
+            z3: zs[3], 
             aid: creds[i].m[3],
+            issuer_key: pin.clone(),
         };
         tokens.push(priv_token.clone());
         match now.elapsed() {
@@ -143,155 +167,59 @@ fn setup_group(group_size: usize) -> Result<Vec<GroupTokenPriv>, std::io::Error>
             }
         }
     }
-    Ok(tokens)
-}
 
-fn prepare_transaction(start: u32, tokens: Vec<GroupTokenPriv>, print: bool) -> (TransactionData, TransactionDataS2) {
-    let now = SystemTime::now();
-    let mut my_tokens = Vec::<GroupToken>::new();
-    for j in 0..tokens.len() {
-        my_tokens.push(tokens[j].token.clone());
+    // A group set up below full size can take on another member later
+    let mut member_creds: Vec<Credential> = creds.into_iter().skip(1).collect();
+    if group_size < MAX_GROUP_SIZE {
+        member_creds.push(leader.add_member(&stream1, &stream2, &shares)?);
     }
-    let mut count: u32 = start;
-    let bytes = tokens[0].aid.to_bytes();
-    let (int_bytes, rest) = bytes.split_at(std::mem::size_of::<u32>());
-    let src: u32 = u32::from_le_bytes(int_bytes.try_into().unwrap());
-    let mut betas = Vec::<FieldElm>::new();
-    for i in 0..DPF_DOMAIN - 2 {
-        betas.push(FieldElm::zero());
+
+    // The leader hands the remaining slots out as invitations. Each member
+    // opens theirs and registers the credential over their own connection.
+    for cred in member_creds {
+        let member = MemberKeyPair::new();
+        let text = invite(group_num, cred, &issuer_key, &group_secret, &member.pk).to_text();
+
+        let invitation = Invitation::from_text(&text).expect("invalid invitation");
+        let member_stream1 = TcpStream::connect(SERVER1)?;
+        let member_stream2 = TcpStream::connect(SERVER2)?;
+        match accept_invitation(&invitation, &member, &member_stream1, &member_stream2) {
+            Ok(priv_token) => tokens.push(priv_token),
+            Err(e) => println!("Error: {e:?}"),
+        }
     }
-    betas.push(FieldElm::from(20u32));
-    let a_src = my_u32_to_bits(DPF_DOMAIN.try_into().unwrap(), src);
-    let a_dest = my_u32_to_bits(DPF_DOMAIN.try_into().unwrap(), src + 3);
-    let beta_last = FieldElm::from(0u32);
-    let keys_src = SketchDPFKey::gen(&a_src, &betas, &beta_last);
-    let keys_dest = SketchDPFKey::gen(&a_dest, &betas, &beta_last);
-    // Randomness
-    // =======================================================
-    let mut rng = rand::thread_rng();
-    let r1 = Scalar::random(&mut rng);
-    let r2_1 = Scalar::random(&mut rng);
-    let r2_2 = Scalar::random(&mut rng);
-    let r2 = r2_1 + r2_2;
-    let r3_1 = Scalar::random(&mut rng);
-    let r3_2 = Scalar::random(&mut rng);
-    let r3 = r3_1 + r3_2;
-    // =======================================================
-    let G: &RistrettoPoint = &GEN_G;
-    let H: &RistrettoPoint = &GEN_H;
-    let nG = G.clone().neg();
-    let nH = H.clone().neg();
-    let id = Scalar::one();
-    let a_sc = Scalar::from(src);
-    let b_sc = Scalar::from(20u32);
-    let v1 = G * r1;
-    let v2 = G * r2;
-    let v3 = G * r3;
-    let e1 = G * a_sc + H * r1;
-    let e2 = G * b_sc + H * r2;
-    let ab_sc = a_sc * b_sc;
-    let e3 = G * ab_sc + H * r3;
-    let tau = a_sc * r2;
-    let ne3 = e3.clone().neg();
-    let mut transcript = Transcript::new(b"Transaction Proof");
-    let transact_pf = transaction::prove_compact(
-        &mut transcript,
-        transaction::ProveAssignments {
-            G: &G,
-            H: &H,
-            nG: &nG,
-            nH: &nH,
-            v1: &v1,
-            v2: &v2,
-            v3: &v3,
-            e1: &e1,
-            e2: &e2,
-            ne3: &ne3,
-            r1: &r1,
-            r3: &r3,
-            a: &a_sc,
-            id: &id,
-            tau: &tau,
-        },
-    )
-    .0;
-    let token_ci = tokens[0].token.cm_aid.decompress().expect("REASON");
-    let token_P = tokens[0].token.P.decompress().expect("REASON");
-    let mut transcript = Transcript::new(b"Group Token Proof");
-    let token_pf = token::prove_compact(
-        &mut transcript,
-        token::ProveAssignments {
-            G: &G,
-            H: &H,
-            P: &token_P,
-            Ti: &token_ci,
-            Ci: &e1,
-            i: &a_sc,
-            rt: &tokens[0].z3,
-            rc: &r1,
-        },
-    )
-    .0;
-    // Package data to send to the servers 
-    let transact_data1 = TransactionData {
-        tokens: my_tokens.clone(),
-        id: count,
-        dpf_src: keys_src[0].clone(),
-        dpf_dest: keys_dest[0].clone(),
-        g_r1: v1.compress(),
-        r2: r2_1,
-        r3: r3_1,
-        com_i: e1.compress(),
-        triple_proof: transact_pf.clone(),
-        token_proof: token_pf.clone(),
-    };
-    let transact_data2 = TransactionDataS2 {
-        id: count,
-        dpf_src: keys_src[1].clone(),
-        dpf_dest: keys_dest[1].clone(),
-        g_r1: v1.compress(),
-        r2: r2_2,
-        r3: r3_2,
-        com_i: e1.compress(),
-    };
-    (transact_data1, transact_data2)
+    Ok(tokens)
 }
 
-fn send_transaction(transact_data1: &TransactionData, transact_data2: &TransactionDataS2) -> io::Result<( )>{
+// Both servers answer with a signed receipt, checked against the server
+// identities the payer pinned
+fn send_transaction(transact_data1: &TransactionData, transact_data2: &TransactionDataS2, pin: &KeyPin) -> io::Result<ReceiptPair> {
     let mut stream1 = TcpStream::connect(SERVER1)?;
     let mut stream2 = TcpStream::connect(SERVER2)?;
 
     // Send to S1
-    let mut encoded1: Vec<u8> = Vec::new();
-    encoded1.push(4u8);
-    encoded1.extend(bincode::serialize(&transact_data1).unwrap());
-    stream1.write(&encoded1).expect("failed to write");
+    stream1.write_all(&[4u8]).expect("failed to write");
+    write_frame(&mut stream1, &bincode::serialize(&transact_data1).unwrap()).expect("failed to write");
     // Send to S2
-    let mut encoded2: Vec<u8> = Vec::new();
-    encoded2.push(4u8);
-    encoded2.extend(bincode::serialize(&transact_data2).unwrap());
-    stream2.write(&encoded2).expect("failed to write");
+    stream2.write_all(&[4u8]).expect("failed to write");
+    write_frame(&mut stream2, &bincode::serialize(&transact_data2).unwrap()).expect("failed to write");
 
-    // Make sure transaction was valid 
-    let mut buf = [0;40192];
-    let mut bytes_read = 0;
-    while bytes_read == 0 {
-        bytes_read = stream1.read(&mut buf)?;
-    }
-    let msg: String = bincode::deserialize(&buf[0..bytes_read]).unwrap();
-    if msg != String::from("Transaction Processed") {
-        println!("Uh oh! Submitted invalid transaction.");
+    // Make sure transaction was valid. A server that couldn't read its
+    // part answers with an empty frame instead of a receipt.
+    let invalid = |e| Error::new(io::ErrorKind::InvalidData, e);
+    let (reply1, reply2) = (read_frame(&mut stream1)?, read_frame(&mut stream2)?);
+    if reply1.is_empty() || reply2.is_empty() {
+        return Err(Error::new(io::ErrorKind::InvalidData, "a server could not read the transaction"));
     }
-    let mut buf = [0;40192];
-    let mut bytes_read = 0;
-    while bytes_read == 0 {
-        bytes_read = stream2.read(&mut buf)?;
-    }
-    let msg: String = bincode::deserialize(&buf[0..bytes_read]).unwrap();
-    if msg != String::from("Transaction Processed") {
-        println!("Uh oh! Submitted invalid transaction.");
+    let receipts = ReceiptPair {
+        s1: bincode::deserialize(&reply1).map_err(invalid)?,
+        s2: bincode::deserialize(&reply2).map_err(invalid)?,
+    };
+    if !receipts_match(transact_data1, transact_data2, &receipts) {
+        return Err(Error::new(io::ErrorKind::InvalidData, "receipt is for another transaction"));
     }
-    Ok(())
+    receipts.verify(pin).map_err(|e| Error::new(io::ErrorKind::PermissionDenied, format!("transaction refused: {:?}", e)))?;
+    Ok(receipts)
 }
 
 fn settle(token: GroupTokenPriv, group_num: u32) -> io::Result<( )> {
@@ -299,93 +227,62 @@ fn settle(token: GroupTokenPriv, group_num: u32) -> io::Result<( )> {
     let mut stream2 = TcpStream::connect(SERVER2)?;
     println!("Settling Group #{:?}", group_num);
     let now = SystemTime::now();
-    // DPF Key generation
-    let alpha_bits = my_u32_to_bits(SETTLE_DOMAIN.try_into().unwrap(), group_num);
-    let mut values = Vec::<FieldElm>::new();
-    for i in 0..SETTLE_DOMAIN - 2 {
-        values.push(FieldElm::zero());
-    }
-    values.push(FieldElm::from(1u32));
-    let (key1, key2) = DPFKey::gen(&alpha_bits, &values, &FieldElm::zero());
-    let r_bytes = rand::thread_rng().gen::<[u8; 16]>();
+    let (s1_data, s2_data) = prepare_settle(group_num, settle_epoch());
+    let epoch = s1_data.epoch;
     // Send to S1
-    let s1_data = SettleData {
-        dpf_key: key1,
-        r_seed: r_bytes.to_vec(),
-    };
-    let mut encoded1: Vec<u8> = Vec::new();
-    encoded1.push(5u8);
-    encoded1.extend(bincode::serialize(&s1_data).unwrap());
-    stream1.write(&encoded1).expect("failed to write");
+    stream1.write_all(&[5u8]).expect("failed to write");
+    write_frame(&mut stream1, &bincode::serialize(&s1_data).unwrap()).expect("failed to write");
     // Send to S2
-    let s2_data = SettleData {
-        dpf_key: key2,
-        r_seed: r_bytes.to_vec(),
-    };
-    let mut encoded2: Vec<u8> = Vec::new();
-    encoded2.push(5u8);
-    encoded2.extend(bincode::serialize(&s2_data).unwrap());
-    stream2.write(&encoded2).expect("failed to write");
+    stream2.write_all(&[5u8]).expect("failed to write");
+    write_frame(&mut stream2, &bincode::serialize(&s2_data).unwrap()).expect("failed to write");
 
-    let mut buf1 = [0;8192];
-    let mut bytes_read1 = 0;
-    while bytes_read1 == 0 {
-        bytes_read1 = stream1.read(&mut buf1)?;
-    }
-    let mut buf2 = [0;8192];
-    let mut bytes_read2 = 0;
-    while bytes_read2 == 0 {
-        bytes_read2 = stream2.read(&mut buf2)?;
-    }
-    let bv_1: Vec<FieldElm> = bincode::deserialize(&buf1[0..bytes_read1]).unwrap();
-    let bv_2: Vec<FieldElm> = bincode::deserialize(&buf2[0..bytes_read2]).unwrap();
-    let mut bv = Vec::<FieldElm>::new();
-    for i in 0..MAX_GROUP_SIZE {
-        let mut sum = FieldElm::zero();
-        sum.add(&bv_1[i]);
-        sum.add(&bv_2[i]);
-        bv.push(sum);
-    }
-    let bv = GroupTokenPriv::decrypt_db(bv.clone(), token.prf_keys.0, token.prf_keys.1, r_bytes.to_vec());
+    // Either server refuses a stale epoch, and then both do
+    let reply1: Result<Vec<FieldElm>, SettleError> = bincode::deserialize(&read_frame(&mut stream1)?).unwrap();
+    let reply2: Result<Vec<FieldElm>, SettleError> = bincode::deserialize(&read_frame(&mut stream2)?).unwrap();
+    let (bv_1, bv_2) = match (reply1, reply2) {
+        (Ok(bv_1), Ok(bv_2)) => (bv_1, bv_2),
+        (Err(e), _) | (_, Err(e)) => return Err(Error::new(io::ErrorKind::PermissionDenied, format!("{:?}", e))),
+    };
+    let bv = combine_balances(&bv_1, &bv_2, &token.group_secret, group_num, epoch);
     Ok(())
 }
 
 fn main() -> io::Result<( )> {
 
     // Setup Groups
-    let mut thread_vec: Vec<thread::JoinHandle<Result<(), Error>>> = Vec::new();
-    let priv_tokens1 = setup_group(MAX_GROUP_SIZE - 1).unwrap();
-    let priv_tokens2 = setup_group(MAX_GROUP_SIZE).unwrap();
-    let priv_tokens4 = setup_group(MAX_GROUP_SIZE).unwrap();
-    let priv_tokens5 = setup_group(MAX_GROUP_SIZE).unwrap();
-
-    let mut client1 = Vec::<GroupTokenPriv>::new();
-    client1.push(priv_tokens1[0].clone());
-
+    let mut thread_vec: Vec<thread::JoinHandle<Result<ReceiptPair, Error>>> = Vec::new();
+    // The operator's pass for creating groups (see operator.rs) is the
+    // first argument
+    let text = std::env::args().nth(1).expect("usage: clients <creation pass>");
+    let pass = CreationPass::from_text(&text).expect("invalid creation pass");
+    let priv_tokens1 = setup_group(MAX_GROUP_SIZE - 1, &pass).unwrap();
+    let priv_tokens2 = setup_group(MAX_GROUP_SIZE, &pass).unwrap();
+    let priv_tokens4 = setup_group(MAX_GROUP_SIZE, &pass).unwrap();
+    let priv_tokens5 = setup_group(MAX_GROUP_SIZE, &pass).unwrap();
 
-    let mut client2 = Vec::<GroupTokenPriv>::new();
-    client2.push(priv_tokens1[0].clone());
-
-
-    let mut client3 = Vec::<GroupTokenPriv>::new();
-    client3.push(priv_tokens1[0].clone());
-
-
-    let mut client4 = Vec::<GroupTokenPriv>::new();
-    client4.push(priv_tokens1[0].clone());
+    // Four members of the first group pay, each hidden among all of the
+    // group's tokens
+    let group1: Vec<GroupToken> = priv_tokens1.iter().map(|t| t.token.clone()).collect();
+    let client1 = priv_tokens1[0].clone();
+    let client2 = priv_tokens1[1].clone();
+    let client3 = priv_tokens1[2].clone();
+    let client4 = priv_tokens1[3].clone();
 
 
     let mut tdatavec = Vec::<(TransactionData, TransactionDataS2)>::new();
 
-    let mut val = false;
+    // Each pays 20 to the account three slots up. The servers refuse
+    // session ids they have seen, so start from a random one on each run.
+    let first: u32 = rand::thread_rng().gen();
+    let pay = |i: u32, payer: &GroupTokenPriv| {
+        prepare_transaction(first.wrapping_add(i), payer, &group1, account_index(&payer.aid) + 3, 20)
+            .map_err(|e| Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))
+    };
     for i in 0..50 {
-        if i == 0 {
-            val = true;
-        }
-        let (tdata1_1, tdata1_2) = prepare_transaction(i, client1.clone(), val);
-        let (tdata2_1, tdata2_2) = prepare_transaction(i + 50, client2.clone(), false);
-        let (tdata3_1, tdata3_2) = prepare_transaction(i + 100, client3.clone(), false);
-        let (tdata4_1, tdata4_2) = prepare_transaction(i + 150, client4.clone(), false);
+        let (tdata1_1, tdata1_2) = pay(i, &client1)?;
+        let (tdata2_1, tdata2_2) = pay(i + 50, &client2)?;
+        let (tdata3_1, tdata3_2) = pay(i + 100, &client3)?;
+        let (tdata4_1, tdata4_2) = pay(i + 150, &client4)?;
         tdatavec.push((tdata1_1, tdata1_2));
         tdatavec.push((tdata2_1, tdata2_2));
         tdatavec.push((tdata3_1, tdata3_2));
@@ -397,12 +294,13 @@ fn main() -> io::Result<( )> {
     for i in 0..TRIALS {
         let td1 = (tdatavec[i].0).clone();
         let td2 = (tdatavec[i].1).clone();
-        let handle = thread::spawn(move || {send_transaction(&td1, &td2)});
+        let pin = client1.issuer_key.clone();
+        let handle = thread::spawn(move || {send_transaction(&td1, &td2, &pin)});
         thread_vec.push(handle);
     }
 
     for handle in thread_vec {
-        handle.join().unwrap();
+        handle.join().unwrap()?;
     }
 
     // For Balance Retrieval Latency, uncomment the following lines and view the Total Time output. 
@@ -0,0 +1,51 @@
+// The operator's tool for handing out group creation passes.
+//
+// Run as `operator [quota]`: loads the operator key from OPERATOR_KEY
+// (creating it on first use), publishes its public key to redis (at
+// PSA_REDIS_URL, as for the servers) for S1 and S2, and prints a pass
+// for creating `quota` groups, to be given to the clients.
+use std::fs;
+use std::io;
+
+use redis::Commands;
+use redis::Connection;
+
+use payapp::creation::{CreationPass, OperatorKey};
+use payapp::{current_epoch, redis_url};
+
+pub const OPERATOR_KEY: &str = "operator.key";
+pub const PASS_LIFETIME: u64 = 30; // epochs
+pub const DEFAULT_QUOTA: u32 = 4;
+
+fn redis_connect() -> redis::RedisResult<Connection> {
+    let client = redis::Client::open(redis_url())?;
+    let con = client.get_connection()?;
+
+    Ok(con)
+}
+
+fn load_key() -> io::Result<OperatorKey> {
+    if let Ok(bytes) = fs::read(OPERATOR_KEY) {
+        return bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    let key = OperatorKey::new();
+    fs::write(OPERATOR_KEY, bincode::serialize(&key).unwrap())?;
+    Ok(key)
+}
+
+fn main() -> io::Result<()> {
+    let quota = match std::env::args().nth(1) {
+        Some(arg) => arg.parse().expect("usage: operator [quota]"),
+        None => DEFAULT_QUOTA,
+    };
+    let key = load_key()?;
+
+    let mut con = redis_connect().expect("failed to connect to redis");
+    let mut redis_key: Vec<u8> = Vec::new();
+    redis_key.extend([0u8, 1u8]); // OPERATOR, TYPE
+    let _ : () = con.set(redis_key, bincode::serialize(&key.pk.compress()).unwrap()).unwrap();
+
+    let pass = CreationPass::new(&key, quota, current_epoch() + PASS_LIFETIME);
+    println!("{}", pass.to_text());
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::fs;
 use std::io;
 use std ::net::{TcpListener,TcpStream};
 use std::io::{Read,Write};
@@ -5,41 +6,48 @@ use std::thread;
 use std::sync::Mutex;
 use std::ops::Deref;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 use std::ops::DerefMut;
-use hmac::{Hmac, Mac, NewMac};
 use rand_chacha::rand_core::SeedableRng;
 use rand_chacha::rand_core::RngCore;
 use std::convert::TryInto;
-use sha2::Sha256;
 use sha2::Digest;
 use rand_chacha::ChaCha8Rng;
-use rand::thread_rng;
-use rand::Rng;
+use redis::RedisResult;
 use redis::Connection;
 use redis::Commands;
 use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::ristretto::CompressedRistretto;
 use curve25519_dalek::scalar::Scalar;
 use payapp::ps::*;
 use payapp::ggm::*;
 use payapp::prg::PrgSeed;
+use payapp::keys::{KeyRegistry, SettleError, SettleLog};
+use payapp::tokens::TokenKeyring;
+use payapp::threshold::{IssuanceContext, IssuerShare, PartialResponse};
+use payapp::membership::{CloseRequest, GroupDirectory, MembershipError, RemovalRequest, SlotRequest};
+use payapp::creation::{CreationError, CreationLedger, GroupCreateRequest};
+use payapp::signature::SigningKey;
+use payapp::transparency::{identity_to_text, IssuerKeyRecord, KeyAttestation};
+use payapp::receipt::{share_digest, TransactionReceipt};
 use payapp::coms::*;
 use payapp::sketch::*;
 use payapp::mpc::*;
+use payapp::burst::Burst;
+use zkp::BatchableProof;
 use payapp::Group;
 use payapp::FieldElm;
 use payapp::MAX_GROUP_SIZE;
 use payapp::MAX_GROUP_NUM;
-use payapp::SETTLE_SIZE;
-use payapp::CRED_REQUEST_1;
-use payapp::TRANSACT_REQ_1;
+use payapp::{read_frame, write_frame};
+use payapp::{current_epoch, redis_url, settle_epoch, EPOCH_SECONDS};
 
-// pub const REDIS: &str = "redis://127.0.0.1:6379";
-pub const REDIS: &str = "redis://10.128.0.4:6379";
+pub const IDENTITY_KEY: &str = "server1.key";
+pub const IDENTITY_PUB: &str = "server1.pub";
 
-fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize>>, database: Arc<Mutex<Vec<FieldElm>>>, prf_keys: Arc<Mutex<Vec<Vec<u8>>>>, mac: &Hmac<Sha256>, streams: &u32) -> io::Result<()> {
+fn handle_client(mut stream: TcpStream, issuer: IssuerShare, peer: IssuerPubKey, counter: Arc<Mutex<usize>>, database: Arc<Mutex<Vec<FieldElm>>>, sequence: Arc<Mutex<u64>>, prf_keys: Arc<Mutex<KeyRegistry>>, settle_log: Arc<Mutex<SettleLog>>, session_log: Arc<Mutex<SessionLog>>, directory: Arc<Mutex<GroupDirectory>>, ledger: Arc<Mutex<CreationLedger>>, keyring: Arc<Mutex<TokenKeyring>>, shows: Arc<Burst<ShowMessage, VerifiedCredential>>, transactions: Arc<Burst<(TransactionStatement, BatchableProof), ()>>, identity: &SigningKey, attestation: &KeyAttestation, streams: &u32) -> io::Result<()> {
 
-    let mut server_data = ServerData::new(issuer);
+    let mut server_data = ServerData::new(issuer, peer);
     let con_try = redis_connect();
     let mut con: Connection = con_try.unwrap();
     let mut notHandled = true;
@@ -61,66 +69,151 @@ fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize
         // }
 
         // TYPE: NEW GROUP REQUEST
-        // Data: PRF Keys
+        // Data: Group Create Request (length-prefixed): our server key,
+        // leader public key and the operator's creation pass
         if buf[0] == 1 {
-            let bytes_read = 48;
-            let mut buf1 = [0;48];
-            stream.read_exact(&mut buf1)?;
+            let buf1 = read_frame(&mut stream)?;
+            let decoded: GroupCreateRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => {
+                    let refused: Result<Vec<usize>, CreationError> = Err(CreationError::BadRequest);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
+                }
+            };
             let mut guard = counter.lock().unwrap();
             let index = guard.deref();
             let group_num = (*index) / MAX_GROUP_SIZE; // GROUP NUM
-            let decoded: (Vec<u8>, Vec<u8>) = bincode::deserialize(&buf1[0..bytes_read]).unwrap();
-            // RECORD THIS SERVER'S PRF KEY
+            let mut ledger_guard = ledger.lock().unwrap();
+            let authorized = ledger_guard.authorize(&decoded.pass, *index);
+            if authorized.is_ok() {
+                let _ : () = con.set(vec![1u8, 9u8], ledger_guard.to_bytes()).unwrap(); // SERVER ID, TYPE
+            }
+            drop(ledger_guard);
+            if let Err(e) = authorized {
+                let result: Result<Vec<usize>, CreationError> = Err(e);
+                write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
+                continue;
+            }
+            // RECORD THIS SERVER'S KEY
             let mut key_guard = prf_keys.lock().unwrap();
-            (*key_guard).remove(MAX_GROUP_NUM - 1);
-            (*key_guard).insert(group_num, decoded.0);
-
-            // SEND S2 ITS PRF KEY
-            let mut key: Vec<u8> = Vec::new();
-            key.extend([2u8, 1u8]); // SERVER ID, TYPE
-            key.extend(group_num.to_be_bytes()); 
-            let _ : () = con.set(key.clone(), decoded.1).unwrap();
+            key_guard.register(group_num, decoded.s1_key);
+            drop(key_guard);
 
-            let (aids, pubkey) = server_data.setup_new_group(guard.deref());
-            let encoded = bincode::serialize(&(aids, pubkey)).unwrap();
-            let _ = stream.write(&encoded);
+            let aids = server_data.setup_new_group(guard.deref());
+            directory.lock().unwrap().create_group(group_num as u32, aids[0] as u64, decoded.leader_pk);
+            let result: Result<Vec<usize>, CreationError> = Ok(aids);
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
             *guard += MAX_GROUP_SIZE;
         }
+        // TYPE: ISSUER KEY ATTESTATION
+        // DATA: None
+        if buf[0] == 7 {
+            write_frame(&mut stream, &bincode::serialize(attestation).unwrap())?;
+        }
         // TYPE: SETUP REGISTRATION TOKENS
-        // DATA: Vector of Credential Requests
+        // DATA: Slot Request (length-prefixed), signed by the group leader
         if buf[0] == 2 {
-            let bytes_read = CRED_REQUEST_1;
-            let mut buf1 = [0;CRED_REQUEST_1];
-            let res = stream.read_exact(&mut buf1);
-            if !res.is_ok() {
-                println!("uh oh!");
-            }
-            let decoded: Vec<issue_blind124_5::CredentialRequest> = bincode::deserialize(&buf1[0..bytes_read]).unwrap();
-            let reg_tokens = server_data.setup_reg_tokens(decoded);
-            let encoded = bincode::serialize(&reg_tokens).unwrap();
-            let _ = stream.write(&encoded);
+            let buf1 = read_frame(&mut stream)?;
+            // Answer even a request we can't read, so the leader isn't
+            // left waiting. Slots are only marked issued once every
+            // credential request has checked out.
+            let result: Result<Vec<(IssuanceContext, PartialResponse)>, MembershipError> = bincode::deserialize::<SlotRequest>(&buf1)
+                .map_err(|_| MembershipError::BadRequest)
+                .and_then(|decoded| {
+                    let partials = server_data.setup_reg_tokens(&decoded.reqs)?;
+                    directory.lock().unwrap().claim_slots(&decoded).map(|_| partials)
+                });
+            let encoded = bincode::serialize(&result).unwrap();
+            write_frame(&mut stream, &encoded)?;
         }
 
         // TYPE: USER REGISTRATION
-        // DATA: Show Message
+        // DATA: Registration Request (length-prefixed)
         if buf[0] == 3 {
-            let bytes_read = 264;
-            let mut buf1 = [0;264];
-            stream.read_exact(&mut buf1)?;
-            let decoded: show_blind345_5::ShowMessage = bincode::deserialize(&buf1[0..bytes_read]).unwrap();
-            let group_token = server_data.register_user(decoded, &mac).unwrap();
+            let buf1 = read_frame(&mut stream)?;
+            let decoded: RegistrationRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => break,
+            };
+            // S2's acknowledgement, left by S2 before it acked the client
+            let mut key: Vec<u8> = Vec::new();
+            key.extend([2u8, 6u8]); // SERVER ID, TYPE
+            key.extend(decoded.showmsg.P.compress().to_bytes());
+            let ack_res: RedisResult<Vec<u8>> = con.get(key.clone());
+            let _ : RedisResult<()> = con.del(key);
+            let peer_ack = ack_res.unwrap_or_default();
+            // Our part of the presentation is checked along with those
+            // of other registrations coming in at the same time
+            let verified = shows.check(decoded.showmsg.clone());
+            let registered = verified.ok_or(ring::error::Unspecified).and_then(|ver_cred| {
+                server_data.register_verified(&decoded, &ver_cred, &peer_ack, &keyring.lock().unwrap())
+            });
+            let group_token = match registered {
+                Ok(token) => token,
+                // Invalid or expired credential: hang up on the client
+                Err(_) => break,
+            };
+            // Payments must take every live token of the group as the ring
+            if directory.lock().unwrap().register_token(decoded.group, &group_token).is_err() {
+                break;
+            }
             let encoded = bincode::serialize(&group_token).unwrap();
             let _ = stream.write_all(&encoded);
         }
 
+        // TYPE: MEMBER REMOVAL
+        // DATA: Removal Request (length-prefixed), signed by the group leader
+        if buf[0] == 6 {
+            let buf1 = read_frame(&mut stream)?;
+            let decoded: RemovalRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => {
+                    let refused: Result<(), MembershipError> = Err(MembershipError::BadRequest);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
+                }
+            };
+            let result: Result<(), MembershipError> = directory.lock().unwrap().remove_member(&decoded);
+            let encoded = bincode::serialize(&result).unwrap();
+            write_frame(&mut stream, &encoded)?;
+        }
+
+        // TYPE: GROUP CLOSE
+        // DATA: Close Request (length-prefixed), signed by the group leader
+        if buf[0] == 8 {
+            let buf1 = read_frame(&mut stream)?;
+            let decoded: CloseRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => {
+                    let refused: Result<(), MembershipError> = Err(MembershipError::BadRequest);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
+                }
+            };
+            let result: Result<(), MembershipError> = directory.lock().unwrap().close_group(&decoded);
+            if result.is_ok() {
+                let mut guard = database.lock().unwrap();
+                ServerData::close_group(guard.deref_mut(), prf_keys.lock().unwrap().deref_mut(), decoded.group as usize);
+            }
+            let encoded = bincode::serialize(&result).unwrap();
+            write_frame(&mut stream, &encoded)?;
+        }
+
         // TYPE: TRANSACTION
-        // DATA: TransactionData struct
+        // DATA: TransactionData struct (length-prefixed, the ring proof
+        // grows with the number of tokens)
         if buf[0] == 4 {
-            let bytes_read = TRANSACT_REQ_1;
-            let mut buf1 = vec![0;TRANSACT_REQ_1];
-            stream.read_exact(&mut buf1)?;
+            let buf1 = read_frame(&mut stream)?;
             let mut sum = 0;
-            let td: TransactionData = bincode::deserialize(&buf1[0..bytes_read]).unwrap();
+            let td: TransactionData = match bincode::deserialize(&buf1) {
+                Ok(td) => td,
+                Err(_) => {
+                    // An empty frame in place of the receipt
+                    write_frame(&mut stream, &[])?;
+                    continue;
+                }
+            };
             let (sketch_src, sketch_dest, eval_all_src, eval_all_dest) = eval_all(&td.dpf_src, &td.dpf_dest);
             // VERIFY DPF SKETCHES
             let seed = PrgSeed::random();
@@ -133,8 +226,16 @@ fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize
             let corshare1d = state1d.cor_share();
             // ===============================================================
             let mut sum = 0;
-            let mut ver = verify_group_tokens(td.token_proof, td.tokens, td.com_i, &mac);
-            if ver == false {
+            let dir_guard = directory.lock().unwrap();
+            let session = SessionContext::new(td.id, &server_data.issuer_shares(), &td.dpf_src, &td.dpf_dest, &td.tokens);
+            // A replayed session id, or a ring other than the group's
+            // live members, fails the transaction like a bad proof
+            let fresh = session_log.lock().unwrap().is_fresh(td.id);
+            let ring_ok = dir_guard.check_ring(&td.tokens).is_ok();
+            let tokens_ok = fresh && ring_ok && verify_group_tokens(td.token_proof, td.tokens, td.com_i, &session, &keyring.lock().unwrap(), dir_guard.revoked());
+            let frozen = dir_guard.frozen_slots();
+            drop(dir_guard);
+            if tokens_ok == false {
                 println!("uh oh!");
             }
             let (com_x, com_ix, g_r2, g_r3) = compute_coms_from_dpf(&eval_all_src, td.r2, td.r3); // Four Ristrettos (compressed)
@@ -162,6 +263,9 @@ fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize
                     g_r3: g_r3,
                     cshare_s: corshare1s.clone(),
                     cshare_d: corshare1d.clone(),
+                    frozen_s: ServerData::frozen_share(&eval_all_src, &frozen),
+                    frozen_d: ServerData::frozen_share(&eval_all_dest, &frozen),
+                    accept: tokens_ok,
                 };
             let mut encoded: Vec<u8> = Vec::new();
             encoded.extend(bincode::serialize(&package).unwrap());
@@ -199,8 +303,8 @@ fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize
                 res = bincode::deserialize(&bin);
             }
             let s2sketch: (OutShare<FieldElm>, OutShare<FieldElm>) = res.unwrap();
-            MulState::verify(&outshare1s, &s2sketch.0);
-            MulState::verify(&outshare1d, &s2sketch.0);
+            let sketch_ok = MulState::verify(&outshare1s, &s2sketch.0)
+                && MulState::verify(&outshare1d, &s2sketch.1);
             // // ======================================================================================
             // // Verify triple proof!
             
@@ -218,42 +322,57 @@ fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize
             let comix = comix_1 + comix_2;
             let g_r1 = td.g_r1.decompress().expect("REASON");
             let com_i = td.com_i.decompress().expect("REASON");
-            let mut ver = true; // same_group_val_verify(&result[..].to_vec(), &(s2data.gp_val_ver));
-            let res = verify_coms_from_dpf(g_r1, g_r2, g_r3, com_i, comx, comix, td.triple_proof);
-            if res.is_err() {
-                ver = false;
-            }
-            let ver = true;
-            let mut success = String::from("Transaction Processed");
+            // Checked along with the proofs of concurrent transactions
+            let statement = TransactionStatement { g_r1, g_r2, g_r3, com_a: com_i, com_b: comx, com_l: comix, session };
+            let mut ver = transactions.check((statement, td.triple_proof)).is_some();
+            ver = ver
+                && sketch_ok
+                && tokens_ok
+                && s2data.accept
+                && ServerData::untouched_frozen(&package.frozen_s, &s2data.frozen_s)
+                && ServerData::untouched_frozen(&package.frozen_d, &s2data.frozen_d);
+            // Answer with a signed receipt, numbered by how many transactions
+            // the database has taken. The session id is used up only now,
+            // so that of two copies in flight at most one is applied.
+            let mut seq_guard = sequence.lock().unwrap();
+            ver = ver && session_log.lock().unwrap().accept(td.id);
             if ver != true {
                 println!("Invalid!");
-                success = String::from("Invalid Transaction");
             }
             else {
                 // Proofs have been verified, now complete transaction
                 let mut guard = database.lock().unwrap();
                 ServerData::transact(guard.deref_mut(), &eval_all_src, &eval_all_dest);
+                *seq_guard += 1;
             }
-            let encoded = bincode::serialize(&success).unwrap();
-            let _ = stream.write(&encoded);
+            let receipt = TransactionReceipt::new(1, identity, td.id, share_digest(&td.dpf_src, &td.dpf_dest), ver, *seq_guard);
+            drop(seq_guard);
+            write_frame(&mut stream, &bincode::serialize(&receipt).unwrap())?;
         }
         // TYPE: SETTLING
-        // DATA: Settle Request
+        // DATA: Settle Request (length-prefixed)
         if buf[0] == 5 {
-            let bytes_read = SETTLE_SIZE;
-            let mut buf1 = [0;SETTLE_SIZE];
-            stream.read_exact(&mut buf1)?;
-            let settle_data: SettleData = bincode::deserialize(&buf1[0..bytes_read]).unwrap();
-            // ENCRYPT THE DATABASE, SEND TO S2
-            let guard = database.lock().unwrap();
-            let key_guard = prf_keys.lock().unwrap();
-            let enc_db1 = ServerData::encrypt_db(guard.deref(), key_guard.deref(), settle_data.r_seed);
-            drop(guard);
-            // NOW PUBLISH ENCRYPTED DATABASE VECTOR
+            let buf1 = read_frame(&mut stream)?;
+            let settle_data: SettleData = match bincode::deserialize(&buf1) {
+                Ok(data) => data,
+                Err(_) => {
+                    let refused: Result<Vec<FieldElm>, SettleError> = Err(SettleError::Malformed);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
+                }
+            };
+            // Settle only in a fresh epoch, and only if S2 does too
+            let accepted = settle_log.lock().unwrap().accept(settle_data.epoch, settle_epoch());
+            let enc_db1: Option<Vec<FieldElm>> = accepted.as_ref().ok().map(|_| {
+                let guard = database.lock().unwrap();
+                let key_guard = prf_keys.lock().unwrap();
+                ServerData::encrypt_db(guard.deref(), key_guard.deref(), settle_data.epoch)
+            });
+            // NOW PUBLISH ENCRYPTED DATABASE VECTOR, OR OUR REFUSAL
             let encoded = bincode::serialize(&enc_db1).unwrap();
-            // println!("{:?}", encoded.len());
             let mut key: Vec<u8> = Vec::new();
             key.extend([1u8, 4u8]); // SERVER ID, TYPE
+            key.extend(settle_data.epoch.to_be_bytes());
             let _ : () = con.set(key.clone(), encoded).unwrap();
             // AWAIT ENCRYPTED DATABASE VECTOR FROM S2
             key[0] = 2u8;
@@ -263,61 +382,136 @@ fn handle_client(mut stream: TcpStream, issuer: Issuer, counter: Arc<Mutex<usize
                 bin = con.get(key.clone()).unwrap();
                 res = bincode::deserialize(&bin);
             }
-            
-            let s2enc_db: Vec<FieldElm> = res.unwrap();
-            let success = String::from("yay!");
-            let encoded = bincode::serialize(&success).unwrap();
-            let _ = stream.write(&encoded);
-            let balance_vec1 = ServerData::settle(&enc_db1, &s2enc_db, &settle_data.dpf_key);
-            let encoded = bincode::serialize(&balance_vec1).unwrap();
-            let _ = stream.write(&encoded);
+            let s2enc_db: Option<Vec<FieldElm>> = res.unwrap();
+            let result: Result<Vec<FieldElm>, SettleError> = accepted.and_then(|_| match (enc_db1, s2enc_db) {
+                (Some(ours), Some(theirs)) => Ok(ServerData::settle(&ours, &theirs, &settle_data.dpf_key)),
+                _ => Err(SettleError::PeerRefused),
+            });
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
         } 
     }
     Ok(())
 }
 
 fn redis_connect() -> redis::RedisResult<Connection> {
-    let client = redis::Client::open(REDIS)?;
+    let client = redis::Client::open(redis_url())?;
     let con = client.get_connection()?;
 
     Ok(con)
 }
 
+// Our long-term identity, which signs the issuer key record and
+// receipts. Wallets pin it, so it is kept in IDENTITY_KEY across
+// restarts (created on first use). The public key is written to
+// IDENTITY_PUB for the operator to hand out with the client (see
+// transparency.rs).
+fn load_identity() -> io::Result<SigningKey> {
+    let identity = match fs::read(IDENTITY_KEY) {
+        Ok(bytes) => bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(_) => {
+            let key = SigningKey::new();
+            fs::write(IDENTITY_KEY, bincode::serialize(&key).unwrap())?;
+            key
+        }
+    };
+    fs::write(IDENTITY_PUB, identity_to_text(&identity.pk.compress()))?;
+    Ok(identity)
+}
+
 fn main() -> io::Result<()> {
 
     let receiver_listener = TcpListener::bind("0.0.0.0:7878").expect("Failed and bind with the sender");
     let mut thread_vec: Vec<thread::JoinHandle<()>> = Vec::new();
 
     // Initialize Server Data
-    let issuer = Issuer::new(5);
+    let issuer = IssuerShare::new(5);
     let mut vec_db = Vec::<FieldElm>::new();
     for _i in 0..MAX_GROUP_SIZE * MAX_GROUP_NUM {
         vec_db.push(FieldElm::zero());
     }
-    let mut vec_keys = Vec::<Vec<u8>>::new();
-    let zbytes = [0u8; 16];
-    for _i in 0..MAX_GROUP_NUM {
-        vec_keys.push(zbytes.to_vec());
-    }
     let database = Arc::new(Mutex::new(vec_db));
-    let prf_keys = Arc::new(Mutex::new(vec_keys));
+    let sequence = Arc::new(Mutex::new(0u64));
+    let prf_keys = Arc::new(Mutex::new(KeyRegistry::new()));
+    let settle_log = Arc::new(Mutex::new(SettleLog::new()));
+    let session_log = Arc::new(Mutex::new(SessionLog::new()));
+    let directory = Arc::new(Mutex::new(GroupDirectory::new()));
     let counter = Arc::new(Mutex::new(0usize));
 
-    type HmacSha256 = Hmac<Sha256>;
-    let random_bytes = thread_rng().gen::<[u8; 32]>();
-    let mac = HmacSha256::new_varkey(&random_bytes).expect("HMAC can take key of any size");
+    // Our tag on group tokens is under our own keys, which never leave
+    // this server. S2 tags tokens under its own. Roll the keys over once
+    // an epoch.
+    let keyring = Arc::new(Mutex::new(TokenKeyring::new(0)));
+    let rollover = keyring.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(EPOCH_SECONDS));
+        rollover.lock().unwrap().rollover(current_epoch());
+    });
+    let mut con = redis_connect().expect("failed to connect to redis");
+
+    // Credentials carry a MAC under each of S1's and S2's issuer keys
+    let mut key: Vec<u8> = Vec::new();
+    key.extend([2u8, 5u8]); // SERVER ID, TYPE
+    let mut res: RedisResult<Vec<u8>> = con.get(key.clone());
+    while res.as_ref().ok().and_then(|bin| bincode::deserialize::<IssuerPubKey>(bin).ok()).is_none() {
+        thread::sleep(Duration::from_millis(100));
+        res = con.get(key.clone());
+    }
+    let peer: IssuerPubKey = bincode::deserialize(&res.unwrap()).unwrap();
+
+    // Publish the issuer key record for S2 to sign too. The shares are
+    // new on every start, so the version is one up from the last record
+    // we published.
+    let identity = load_identity()?;
+    let mut key: Vec<u8> = Vec::new();
+    key.extend([1u8, 8u8]); // SERVER ID, TYPE
+    let last: RedisResult<Vec<u8>> = con.get(key.clone());
+    let version = last.ok()
+        .and_then(|bin| bincode::deserialize::<IssuerKeyRecord>(&bin).ok())
+        .map_or(1, |record| record.version + 1);
+    let record = IssuerKeyRecord { version, shares: vec![issuer.pubkey.clone(), peer.clone()] };
+    let _ : () = con.set(key, bincode::serialize(&record).unwrap()).unwrap();
+    let attestation = KeyAttestation::new(1, &identity, record);
+
+    // Groups are only created for holders of a pass from the operator,
+    // whose public key the operator tool publishes (see operator.rs)
+    let mut key: Vec<u8> = Vec::new();
+    key.extend([0u8, 1u8]); // OPERATOR, TYPE
+    let mut res: RedisResult<Vec<u8>> = con.get(key.clone());
+    while res.as_ref().ok().and_then(|bin| bincode::deserialize::<CompressedRistretto>(bin).ok()).is_none() {
+        thread::sleep(Duration::from_millis(100));
+        res = con.get(key.clone());
+    }
+    let operator_pk: CompressedRistretto = bincode::deserialize(&res.unwrap()).unwrap();
+    // Pick up the quotas used before a restart
+    let saved: RedisResult<Vec<u8>> = con.get(vec![1u8, 9u8]); // SERVER ID, TYPE
+    let ledger = saved.ok()
+        .and_then(|bin| CreationLedger::restore(operator_pk, &bin))
+        .unwrap_or_else(|| CreationLedger::new(operator_pk));
+    let ledger = Arc::new(Mutex::new(ledger));
+    let shows = Arc::new(registration_burst(&issuer, 0));
+    let transactions = Arc::new(transaction_burst());
 
     let mut streams = 0;
     for stream in receiver_listener.incoming() {
         let stream = stream.expect("failed");
         let counter = counter.clone();
         let database = database.clone();
+        let sequence = sequence.clone();
         let prf_keys = prf_keys.clone();
+        let settle_log = settle_log.clone();
+        let session_log = session_log.clone();
+        let directory = directory.clone();
+        let ledger = ledger.clone();
         let my_issuer = issuer.clone();
-        let my_mac = mac.clone();
+        let my_peer = peer.clone();
+        let my_keyring = keyring.clone();
+        let shows = shows.clone();
+        let transactions = transactions.clone();
+        let my_identity = identity.clone();
+        let my_attestation = attestation.clone();
         streams += 1;
         let handle = thread::spawn(move || {
-            handle_client(stream, my_issuer, counter, database, prf_keys, &my_mac, &streams).unwrap_or_else(|error| eprintln!("{:?}",error))
+            handle_client(stream, my_issuer, my_peer, counter, database, sequence, prf_keys, settle_log, session_log, directory, ledger, my_keyring, shows, transactions, &my_identity, &my_attestation, &streams).unwrap_or_else(|error| eprintln!("{:?}",error))
         });
         thread_vec.push(handle);
     }
@@ -2,6 +2,7 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 #![allow(unused_variables)]
+use std::fs;
 use std::io;
 use std ::net::{TcpListener,TcpStream};
 use std::io::{Read,Write};
@@ -25,24 +26,34 @@ use rand::Rng;
 use redis::Connection;
 use redis::Commands;
 use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::ristretto::CompressedRistretto;
 use curve25519_dalek::scalar::Scalar;
 use payapp::ps::*;
 use payapp::ggm::*;
 use payapp::prg::PrgSeed;
+use payapp::keys::{KeyRegistry, SettleError, SettleLog};
+use payapp::tokens::{validate_tokens, TokenKeyring};
+use payapp::{current_epoch, redis_url, settle_epoch, EPOCH_SECONDS};
+use payapp::threshold::{IssuanceContext, IssuerShare};
+use payapp::membership::{CloseRequest, GroupDirectory, MembershipError, RemovalRequest, SlotRequest};
+use payapp::creation::{CreationError, CreationLedger, PeerGroupRequest};
+use payapp::signature::SigningKey;
+use payapp::transparency::{identity_to_text, IssuerKeyRecord, KeyAttestation};
+use payapp::receipt::{share_digest, TransactionReceipt};
 use payapp::coms::*;
 use payapp::sketch::*;
 use payapp::mpc::*;
+use payapp::burst::Burst;
 use payapp::Group;
 use payapp::FieldElm;
 use payapp::MAX_GROUP_SIZE;
 use payapp::MAX_GROUP_NUM;
-use payapp::SETTLE_SIZE;
-use payapp::TRANSACT_REQ_2;
+use payapp::{read_frame, write_frame};
 
-// pub const REDIS: &str = "redis://127.0.0.1:6379";
-pub const REDIS: &str = "redis://10.128.0.4:6379";
+pub const IDENTITY_KEY: &str = "server2.key";
+pub const IDENTITY_PUB: &str = "server2.pub";
 
-fn handle_client(mut stream: TcpStream, counter: Arc<Mutex<usize>>, database: Arc<Mutex<Vec<FieldElm>>>) -> io::Result<()> {
+fn handle_client(mut stream: TcpStream, issuer: IssuerShare, counter: Arc<Mutex<usize>>, database: Arc<Mutex<Vec<FieldElm>>>, sequence: Arc<Mutex<u64>>, prf_keys: Arc<Mutex<KeyRegistry>>, settle_log: Arc<Mutex<SettleLog>>, session_log: Arc<Mutex<SessionLog>>, directory: Arc<Mutex<GroupDirectory>>, ledger: Arc<Mutex<CreationLedger>>, keyring: Arc<Mutex<TokenKeyring>>, shows: Arc<Burst<ShowMessage, VerifiedCredential>>, identity: &SigningKey, attestation: &KeyAttestation) -> io::Result<()> {
 
     let con_try = redis_connect();
     let mut con: Connection = con_try.unwrap();
@@ -60,34 +71,146 @@ fn handle_client(mut stream: TcpStream, counter: Arc<Mutex<usize>>, database: Ar
         	notHandled = true;
         }
 
-        // Server 1 handles all new group requests
+        // Server 1 allocates the slots of new groups; the leader then
+        // registers the group with us
+
+        // TYPE: GROUP REGISTRATION
+        // DATA: Peer Group Request (length-prefixed): the group number S1
+        // assigned, our server key, the leader public key and the
+        // operator's creation pass
+        if buf[0] == 1 {
+            let buf1 = read_frame(&mut stream)?;
+            let decoded: PeerGroupRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => {
+                    let refused: Result<(), CreationError> = Err(CreationError::BadRequest);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
+                }
+            };
+            let mut dir_guard = directory.lock().unwrap();
+            let first_aid = decoded.group as usize * MAX_GROUP_SIZE;
+            let result: Result<(), CreationError> = if dir_guard.has_group(decoded.group) {
+                Err(CreationError::GroupTaken)
+            } else {
+                let mut ledger_guard = ledger.lock().unwrap();
+                let authorized = ledger_guard.authorize(&decoded.pass, first_aid);
+                if authorized.is_ok() {
+                    let _ : () = con.set(vec![2u8, 9u8], ledger_guard.to_bytes()).unwrap(); // SERVER ID, TYPE
+                }
+                authorized
+            };
+            if result.is_ok() {
+                dir_guard.create_group(decoded.group, first_aid as u64, decoded.leader_pk);
+                prf_keys.lock().unwrap().register(decoded.group as usize, decoded.s2_key);
+            }
+            drop(dir_guard);
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
+        }
+
+        // TYPE: SETUP REGISTRATION TOKENS
+        // DATA: The leader's Slot Request and the issuance contexts S1
+        // answered it with (length-prefixed)
+        if buf[0] == 2 {
+            let buf1 = read_frame(&mut stream)?;
+            // Check the request against our own directory, and every
+            // context against its request, before marking any slot issued.
+            // A request we can't read gets an answer too.
+            let result: Result<Vec<issue_blind124_5::PartialResponse>, MembershipError> = match bincode::deserialize::<(SlotRequest, Vec<IssuanceContext>)>(&buf1) {
+                Ok((slot_req, ctxs)) if ctxs.len() == slot_req.reqs.len() => slot_req.reqs.iter().zip(&ctxs)
+                    .map(|(req, ctx)| issuer.issue_blind124_5(req, ctx).map_err(|_| MembershipError::BadRequest))
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|partials| directory.lock().unwrap().claim_slots(&slot_req).map(|_| partials)),
+                _ => Err(MembershipError::BadRequest),
+            };
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
+        }
+
+        // TYPE: USER REGISTRATION
+        // DATA: Registration Request (length-prefixed)
+        if buf[0] == 3 {
+            let buf1 = read_frame(&mut stream)?;
+            let req: RegistrationRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => continue,
+            };
+            // Check our part of the presentation, along with those of
+            // other registrations coming in at the same time, and only
+            // then vouch for this very message to S1 with our tag on the
+            // token. The token is filed under its group for the ring check.
+            let ack = shows.check(req.showmsg.clone())
+                .and_then(|ver_cred| acknowledge_verified(&req, &ver_cred, &keyring.lock().unwrap()).ok())
+                .filter(|ack| directory.lock().unwrap().register_token(req.group, &ack.token).is_ok());
+            let accepted = ack.is_some();
+            if let Some(ack) = ack {
+                let mut key: Vec<u8> = Vec::new();
+                key.extend([2u8, 6u8]); // SERVER ID, TYPE
+                key.extend(req.showmsg.P.compress().to_bytes());
+                let _ : () = con.set(key, bincode::serialize(&ack).unwrap()).unwrap();
+            }
+            write_frame(&mut stream, &bincode::serialize(&accepted).unwrap())?;
+        }
+
+        // TYPE: MEMBER REMOVAL
+        // DATA: Removal Request (length-prefixed), signed by the group leader
+        if buf[0] == 6 {
+            let buf1 = read_frame(&mut stream)?;
+            let result: Result<(), MembershipError> = match bincode::deserialize::<RemovalRequest>(&buf1) {
+                Ok(req) => directory.lock().unwrap().remove_member(&req),
+                Err(_) => Err(MembershipError::BadRequest),
+            };
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
+        }
+
+        // TYPE: GROUP CLOSE
+        // DATA: Close Request (length-prefixed), signed by the group leader
+        if buf[0] == 8 {
+            let buf1 = read_frame(&mut stream)?;
+            let decoded: CloseRequest = match bincode::deserialize(&buf1) {
+                Ok(req) => req,
+                Err(_) => {
+                    let refused: Result<(), MembershipError> = Err(MembershipError::BadRequest);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
+                }
+            };
+            let result: Result<(), MembershipError> = directory.lock().unwrap().close_group(&decoded);
+            if result.is_ok() {
+                let mut guard = database.lock().unwrap();
+                ServerData::close_group(guard.deref_mut(), prf_keys.lock().unwrap().deref_mut(), decoded.group as usize);
+            }
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
+        }
+
         // TYPE: TRANSACTION
-        // DATA: TransactionData struct
+        // DATA: TransactionDataS2 struct (length-prefixed)
         if buf[0] == 4 {
-            let bytes_read = TRANSACT_REQ_2;
-            let mut buf1 = vec![0;TRANSACT_REQ_2];
-            stream.read_exact(&mut buf1)?;
-            let res = bincode::deserialize(&buf1[0..bytes_read]);
-            if res.is_err() {
-                let success = String::from("skipping");
-                let encoded = bincode::serialize(&success).unwrap();
-                let _ = stream.write(&encoded);
-                continue;
-            }
-            let td: TransactionDataS2 = res.unwrap();
+            let buf1 = read_frame(&mut stream)?;
+            let td: TransactionDataS2 = match bincode::deserialize(&buf1) {
+                Ok(td) => td,
+                Err(_) => {
+                    // An empty frame in place of the receipt
+                    write_frame(&mut stream, &[])?;
+                    continue;
+                }
+            };
             let (sketch_src, sketch_dest, eval_all_src, eval_all_dest) = eval_all(&td.dpf_src, &td.dpf_dest);
             // // ============================ VERIFY DPF SKETCHES =======================================
             let seed = PrgSeed::random();
             let mut sketches = vec![];
             sketches.push((&td.dpf_src).sketch_at(&sketch_src, &mut seed.to_rng()));
             sketches.push((&td.dpf_dest).sketch_at(&sketch_dest, &mut seed.to_rng()));
-            let state2s = MulState::new(false, (&td.dpf_src).triples.clone(), &(&td.dpf_src).mac_key, &(&td.dpf_src).mac_key2, &(&td.dpf_src).val_share, &(&td.dpf_src).val2_share, &sketches[0]);
-            let state2d = MulState::new(false, (&td.dpf_dest).triples.clone(), &(&td.dpf_dest).mac_key, &(&td.dpf_dest).mac_key2, &(&td.dpf_dest).val_share, &(&td.dpf_dest).val2_share, &sketches[1]);
+            let state2s = MulState::new(true, (&td.dpf_src).triples.clone(), &(&td.dpf_src).mac_key, &(&td.dpf_src).mac_key2, &(&td.dpf_src).val_share, &(&td.dpf_src).val2_share, &sketches[0]);
+            let state2d = MulState::new(true, (&td.dpf_dest).triples.clone(), &(&td.dpf_dest).mac_key, &(&td.dpf_dest).mac_key2, &(&td.dpf_dest).val_share, &(&td.dpf_dest).val2_share, &sketches[1]);
             let corshare2s = state2s.cor_share();
             let corshare2d = state2d.cor_share();
             // // ===========================================================================
             let (com_x, com_ix, g_r2, g_r3) = compute_coms_from_dpf(&eval_all_src, td.r2, td.r3); // Four Ristrettos (compressed)
             let w1 = same_group_val_compute(&eval_all_src, &eval_all_dest, false);
+            let dir_guard = directory.lock().unwrap();
+            let frozen = dir_guard.frozen_slots();
+            let ring_ok = dir_guard.check_ring(&td.tokens).is_ok();
+            drop(dir_guard);
             let mut prg: ChaCha8Rng = ChaCha8Rng::seed_from_u64((td.id as u64) + 56789u64);
             let zero_bytes = [0u8; 16];
             let mut rvec = Vec::<FieldElm>::new();
@@ -103,6 +226,13 @@ fn handle_client(mut stream: TcpStream, counter: Arc<Mutex<usize>>, database: Ar
                 prod.mul(&w1[i]);
                 prod.mul(&rvec[i]);
             }
+            // We only check our own tags; S1 checks its own. Session ids
+            // are checked on both servers.
+            let fresh = session_log.lock().unwrap().is_fresh(td.id);
+            let tokens_ok = fresh && ring_ok && validate_tokens(&td.tokens, &keyring.lock().unwrap()).is_ok();
+            if !tokens_ok {
+                println!("uh oh!");
+            }
             let package = TransactionPackage {
                 strin: "Server2", 
                 com_x: com_x,
@@ -111,6 +241,9 @@ fn handle_client(mut stream: TcpStream, counter: Arc<Mutex<usize>>, database: Ar
                 g_r3: g_r3,
                 cshare_s: corshare2s.clone(),
                 cshare_d: corshare2d.clone(),
+                frozen_s: ServerData::frozen_share(&eval_all_src, &frozen),
+                frozen_d: ServerData::frozen_share(&eval_all_dest, &frozen),
+                accept: tokens_ok,
             };
             let mut encoded: Vec<u8> = Vec::new();
             encoded.extend(bincode::serialize(&package).unwrap());
@@ -151,89 +284,106 @@ fn handle_client(mut stream: TcpStream, counter: Arc<Mutex<usize>>, database: Ar
                 res = bincode::deserialize(&bin);
             }
             let s1sketch: (OutShare<FieldElm>, OutShare<FieldElm>) = res.unwrap();
-            MulState::verify(&outshare2s, &s1sketch.0);
-            MulState::verify(&outshare2d, &s1sketch.0);
+            let sketch_ok = MulState::verify(&outshare2s, &s1sketch.0)
+                && MulState::verify(&outshare2d, &s1sketch.1);
             // ======================================================================================
-            let ver = true; 
-            let mut success = String::from("Transaction Processed");
+            let mut ver = sketch_ok
+                && tokens_ok
+                && s1data.accept
+                && ServerData::untouched_frozen(&package.frozen_s, &s1data.frozen_s)
+                && ServerData::untouched_frozen(&package.frozen_d, &s1data.frozen_d);
+            // Answer with a signed receipt, numbered by how many transactions
+            // the database has taken. The session id is used up only now,
+            // so that of two copies in flight at most one is applied.
+            let mut seq_guard = sequence.lock().unwrap();
+            ver = ver && session_log.lock().unwrap().accept(td.id);
             if ver != true {
                 println!("Invalid!");
-                success = String::from("Invalid Transaction");
             }
             else {
                 // Proofs have been verified, now complete transaction
                 let mut guard = database.lock().unwrap();
                 ServerData::transact(guard.deref_mut(), &eval_all_src, &eval_all_dest);
-                let success = String::from("Transaction Processed");
+                *seq_guard += 1;
             }
-            let encoded = bincode::serialize(&success).unwrap();
-            let _ = stream.write(&encoded);
+            let receipt = TransactionReceipt::new(2, identity, td.id, share_digest(&td.dpf_src, &td.dpf_dest), ver, *seq_guard);
+            drop(seq_guard);
+            write_frame(&mut stream, &bincode::serialize(&receipt).unwrap())?;
+        }
+        // TYPE: ISSUER KEY ATTESTATION
+        // DATA: None
+        if buf[0] == 7 {
+            write_frame(&mut stream, &bincode::serialize(attestation).unwrap())?;
         }
         // TYPE: SETTLING
-        // DATA: Settle Request
+        // DATA: Settle Request (length-prefixed)
         if buf[0] == 5 {
-            let bytes_read = SETTLE_SIZE;
-            let mut buf1 = vec![0;SETTLE_SIZE];
-            stream.read_exact(&mut buf1)?;
-            let settle_data: SettleData = bincode::deserialize(&buf1[0..bytes_read]).unwrap();
-            // ENCRYPT THE DATABASE, SEND TO S1
-            let mut guard = database.lock().unwrap();
-            let mut key_arr = Vec::<Vec<u8>>::new();
-            let mut key: Vec<u8> = Vec::new();
-            for j in 0..MAX_GROUP_NUM {
-                key.extend([2u8, 1u8]);
-                key.extend(j.to_be_bytes());
-                let res: RedisResult<Vec<u8>> = con.get(key.clone()); 
-                if res.is_ok() {
-                    let vec = res.unwrap();
-                    if vec.len() == 0 {
-                        let zbytes = [0u8; 16];
-                        key_arr.push(zbytes.to_vec());
-                    }
-                    else {
-                        key_arr.push(vec);
-                    }
+            let buf1 = read_frame(&mut stream)?;
+            let settle_data: SettleData = match bincode::deserialize(&buf1) {
+                Ok(data) => data,
+                Err(_) => {
+                    let refused: Result<Vec<FieldElm>, SettleError> = Err(SettleError::Malformed);
+                    write_frame(&mut stream, &bincode::serialize(&refused).unwrap())?;
+                    continue;
                 }
-                key.clear();
-            }
-            let enc_db2 = ServerData::encrypt_db(guard.deref(), &key_arr, settle_data.r_seed);
-            drop(guard);
+            };
+            // Settle only in a fresh epoch, and only if S1 does too
+            let accepted = settle_log.lock().unwrap().accept(settle_data.epoch, settle_epoch());
+            let enc_db2: Option<Vec<FieldElm>> = accepted.as_ref().ok().map(|_| {
+                let guard = database.lock().unwrap();
+                let key_guard = prf_keys.lock().unwrap();
+                ServerData::encrypt_db(guard.deref(), key_guard.deref(), settle_data.epoch)
+            });
+            // NOW PUBLISH ENCRYPTED DATABASE VECTOR, OR OUR REFUSAL
             let encoded = bincode::serialize(&enc_db2).unwrap();
             let mut key: Vec<u8> = Vec::new();
             key.extend([2u8, 4u8]); // SERVER ID, TYPE
+            key.extend(settle_data.epoch.to_be_bytes());
             let _ : () = con.set(key.clone(), encoded).unwrap();
-            // WAIT for response
+            // AWAIT ENCRYPTED DATABASE VECTOR FROM S1
             key[0] = 1u8;
-            let mut res = con.get(key.clone());
-            while res.is_err() {
-                res = con.get(key.clone());
-            }
-            let mut bin: Vec<u8> = res.unwrap();
+            let mut bin: Vec<u8> = con.get(key.clone()).unwrap();
             let mut res = bincode::deserialize(&bin);
             while res.is_err() {
                 bin = con.get(key.clone()).unwrap();
                 res = bincode::deserialize(&bin);
             }
-            let s1enc_db: Vec<FieldElm> = res.unwrap(); 
-
-            let success = String::from("yay!");
-            let encoded = bincode::serialize(&success).unwrap();
-            let _ = stream.write(&encoded);
-            let balance_vec2 = ServerData::settle(&enc_db2, &s1enc_db, &settle_data.dpf_key);
-            let encoded = bincode::serialize(&balance_vec2).unwrap();
-            let _ = stream.write(&encoded);
+            let s1enc_db: Option<Vec<FieldElm>> = res.unwrap();
+            let result: Result<Vec<FieldElm>, SettleError> = accepted.and_then(|_| match (enc_db2, s1enc_db) {
+                (Some(ours), Some(theirs)) => Ok(ServerData::settle(&ours, &theirs, &settle_data.dpf_key)),
+                _ => Err(SettleError::PeerRefused),
+            });
+            write_frame(&mut stream, &bincode::serialize(&result).unwrap())?;
         }
     }
     Ok(())
 }
 
 fn redis_connect() -> redis::RedisResult<Connection> {
-    let client = redis::Client::open(REDIS)?;
+    let client = redis::Client::open(redis_url())?;
     let con = client.get_connection()?;
 
     Ok(con)
 }
 
+// Our long-term identity, which signs the issuer key record and
+// receipts. Wallets pin it, so it is kept in IDENTITY_KEY across
+// restarts (created on first use). The public key is written to
+// IDENTITY_PUB for the operator to hand out with the client (see
+// transparency.rs).
+fn load_identity() -> io::Result<SigningKey> {
+    let identity = match fs::read(IDENTITY_KEY) {
+        Ok(bytes) => bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(_) => {
+            let key = SigningKey::new();
+            fs::write(IDENTITY_KEY, bincode::serialize(&key).unwrap())?;
+            key
+        }
+    };
+    fs::write(IDENTITY_PUB, identity_to_text(&identity.pk.compress()))?;
+    Ok(identity)
+}
+
 fn main() -> io::Result<()> {
 
     // Establish TCP Connection
@@ -241,20 +391,85 @@ fn main() -> io::Result<()> {
     let mut thread_vec: Vec<thread::JoinHandle<()>> = Vec::new();
 
     // Initialize Server Data
-    let issuer = Issuer::new(5);
+    // Our issuer key; S1 picks up the public part
+    let issuer = IssuerShare::new(5);
+    let mut con = redis_connect().expect("failed to connect to redis");
+    let mut key: Vec<u8> = Vec::new();
+    key.extend([2u8, 5u8]); // SERVER ID, TYPE
+    let _ : () = con.set(key, bincode::serialize(&issuer.pubkey).unwrap()).unwrap();
+
+    // Sign the issuer key record S1 publishes, once it has our share
+    let identity = load_identity()?;
+    let my_share = bincode::serialize(&issuer.pubkey).unwrap();
+    let mut key: Vec<u8> = Vec::new();
+    key.extend([1u8, 8u8]); // SERVER ID, TYPE
+    let has_my_share = |bin: &Vec<u8>| {
+        bincode::deserialize::<IssuerKeyRecord>(bin).ok()
+            .filter(|record| record.shares.len() == 2 && bincode::serialize(&record.shares[1]).unwrap() == my_share)
+    };
+    let mut res: RedisResult<Vec<u8>> = con.get(key.clone());
+    while res.as_ref().ok().and_then(has_my_share).is_none() {
+        thread::sleep(Duration::from_millis(100));
+        res = con.get(key.clone());
+    }
+    let record = has_my_share(&res.unwrap()).unwrap();
+    let attestation = KeyAttestation::new(2, &identity, record);
     let counter = Arc::new(Mutex::new(0usize));
     let mut vec = Vec::<FieldElm>::new();
     for i in 0..MAX_GROUP_NUM * MAX_GROUP_SIZE {
         vec.push(FieldElm::zero());
     }
     let database = Arc::new(Mutex::new(vec));
+    let sequence = Arc::new(Mutex::new(0u64));
+    let prf_keys = Arc::new(Mutex::new(KeyRegistry::new()));
+    let settle_log = Arc::new(Mutex::new(SettleLog::new()));
+    let session_log = Arc::new(Mutex::new(SessionLog::new()));
+    let directory = Arc::new(Mutex::new(GroupDirectory::new()));
+
+    // Groups are registered with us under the operator's pass too (see
+    // operator.rs)
+    let mut key: Vec<u8> = Vec::new();
+    key.extend([0u8, 1u8]); // OPERATOR, TYPE
+    let mut res: RedisResult<Vec<u8>> = con.get(key.clone());
+    while res.as_ref().ok().and_then(|bin| bincode::deserialize::<CompressedRistretto>(bin).ok()).is_none() {
+        thread::sleep(Duration::from_millis(100));
+        res = con.get(key.clone());
+    }
+    let operator_pk: CompressedRistretto = bincode::deserialize(&res.unwrap()).unwrap();
+    // Pick up the quotas used before a restart
+    let saved: RedisResult<Vec<u8>> = con.get(vec![2u8, 9u8]); // SERVER ID, TYPE
+    let ledger = saved.ok()
+        .and_then(|bin| CreationLedger::restore(operator_pk, &bin))
+        .unwrap_or_else(|| CreationLedger::new(operator_pk));
+    let ledger = Arc::new(Mutex::new(ledger));
+
+    // Our tag on group tokens is under our own keys, rolled over once an
+    // epoch
+    let keyring = Arc::new(Mutex::new(TokenKeyring::new(1)));
+    let rollover = keyring.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(EPOCH_SECONDS));
+        rollover.lock().unwrap().rollover(current_epoch());
+    });
+    let shows = Arc::new(registration_burst(&issuer, 1));
 
     for stream in receiver_listener.incoming() {
         let stream = stream.expect("failed");
         let counter = counter.clone();
         let database = database.clone();
+        let sequence = sequence.clone();
+        let prf_keys = prf_keys.clone();
+        let settle_log = settle_log.clone();
+        let session_log = session_log.clone();
+        let directory = directory.clone();
+        let ledger = ledger.clone();
+        let my_keyring = keyring.clone();
+        let shows = shows.clone();
+        let my_issuer = issuer.clone();
+        let my_identity = identity.clone();
+        let my_attestation = attestation.clone();
         let handle = thread::spawn(move || {
-            handle_client(stream, counter, database).unwrap_or_else(|error| eprintln!("{:?}",error))
+            handle_client(stream, my_issuer, counter, database, sequence, prf_keys, settle_log, session_log, directory, ledger, my_keyring, shows, &my_identity, &my_attestation).unwrap_or_else(|error| eprintln!("{:?}",error))
         });
         thread_vec.push(handle);
     }
@@ -0,0 +1,138 @@
+// Batch verification across a server's connections.
+//
+// The servers handle each connection on its own thread, so a burst of
+// registrations or transactions arrives as many threads each holding
+// one proof. A Burst collects them: a thread queues its item and waits,
+// and whichever thread finds no batch running takes everything queued
+// so far and checks it at once (see verify_show_batch and
+// batch_verify_coms_from_dpf). A lone request is checked as soon as it
+// arrives, so an idle server adds no latency. When a batch fails, its
+// items are checked one at a time, so one bad proof only fails its own
+// request.
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+type BatchCheck<T, R> = Box<dyn Fn(&[T]) -> Option<Vec<R>> + Send + Sync>;
+type SingleCheck<T, R> = Box<dyn Fn(&T) -> Option<R> + Send + Sync>;
+
+pub struct Burst<T, R> {
+    queue: Mutex<Queue<T, R>>,
+    done: Condvar,
+    check_batch: BatchCheck<T, R>,
+    check_one: SingleCheck<T, R>,
+}
+
+struct Queue<T, R> {
+    next_ticket: u64,
+    pending: Vec<(u64, T)>,
+    results: HashMap<u64, Option<R>>,
+    running: bool,
+}
+
+impl<T, R> Burst<T, R> {
+    // check_batch returns one result per item, or None if any item
+    // fails; check_one is the fallback for a failed batch.
+    pub fn new<B, S>(check_batch: B, check_one: S) -> Burst<T, R>
+    where
+        B: Fn(&[T]) -> Option<Vec<R>> + Send + Sync + 'static,
+        S: Fn(&T) -> Option<R> + Send + Sync + 'static,
+    {
+        Burst {
+            queue: Mutex::new(Queue { next_ticket: 0, pending: Vec::new(), results: HashMap::new(), running: false }),
+            done: Condvar::new(),
+            check_batch: Box::new(check_batch),
+            check_one: Box::new(check_one),
+        }
+    }
+
+    // Check item together with whatever else is queued, and return its
+    // own result once its batch has run
+    pub fn check(&self, item: T) -> Option<R> {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, item));
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if queue.running {
+                queue = self.done.wait(queue).unwrap();
+                continue;
+            }
+            // Run the next batch, ours included, outside the lock
+            queue.running = true;
+            let (tickets, items): (Vec<u64>, Vec<T>) = std::mem::take(&mut queue.pending).into_iter().unzip();
+            drop(queue);
+            let results = self.run(&items);
+            queue = self.queue.lock().unwrap();
+            queue.results.extend(tickets.into_iter().zip(results));
+            queue.running = false;
+            self.done.notify_all();
+        }
+    }
+
+    fn run(&self, items: &[T]) -> Vec<Option<R>> {
+        match (self.check_batch)(items) {
+            Some(results) if results.len() == items.len() => results.into_iter().map(Some).collect(),
+            _ => items.iter().map(|item| (self.check_one)(item)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Even numbers pass. Each batch is logged, and the first one waits
+    // for a go-ahead so that others can queue up behind it.
+    fn evens(log: mpsc::Sender<Vec<u32>>, go: mpsc::Receiver<()>) -> Burst<u32, u32> {
+        let (log, go) = (Mutex::new(log), Mutex::new(Some(go)));
+        Burst::new(
+            move |xs: &[u32]| {
+                if let Some(go) = go.lock().unwrap().take() {
+                    go.recv().unwrap();
+                }
+                log.lock().unwrap().send(xs.to_vec()).unwrap();
+                xs.iter().all(|x| x & 1 == 0).then(|| xs.iter().map(|x| x / 2).collect())
+            },
+            |x: &u32| (x & 1 == 0).then(|| x / 2),
+        )
+    }
+
+    #[test]
+    fn queued_items_share_a_batch() {
+        let (log, batches) = mpsc::channel();
+        let (go, wait) = mpsc::channel();
+        let burst = Arc::new(evens(log, wait));
+        let check = |x: u32| {
+            let burst = burst.clone();
+            thread::spawn(move || burst.check(x))
+        };
+
+        let first = check(2);
+        while !burst.queue.lock().unwrap().running {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let rest: Vec<_> = [4, 7, 8].iter().map(|&x| check(x)).collect();
+        while burst.queue.lock().unwrap().pending.len() < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        go.send(()).unwrap();
+
+        // The odd one fails the second batch, and only its own check
+        assert_eq!(first.join().unwrap(), Some(1));
+        let results: Vec<_> = rest.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![Some(2), None, Some(4)]);
+        let mut second = batches.iter().nth(1).unwrap();
+        second.sort_unstable();
+        assert_eq!(second, vec![4, 7, 8]);
+
+        // A lone item is checked at once
+        assert_eq!(burst.check(6), Some(3));
+    }
+}
@@ -0,0 +1,279 @@
+// Client-side steps that don't touch the network.
+//
+// The clients binary talks to the servers over TCP, but a wallet in a
+// browser or on a phone has its own transport. The steps here turn a
+// member's state into the messages for S1 and S2 and turn the servers'
+// replies back into state, so every client shares them: registering a
+// credential for a group token, preparing a payment and checking its
+// receipts, and reading the group's balances at settlement.
+#![allow(non_snake_case)]
+
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use std::convert::TryInto;
+use std::ops::Neg;
+
+use crate::coms::{transaction, SessionContext, GEN_G, GEN_H};
+use crate::dpf::PointDPFKey;
+use crate::ggm::{show_blind34_5, Credential, IssuerPubKey};
+use crate::keys::GroupMasterSecret;
+use crate::ps::{GroupToken, GroupTokenPriv, RegistrationRequest, SettleData, TransactionData, TransactionDataS2};
+use crate::receipt::{share_digest, ReceiptPair};
+use crate::ringproof::{decompress_ring, RingProof, RingWitness};
+use crate::sketch::SketchPointDPFKey;
+use crate::transparency::KeyPin;
+use crate::{my_u32_to_bits, scalar_to_u64, FieldElm, Group, DPF_DOMAIN, MAX_GROUP_SIZE, SETTLE_DOMAIN};
+
+// The database slot of an account, from the AID in its credential
+pub fn account_index(aid: &Scalar) -> u32 {
+    let bytes = aid.to_bytes();
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+// Show a credential for registration, with a part for each server's
+// key share, and prove which group's block the AID is in. The request
+// goes to S2 and then S1 (opcode 3); the commitment openings are kept
+// for payments.
+pub fn registration_request(credential: &Credential, shares: &[IssuerPubKey]) -> (Vec<Scalar>, Vec<u8>) {
+    let (zs, showmsg) = show_blind34_5::show_threshold(credential, shares);
+    let req = RegistrationRequest::new(showmsg, &zs, account_index(&credential.m[3]) as u64);
+    (zs, bincode::serialize(&req).unwrap())
+}
+
+// A member's state for a slot once S1 has issued its group token
+pub fn member_token(
+    group_secret: GroupMasterSecret,
+    credential: &Credential,
+    zs: &[Scalar],
+    token: GroupToken,
+    issuer_key: KeyPin,
+) -> GroupTokenPriv {
+    GroupTokenPriv {
+        group_secret,
+        token,
+        z3: zs[3],
+        aid: credential.m[3],
+        issuer_key,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionError {
+    // The payer's own token is not among the group's tokens
+    NotInGroup,
+    // A group token does not hold valid points
+    BadToken,
+}
+
+// Pay amount from the payer's slot to slot dest. The payer proves
+// ownership of one of the group's tokens without revealing which, so
+// the servers see every token of the group. session_id must be fresh:
+// each server refuses an id it has applied a transaction under before
+// (see coms::SessionLog).
+pub fn prepare_transaction(
+    session_id: u32,
+    payer: &GroupTokenPriv,
+    group_tokens: &[GroupToken],
+    dest: u32,
+    amount: u32,
+) -> Result<(TransactionData, TransactionDataS2), TransactionError> {
+    let index = group_tokens
+        .iter()
+        .position(|t| t.uid == payer.token.uid)
+        .ok_or(TransactionError::NotInGroup)?;
+    let points: Vec<(CompressedRistretto, CompressedRistretto)> = group_tokens.iter().map(|t| (t.P, t.cm_aid)).collect();
+    let ring = decompress_ring(&points).ok_or(TransactionError::BadToken)?;
+    let src = account_index(&payer.aid);
+
+    // The amount only sits on the leaves, so standard DPF keys do. The
+    // last bit is padding only all-prefix keys use.
+    let beta = FieldElm::from(amount);
+    let a_src = my_u32_to_bits(DPF_DOMAIN.try_into().unwrap(), src);
+    let a_dest = my_u32_to_bits(DPF_DOMAIN.try_into().unwrap(), dest);
+    let [src1, src2] = SketchPointDPFKey::gen(&a_src[..DPF_DOMAIN - 1], &beta);
+    let [dest1, dest2] = SketchPointDPFKey::gen(&a_dest[..DPF_DOMAIN - 1], &beta);
+
+    let mut rng = rand::thread_rng();
+    let r1 = Scalar::random(&mut rng);
+    let (r2_1, r2_2) = (Scalar::random(&mut rng), Scalar::random(&mut rng));
+    let (r3_1, r3_2) = (Scalar::random(&mut rng), Scalar::random(&mut rng));
+    let (r2, r3) = (r2_1 + r2_2, r3_1 + r3_2);
+
+    let G: &RistrettoPoint = &GEN_G;
+    let H: &RistrettoPoint = &GEN_H;
+    let a = Scalar::from(src);
+    let b = Scalar::from(amount);
+    let v1 = G * r1;
+    let e1 = G * a + H * r1;
+    let e3 = G * (a * b) + H * r3;
+
+    // Both proofs are bound to this transaction
+    let session = SessionContext::new(session_id, payer.issuer_key.shares(), &src1, &dest1, group_tokens);
+    let mut transcript = session.transcript(b"Transaction Proof");
+    let (triple_proof, _) = transaction::prove_batchable(
+        &mut transcript,
+        transaction::ProveAssignments {
+            G,
+            H,
+            nG: &G.neg(),
+            nH: &H.neg(),
+            v1: &v1,
+            v2: &(G * r2),
+            v3: &(G * r3),
+            e1: &e1,
+            e2: &(G * b + H * r2),
+            ne3: &e3.neg(),
+            r1: &r1,
+            r3: &r3,
+            a: &a,
+            id: &Scalar::one(),
+            tau: &(a * r2),
+        },
+    );
+    let witness = RingWitness { index, i: a, rt: payer.z3, rc: r1 };
+    let mut transcript = session.transcript(b"Group Token Proof");
+    let token_proof = RingProof::prove(&mut transcript, &ring, &e1, &witness);
+
+    let s1 = TransactionData {
+        tokens: group_tokens.to_vec(),
+        id: session_id,
+        dpf_src: src1,
+        dpf_dest: dest1,
+        g_r1: v1.compress(),
+        r2: r2_1,
+        r3: r3_1,
+        com_i: e1.compress(),
+        triple_proof,
+        token_proof,
+    };
+    let s2 = TransactionDataS2 {
+        tokens: group_tokens.to_vec(),
+        id: session_id,
+        dpf_src: src2,
+        dpf_dest: dest2,
+        g_r1: v1.compress(),
+        r2: r2_2,
+        r3: r3_2,
+        com_i: e1.compress(),
+    };
+    Ok((s1, s2))
+}
+
+// Whether the servers' receipts are for the key shares this client sent
+// them. Their signatures are checked separately, with ReceiptPair::verify.
+pub fn receipts_match(s1: &TransactionData, s2: &TransactionDataS2, receipts: &ReceiptPair) -> bool {
+    receipts.s1.share_digest == share_digest(&s1.dpf_src, &s1.dpf_dest)
+        && receipts.s2.share_digest == share_digest(&s2.dpf_src, &s2.dpf_dest)
+        && receipts.s1.session_id == s1.id
+}
+
+// Ask for the balances of a group in a fresh settlement epoch, normally
+// settle_epoch(). The last bit of the group number is padding only
+// all-prefix keys use.
+pub fn prepare_settle(group: u32, epoch: u64) -> (SettleData, SettleData) {
+    let alpha_bits = my_u32_to_bits(SETTLE_DOMAIN.try_into().unwrap(), group);
+    let (key1, key2) = PointDPFKey::gen(&alpha_bits[..SETTLE_DOMAIN - 1], &FieldElm::from(1u32));
+    (
+        SettleData { dpf_key: key1, epoch },
+        SettleData { dpf_key: key2, epoch },
+    )
+}
+
+// Add up the servers' answers to a settlement and strip the masks
+pub fn combine_balances(
+    bv1: &[FieldElm],
+    bv2: &[FieldElm],
+    group_secret: &GroupMasterSecret,
+    group: u32,
+    epoch: u64,
+) -> Vec<FieldElm> {
+    let bv = bv1
+        .iter()
+        .zip(bv2.iter())
+        .take(MAX_GROUP_SIZE)
+        .map(|(x, y)| {
+            let mut sum = x.clone();
+            sum.add(y);
+            sum
+        })
+        .collect();
+    GroupTokenPriv::decrypt_db(bv, group_secret, group, epoch)
+}
+
+// Balances are small and may be negative; anything else means the
+// servers' answers were not masked with this group's keys
+pub fn signed_balance(x: &FieldElm) -> Option<i64> {
+    if let Some(v) = scalar_to_u64(&x.value) {
+        return Some(v as i64).filter(|v| *v >= 0);
+    }
+    scalar_to_u64(&-x.value).map(|v| -(v as i64)).filter(|v| *v < 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::coms::verify_group_tokens;
+    use crate::keys::KeyRegistry;
+    use crate::membership::RevocationList;
+    use crate::ps::ServerData;
+    use crate::test_util::TestServers;
+    use crate::MAX_GROUP_NUM;
+
+    #[test]
+    fn prepared_transaction_verifies() {
+        let mut servers = TestServers::new();
+        let group_secret = GroupMasterSecret::random();
+        let members: Vec<GroupTokenPriv> = (20..23).map(|aid| servers.member(aid, &group_secret)).collect();
+        let tokens: Vec<GroupToken> = members.iter().map(|m| m.token.clone()).collect();
+        assert_eq!(account_index(&members[1].aid), 21);
+
+        let (s1_data, s2_data) = prepare_transaction(9, &members[1], &tokens, 22, 15).unwrap();
+        let session = SessionContext::new(9, members[1].issuer_key.shares(), &s1_data.dpf_src, &s1_data.dpf_dest, &tokens);
+        assert!(verify_group_tokens(s1_data.token_proof.clone(), tokens.clone(), s1_data.com_i, &session, &servers.keyrings[0], &RevocationList::default()));
+
+        // The key shares move the amount out of the payer's slot and into
+        // the payee's
+        let slot = |i: u32| my_u32_to_bits(DPF_DOMAIN as u8, i)[..DPF_DOMAIN - 1].to_vec();
+        let mut paid = s1_data.dpf_src.eval(&slot(21));
+        paid.add(&s2_data.dpf_src.eval(&slot(21)));
+        assert_eq!(paid, FieldElm::from(15u32));
+        let mut received = s1_data.dpf_dest.eval(&slot(22));
+        received.add(&s2_data.dpf_dest.eval(&slot(22)));
+        assert_eq!(received, FieldElm::from(15u32));
+        let mut other = s1_data.dpf_src.eval(&slot(22));
+        other.add(&s2_data.dpf_src.eval(&slot(22)));
+        assert_eq!(other, FieldElm::zero());
+
+        // A payer outside the ring, or a ring with a broken token, is
+        // refused before anything is sent
+        assert_eq!(prepare_transaction(10, &members[1], &tokens[..1], 22, 15).err(), Some(TransactionError::NotInGroup));
+        let mut broken = tokens.clone();
+        broken[0].P = CompressedRistretto([0xff; 32]);
+        assert_eq!(prepare_transaction(10, &members[1], &broken, 22, 15).err(), Some(TransactionError::BadToken));
+    }
+
+    #[test]
+    fn settle_round_trip() {
+        let group = 3;
+        let master = GroupMasterSecret::random();
+        let mut reg1 = KeyRegistry::new();
+        let mut reg2 = KeyRegistry::new();
+        reg1.register(group, master.server_key(1));
+        reg2.register(group, master.server_key(2));
+
+        // Each server holds a share of the database
+        let db: Vec<FieldElm> = (0..MAX_GROUP_NUM * MAX_GROUP_SIZE).map(|i| FieldElm::from(i as u32)).collect();
+        let zero = vec![FieldElm::zero(); db.len()];
+
+        let (d1, d2) = prepare_settle(group as u32, 5);
+        assert_eq!(d1.epoch, d2.epoch);
+        let enc1 = ServerData::encrypt_db(&db, &reg1, d1.epoch);
+        let enc2 = ServerData::encrypt_db(&zero, &reg2, d2.epoch);
+        let bv1 = ServerData::settle(&enc1, &enc2, &d1.dpf_key);
+        let bv2 = ServerData::settle(&enc2, &enc1, &d2.dpf_key);
+
+        let balances = combine_balances(&bv1, &bv2, &master, group as u32, d1.epoch);
+        assert_eq!(balances[..], db[group * MAX_GROUP_SIZE..(group + 1) * MAX_GROUP_SIZE]);
+    }
+}
@@ -1,5 +1,5 @@
 #![allow(non_snake_case)]
-use zkp::CompactProof;
+use zkp::BatchableProof;
 use zkp::ProofError;
 use zkp::Transcript;
 use curve25519_dalek::scalar::Scalar;
@@ -8,15 +8,20 @@ use curve25519_dalek::ristretto::CompressedRistretto;
 use curve25519_dalek::ristretto::RistrettoBasepointTable;
 use curve25519_dalek::constants as dalek_constants;
 use curve25519_dalek::traits::Identity;
-use sha2::Sha256;
-use hmac::{Hmac, Mac, NewMac};
+use crate::batch;
+use crate::burst::Burst;
 use crate::Group;
 use crate::FieldElm;
 use sha2::Sha512;
-use crate::sketch::SketchDPFKey;
-use std::convert::TryInto;
+use crate::sketch::SketchPointDPFKey;
 use std::ops::Neg;
+use std::collections::HashSet;
 use crate::ps::GroupToken;
+use crate::membership::RevocationList;
+use crate::ringproof::{decompress_ring, RingProof};
+use crate::tokens::{validate_tokens, TokenKeyring};
+use crate::ggm::IssuerPubKey;
+use sha2::{Digest, Sha256};
 use crate::MAX_GROUP_SIZE;
 use crate::MAX_GROUP_NUM;
 
@@ -47,47 +52,108 @@ define_proof! {
   ne3 = (id*ne3),
   G = (a*e2 + r3*H + tau*nH + id*ne3 + id*G)
 }
-define_proof! {
-  token,                         // Name of the module
-  "Group Token Proof",           // Label for the proof statement
-  (i, rt, rc),                   // Secret variables
-  (Ti, Ci),                      // Pub variables unique to each proof
-  (G, H, P) :                     // Pub variables common between proofs
-  Ti = (i*P + rt*G),
-  Ci = (i*G + rc*H)
-}
 // ========================================================================
 
-pub fn verify_group_tokens(proof: CompactProof, tokens: Vec<GroupToken>, ci: CompressedRistretto, mac: &Hmac<Sha256>) -> bool {
+// What the proofs of a transaction are bound to: the transaction's id,
+// the servers it is submitted to (identified by their issuer key
+// shares), the public parts of its DPF keys and the group tokens it
+// shows. Both proofs start their transcripts from the context, so a
+// proof can't be lifted into another transaction that reuses the same
+// commitments.
+#[derive(Clone, Debug)]
+pub struct SessionContext {
+	session_id: u32,
+	servers: Vec<[u8; 32]>,
+	dpf_digest: [u8; 32],
+	tokens_digest: [u8; 32],
+}
 
-	let G: &RistrettoPoint = &GEN_G;
-	let H: &RistrettoPoint = &GEN_H;
-	let mut retval = false;
-	let my_mac = mac.clone();
-	// VERIFY PROOFS
-	for i in 0..tokens.len() {
-		let mut transcript = Transcript::new(b"Group Token Proof");
-	    let ver = token::verify_compact(
-	        &proof,
-	        &mut transcript,
-	        token::VerifyAssignments {
-	            G: &G.compress(),
-	            H: &H.compress(),
-	            P: &tokens[i].P,
-	            Ti: &tokens[i].cm_aid,
-	            Ci: &ci,
-	        },
-	    );
-	    if ver.is_ok() {
-	    	retval = true;
-	    }
-	    let blah = my_mac.clone().verify(&tokens[i].mac_tag[..]);
-	    if blah.is_ok() {
-	    	println!("mac tag verified!");
-	    }
-
-	}
-    return retval;
+impl SessionContext {
+
+	pub fn new(
+		session_id: u32,
+		servers: &[IssuerPubKey],
+		dpf_src: &SketchPointDPFKey<FieldElm>,
+		dpf_dest: &SketchPointDPFKey<FieldElm>,
+		tokens: &[GroupToken],
+	) -> SessionContext {
+		let digest = |bytes: &[u8]| -> [u8; 32] { Sha256::digest(bytes).into() };
+		let servers = servers.iter().map(|s| digest(&bincode::serialize(s).unwrap())).collect();
+		let mut dpf = dpf_src.key.public_parts();
+		dpf.extend(dpf_dest.key.public_parts());
+		SessionContext {
+			session_id,
+			servers,
+			dpf_digest: digest(&dpf),
+			tokens_digest: digest(&bincode::serialize(tokens).unwrap()),
+		}
+	}
+
+	// A transcript for the proof with the given label, bound to the
+	// session
+	pub fn transcript(&self, label: &'static [u8]) -> Transcript {
+		let mut transcript = Transcript::new(label);
+		transcript.append_message(b"session id", &self.session_id.to_le_bytes());
+		for server in &self.servers {
+			transcript.append_message(b"server", server);
+		}
+		transcript.append_message(b"dpf keys", &self.dpf_digest);
+		transcript.append_message(b"group tokens", &self.tokens_digest);
+		transcript
+	}
+}
+
+// The session ids a server has taken transactions for. The id is the
+// client's choice, so a server refuses any id it has seen before:
+// otherwise the same transaction, proofs and all, could be replayed to
+// both servers and applied twice. Only an applied transaction uses up
+// its id, so a rejected one can be retried under the same id.
+#[derive(Clone, Debug, Default)]
+pub struct SessionLog {
+	seen: HashSet<u32>,
+}
+
+impl SessionLog {
+
+	pub fn new() -> SessionLog {
+		SessionLog { seen: HashSet::new() }
+	}
+
+	// Whether no transaction has been applied under a session id yet
+	pub fn is_fresh(&self, session_id: u32) -> bool {
+		!self.seen.contains(&session_id)
+	}
+
+	// Record a session id, and say whether it was fresh
+	pub fn accept(&mut self, session_id: u32) -> bool {
+		self.seen.insert(session_id)
+	}
+}
+
+pub fn verify_group_tokens(proof: RingProof, tokens: Vec<GroupToken>, ci: CompressedRistretto, session: &SessionContext, keyring: &TokenKeyring, revoked: &RevocationList) -> bool {
+
+	// Every token must carry a valid tag from this server, must not have
+	// expired, and must belong to a member who has not been removed
+	if validate_tokens(&tokens, keyring).is_err() {
+		return false;
+	}
+	if tokens.iter().any(|token| revoked.is_revoked(&token.uid)) {
+		return false;
+	}
+	// VERIFY PROOF
+	// The payer proves that the commitment to the source index opens to
+	// the AID of one of the tokens, without saying which one
+	let points: Vec<(CompressedRistretto, CompressedRistretto)> = tokens.iter().map(|t| (t.P, t.cm_aid)).collect();
+	let ring = match decompress_ring(&points) {
+		Some(ring) => ring,
+		None => return false,
+	};
+	let ci = match ci.decompress() {
+		Some(ci) => ci,
+		None => return false,
+	};
+	let mut transcript = session.transcript(b"Group Token Proof");
+	proof.verify(&mut transcript, &ring, &ci).is_ok()
 }
 pub fn create_com(val: FieldElm, rand: Scalar) -> (RistrettoPoint, RistrettoPoint) {
 
@@ -108,25 +174,18 @@ pub fn compute_coms_from_dpf(
 	r_lambda: Scalar ,
 ) -> (CompressedRistretto, CompressedRistretto, CompressedRistretto, CompressedRistretto) {
 
+	let slots = &vec_eval[..MAX_GROUP_NUM * MAX_GROUP_SIZE];
 	// Create commitment to value BETA
-	let mut beta_b = FieldElm::zero();
+	let beta_b = batch::sum(slots);
 	// Create commitment to LAMBDA = ALPHA * BETA
-	let mut alpha_b = FieldElm::zero();
-	for i in 0..MAX_GROUP_NUM * MAX_GROUP_SIZE {
-		beta_b.add(&vec_eval[i]);
-		let mut sum = FieldElm::zero();
-		sum.add(&vec_eval[i]);
-		let index: u32 = (i).try_into().unwrap();
-		sum.mul(&FieldElm::from(index));
-		alpha_b.add(&sum);
-	}
+	let alpha_b = batch::index_inner_product(slots);
 	let (com_beta, g_rb) = create_com(beta_b, r_beta);
 	let (com_lam, g_rl) = create_com(alpha_b, r_lambda);
 
 	return (com_beta.compress(), com_lam.compress(), g_rb.compress(), g_rl.compress());
 }
 
-pub fn eval_all(keyb_s: &SketchDPFKey<FieldElm, FieldElm>, keyb_d: &SketchDPFKey<FieldElm, FieldElm>) -> (Vec<(FieldElm, FieldElm)>, Vec<(FieldElm, FieldElm)>, Vec<FieldElm>, Vec<FieldElm>) {
+pub fn eval_all(keyb_s: &SketchPointDPFKey<FieldElm>, keyb_d: &SketchPointDPFKey<FieldElm>) -> (Vec<(FieldElm, FieldElm)>, Vec<(FieldElm, FieldElm)>, Vec<FieldElm>, Vec<FieldElm>) {
 	let mut eval_vec_src = Vec::<FieldElm>::new();
 	let mut eval_vec_dest = Vec::<FieldElm>::new();
 
@@ -145,21 +204,13 @@ pub fn eval_all(keyb_s: &SketchDPFKey<FieldElm, FieldElm>, keyb_d: &SketchDPFKey
 // S1 & S2
 // Should produce a share of the all-zero vector of length N, where N is the num of groups
 pub fn same_group_val_compute(eval_all_src: &Vec<FieldElm>, eval_all_dest: &Vec<FieldElm>, server1: bool) -> Vec<FieldElm> {
-	let mut result = Vec::<FieldElm>::new();
-	for i in 0..MAX_GROUP_NUM {
-		let mut sum_src = FieldElm::zero();
-		let mut sum_dest = FieldElm::zero();
-		let mut diff = FieldElm::zero();
-		for j in 0..MAX_GROUP_SIZE {
-			sum_src.add(&eval_all_src[j + i * MAX_GROUP_SIZE]);
-			sum_dest.add(&eval_all_dest[j + i * MAX_GROUP_SIZE]);
-		}
-		diff.add(&sum_src);
-		diff.sub(&sum_dest);
-		if server1 {
+	let n = MAX_GROUP_NUM * MAX_GROUP_SIZE;
+	let mut result = batch::group_sums(&eval_all_src[..n], MAX_GROUP_SIZE);
+	batch::sub_assign(&mut result, &batch::group_sums(&eval_all_dest[..n], MAX_GROUP_SIZE));
+	if server1 {
+		for diff in result.iter_mut() {
 			diff.negate();
 		}
-		result.push(diff);
 	}
 	return result;
 }
@@ -178,6 +229,7 @@ pub fn same_group_val_verify(result_1: &Vec<u8>, result_2: &Vec<u8>) -> bool {
 // Verify the commitments computed from the DPFs. This function is only 
 // used by S1. If this verifies, we know that the commitments to ALPHA
 // and BETA are valid.
+#[allow(clippy::too_many_arguments)]
 pub fn verify_coms_from_dpf(
 	g_r1: RistrettoPoint,
 	g_r2: RistrettoPoint,
@@ -185,7 +237,8 @@ pub fn verify_coms_from_dpf(
 	com_a: RistrettoPoint,
 	com_b: RistrettoPoint,
 	com_l: RistrettoPoint,
-	transact_pf: CompactProof,
+	transact_pf: BatchableProof,
+	session: &SessionContext,
 ) -> Result<(RistrettoPoint, RistrettoPoint), ProofError> {
 
 	let G: &RistrettoPoint = &GEN_G;
@@ -195,8 +248,8 @@ pub fn verify_coms_from_dpf(
 	let one = RistrettoPoint::identity();
 	let ncom_l = com_l.clone().neg();
 	// VERIFY PROOF
-	let mut transcript = Transcript::new(b"Transaction Proof");
-    transaction::verify_compact(
+	let mut transcript = session.transcript(b"Transaction Proof");
+    transaction::verify_batchable(
         &transact_pf,
         &mut transcript,
         transaction::VerifyAssignments {
@@ -214,3 +267,214 @@ pub fn verify_coms_from_dpf(
     )?;
     Ok((com_a, com_b))
 }
+
+// The public points of one transaction proof, computed by S1 from the
+// DPF outputs as in verify_coms_from_dpf.
+#[derive(Clone)]
+pub struct TransactionStatement {
+	pub g_r1: RistrettoPoint,
+	pub g_r2: RistrettoPoint,
+	pub g_r3: RistrettoPoint,
+	pub com_a: RistrettoPoint,
+	pub com_b: RistrettoPoint,
+	pub com_l: RistrettoPoint,
+	pub session: SessionContext,
+}
+
+// Verify the transaction proofs of a batch of transactions with a
+// single randomized multiscalar multiplication. proofs[i] is the proof
+// for statements[i]. The batch passes or fails as a whole; verify the
+// proofs one at a time with verify_coms_from_dpf to find a bad one.
+pub fn batch_verify_coms_from_dpf(
+	statements: &[TransactionStatement],
+	proofs: &[BatchableProof],
+) -> Result<(), ProofError> {
+	if statements.len() != proofs.len() {
+		return Err(ProofError::BatchSizeMismatch);
+	}
+	if statements.is_empty() {
+		return Ok(());
+	}
+	let G: &RistrettoPoint = &GEN_G;
+	let H: &RistrettoPoint = &GEN_H;
+	let points = |f: fn(&TransactionStatement) -> RistrettoPoint| {
+		statements.iter().map(|s| f(s).compress()).collect()
+	};
+	let mut transcripts: Vec<Transcript> = statements.iter().map(|s| s.session.transcript(b"Transaction Proof")).collect();
+	transaction::batch_verify(
+		proofs,
+		transcripts.iter_mut().collect(),
+		transaction::BatchVerifyAssignments {
+			G: G.compress(),
+			H: H.compress(),
+			nG: G.neg().compress(),
+			nH: H.neg().compress(),
+			v1: points(|s| s.g_r1),
+			e1: points(|s| s.com_a),
+			v2: points(|s| s.g_r2),
+			e2: points(|s| s.com_b),
+			v3: points(|s| s.g_r3),
+			ne3: points(|s| s.com_l.neg()),
+		},
+	)
+}
+
+// S1 checks the transaction proofs of concurrent transactions together
+// (see burst.rs)
+pub fn transaction_burst() -> Burst<(TransactionStatement, BatchableProof), ()> {
+	Burst::new(
+		|items: &[(TransactionStatement, BatchableProof)]| {
+			let (statements, proofs): (Vec<_>, Vec<_>) = items.iter().cloned().unzip();
+			batch_verify_coms_from_dpf(&statements, &proofs).ok().map(|_| vec![(); items.len()])
+		},
+		|(st, proof): &(TransactionStatement, BatchableProof)| {
+			verify_coms_from_dpf(st.g_r1, st.g_r2, st.g_r3, st.com_a, st.com_b, st.com_l, proof.clone(), &st.session).ok().map(|_| ())
+		},
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::ggm::Issuer;
+	use crate::ringproof::RingWitness;
+	use crate::{current_epoch, u32_to_bits, DPF_DOMAIN};
+
+	fn session(id: u32, servers: &[IssuerPubKey], tokens: &[GroupToken]) -> SessionContext {
+		let alpha = u32_to_bits(DPF_DOMAIN as u8 - 1, id);
+		let [src, _] = SketchPointDPFKey::gen(&alpha, &FieldElm::zero());
+		let [dest, _] = SketchPointDPFKey::gen(&alpha, &FieldElm::zero());
+		SessionContext::new(id, servers, &src, &dest, tokens)
+	}
+
+	// A transaction proof for payer AID a paying b, built the way the
+	// client builds it
+	fn transaction(a: u32, b: u32, session: SessionContext) -> (TransactionStatement, BatchableProof) {
+		let mut rng = rand::thread_rng();
+		let (r1, r2, r3) = (Scalar::random(&mut rng), Scalar::random(&mut rng), Scalar::random(&mut rng));
+		let G: &RistrettoPoint = &GEN_G;
+		let H: &RistrettoPoint = &GEN_H;
+		let (a, b) = (Scalar::from(a), Scalar::from(b));
+		let tau = a * r2;
+		let mut transcript = session.transcript(b"Transaction Proof");
+		let st = TransactionStatement {
+			g_r1: G * r1,
+			g_r2: G * r2,
+			g_r3: G * r3,
+			com_a: G * a + H * r1,
+			com_b: G * b + H * r2,
+			com_l: G * (a * b) + H * r3,
+			session,
+		};
+		let (proof, _) = transaction::prove_batchable(
+			&mut transcript,
+			transaction::ProveAssignments {
+				G: G,
+				H: H,
+				nG: &G.neg(),
+				nH: &H.neg(),
+				v1: &st.g_r1,
+				v2: &st.g_r2,
+				v3: &st.g_r3,
+				e1: &st.com_a,
+				e2: &st.com_b,
+				ne3: &st.com_l.neg(),
+				r1: &r1,
+				r3: &r3,
+				a: &a,
+				id: &Scalar::one(),
+				tau: &tau,
+			},
+		);
+		(st, proof)
+	}
+
+	fn verify(st: &TransactionStatement, proof: &BatchableProof) -> bool {
+		verify_coms_from_dpf(st.g_r1, st.g_r2, st.g_r3, st.com_a, st.com_b, st.com_l, proof.clone(), &st.session).is_ok()
+	}
+
+	#[test]
+	fn batch_transactions() {
+		let servers = [Issuer::new(5).pubkey, Issuer::new(5).pubkey];
+		let batch = |n| -> (Vec<_>, Vec<_>) { (0..n).map(|i| transaction(i, 20, session(i, &servers, &[]))).unzip() };
+		let (statements, proofs) = batch(4);
+		assert!(batch_verify_coms_from_dpf(&statements, &proofs).is_ok());
+		assert!(batch_verify_coms_from_dpf(&[], &[]).is_ok());
+		assert!(batch_verify_coms_from_dpf(&statements[1..], &proofs).is_err());
+
+		// A commitment to a product other than a*b spoils the batch
+		let (mut statements, proofs) = batch(4);
+		statements[2].com_l += *GEN_G;
+		assert!(batch_verify_coms_from_dpf(&statements, &proofs).is_err());
+		assert!(!verify(&statements[2], &proofs[2]));
+		assert!(verify(&statements[0], &proofs[0]));
+
+		let burst = transaction_burst();
+		assert!(burst.check((statements[0].clone(), proofs[0].clone())).is_some());
+		assert!(burst.check((statements[2].clone(), proofs[2].clone())).is_none());
+	}
+
+	#[test]
+	fn transaction_bound_to_session() {
+		let servers = [Issuer::new(5).pubkey, Issuer::new(5).pubkey];
+		let (mut st, proof) = transaction(3, 20, session(7, &servers, &[]));
+		assert!(verify(&st, &proof));
+
+		// The same commitments under another id, other DPF keys or other
+		// servers don't verify
+		st.session = session(8, &servers, &[]);
+		assert!(!verify(&st, &proof));
+		st.session = session(7, &servers, &[]);
+		assert!(!verify(&st, &proof));
+		st.session.session_id = 7;
+		st.session.servers.swap(0, 1);
+		assert!(!verify(&st, &proof));
+	}
+
+	#[test]
+	fn token_proof_bound_to_session() {
+		let mut rng = rand::thread_rng();
+		let keyring = TokenKeyring::new(0);
+		let revoked = RevocationList::default();
+		let servers = [Issuer::new(5).pubkey, Issuer::new(5).pubkey];
+		let G: &RistrettoPoint = &GEN_G;
+		let H: &RistrettoPoint = &GEN_H;
+
+		// Group tokens whose commitment to the AID is aid*P + rt*G
+		let aid = Scalar::from(4u64);
+		let witnesses: Vec<Scalar> = (0..3).map(|_| Scalar::random(&mut rng)).collect();
+		let tokens: Vec<GroupToken> = witnesses
+			.iter()
+			.map(|rt| {
+				let P = RistrettoPoint::random(&mut rng);
+				let mut token = GroupToken::new(P.compress(), Scalar::random(&mut rng), (aid * P + rt * G).compress(), current_epoch());
+				keyring.issue(&mut token);
+				token
+			})
+			.collect();
+		let points: Vec<(CompressedRistretto, CompressedRistretto)> = tokens.iter().map(|t| (t.P, t.cm_aid)).collect();
+		let ring = decompress_ring(&points).unwrap();
+		let rc = Scalar::random(&mut rng);
+		let ci = aid * G + rc * H;
+		let witness = RingWitness { index: 1, i: aid, rt: witnesses[1], rc };
+
+		let ctx = session(5, &servers, &tokens);
+		let proof = RingProof::prove(&mut ctx.transcript(b"Group Token Proof"), &ring, &ci, &witness);
+		assert!(verify_group_tokens(proof.clone(), tokens.clone(), ci.compress(), &ctx, &keyring, &revoked));
+
+		let other = session(6, &servers, &tokens);
+		assert!(!verify_group_tokens(proof, tokens, ci.compress(), &other, &keyring, &revoked));
+	}
+
+	#[test]
+	fn session_ids_are_single_use() {
+		let mut log = SessionLog::new();
+		assert!(log.is_fresh(5));
+		assert!(log.is_fresh(5));
+		assert!(log.accept(5));
+		assert!(log.accept(6));
+		assert!(!log.is_fresh(5));
+		assert!(!log.accept(5));
+	}
+}
@@ -0,0 +1,232 @@
+// Authorizing group creation.
+//
+// Creating a group takes a block of MAX_GROUP_SIZE slots of the
+// database, which has room for MAX_GROUP_NUM groups, so S1 only creates
+// groups for holders of a CreationPass from the operator. A pass names
+// a random pass ID, a quota of groups and an expiry epoch, signed with
+// the operator's key (see signature.rs). S1 counts the groups created under each pass ID and
+// refuses a pass once its quota is used up. When the database is full
+// S1 says so rather than handing out slots it doesn't have.
+//
+// S2 keeps a ledger of its own: once S1 has assigned the group number,
+// the leader registers the group with S2 under the same pass (a
+// PeerGroupRequest). S2 records the group's leader first come first
+// served, and checks slot requests against that record (see
+// membership.rs) instead of taking S1's word for them.
+#![allow(non_snake_case)]
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use data_encoding::BASE32_NOPAD;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::current_epoch;
+use crate::signature::{self, Signature, SigningKey};
+use crate::{MAX_GROUP_NUM, MAX_GROUP_SIZE};
+
+pub const PASS_ID_SIZE: usize = 16;
+const PASS_CONTEXT: &[u8] = b"PSA creation pass";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CreationError {
+    // The operator's signature on the pass did not verify
+    BadPass,
+    // The pass's last validity epoch has passed
+    Expired(u64),
+    // Every group the pass allows has been created
+    QuotaExhausted,
+    // There are no free slots left for another group
+    DatabaseFull,
+    // S2 already has a leader for the group
+    GroupTaken,
+    // The request could not be decoded
+    BadRequest,
+}
+
+pub type OperatorKey = SigningKey;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CreationPass {
+    pub id: [u8; PASS_ID_SIZE],
+    pub quota: u32,
+    pub expiry: u64,
+    sig: Signature,
+}
+
+fn pass_message(id: &[u8; PASS_ID_SIZE], quota: u32, expiry: u64) -> Vec<u8> {
+    let mut msg = id.to_vec();
+    msg.extend(quota.to_be_bytes());
+    msg.extend(expiry.to_be_bytes());
+    msg
+}
+
+impl CreationPass {
+    // A pass for creating up to `quota` groups until `expiry`
+    pub fn new(operator: &OperatorKey, quota: u32, expiry: u64) -> CreationPass {
+        let id = rand::thread_rng().gen::<[u8; PASS_ID_SIZE]>();
+        let sig = operator.sign(PASS_CONTEXT, &pass_message(&id, quota, expiry));
+        CreationPass { id, quota, expiry, sig }
+    }
+
+    fn verify(&self, operator_pk: &CompressedRistretto) -> bool {
+        signature::verify(operator_pk, PASS_CONTEXT, &pass_message(&self.id, self.quota, self.expiry), &self.sig)
+    }
+
+    // Unpadded base32, as for invitations
+    pub fn to_text(&self) -> String {
+        BASE32_NOPAD.encode(&bincode::serialize(self).unwrap())
+    }
+
+    pub fn from_text(text: &str) -> Option<CreationPass> {
+        let bytes = BASE32_NOPAD.decode(text.trim().as_bytes()).ok()?;
+        bincode::deserialize(&bytes).ok()
+    }
+}
+
+// What a group leader sends S1 to create a group: S1's PRF key, the
+// leader's public key and the pass.
+#[derive(Serialize, Deserialize)]
+pub struct GroupCreateRequest {
+    pub s1_key: Vec<u8>,
+    pub leader_pk: CompressedRistretto,
+    pub pass: CreationPass,
+}
+
+// What a group leader sends S2 once S1 has created the group: the group
+// number S1 assigned, S2's PRF key, the leader's public key and the same
+// pass. S1 never sees S2's key.
+#[derive(Serialize, Deserialize)]
+pub struct PeerGroupRequest {
+    pub group: u32,
+    pub s2_key: Vec<u8>,
+    pub leader_pk: CompressedRistretto,
+    pub pass: CreationPass,
+}
+
+// A server's record of the groups created under each pass. The servers
+// keep it in redis (see to_bytes), so that quotas survive a restart.
+#[derive(Clone, Debug)]
+pub struct CreationLedger {
+    operator_pk: CompressedRistretto,
+    used: HashMap<[u8; PASS_ID_SIZE], u32>,
+}
+
+impl CreationLedger {
+    pub fn new(operator_pk: CompressedRistretto) -> CreationLedger {
+        CreationLedger {
+            operator_pk,
+            used: HashMap::new(),
+        }
+    }
+
+    // Check that there is room for a group starting at slot `next`, and
+    // that the pass allows another group, and count the group against
+    // the pass's quota. A refused request doesn't use up the quota.
+    pub fn authorize(&mut self, pass: &CreationPass, next: usize) -> Result<(), CreationError> {
+        if next + MAX_GROUP_SIZE > MAX_GROUP_SIZE * MAX_GROUP_NUM {
+            return Err(CreationError::DatabaseFull);
+        }
+        if !pass.verify(&self.operator_pk) {
+            return Err(CreationError::BadPass);
+        }
+        if pass.expiry < current_epoch() {
+            return Err(CreationError::Expired(pass.expiry));
+        }
+        let used = self.used.entry(pass.id).or_insert(0);
+        if *used >= pass.quota {
+            return Err(CreationError::QuotaExhausted);
+        }
+        *used += 1;
+        Ok(())
+    }
+
+    // How many more groups the pass allows
+    pub fn remaining(&self, pass: &CreationPass) -> u32 {
+        pass.quota - self.used.get(&pass.id).copied().unwrap_or(0)
+    }
+
+    // The groups counted against each pass
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self.used).unwrap()
+    }
+
+    // A ledger with the counts saved by to_bytes, or None if they can't
+    // be read
+    pub fn restore(operator_pk: CompressedRistretto, bytes: &[u8]) -> Option<CreationLedger> {
+        let used = bincode::deserialize(bytes).ok()?;
+        Some(CreationLedger { operator_pk, used })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota() {
+        let op = OperatorKey::new();
+        let mut ledger = CreationLedger::new(op.pk.compress());
+        let pass = CreationPass::new(&op, 2, current_epoch());
+        assert_eq!(ledger.authorize(&pass, 0), Ok(()));
+        assert_eq!(ledger.authorize(&pass, MAX_GROUP_SIZE), Ok(()));
+        assert_eq!(ledger.remaining(&pass), 0);
+        assert_eq!(ledger.authorize(&pass, 2 * MAX_GROUP_SIZE), Err(CreationError::QuotaExhausted));
+
+        // Another pass has its own quota
+        let other = CreationPass::new(&op, 1, current_epoch());
+        assert_eq!(ledger.authorize(&other, 2 * MAX_GROUP_SIZE), Ok(()));
+
+        // The pass survives its text encoding
+        let text = other.to_text();
+        let decoded = CreationPass::from_text(&text).unwrap();
+        assert_eq!(ledger.authorize(&decoded, 3 * MAX_GROUP_SIZE), Err(CreationError::QuotaExhausted));
+        assert!(CreationPass::from_text("not base32!").is_none());
+    }
+
+    #[test]
+    fn bad_passes() {
+        let op = OperatorKey::new();
+        let mut ledger = CreationLedger::new(op.pk.compress());
+
+        // Passes from anyone but the operator, or with a raised quota,
+        // are refused
+        let forged = CreationPass::new(&OperatorKey::new(), 5, current_epoch());
+        assert_eq!(ledger.authorize(&forged, 0), Err(CreationError::BadPass));
+        let mut pass = CreationPass::new(&op, 1, current_epoch());
+        pass.quota = 100;
+        assert_eq!(ledger.authorize(&pass, 0), Err(CreationError::BadPass));
+
+        let expired = CreationPass::new(&op, 1, current_epoch() - 1);
+        assert_eq!(ledger.authorize(&expired, 0), Err(CreationError::Expired(current_epoch() - 1)));
+    }
+
+    #[test]
+    fn database_full() {
+        let op = OperatorKey::new();
+        let mut ledger = CreationLedger::new(op.pk.compress());
+        let pass = CreationPass::new(&op, 1, current_epoch());
+        let last = MAX_GROUP_SIZE * (MAX_GROUP_NUM - 1);
+        assert_eq!(ledger.authorize(&pass, last + MAX_GROUP_SIZE), Err(CreationError::DatabaseFull));
+
+        // A full database doesn't use up the pass
+        assert_eq!(ledger.remaining(&pass), 1);
+        assert_eq!(ledger.authorize(&pass, last), Ok(()));
+    }
+
+    #[test]
+    fn restore() {
+        let op = OperatorKey::new();
+        let mut ledger = CreationLedger::new(op.pk.compress());
+        let pass = CreationPass::new(&op, 2, current_epoch());
+        ledger.authorize(&pass, 0).unwrap();
+
+        // A restarted server picks up where it left off
+        let mut restored = CreationLedger::restore(op.pk.compress(), &ledger.to_bytes()).unwrap();
+        assert_eq!(restored.remaining(&pass), 1);
+        restored.authorize(&pass, MAX_GROUP_SIZE).unwrap();
+        assert_eq!(restored.authorize(&pass, 2 * MAX_GROUP_SIZE), Err(CreationError::QuotaExhausted));
+        assert!(CreationLedger::restore(op.pk.compress(), b"junk").is_none());
+    }
+}
@@ -1,10 +1,12 @@
 use crate::prg;
 use crate::Group;
 use crate::prg::PrgSeed;
-use crate::DPF_DOMAIN;
-use crate::SETTLE_DOMAIN;
 use serde::Deserialize;
 use serde::Serialize;
+use rayon::prelude::*;
+
+// Subtrees of this many levels are evaluated as one batch by eval_tree
+const EVAL_BATCH_LEVELS: usize = 10;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CorWord<T> {
@@ -21,6 +23,14 @@ pub struct DPFKey<T,U> {
     cor_word_last: CorWord<U>,
 }
 
+impl<T: Serialize, U: Serialize> DPFKey<T,U> {
+    // The correction words, which both keys of a pair share. The rest
+    // of a key is known only to the server holding it.
+    pub fn public_parts(&self) -> Vec<u8> {
+        bincode::serialize(&(&self.cor_words, &self.cor_word_last)).unwrap()
+    }
+}
+
 #[derive(Clone)]
 pub struct EvalState {
     level: usize,
@@ -120,12 +130,12 @@ fn gen_cor_word<W>(bit: bool, value: W, bits: &mut (bool, bool), seeds: &mut (pr
     }
 
     let converted = seeds.map(|s| s.convert());
-    if cw.word.is_some() {
-        cw.word = Some(value);
-        cw.word.clone().expect("REASON").sub(&converted.0.word);
-        cw.word.clone().expect("REASON").add(&converted.1.word);
+    if let Some(word) = cw.word.as_mut() {
+        *word = value;
+        word.sub(&converted.0.word);
+        word.add(&converted.1.word);
         if bits.1 {
-            cw.word.clone().expect("REASON").negate();
+            word.negate();
         }
     }
 
@@ -136,6 +146,99 @@ fn gen_cor_word<W>(bit: bool, value: W, bits: &mut (bool, bool), seeds: &mut (pr
 }
 
 
+// A GGM tree that eval_tree can evaluate level by level.
+trait Tree<T>: Sync {
+    fn levels(&self) -> usize;
+    // How many outputs each leaf holds
+    fn width(&self) -> usize {
+        1
+    }
+    fn root(&self) -> EvalState;
+    // The two children of a node above the last level
+    fn children(&self, state: &EvalState) -> (EvalState, EvalState);
+    // The outputs of the two leaves under a node on the level above the
+    // leaves, or as many of them as fit in `out`
+    fn leaves(&self, state: &EvalState, out: &mut [T]);
+}
+
+// Evaluate the first out.len() outputs of a tree, in order, into `out`.
+// The top of the tree is expanded a level at a time until it has split
+// into subtrees of 2^EVAL_BATCH_LEVELS leaves, and those are expanded a
+// level at a time on the rayon pool, each writing its own part of
+// `out`. Subtrees past the end of `out` are never expanded.
+fn eval_tree<T: Send, K: Tree<T>>(key: &K, out: &mut [T]) {
+    let levels = key.levels();
+    assert!(levels >= 1 && out.len() <= key.width() << levels);
+    if out.is_empty() {
+        return;
+    }
+    let split = levels.saturating_sub(EVAL_BATCH_LEVELS);
+    let mut frontier = vec![key.root()];
+    for _ in 0..split {
+        frontier = expand_level(key, &frontier, out.len());
+    }
+    out.par_chunks_mut(key.width() << (levels - split))
+        .zip(frontier.par_iter())
+        .for_each(|(leaves, root)| eval_subtree(key, root, leaves));
+}
+
+// The children of each state, in order, as far as they are needed for
+// the first n outputs under the states
+fn expand_level<T, K: Tree<T>>(key: &K, states: &[EvalState], n: usize) -> Vec<EvalState> {
+    let span = key.width() << (key.levels() - states[0].level - 1);
+    let needed = n.div_ceil(span);
+    let mut next = Vec::with_capacity(needed);
+    for state in states {
+        let (left, right) = key.children(state);
+        for child in [left, right] {
+            if next.len() == needed {
+                return next;
+            }
+            next.push(child);
+        }
+    }
+    next
+}
+
+fn eval_subtree<T, K: Tree<T>>(key: &K, root: &EvalState, out: &mut [T]) {
+    let mut states = vec![root.clone()];
+    while states[0].level + 1 < key.levels() {
+        states = expand_level(key, &states, out.len());
+    }
+    for (state, leaves) in states.iter().zip(out.chunks_mut(2 * key.width())) {
+        key.leaves(state, leaves);
+    }
+}
+
+impl<T,U> Tree<T> for DPFKey<T,U>
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug + Sync,
+    U: prg::FromRng + Clone + Group + std::fmt::Debug + Sync
+{
+    fn levels(&self) -> usize {
+        self.domain_size()
+    }
+
+    fn root(&self) -> EvalState {
+        self.eval_init()
+    }
+
+    fn children(&self, state: &EvalState) -> (EvalState, EvalState) {
+        let tau = state.seed.expand();
+        let (left, _) = self.my_eval_bit(state, tau.seeds.0.clone(), tau.bits.0, false, &false);
+        let (right, _) = self.my_eval_bit(state, tau.seeds.1.clone(), tau.bits.1, true, &false);
+        (left, right)
+    }
+
+    fn leaves(&self, state: &EvalState, out: &mut [T]) {
+        let tau = state.seed.expand();
+        out[0] = self.my_eval_bit(state, tau.seeds.0.clone(), tau.bits.0, false, &true).1;
+        if out.len() > 1 {
+            out[1] = self.my_eval_bit(state, tau.seeds.1.clone(), tau.bits.1, true, &true).1;
+        }
+    }
+}
+
 /// All-prefix DPF implementation.
 impl<T,U> DPFKey<T,U>
 where
@@ -310,48 +413,43 @@ where
 
         (out, last)
     }
-    pub fn eval_all(&self) -> Vec<T> {
-        let mut out = vec![];
-        let state = self.eval_init();
-        self.eval_all_actual(0, &mut out, &state);
+    // Every leaf of the tree, in order. The depth of the tree is the
+    // number of correction words in the key.
+    pub fn eval_all(&self) -> Vec<T>
+        where T: Send + Sync, U: Sync
+    {
+        self.eval_first(1 << self.domain_size())
+    }
 
+    // The first n leaves of the tree, in order
+    pub fn eval_first(&self, n: usize) -> Vec<T>
+        where T: Send + Sync, U: Sync
+    {
+        let mut out = vec![T::zero(); n];
+        self.eval_first_into(&mut out);
         out
     }
-    pub fn eval_all_actual(&self, len: usize, out: &mut Vec<T>, state: &EvalState) {
 
-        let bit_0 = false;
-        let bit_1 = true;
-        let target = (len + 1) == DPF_DOMAIN - 1;
-        let tau = state.seed.expand();
-        let seed0 = tau.seeds.get(bit_0);
-        let seed1 = tau.seeds.get(bit_1);
-        let new_bit0 = tau.bits.get(bit_0);
-        let new_bit1 = tau.bits.get(bit_1);
-        let (state_new_0, word_0) = self.my_eval_bit(&state, seed0.clone(), *new_bit0, bit_0, &target);
-        let (state_new_1, word_1) = self.my_eval_bit(&state, seed1.clone(), *new_bit1, bit_1, &target);
-
-        // Base Case! We've hit the target length
-        if target {
-            out.push(word_0);
-            out.push(word_1);
-            return; 
-        }
-        // Otherwise keep adding
-        self.eval_all_actual(len + 1, out, &state_new_0);
-        self.eval_all_actual(len + 1, out, &state_new_1);
+    pub fn eval_first_into(&self, out: &mut [T])
+        where T: Send + Sync, U: Sync
+    {
+        eval_tree(self, out);
     }
-    pub fn eval_all_settle(&self) -> Vec<T> {
+
+    // The depth-first walk eval_all used to do, kept as a reference for
+    // tests and benchmarks
+    pub fn eval_all_recursive(&self) -> Vec<T> {
         let mut out = vec![];
         let state = self.eval_init();
-        self.eval_all_actual_settle(0, &mut out, &state);
+        self.eval_all_actual(self.domain_size(), &mut out, &state);
 
         out
     }
-    pub fn eval_all_actual_settle(&self, len: usize, out: &mut Vec<T>, state: &EvalState) {
+    fn eval_all_actual(&self, levels: usize, out: &mut Vec<T>, state: &EvalState) {
 
         let bit_0 = false;
         let bit_1 = true;
-        let target = (len + 1) == SETTLE_DOMAIN - 1;
+        let target = state.level + 1 == levels;
         let tau = state.seed.expand();
         let seed0 = tau.seeds.get(bit_0);
         let seed1 = tau.seeds.get(bit_1);
@@ -367,8 +465,8 @@ where
             return; 
         }
         // Otherwise keep adding
-        self.eval_all_actual_settle(len + 1, out, &state_new_0);
-        self.eval_all_actual_settle(len + 1, out, &state_new_1);
+        self.eval_all_actual(levels, out, &state_new_0);
+        self.eval_all_actual(levels, out, &state_new_1);
     }
 
     pub fn gen_from_str(s: &str) -> (Self, Self) {
@@ -381,3 +479,546 @@ where
         self.cor_words.len()
     }
 }
+
+// The seed and bit corrections for one level of a PointDPFKey
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SeedCorWord {
+    pub seed: prg::PrgSeed,
+    pub bits: (bool, bool),
+}
+
+// The seed and bit corrections of a standard DPF key pair for the path
+// to alpha, with the root seeds, and the two keys' seeds and bits at the
+// leaf on the path
+#[allow(clippy::type_complexity)]
+fn gen_seed_cor_words(alpha_bits: &[bool]) -> ((PrgSeed, PrgSeed), Vec<SeedCorWord>, (PrgSeed, PrgSeed), (bool, bool)) {
+    debug_assert!(!alpha_bits.is_empty());
+
+    let root_seeds = (prg::PrgSeed::random(), prg::PrgSeed::random());
+    let mut seeds = root_seeds.clone();
+    let mut bits = (false, true);
+
+    let mut cor_words = Vec::with_capacity(alpha_bits.len());
+    for &bit in alpha_bits {
+        let data = seeds.map(|s| s.expand());
+        let keep = bit;
+        let lose = !keep;
+
+        let cw = SeedCorWord {
+            seed: data.0.seeds.get(lose) ^ data.1.seeds.get(lose),
+            bits: (
+                data.0.bits.0 ^ data.1.bits.0 ^ bit ^ true,
+                data.0.bits.1 ^ data.1.bits.1 ^ bit,
+            ),
+        };
+
+        for (b, seed) in seeds.iter_mut() {
+            *seed = data.get(b).seeds.get(keep).clone();
+            let mut newbit = *data.get(b).bits.get(keep);
+            if *bits.get(b) {
+                *seed = &*seed ^ &cw.seed;
+                newbit ^= cw.bits.get(keep);
+            }
+            *bits.get_mut(b) = newbit;
+        }
+        cor_words.push(cw);
+    }
+
+    (root_seeds, cor_words, seeds, bits)
+}
+
+// Apply a level's correction to the seed and bit of a child of `state`
+fn correct(cor_words: &[SeedCorWord], state: &EvalState, seed: &PrgSeed, bit: bool, dir: bool) -> EvalState {
+    let cw = &cor_words[state.level];
+    let (seed, bit) = if state.bit {
+        (seed ^ &cw.seed, bit ^ cw.bits.get(dir))
+    } else {
+        (seed.clone(), bit)
+    };
+    EvalState {
+        level: state.level + 1,
+        seed,
+        bit,
+    }
+}
+
+/// Standard DPF implementation.
+///
+/// Only the leaves carry a value, so the key has one output correction
+/// word instead of one per level, and evaluation converts a seed into a
+/// value only at the leaves.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PointDPFKey<T> {
+    key_idx: bool,
+    root_seed: prg::PrgSeed,
+    pub cor_words: Vec<SeedCorWord>,
+    pub cor_word_last: T,
+}
+
+impl<T: Serialize> PointDPFKey<T> {
+    // The correction words, which both keys of a pair share
+    pub fn public_parts(&self) -> Vec<u8> {
+        bincode::serialize(&(&self.cor_words, &self.cor_word_last)).unwrap()
+    }
+}
+
+impl<T> PointDPFKey<T>
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug
+{
+    // Keys for the function that is `value` at alpha and zero elsewhere,
+    // on a domain of 2^alpha_bits.len() leaves
+    pub fn gen(alpha_bits: &[bool], value: &T) -> (PointDPFKey<T>, PointDPFKey<T>) {
+        let (root_seeds, cor_words, seeds, bits) = gen_seed_cor_words(alpha_bits);
+
+        // The leaves at alpha must add up to value
+        let converted = seeds.map(|s| s.convert::<T>());
+        let mut cor_word_last = value.clone();
+        cor_word_last.sub(&converted.0.word);
+        cor_word_last.add(&converted.1.word);
+        if bits.1 {
+            cor_word_last.negate();
+        }
+
+        (
+            PointDPFKey {
+                key_idx: false,
+                root_seed: root_seeds.0,
+                cor_words: cor_words.clone(),
+                cor_word_last: cor_word_last.clone(),
+            },
+            PointDPFKey {
+                key_idx: true,
+                root_seed: root_seeds.1,
+                cor_words,
+                cor_word_last,
+            },
+        )
+    }
+
+    pub fn eval_init(&self) -> EvalState {
+        EvalState {
+            level: 0,
+            seed: self.root_seed.clone(),
+            bit: self.key_idx,
+        }
+    }
+
+    fn leaf(&self, state: &EvalState) -> T {
+        let mut word = state.seed.convert::<T>().word;
+        if state.bit {
+            word.add(&self.cor_word_last);
+        }
+        if self.key_idx {
+            word.negate();
+        }
+        word
+    }
+
+    fn leaf_state(&self, idx: &[bool]) -> EvalState {
+        debug_assert!(idx.len() == self.domain_size());
+        let mut state = self.eval_init();
+        for &dir in idx {
+            let tau = state.seed.expand_dir(!dir, dir);
+            state = correct(&self.cor_words, &state, tau.seeds.get(dir), *tau.bits.get(dir), dir);
+        }
+        state
+    }
+
+    pub fn eval(&self, idx: &[bool]) -> T {
+        self.leaf(&self.leaf_state(idx))
+    }
+
+    // Whether the output correction word is added in at a leaf. At
+    // alpha, exactly one key of a pair adds it.
+    pub fn leaf_bit(&self, idx: &[bool]) -> bool {
+        self.leaf_state(idx).bit
+    }
+
+    // Whether this is the second key of its pair, whose outputs are
+    // negated
+    pub fn key_idx(&self) -> bool {
+        self.key_idx
+    }
+
+    pub fn eval_all(&self) -> Vec<T>
+        where T: Send + Sync
+    {
+        self.eval_first(1 << self.domain_size())
+    }
+
+    pub fn eval_first(&self, n: usize) -> Vec<T>
+        where T: Send + Sync
+    {
+        let mut out = vec![T::zero(); n];
+        self.eval_first_into(&mut out);
+        out
+    }
+
+    pub fn eval_first_into(&self, out: &mut [T])
+        where T: Send + Sync
+    {
+        eval_tree(self, out);
+    }
+
+    pub fn domain_size(&self) -> usize {
+        self.cor_words.len()
+    }
+}
+
+impl<T> Tree<T> for PointDPFKey<T>
+where
+    T: prg::FromRng + Clone + Group + std::fmt::Debug + Sync
+{
+    fn levels(&self) -> usize {
+        self.domain_size()
+    }
+
+    fn root(&self) -> EvalState {
+        self.eval_init()
+    }
+
+    fn children(&self, state: &EvalState) -> (EvalState, EvalState) {
+        let tau = state.seed.expand();
+        (
+            correct(&self.cor_words, state, &tau.seeds.0, tau.bits.0, false),
+            correct(&self.cor_words, state, &tau.seeds.1, tau.bits.1, true),
+        )
+    }
+
+    fn leaves(&self, state: &EvalState, out: &mut [T]) {
+        let (left, right) = self.children(state);
+        out[0] = self.leaf(&left);
+        if out.len() > 1 {
+            out[1] = self.leaf(&right);
+        }
+    }
+}
+
+/// Early-termination DPF implementation.
+///
+/// A standard DPF whose leaves each hold `width` adjacent outputs, packed
+/// several to an AES block of the leaf seed's PRG stream, so the last
+/// levels of a tree with one output per leaf are never expanded. With a
+/// width of MAX_GROUP_SIZE a leaf covers the slots of one group. The
+/// output correction word has an element for each output of a leaf.
+///
+/// Outputs are in one of the small fields: a FieldElm needs more than a
+/// block, so it can't be packed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackedDPFKey<T> {
+    key_idx: bool,
+    root_seed: prg::PrgSeed,
+    pub cor_words: Vec<SeedCorWord>,
+    pub cor_word_last: Vec<T>,
+}
+
+impl<T: Serialize> PackedDPFKey<T> {
+    // The correction words, which both keys of a pair share
+    pub fn public_parts(&self) -> Vec<u8> {
+        bincode::serialize(&(&self.cor_words, &self.cor_word_last)).unwrap()
+    }
+}
+
+impl<T> PackedDPFKey<T>
+where
+    T: prg::PackedWord + Clone + std::fmt::Debug
+{
+    // Keys for the function that is `value` at output `offset` of leaf
+    // alpha and zero elsewhere, on a domain of 2^block_bits.len() leaves
+    // of `width` outputs each
+    pub fn gen(block_bits: &[bool], width: usize, offset: usize, value: &T) -> (PackedDPFKey<T>, PackedDPFKey<T>) {
+        assert!(offset < width);
+        let (root_seeds, cor_words, seeds, bits) = gen_seed_cor_words(block_bits);
+
+        // The leaves at alpha must add up to value at offset and zero at
+        // the other outputs
+        let words = seeds.map(|s| s.convert_words::<T>(width));
+        let mut cor_word_last = Vec::with_capacity(width);
+        for j in 0..width {
+            let mut word = if j == offset { value.clone() } else { T::zero() };
+            word.sub(&words.0[j]);
+            word.add(&words.1[j]);
+            if bits.1 {
+                word.negate();
+            }
+            cor_word_last.push(word);
+        }
+
+        (
+            PackedDPFKey {
+                key_idx: false,
+                root_seed: root_seeds.0,
+                cor_words: cor_words.clone(),
+                cor_word_last: cor_word_last.clone(),
+            },
+            PackedDPFKey {
+                key_idx: true,
+                root_seed: root_seeds.1,
+                cor_words,
+                cor_word_last,
+            },
+        )
+    }
+
+    pub fn eval_init(&self) -> EvalState {
+        EvalState {
+            level: 0,
+            seed: self.root_seed.clone(),
+            bit: self.key_idx,
+        }
+    }
+
+    fn leaf(&self, state: &EvalState) -> Vec<T> {
+        let mut words = state.seed.convert_words::<T>(self.width());
+        for (word, cw) in words.iter_mut().zip(&self.cor_word_last) {
+            if state.bit {
+                word.add(cw);
+            }
+            if self.key_idx {
+                word.negate();
+            }
+        }
+        words
+    }
+
+    // The outputs of one leaf
+    pub fn eval(&self, block_bits: &[bool]) -> Vec<T> {
+        debug_assert!(block_bits.len() == self.domain_size());
+        let mut state = self.eval_init();
+        for &dir in block_bits {
+            let tau = state.seed.expand_dir(!dir, dir);
+            state = correct(&self.cor_words, &state, tau.seeds.get(dir), *tau.bits.get(dir), dir);
+        }
+        self.leaf(&state)
+    }
+
+    pub fn eval_all(&self) -> Vec<T>
+        where T: Send + Sync
+    {
+        self.eval_first(self.width() << self.domain_size())
+    }
+
+    // The first n outputs, in order
+    pub fn eval_first(&self, n: usize) -> Vec<T>
+        where T: Send + Sync
+    {
+        let mut out = vec![T::zero(); n];
+        self.eval_first_into(&mut out);
+        out
+    }
+
+    pub fn eval_first_into(&self, out: &mut [T])
+        where T: Send + Sync
+    {
+        eval_tree(self, out);
+    }
+
+    pub fn domain_size(&self) -> usize {
+        self.cor_words.len()
+    }
+
+    // How many outputs each leaf holds
+    pub fn width(&self) -> usize {
+        self.cor_word_last.len()
+    }
+}
+
+impl<T> Tree<T> for PackedDPFKey<T>
+where
+    T: prg::PackedWord + Clone + std::fmt::Debug + Sync
+{
+    fn levels(&self) -> usize {
+        self.domain_size()
+    }
+
+    fn width(&self) -> usize {
+        self.cor_word_last.len()
+    }
+
+    fn root(&self) -> EvalState {
+        self.eval_init()
+    }
+
+    fn children(&self, state: &EvalState) -> (EvalState, EvalState) {
+        let tau = state.seed.expand();
+        (
+            correct(&self.cor_words, state, &tau.seeds.0, tau.bits.0, false),
+            correct(&self.cor_words, state, &tau.seeds.1, tau.bits.1, true),
+        )
+    }
+
+    fn leaves(&self, state: &EvalState, out: &mut [T]) {
+        let (left, right) = self.children(state);
+        let (out_left, out_right) = out.split_at_mut(out.len().min(self.width()));
+        for (o, word) in out_left.iter_mut().zip(self.leaf(&left)) {
+            *o = word;
+        }
+        if !out_right.is_empty() {
+            for (o, word) in out_right.iter_mut().zip(self.leaf(&right)) {
+                *o = word;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::my_u32_to_bits;
+    use crate::FieldElm;
+
+    fn key_pair(levels: usize, alpha: u32) -> (DPFKey<FieldElm, FieldElm>, DPFKey<FieldElm, FieldElm>) {
+        let alpha_bits = my_u32_to_bits(levels as u8 + 1, alpha);
+        let mut values = vec![FieldElm::zero(); levels - 1];
+        values.push(FieldElm::one());
+        DPFKey::gen(&alpha_bits, &values, &FieldElm::zero())
+    }
+
+    #[test]
+    fn eval_all_matches_recursive() {
+        // Small trees are one batch, larger ones are split across threads
+        for &levels in &[1, 3, EVAL_BATCH_LEVELS, EVAL_BATCH_LEVELS + 3] {
+            let (key0, key1) = key_pair(levels, 5);
+            for key in &[key0, key1] {
+                assert_eq!(key.eval_all().len(), 1 << levels);
+                assert_eq!(key.eval_all(), key.eval_all_recursive());
+            }
+        }
+    }
+
+    #[test]
+    fn eval_first() {
+        // Prefixes that end inside a batch, on a batch boundary and inside
+        // a pair of leaves
+        let levels = EVAL_BATCH_LEVELS + 2;
+        let (key, _) = key_pair(levels, 1500);
+        let all = key.eval_all_recursive();
+        for &n in &[0, 1, 7, 500, 1 << EVAL_BATCH_LEVELS, (1 << EVAL_BATCH_LEVELS) + 1, 3001, 1 << levels] {
+            assert_eq!(key.eval_first(n), all[..n].to_vec());
+        }
+
+        // Keys of different depths side by side
+        let (small, _) = key_pair(3, 2);
+        assert_eq!(small.eval_first(5), small.eval_all_recursive()[..5].to_vec());
+    }
+
+    #[test]
+    fn point_function() {
+        let levels = EVAL_BATCH_LEVELS + 1;
+        let alpha = 1234;
+        let alpha_bits = my_u32_to_bits(levels as u8 + 1, alpha);
+        let value = FieldElm::from(20u32);
+        let (key0, key1) = PointDPFKey::gen(&alpha_bits[..levels], &value);
+
+        let (all0, all1) = (key0.eval_all(), key1.eval_all());
+        assert_eq!(all0.len(), 1 << levels);
+        for (i, (mut y, y1)) in all0.into_iter().zip(all1).enumerate() {
+            y.add(&y1);
+            let expected = if i == alpha as usize { value.clone() } else { FieldElm::zero() };
+            assert_eq!(y, expected);
+        }
+
+        // Single points and prefixes agree with the full evaluation
+        let mut y = key0.eval(&alpha_bits[..levels]);
+        y.add(&key1.eval(&alpha_bits[..levels]));
+        assert_eq!(y, value);
+        assert_eq!(key0.eval_first(1500), key0.eval_all()[..1500].to_vec());
+    }
+
+    #[test]
+    fn point_keys_are_smaller() {
+        let levels = 9;
+        let alpha_bits = my_u32_to_bits(levels as u8 + 1, 3);
+        let (point, _) = PointDPFKey::gen(&alpha_bits[..levels], &FieldElm::one());
+        let (prefix, _) = key_pair(levels, 3);
+        let point_len = bincode::serialize(&point).unwrap().len();
+        assert!(point_len < bincode::serialize(&prefix).unwrap().len());
+    }
+
+    fn combine<T: Group>(a: Vec<T>, b: Vec<T>) -> Vec<T> {
+        a.into_iter().zip(b).map(|(mut x, y)| { x.add(&y); x }).collect()
+    }
+
+    #[test]
+    fn packed_matches_point() {
+        // Eight outputs a leaf stand in for the last three levels of a
+        // standard key
+        let levels = EVAL_BATCH_LEVELS + 2;
+        let slot = 2021;
+        let slot_bits = my_u32_to_bits(levels as u8 + 1, slot);
+        let value = 20u64;
+        let (point0, point1) = PointDPFKey::gen(&slot_bits[..levels], &value);
+        let (packed0, packed1) = PackedDPFKey::gen(&slot_bits[..levels - 3], 8, slot as usize % 8, &value);
+
+        let expected = combine(point0.eval_all(), point1.eval_all());
+        assert_eq!(combine(packed0.eval_all(), packed1.eval_all()), expected);
+        assert_eq!(packed0.eval_first(2500), packed0.eval_all()[..2500].to_vec());
+    }
+
+    #[test]
+    fn packed_matches_prefix() {
+        // The leaves of an all-prefix key, with zero at every other level
+        let levels = EVAL_BATCH_LEVELS + 2;
+        let slot = 1337;
+        let slot_bits = my_u32_to_bits(levels as u8 + 1, slot);
+        let value = 20u64;
+        let mut values = vec![0u64; levels - 1];
+        values.push(value);
+        let (prefix0, prefix1) = DPFKey::gen(&slot_bits, &values, &0u64);
+        let (packed0, packed1) = PackedDPFKey::gen(&slot_bits[..levels - 3], 8, slot as usize % 8, &value);
+
+        let expected = combine(prefix0.eval_all(), prefix1.eval_all());
+        assert_eq!(expected[slot as usize], value);
+        assert_eq!(combine(packed0.eval_all(), packed1.eval_all()), expected);
+    }
+
+    #[test]
+    fn packed_groups() {
+        // One leaf per group, for the payment database's layout
+        let (groups, width) = (50, 10);
+        let (group, member) = (37, 4);
+        let block_bits = my_u32_to_bits(7, group);
+        let value = std::num::Wrapping(3u64);
+        let (key0, key1) = PackedDPFKey::gen(&block_bits[..6], width, member, &value);
+
+        let (all0, all1) = (key0.eval_first(groups * width), key1.eval_first(groups * width));
+        for (i, (mut y, y1)) in all0.into_iter().zip(all1).enumerate() {
+            y.add(&y1);
+            let expected = if i == group as usize * width + member { value } else { Group::zero() };
+            assert_eq!(y, expected);
+        }
+
+        let mut leaf = key0.eval(&block_bits[..6]);
+        for (y, y1) in leaf.iter_mut().zip(key1.eval(&block_bits[..6])) {
+            y.add(&y1);
+        }
+        assert_eq!(leaf[member], value);
+    }
+
+    // The payload can be any field, including the small ones
+    fn point_function_over<T>(value: T)
+    where
+        T: prg::FromRng + Clone + Group + std::fmt::Debug + PartialEq + Send + Sync,
+    {
+        let levels = 9;
+        let alpha = 300;
+        let alpha_bits = my_u32_to_bits(levels as u8 + 1, alpha);
+        let (key0, key1) = PointDPFKey::gen(&alpha_bits[..levels], &value);
+        for (i, (mut y, y1)) in key0.eval_all().into_iter().zip(key1.eval_all()).enumerate() {
+            y.add(&y1);
+            let expected = if i == alpha as usize { value.clone() } else { T::zero() };
+            assert_eq!(y, expected);
+        }
+    }
+
+    #[test]
+    fn small_field_payloads() {
+        point_function_over(20u64);
+        point_function_over(20u128);
+        let mut debt = 0u128;
+        debt.sub(&20u128);
+        point_function_over(debt);
+        point_function_over(-std::num::Wrapping(20u64));
+    }
+}
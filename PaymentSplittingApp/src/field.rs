@@ -11,6 +11,8 @@ use serde::Deserialize;
 use serde::Serialize;
 use std::cmp::Ordering;
 use std::convert::TryInto;
+use std::num::Wrapping;
+use curve25519_dalek::constants::BASEPOINT_ORDER;
 use curve25519_dalek::scalar::Scalar;
 use std::u32;
 
@@ -19,17 +21,22 @@ pub struct FieldElm {
     pub value: Scalar,
 }
 
-// 255-bit modulus:   p = 2^255 - 10
-// const MODULUS_STR: &[u8] = b"7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+// FieldElm arithmetic is modulo the order of the Ristretto group, so
+// DPF outputs can go straight into Pedersen commitments. The order
+// itself reduces to zero.
+const MODULUS: FieldElm = FieldElm { value: BASEPOINT_ORDER };
 
-// 127-bit modulus:   p = 2^127 f- 1
-const MODULUS_STR: &[u8] = b"7fffffffffffffffffffffffffffffff";
+// Smaller fields for the DPF payload, on plain integers: u64 modulo
+// p = 2^63 - 25 and u128 modulo the Mersenne prime p = 2^127 - 1. They
+// make DPF evaluation and the database updates cheaper, but shares in
+// them don't add up in the scalar field, so anything a commitment is
+// computed over stays a FieldElm.
 
-// const MODULUS_STR: &[u8] = b"7fffffffffffffE7";
+//  63-bit modulus:   p = 2^63 - 25
+pub const MODULUS_64: u64 = 9223372036854775783u64;
 
-//  63-bit modulus:   p = 2^63 - 25;
-const MODULUS_64: u64 = 9223372036854775783u64;
-const MODULUS_64_BIG: u128 = 9223372036854775783u128;
+// 127-bit modulus:   p = 2^127 - 1
+pub const MODULUS_127: u128 = (1u128 << 127) - 1;
 
 
 // impl FieldElm {
@@ -133,18 +140,259 @@ impl crate::prg::FromRng for FieldElm {
 
 impl crate::Share for FieldElm {}
 
-impl<T> crate::Group for (T, T)
+impl crate::Group for u64 {
+    #[inline]
+    fn zero() -> Self {
+        0u64
+    }
+
+    #[inline]
+    fn one() -> Self {
+        1u64
+    }
+
+    #[inline]
+    fn add(&mut self, other: &Self) {
+        // Both are below 2^63, so the sum can't overflow
+        *self += other;
+        if *self >= MODULUS_64 {
+            *self -= MODULUS_64;
+        }
+    }
+
+    #[inline]
+    fn mul(&mut self, other: &Self) {
+        let res = (*self as u128) * (*other as u128);
+        *self = (res % MODULUS_64 as u128) as u64;
+    }
+
+    #[inline]
+    fn add_lazy(&mut self, other: &Self) {
+        self.add(other);
+    }
+
+    #[inline]
+    fn mul_lazy(&mut self, other: &Self) {
+        self.mul(other);
+    }
+
+    #[inline]
+    fn reduce(&mut self) {
+        *self %= MODULUS_64;
+    }
+
+    #[inline]
+    fn sub(&mut self, other: &Self) {
+        let mut neg = *other;
+        neg.negate();
+        self.add(&neg);
+    }
+
+    #[inline]
+    fn negate(&mut self) {
+        if *self != 0 {
+            *self = MODULUS_64 - *self;
+        }
+    }
+}
+
+impl crate::prg::FromRng for u64 {
+    #[inline]
+    fn from_rng(&mut self, rng: &mut impl rand::Rng) {
+        // Rejection sampling on 63 bits, which almost never loops. Both
+        // servers read the same stream, so they still agree.
+        loop {
+            *self = rng.next_u64() >> 1;
+            if *self < MODULUS_64 {
+                return;
+            }
+        }
+    }
+}
+
+// Two to an AES block. Dropping the top bit and reducing once leaves a
+// bias of 25 / 2^63.
+impl crate::prg::PackedWord for u64 {
+    const BYTES: usize = 8;
+
+    #[inline]
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut word = u64::from_le_bytes(bytes.try_into().unwrap()) >> 1;
+        if word >= MODULUS_64 {
+            word -= MODULUS_64;
+        }
+        word
+    }
+}
+
+impl crate::Share for u64 {}
+
+// Reduce a value below 2^128 modulo 2^127 - 1
+#[inline]
+fn reduce_127(x: u128) -> u128 {
+    let x = (x & MODULUS_127) + (x >> 127);
+    if x >= MODULUS_127 {
+        x - MODULUS_127
+    } else {
+        x
+    }
+}
+
+impl crate::Group for u128 {
+    #[inline]
+    fn zero() -> Self {
+        0u128
+    }
+
+    #[inline]
+    fn one() -> Self {
+        1u128
+    }
+
+    #[inline]
+    fn add(&mut self, other: &Self) {
+        *self = reduce_127(*self + other);
+    }
+
+    #[inline]
+    fn mul(&mut self, other: &Self) {
+        // Schoolbook on 64-bit limbs into hi * 2^128 + lo. Both factors
+        // are below 2^127, so the middle terms can't overflow.
+        let (a0, a1) = (*self as u64 as u128, *self >> 64);
+        let (b0, b1) = (*other as u64 as u128, *other >> 64);
+        let ll = a0 * b0;
+        let mid = a0 * b1 + a1 * b0;
+        let lo = ll.wrapping_add(mid << 64);
+        let hi = a1 * b1 + (mid >> 64) + (lo < ll) as u128;
+        // 2^127 = 1, so the bits from 127 up fold onto the bottom
+        *self = reduce_127((lo & MODULUS_127) + ((hi << 1) | (lo >> 127)));
+    }
+
+    #[inline]
+    fn add_lazy(&mut self, other: &Self) {
+        self.add(other);
+    }
+
+    #[inline]
+    fn mul_lazy(&mut self, other: &Self) {
+        self.mul(other);
+    }
+
+    #[inline]
+    fn reduce(&mut self) {
+        *self = reduce_127(*self);
+    }
+
+    #[inline]
+    fn sub(&mut self, other: &Self) {
+        let mut neg = *other;
+        neg.negate();
+        self.add(&neg);
+    }
+
+    #[inline]
+    fn negate(&mut self) {
+        if *self != 0 {
+            *self = MODULUS_127 - *self;
+        }
+    }
+}
+
+impl crate::prg::FromRng for u128 {
+    #[inline]
+    fn from_rng(&mut self, rng: &mut impl rand::Rng) {
+        loop {
+            *self = rng.gen::<u128>() >> 1;
+            if *self < MODULUS_127 {
+                return;
+            }
+        }
+    }
+}
+
+impl crate::Share for u128 {}
+
+// The ring Z_2^64, for balances: arithmetic is plain wrapping u64
+// arithmetic, with nothing to reduce. Sketches aren't sound over a ring,
+// so keys with this payload are checked in a field instead (see
+// sketch::RingSketchDPFKey, which also says why the database can't use
+// it yet).
+impl crate::Group for Wrapping<u64> {
+    #[inline]
+    fn zero() -> Self {
+        Wrapping(0)
+    }
+
+    #[inline]
+    fn one() -> Self {
+        Wrapping(1)
+    }
+
+    #[inline]
+    fn add(&mut self, other: &Self) {
+        *self += other;
+    }
+
+    #[inline]
+    fn mul(&mut self, other: &Self) {
+        *self *= other;
+    }
+
+    #[inline]
+    fn add_lazy(&mut self, other: &Self) {
+        *self += other;
+    }
+
+    #[inline]
+    fn mul_lazy(&mut self, other: &Self) {
+        *self *= other;
+    }
+
+    #[inline]
+    fn reduce(&mut self) {}
+
+    #[inline]
+    fn sub(&mut self, other: &Self) {
+        *self -= other;
+    }
+
+    #[inline]
+    fn negate(&mut self) {
+        *self = -*self;
+    }
+}
+
+impl crate::prg::FromRng for Wrapping<u64> {
+    #[inline]
+    fn from_rng(&mut self, rng: &mut impl rand::Rng) {
+        *self = Wrapping(rng.next_u64());
+    }
+}
+
+impl crate::prg::PackedWord for Wrapping<u64> {
+    const BYTES: usize = 8;
+
+    #[inline]
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Wrapping(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+impl crate::Share for Wrapping<u64> {}
+
+impl<T, U> crate::Group for (T, U)
 where
     T: crate::Group + Clone,
+    U: crate::Group + Clone,
 {
     #[inline]
     fn zero() -> Self {
-        (T::zero(), T::zero())
+        (T::zero(), U::zero())
     }
 
     #[inline]
     fn one() -> Self {
-        (T::one(), T::one())
+        (T::one(), U::one())
     }
 
     #[inline]
@@ -194,13 +442,14 @@ where
     }
 }
 
-impl<T> crate::prg::FromRng for (T, T)
+impl<T, U> crate::prg::FromRng for (T, U)
 where
     T: crate::prg::FromRng + crate::Group,
+    U: crate::prg::FromRng + crate::Group,
 {
     fn from_rng(&mut self, mut rng: &mut impl rand::Rng) {
         self.0 = T::zero();
-        self.1 = T::zero();
+        self.1 = U::zero();
         self.0.from_rng(&mut rng);
         self.1.from_rng(&mut rng);
     }
@@ -334,4 +583,62 @@ mod tests {
         res.add(&negx);
         assert_eq!(zero, res);
     }
-}
+
+    #[test]
+    fn mersenne127() {
+        let mut res = u128::zero();
+        res.add(&(MODULUS_127 - 1));
+        res.add(&3u128);
+        assert_eq!(res, 2u128);
+
+        // (p - 1)^2 = 1, and 2^64 * 2^64 = 2^128 = 2
+        let mut sq = MODULUS_127 - 1;
+        sq.mul(&(MODULUS_127 - 1));
+        assert_eq!(sq, 1u128);
+        let mut pow = 1u128 << 64;
+        pow.mul(&(1u128 << 64));
+        assert_eq!(pow, 2u128);
+
+        let mut x = 1123123u128;
+        x.sub(&1123125u128);
+        assert_eq!(x, MODULUS_127 - 2);
+        x.negate();
+        assert_eq!(x, 2u128);
+    }
+
+    #[test]
+    fn mersenne127_mul_matches_bigint() {
+        let p = BigUint::from(MODULUS_127);
+        for _ in 0..100 {
+            let a = u128::random();
+            let b = u128::random();
+            let mut prod = a;
+            prod.mul(&b);
+            let expected = BigUint::from(a) * BigUint::from(b) % &p;
+            assert_eq!(BigUint::from(prod), expected);
+        }
+    }
+
+    #[test]
+    fn small_fields_sample_below_modulus() {
+        for _ in 0..100 {
+            assert!(u64::random() < MODULUS_64);
+            assert!(u128::random() < MODULUS_127);
+        }
+    }
+
+    #[test]
+    fn ring64() {
+        let mut x = Wrapping(u64::MAX);
+        x.add(&Wrapping(3));
+        assert_eq!(x, Wrapping(2));
+        x.sub(&Wrapping(5));
+        assert_eq!(x, Wrapping(u64::MAX - 2));
+        x.negate();
+        assert_eq!(x, Wrapping(3));
+        x.mul(&Wrapping(1 << 63));
+        assert_eq!(x, Wrapping(1 << 63));
+
+        let (s0, s1) = Wrapping(1234u64).share();
+        assert_eq!(s0 + s1, Wrapping(1234));
+    }}
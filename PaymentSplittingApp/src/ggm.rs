@@ -18,6 +18,16 @@ use curve25519_dalek::constants as dalek_constants;
 use curve25519_dalek::ristretto::RistrettoBasepointTable;
 use curve25519_dalek::ristretto::RistrettoPoint;
 use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+
+use zkp::toolbox::batch_verifier::BatchVerifier;
+use zkp::toolbox::prover::Prover;
+use zkp::toolbox::verifier::Verifier;
+use zkp::toolbox::SchnorrCS;
+use zkp::BatchableProof;
+use zkp::CompactProof;
+use zkp::ProofError;
+use zkp::Transcript;
 
 use lazy_static::lazy_static;
 
@@ -33,8 +43,8 @@ lazy_static! {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IssuerPrivKey {
-    x0tilde: Scalar,
-    x: Vec<Scalar>,
+    pub(crate) x0tilde: Scalar,
+    pub(crate) x: Vec<Scalar>,
 }
 
 impl IssuerPrivKey {
@@ -54,7 +64,7 @@ impl IssuerPrivKey {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IssuerPubKey {
-    X: Vec<RistrettoPoint>,
+    pub(crate) X: Vec<RistrettoPoint>,
 }
 
 impl IssuerPubKey {
@@ -95,428 +105,943 @@ impl Issuer {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Credential {
-    P: RistrettoPoint,
-    Q: RistrettoPoint,
+    pub(crate) P: RistrettoPoint,
+    // The MAC, kept in one part per issuer key it was issued under (see
+    // threshold.rs); a single issuer gives a single part
+    pub(crate) Q: Vec<RistrettoPoint>,
     // For numbering consistency with the Hyphae paper, the attributes
     // are stored in m[1], m[2], ... ; the m[0] element is set to the
     // dummy value 0.
     pub m: Vec<Scalar>,
 }
 
-
-// A submodule for issuing credentials with 5 attributes, of which
-// attributes 1, 2, and 4 are blinded (the issuer does not see them),
-// and only attributes 3 and 5 are visible to the issuer.
+// Generic issuing and showing of credentials with any number of
+// attributes and any selection of hidden and revealed attributes.
 //
-// One might imagine generalizing this submodule using a macro.
-// Currently, the number of attributes and the selection of which are
-// blinded have to be hardcoded in order to use the very helpful zkp
-// proof macros.  This shouldn't be a problem in practice, as one
-// generally knows the set of statements one will require at compile,
-// and not at run, time.
-pub mod issue_blind124_5 {
-    use curve25519_dalek::ristretto::RistrettoBasepointTable;
-    use curve25519_dalek::ristretto::RistrettoPoint;
-    use curve25519_dalek::scalar::Scalar;
-    use curve25519_dalek::traits::IsIdentity;
-
-    use zkp::CompactProof;
-    use zkp::ProofError;
-    use zkp::Transcript;
-    use serde::Serialize;
-    use serde::Deserialize;
-
-    use super::{Credential, Issuer, IssuerPubKey};
-    use super::{CMZ_A, CMZ_A_TABLE, CMZ_B, CMZ_B_TABLE};
-
-    // Example of a 5-attribute credential where the issuer sees attributes
-    // 3 and 5, but attributes 1, 2, and 4 are blinded.
-    #[derive(Serialize, Deserialize)]
-    pub struct CredentialRequest {
-        D: RistrettoPoint,
-        Encm1B: (RistrettoPoint, RistrettoPoint),
-        m3: Scalar,
-        piUserBlinding: CompactProof,
-    }
-
-    #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-    pub struct CredentialRequestState {
-        d: Scalar,
-        D: RistrettoPoint,
-        Encm1B: (RistrettoPoint, RistrettoPoint),
-        m1: Scalar,
-        m3: Scalar,
-    }
-
-    #[derive(Serialize, Deserialize)]
-    pub struct CredentialResponse {
-        P: RistrettoPoint,
-        EncQ: (RistrettoPoint, RistrettoPoint),
-        T1: RistrettoPoint,
-        piBlindIssue: CompactProof,
-    }
-
-    // The client-created proof that the blinded attributes in the request
-    // to issue a credential are well formed.  If you want the client to
-    // prove other statements about the blinded attributes (m1, m2, m4 in
-    // this example), this is where to add them (and in the code that
-    // creates and verifies this proof of course).
-    define_proof! {
-        userblinding,
-        "Blind124 5 userblind proof",
-        (d, e1, m1),
-        (Encm1B0, Encm1B1, D),
-        (B) :
-        Encm1B0 = (e1*B),
-        Encm1B1 = (m1*B + e1*D),
-        D = (d*B)
-    }
-
-    // The issuer-created proof for the same scenario
-    define_proof! {
-        blindissue,
-        "Blind124 5 issuing proof",
-        (x0, x0tilde, x1, x3, s, b, t1),
-        (P, EncQ0, EncQ1, X0, X1, X3, P3, T1, D,
-            Encm1B0, Encm1B1),
-        (A, B) :
-        X1 = (x1*A),
-        X3 = (x3*A),
-        X0 = (x0*B + x0tilde*A),
-        P = (b*B),
-        T1 = (b*X1),
-        T1 = (t1*A),
-        EncQ0 = (s*B + t1*Encm1B0),
-        EncQ1 = (s*D + t1*Encm1B1 + x0*P + x3*P3)
-    }
-
-    pub fn request(
-        m1: &Scalar,
-        m2: &Scalar,
-        m3: &Scalar,
-        m4: &Scalar,
-        m5: &Scalar,
-    ) -> (CredentialRequest, CredentialRequestState) {
-        let B: &RistrettoPoint = &CMZ_B;
-        let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+// Rather than hardcoding the statements with the zkp proof macros, the
+// proofs are built at run time with the zkp toolbox from a layout that
+// says, for each attribute, whether the issuer (or the verifier of a
+// showing) sees it. The cmz_issue! and cmz_show! macros below wrap a
+// fixed layout into a submodule with the usual request/verify/show
+// functions, so a new protocol is a few lines; see issue_blind124_5 and
+// show_blind34_5 at the end of this file.
 
-        // Pick an ElGamal keypair
-        let mut rng = rand::thread_rng();
-        let d = Scalar::random(&mut rng);
-        let D = &d * Btable;
-
-        // Encrypt the attributes to be blinded (each times the
-        // basepoint B) to the public key we just created
-        let e1 = Scalar::random(&mut rng);
-        let Encm1B = (&e1 * Btable, m1 * Btable + e1 * D);
-
-        let mut transcript = Transcript::new(b"Blind124 5 userblind proof");
-        let piUserBlinding = userblinding::prove_compact(
-            &mut transcript,
-            userblinding::ProveAssignments {
-                B: &B,
-                Encm1B0: &Encm1B.0,
-                Encm1B1: &Encm1B.1,
-                D: &D,
-                d: &d,
-                e1: &e1,
-                m1: &m1,
-            },
-        )
-        .0;
-        (
-            CredentialRequest {
-                D,
-                Encm1B,
-                piUserBlinding,
-                m3: *m3,
-            },
-            CredentialRequestState {
-                d,
-                D,
-                Encm1B,
-                m1: *m1,
-                m3: *m3,
-            },
-        )
+// Whether an attribute is seen by the other party, or only appears to
+// them inside an ElGamal ciphertext (issuing) or a Pedersen commitment
+// (showing).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    Hidden,
+    Revealed,
+}
+
+// Each protocol gets its own transcript, bound to the layout so that a
+// proof for one layout is never accepted for another.
+pub(crate) fn cmz_transcript(label: &'static [u8], proof: &'static [u8], layout: &[Visibility]) -> Transcript {
+    let mut transcript = Transcript::new(label);
+    transcript.append_message(b"proof", proof);
+    let layout_bytes: Vec<u8> = layout.iter().map(|v| *v as u8).collect();
+    transcript.append_message(b"layout", &layout_bytes);
+    transcript
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CredentialRequest {
+    pub(crate) D: RistrettoPoint,
+    // Encryptions of m_i*B for the hidden attributes, in order
+    pub(crate) EncmB: Vec<(RistrettoPoint, RistrettoPoint)>,
+    // Attributes 1..n, with the hidden ones left out
+    pub(crate) m: Vec<Option<Scalar>>,
+    piUserBlinding: CompactProof,
+}
+
+impl CredentialRequest {
+    // The value of attribute i (counting from 1), if it is revealed
+    pub fn revealed(&self, i: usize) -> Option<Scalar> {
+        i.checked_sub(1).and_then(|k| self.m.get(k).copied().flatten())
     }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CredentialRequestState {
+    pub(crate) d: Scalar,
+    pub(crate) D: RistrettoPoint,
+    pub(crate) EncmB: Vec<(RistrettoPoint, RistrettoPoint)>,
+    pub(crate) m: Vec<Scalar>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CredentialResponse {
+    P: RistrettoPoint,
+    EncQ: (RistrettoPoint, RistrettoPoint),
+    // T_i = b*X_i for the hidden attributes, in order
+    T: Vec<RistrettoPoint>,
+    piBlindIssue: CompactProof,
+}
+
+// Create a request to issue a credential on the given attributes
+// (attrs[0] is attribute 1). The hidden ones are encrypted to a fresh
+// ElGamal key, with a proof that the ciphertexts are well formed.
+pub fn request(
+    label: &'static [u8],
+    layout: &[Visibility],
+    attrs: &[Scalar],
+) -> (CredentialRequest, CredentialRequestState) {
+    assert_eq!(layout.len(), attrs.len());
+    let B: &RistrettoPoint = &CMZ_B;
+    let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
 
-    impl Issuer {
-        // Issue a credential with 5 attributes, of which attributes 1, 2,
-        // and 4 are blinded from the issuer, and 3 and 5 are visible.
-        pub fn issue_blind124_5(
-            &self,
-            req: CredentialRequest,
-        ) -> Result<CredentialResponse, ProofError> {
-            let A: &RistrettoPoint = &CMZ_A;
-            let B: &RistrettoPoint = &CMZ_B;
-            let Atable: &RistrettoBasepointTable = &CMZ_A_TABLE;
-            let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
-
-            // First check the proof in the request
-            let mut transcript = Transcript::new(b"Blind124 5 userblind proof");
-            userblinding::verify_compact(
-                &req.piUserBlinding,
-                &mut transcript,
-                userblinding::VerifyAssignments {
-                    B: &B.compress(),
-                    Encm1B0: &req.Encm1B.0.compress(),
-                    Encm1B1: &req.Encm1B.1.compress(),
-                    D: &req.D.compress(),
-                },
-            )?;
-
-            // Compute the MAC on the visible attributes
-            let mut rng = rand::thread_rng();
-            let b = Scalar::random(&mut rng);
-            let P = &b * Btable;
-            let QHc =
-                (self.privkey.x[0] + (self.privkey.x[3] * req.m3)) * P;
-
-            // El Gamal encrypt it to the public key req.D
-            let s = Scalar::random(&mut rng);
-            let EncQHc = (&s * Btable, QHc + s * req.D);
-
-            // Homomorphically compute the part of the MAC corresponding to
-            // the blinded attributes
-            let t1 = self.privkey.x[1] * b;
-            let T1 = &t1 * Atable;
-            let EncQ1 = (t1 * req.Encm1B.0, t1 * req.Encm1B.1);
-
-            let EncQ = (
-                EncQHc.0 + EncQ1.0,
-                EncQHc.1 + EncQ1.1,
-            );
-
-            let mut transcript = Transcript::new(b"Blind124 5 issuing proof");
-            let piBlindIssue = blindissue::prove_compact(
-                &mut transcript,
-                blindissue::ProveAssignments {
-                    A: &A,
-                    B: &B,
-                    P: &P,
-                    EncQ0: &EncQ.0,
-                    EncQ1: &EncQ.1,
-                    X0: &self.pubkey.X[0],
-                    X1: &self.pubkey.X[1],
-                    X3: &self.pubkey.X[3],
-                    P3: &(req.m3 * P),
-                    T1: &T1,
-                    D: &req.D,
-                    Encm1B0: &req.Encm1B.0,
-                    Encm1B1: &req.Encm1B.1,
-                    x0: &self.privkey.x[0],
-                    x0tilde: &self.privkey.x0tilde,
-                    x1: &self.privkey.x[1],
-                    x3: &self.privkey.x[3],
-                    s: &s,
-                    b: &b,
-                    t1: &t1,
-                },
-            )
-            .0;
-
-            Ok(CredentialResponse {
-                P,
-                EncQ,
-                T1,
-                piBlindIssue,
-            })
+    // Pick an ElGamal keypair
+    let mut rng = rand::thread_rng();
+    let d = Scalar::random(&mut rng);
+    let D = &d * Btable;
+
+    let mut transcript = cmz_transcript(label, b"userblind", layout);
+    let mut prover = Prover::new(b"CMZ userblind proof", &mut transcript);
+    let var_B = prover.allocate_point(b"B", *B).0;
+    let var_d = prover.allocate_scalar(b"d", d);
+    let var_D = prover.allocate_point(b"D", D).0;
+    prover.constrain(var_D, vec![(var_d, var_B)]);
+
+    let mut EncmB = Vec::new();
+    let mut m = Vec::new();
+    for (vis, attr) in layout.iter().zip(attrs) {
+        if *vis == Visibility::Revealed {
+            m.push(Some(*attr));
+            continue;
         }
+        m.push(None);
+
+        // Encrypt the attribute (times the basepoint B) to D
+        let e = Scalar::random(&mut rng);
+        let Enc = (&e * Btable, attr * Btable + e * D);
+        let var_e = prover.allocate_scalar(b"e", e);
+        let var_m = prover.allocate_scalar(b"m", *attr);
+        let var_E0 = prover.allocate_point(b"EncmB0", Enc.0).0;
+        let var_E1 = prover.allocate_point(b"EncmB1", Enc.1).0;
+        prover.constrain(var_E0, vec![(var_e, var_B)]);
+        prover.constrain(var_E1, vec![(var_m, var_B), (var_e, var_D)]);
+        EncmB.push(Enc);
     }
+    let piUserBlinding = prover.prove_compact();
 
-    pub fn verify(
-        state: CredentialRequestState,
-        resp: CredentialResponse,
-        pubkey: &IssuerPubKey,
-    ) -> Result<Credential, ProofError> {
+    let mut all = vec![Scalar::zero()];
+    all.extend_from_slice(attrs);
+    (
+        CredentialRequest {
+            D,
+            EncmB: EncmB.clone(),
+            m,
+            piUserBlinding,
+        },
+        CredentialRequestState {
+            d,
+            D,
+            EncmB,
+            m: all,
+        },
+    )
+}
+
+// Check that a request matches the layout and that its ciphertexts are
+// well formed, for an issuer key covering num_keys - 1 attributes.
+pub(crate) fn verify_request(
+    label: &'static [u8],
+    layout: &[Visibility],
+    req: &CredentialRequest,
+    num_keys: usize,
+) -> Result<(), ProofError> {
+    let B: &RistrettoPoint = &CMZ_B;
+    let n = layout.len();
+
+    let matches_layout = layout
+        .iter()
+        .zip(&req.m)
+        .all(|(vis, m)| (*vis == Visibility::Hidden) == m.is_none());
+    let num_hidden = req.m.iter().filter(|m| m.is_none()).count();
+    if req.m.len() != n || !matches_layout || req.EncmB.len() != num_hidden || num_keys <= n {
+        return Err(ProofError::VerificationFailure);
+    }
+
+    let mut transcript = cmz_transcript(label, b"userblind", layout);
+    let mut verifier = Verifier::new(b"CMZ userblind proof", &mut transcript);
+    let var_B = verifier.allocate_point(b"B", B.compress())?;
+    let var_d = verifier.allocate_scalar(b"d");
+    let var_D = verifier.allocate_point(b"D", req.D.compress())?;
+    verifier.constrain(var_D, vec![(var_d, var_B)]);
+    for Enc in &req.EncmB {
+        let var_e = verifier.allocate_scalar(b"e");
+        let var_m = verifier.allocate_scalar(b"m");
+        let var_E0 = verifier.allocate_point(b"EncmB0", Enc.0.compress())?;
+        let var_E1 = verifier.allocate_point(b"EncmB1", Enc.1.compress())?;
+        verifier.constrain(var_E0, vec![(var_e, var_B)]);
+        verifier.constrain(var_E1, vec![(var_m, var_B), (var_e, var_D)]);
+    }
+    verifier.verify_compact(&req.piUserBlinding)
+}
+
+impl Issuer {
+    // Issue a credential for the given layout. The MAC on the revealed
+    // attributes is computed directly; the part for the hidden ones is
+    // computed homomorphically on the ciphertexts in the request.
+    pub fn issue(
+        &self,
+        label: &'static [u8],
+        layout: &[Visibility],
+        req: &CredentialRequest,
+    ) -> Result<CredentialResponse, ProofError> {
         let A: &RistrettoPoint = &CMZ_A;
         let B: &RistrettoPoint = &CMZ_B;
+        let Atable: &RistrettoBasepointTable = &CMZ_A_TABLE;
+        let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+        let n = layout.len();
 
-        if resp.P.is_identity() {
-            return Err(ProofError::VerificationFailure);
+        // First check the proof in the request
+        verify_request(label, layout, req, self.privkey.x.len())?;
+
+        // Compute the MAC on the visible attributes
+        let mut rng = rand::thread_rng();
+        let b = Scalar::random(&mut rng);
+        let P = &b * Btable;
+        let mut xm = self.privkey.x[0];
+        for (i, m) in req.m.iter().enumerate() {
+            if let Some(m) = m {
+                xm += self.privkey.x[i + 1] * m;
+            }
+        }
+        let QHc = xm * P;
+
+        // El Gamal encrypt it to the public key req.D
+        let s = Scalar::random(&mut rng);
+        let mut EncQ = (&s * Btable, QHc + s * req.D);
+
+        let mut transcript = cmz_transcript(label, b"blindissue", layout);
+        let mut prover = Prover::new(b"CMZ blind issuing proof", &mut transcript);
+        let var_A = prover.allocate_point(b"A", *A).0;
+        let var_B = prover.allocate_point(b"B", *B).0;
+        let var_D = prover.allocate_point(b"D", req.D).0;
+        let var_P = prover.allocate_point(b"P", P).0;
+        let var_x0 = prover.allocate_scalar(b"x0", self.privkey.x[0]);
+        let var_x0tilde = prover.allocate_scalar(b"x0tilde", self.privkey.x0tilde);
+        let var_s = prover.allocate_scalar(b"s", s);
+        let var_b = prover.allocate_scalar(b"b", b);
+        let var_X0 = prover.allocate_point(b"X0", self.pubkey.X[0]).0;
+        prover.constrain(var_X0, vec![(var_x0, var_B), (var_x0tilde, var_A)]);
+        prover.constrain(var_P, vec![(var_b, var_B)]);
+
+        let mut EncQ0_terms = vec![(var_s, var_B)];
+        let mut EncQ1_terms = vec![(var_s, var_D), (var_x0, var_P)];
+        let mut T = Vec::new();
+        let mut hidden = req.EncmB.iter();
+        for i in 1..=n {
+            let var_xi = prover.allocate_scalar(b"x", self.privkey.x[i]);
+            let var_Xi = prover.allocate_point(b"X", self.pubkey.X[i]).0;
+            prover.constrain(var_Xi, vec![(var_xi, var_A)]);
+            match req.m[i - 1] {
+                // A zero attribute contributes nothing to the MAC, and
+                // the identity point can't be a proof variable
+                Some(m) if m == Scalar::zero() => {}
+                Some(m) => {
+                    let var_Pi = prover.allocate_point(b"mP", m * P).0;
+                    EncQ1_terms.push((var_xi, var_Pi));
+                }
+                None => {
+                    // Homomorphically compute the part of the MAC
+                    // corresponding to the blinded attribute
+                    let Enc = hidden.next().unwrap();
+                    let t = self.privkey.x[i] * b;
+                    let Ti = &t * Atable;
+                    EncQ.0 += t * Enc.0;
+                    EncQ.1 += t * Enc.1;
+                    let var_t = prover.allocate_scalar(b"t", t);
+                    let var_Ti = prover.allocate_point(b"T", Ti).0;
+                    let var_E0 = prover.allocate_point(b"EncmB0", Enc.0).0;
+                    let var_E1 = prover.allocate_point(b"EncmB1", Enc.1).0;
+                    prover.constrain(var_Ti, vec![(var_b, var_Xi)]);
+                    prover.constrain(var_Ti, vec![(var_t, var_A)]);
+                    EncQ0_terms.push((var_t, var_E0));
+                    EncQ1_terms.push((var_t, var_E1));
+                    T.push(Ti);
+                }
+            }
         }
+        let var_EncQ0 = prover.allocate_point(b"EncQ0", EncQ.0).0;
+        let var_EncQ1 = prover.allocate_point(b"EncQ1", EncQ.1).0;
+        prover.constrain(var_EncQ0, EncQ0_terms);
+        prover.constrain(var_EncQ1, EncQ1_terms);
+        let piBlindIssue = prover.prove_compact();
 
-        let mut transcript = Transcript::new(b"Blind124 5 issuing proof");
-        blindissue::verify_compact(
-            &resp.piBlindIssue,
-            &mut transcript,
-            blindissue::VerifyAssignments {
-                A: &A.compress(),
-                B: &B.compress(),
-                P: &resp.P.compress(),
-                EncQ0: &resp.EncQ.0.compress(),
-                EncQ1: &resp.EncQ.1.compress(),
-                X0: &pubkey.X[0].compress(),
-                X1: &pubkey.X[1].compress(),
-                X3: &pubkey.X[3].compress(),
-                P3: &(state.m3 * resp.P).compress(),
-                T1: &resp.T1.compress(),
-                D: &state.D.compress(),
-                Encm1B0: &state.Encm1B.0.compress(),
-                Encm1B1: &state.Encm1B.1.compress(),
-            },
-        )?;
-
-        // Decrypt EncQ
-        let Q = resp.EncQ.1 - (state.d * resp.EncQ.0);
-
-        Ok(Credential {
-            P: resp.P,
-            Q,
-            m: vec![
-                Scalar::zero(),
-                state.m1,
-                state.m1,
-                state.m3,
-                state.m3,
-                state.m3,
-            ],
+        Ok(CredentialResponse {
+            P,
+            EncQ,
+            T,
+            piBlindIssue,
         })
     }
 }
 
-// A submodule for showing credentials with 5 attributes, blinding
-// attributes 3, 4, and 5, and displaying attributes 1 and 2.  As above,
-// this could possibly be generated by a Rust macro in the future.
-pub mod show_blind345_5 {
-    use curve25519_dalek::ristretto::RistrettoBasepointTable;
-    use curve25519_dalek::ristretto::RistrettoPoint;
-    use curve25519_dalek::scalar::Scalar;
-    use curve25519_dalek::traits::IsIdentity;
-
-    use zkp::CompactProof;
-    use zkp::ProofError;
-    use zkp::Transcript;
-    use serde::Serialize;
-    use serde::Deserialize;
-
-    use super::{Credential, Issuer, IssuerPubKey, CMZ_A, CMZ_A_TABLE};
-
-    // A typo in the Hyphae paper (Section 4.4): P must also be sent to
-    // the issuer in the credential presentation message.
-    #[derive(Serialize, Deserialize)]
-    pub struct ShowMessage {
-        pub P: RistrettoPoint,
-        m1: Scalar,
-        Cm3: RistrettoPoint,
-        CQ: RistrettoPoint,
-        piCredShow: CompactProof,
-    }
-
-    #[derive(Debug)]
-    pub struct VerifiedCredential {
-        pub m1: Scalar,
-        pub Cm3: RistrettoPoint,
-    }
-
-    // If you want to prove additional statements about the blinded
-    // attributes when showing them, this is the place to add those
-    // statements (and also the code that creates and verifies this
-    // proof).
-    define_proof! {
-        show,
-        "Blind345 5 showing proof",
-        (m3, z3, negzQ),
-        (P, Cm3, V, X3),
-        (A) :
-        Cm3 = (m3*P + z3*A),
-        V = (z3*X3 + negzQ*A)
-    }
-
-    pub fn show(cred: &Credential, pubkey: &IssuerPubKey) -> (Scalar, ShowMessage) {
-        let A: &RistrettoPoint = &CMZ_A;
-        let Atable: &RistrettoBasepointTable = &CMZ_A_TABLE;
+// Check the issuer's proof and decrypt the MAC to get the credential.
+pub fn verify_issue(
+    label: &'static [u8],
+    layout: &[Visibility],
+    state: CredentialRequestState,
+    resp: CredentialResponse,
+    pubkey: &IssuerPubKey,
+) -> Result<Credential, ProofError> {
+    let A: &RistrettoPoint = &CMZ_A;
+    let B: &RistrettoPoint = &CMZ_B;
+    let n = layout.len();
 
-        // Reblind P and Q
-        let mut rng = rand::thread_rng();
-        let t = Scalar::random(&mut rng);
-        let P = t * cred.P;
-        let Q = t * cred.Q;
+    if resp.P.is_identity() || resp.T.len() != state.EncmB.len() || pubkey.X.len() <= n {
+        return Err(ProofError::VerificationFailure);
+    }
 
-        // Form Pedersen commitments to the blinded attributes
-        let z3 = Scalar::random(&mut rng);
-        let Cm3 = cred.m[3] * P + &z3 * Atable;
+    let mut transcript = cmz_transcript(label, b"blindissue", layout);
+    let mut verifier = Verifier::new(b"CMZ blind issuing proof", &mut transcript);
+    let var_A = verifier.allocate_point(b"A", A.compress())?;
+    let var_B = verifier.allocate_point(b"B", B.compress())?;
+    let var_D = verifier.allocate_point(b"D", state.D.compress())?;
+    let var_P = verifier.allocate_point(b"P", resp.P.compress())?;
+    let var_x0 = verifier.allocate_scalar(b"x0");
+    let var_x0tilde = verifier.allocate_scalar(b"x0tilde");
+    let var_s = verifier.allocate_scalar(b"s");
+    let var_b = verifier.allocate_scalar(b"b");
+    let var_X0 = verifier.allocate_point(b"X0", pubkey.X[0].compress())?;
+    verifier.constrain(var_X0, vec![(var_x0, var_B), (var_x0tilde, var_A)]);
+    verifier.constrain(var_P, vec![(var_b, var_B)]);
 
-        // Form a Pedersen commitment to the MAC Q
+    let mut EncQ0_terms = vec![(var_s, var_B)];
+    let mut EncQ1_terms = vec![(var_s, var_D), (var_x0, var_P)];
+    let mut hidden = state.EncmB.iter().zip(&resp.T);
+    for i in 1..=n {
+        let var_xi = verifier.allocate_scalar(b"x");
+        let var_Xi = verifier.allocate_point(b"X", pubkey.X[i].compress())?;
+        verifier.constrain(var_Xi, vec![(var_xi, var_A)]);
+        match layout[i - 1] {
+            Visibility::Revealed if state.m[i] == Scalar::zero() => {}
+            Visibility::Revealed => {
+                let var_Pi = verifier.allocate_point(b"mP", (state.m[i] * resp.P).compress())?;
+                EncQ1_terms.push((var_xi, var_Pi));
+            }
+            Visibility::Hidden => {
+                let (Enc, Ti) = hidden.next().ok_or(ProofError::VerificationFailure)?;
+                let var_t = verifier.allocate_scalar(b"t");
+                let var_Ti = verifier.allocate_point(b"T", Ti.compress())?;
+                let var_E0 = verifier.allocate_point(b"EncmB0", Enc.0.compress())?;
+                let var_E1 = verifier.allocate_point(b"EncmB1", Enc.1.compress())?;
+                verifier.constrain(var_Ti, vec![(var_b, var_Xi)]);
+                verifier.constrain(var_Ti, vec![(var_t, var_A)]);
+                EncQ0_terms.push((var_t, var_E0));
+                EncQ1_terms.push((var_t, var_E1));
+            }
+        }
+    }
+    let var_EncQ0 = verifier.allocate_point(b"EncQ0", resp.EncQ.0.compress())?;
+    let var_EncQ1 = verifier.allocate_point(b"EncQ1", resp.EncQ.1.compress())?;
+    verifier.constrain(var_EncQ0, EncQ0_terms);
+    verifier.constrain(var_EncQ1, EncQ1_terms);
+    verifier.verify_compact(&resp.piBlindIssue)?;
+
+    // Decrypt EncQ
+    let Q = resp.EncQ.1 - (state.d * resp.EncQ.0);
+
+    Ok(Credential {
+        P: resp.P,
+        Q: vec![Q],
+        m: state.m,
+    })
+}
+
+// An attribute as it appears in a credential presentation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ShownAttr {
+    Revealed(Scalar),
+    // Pedersen commitment m_i*P + z_i*A
+    Committed(RistrettoPoint),
+}
+
+fn revealed_attr(attrs: &[ShownAttr], i: usize) -> Option<Scalar> {
+    match i.checked_sub(1).and_then(|k| attrs.get(k)) {
+        Some(ShownAttr::Revealed(m)) => Some(*m),
+        _ => None,
+    }
+}
+
+fn committed_attr(attrs: &[ShownAttr], i: usize) -> Option<RistrettoPoint> {
+    match i.checked_sub(1).and_then(|k| attrs.get(k)) {
+        Some(ShownAttr::Committed(C)) => Some(*C),
+        _ => None,
+    }
+}
+
+// A typo in the Hyphae paper (Section 4.4): P must also be sent to
+// the issuer in the credential presentation message. The proofs are
+// sent with their commitments (rather than in compact form) so that a
+// burst of presentations can be checked at once; see
+// verify_show_proofs_batch.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShowMessage {
+    pub P: RistrettoPoint,
+    pub(crate) attrs: Vec<ShownAttr>,
+    // A commitment to each part of the MAC, and a proof for each
+    pub(crate) CQ: Vec<RistrettoPoint>,
+    piCredShow: Vec<BatchableProof>,
+}
+
+impl ShowMessage {
+    // The number of MAC parts shown, one per issuer key
+    pub fn parts(&self) -> usize {
+        self.CQ.len()
+    }
+
+    pub fn revealed(&self, i: usize) -> Option<Scalar> {
+        revealed_attr(&self.attrs, i)
+    }
+
+    pub fn commitment(&self, i: usize) -> Option<RistrettoPoint> {
+        committed_attr(&self.attrs, i)
+    }
+}
+
+// What the issuer learns from a valid presentation: the revealed
+// attributes, and verified Pedersen commitments to the hidden ones so
+// that additional things can be proved about them in zero knowledge.
+#[derive(Debug)]
+pub struct VerifiedCredential {
+    pub P: RistrettoPoint,
+    attrs: Vec<ShownAttr>,
+}
+
+impl VerifiedCredential {
+    pub fn revealed(&self, i: usize) -> Option<Scalar> {
+        revealed_attr(&self.attrs, i)
+    }
+
+    pub fn commitment(&self, i: usize) -> Option<RistrettoPoint> {
+        committed_attr(&self.attrs, i)
+    }
+}
+
+// Each part of a presentation is proved separately, so that whoever
+// holds one issuer key can check its part alone.
+fn show_transcript(label: &'static [u8], layout: &[Visibility], part: usize) -> Transcript {
+    let mut transcript = cmz_transcript(label, b"show", layout);
+    transcript.append_u64(b"part", part as u64);
+    transcript
+}
+
+// Show a credential to the issuer, revealing the attributes marked
+// Revealed and committing to the rest. pubkeys[j] is the key part j of
+// the MAC was issued under. Also returns the commitment openings z_i,
+// indexed like cred.m (zero for revealed attributes).
+pub fn show(
+    label: &'static [u8],
+    layout: &[Visibility],
+    cred: &Credential,
+    pubkeys: &[IssuerPubKey],
+) -> (Vec<Scalar>, ShowMessage) {
+    let n = layout.len();
+    assert!(cred.m.len() > n && cred.Q.len() == pubkeys.len());
+    assert!(pubkeys.iter().all(|pubkey| pubkey.X.len() > n));
+    let A: &RistrettoPoint = &CMZ_A;
+    let Atable: &RistrettoBasepointTable = &CMZ_A_TABLE;
+
+    // Reblind P (and each part of Q below)
+    let mut rng = rand::thread_rng();
+    let t = Scalar::random(&mut rng);
+    let P = t * cred.P;
+
+    // Form Pedersen commitments to the blinded attributes, shared by
+    // all the parts
+    let mut zs = vec![Scalar::zero(); n + 1];
+    let mut attrs = Vec::with_capacity(n);
+    for i in 1..=n {
+        if layout[i - 1] == Visibility::Revealed {
+            attrs.push(ShownAttr::Revealed(cred.m[i]));
+            continue;
+        }
+        zs[i] = Scalar::random(&mut rng);
+        attrs.push(ShownAttr::Committed(cred.m[i] * P + &zs[i] * Atable));
+    }
+
+    let mut CQ = Vec::with_capacity(pubkeys.len());
+    let mut piCredShow = Vec::with_capacity(pubkeys.len());
+    for (part, (Q, pubkey)) in cred.Q.iter().zip(pubkeys).enumerate() {
+        // Form a Pedersen commitment to this part of the MAC
         // We flip the sign of zQ from that of the Hyphae paper so that
         // the ZKP has a "+" instead of a "-", as that's what the zkp
-        // macro supports.
+        // toolbox supports.
         let negzQ = Scalar::random(&mut rng);
-        let CQ = Q - &negzQ * Atable;
-
-        // Compute the "error factor"
-        let V = z3 * pubkey.X[3] + &negzQ * Atable;
-
-        // Create the ZKP
-        let mut transcript = Transcript::new(b"Blind345 5 showing proof");
-        let piCredShow = show::prove_compact(
-            &mut transcript,
-            show::ProveAssignments {
-                A: &A,
-                P: &P,
-                Cm3: &Cm3,
-                V: &V,
-                X3: &pubkey.X[3],
-                m3: &cred.m[3],
-                z3: &z3,
-                negzQ: &negzQ,
-            },
-        )
-        .0;
-        (z3, 
+        CQ.push(t * Q - &negzQ * Atable);
+
+        let mut transcript = show_transcript(label, layout, part);
+        let mut prover = Prover::new(b"CMZ showing proof", &mut transcript);
+        let var_A = prover.allocate_point(b"A", *A).0;
+        let var_P = prover.allocate_point(b"P", P).0;
+        let var_negzQ = prover.allocate_scalar(b"negzQ", negzQ);
+
+        // Compute the "error factor" V for this part's key
+        let mut V = &negzQ * Atable;
+        let mut V_terms = vec![(var_negzQ, var_A)];
+        for (k, attr) in attrs.iter().enumerate() {
+            if let ShownAttr::Committed(Cm) = attr {
+                let i = k + 1;
+                V += zs[i] * pubkey.X[i];
+                let var_m = prover.allocate_scalar(b"m", cred.m[i]);
+                let var_z = prover.allocate_scalar(b"z", zs[i]);
+                let var_Cm = prover.allocate_point(b"Cm", *Cm).0;
+                let var_Xi = prover.allocate_point(b"X", pubkey.X[i]).0;
+                prover.constrain(var_Cm, vec![(var_m, var_P), (var_z, var_A)]);
+                V_terms.push((var_z, var_Xi));
+            }
+        }
+        let var_V = prover.allocate_point(b"V", V).0;
+        prover.constrain(var_V, V_terms);
+        piCredShow.push(prover.prove_batchable());
+    }
+
+    (
+        zs,
         ShowMessage {
             P,
-            m1: cred.m[1],
-            Cm3,
+            attrs,
             CQ,
             piCredShow,
-        })
+        },
+    )
+}
+
+// Check that a presentation matches the layout and has a proof for
+// every part of the MAC, for an issuer key covering num_keys - 1
+// attributes.
+pub(crate) fn check_show_layout(layout: &[Visibility], showmsg: &ShowMessage, num_keys: usize) -> bool {
+    let matches_layout = layout.iter().zip(&showmsg.attrs).all(|(vis, attr)| {
+        matches!(
+            (vis, attr),
+            (Visibility::Revealed, ShownAttr::Revealed(_)) | (Visibility::Hidden, ShownAttr::Committed(_))
+        )
+    });
+    let parts_match = showmsg.CQ.len() == showmsg.piCredShow.len();
+    !showmsg.P.is_identity() && showmsg.attrs.len() == layout.len() && matches_layout && parts_match && num_keys > layout.len()
+}
+
+// Check the proof for one part of a presentation, given the "error
+// factor" Vprime recomputed with the private key of that part.
+pub(crate) fn verify_show_proof(
+    label: &'static [u8],
+    layout: &[Visibility],
+    showmsg: &ShowMessage,
+    part: usize,
+    pubkey: &IssuerPubKey,
+    Vprime: RistrettoPoint,
+) -> Result<(), ProofError> {
+    let A: &RistrettoPoint = &CMZ_A;
+    let proof = showmsg.piCredShow.get(part).ok_or(ProofError::VerificationFailure)?;
+
+    let mut transcript = show_transcript(label, layout, part);
+    let mut verifier = Verifier::new(b"CMZ showing proof", &mut transcript);
+    let var_A = verifier.allocate_point(b"A", A.compress())?;
+    let var_P = verifier.allocate_point(b"P", showmsg.P.compress())?;
+    let var_negzQ = verifier.allocate_scalar(b"negzQ");
+
+    let mut V_terms = vec![(var_negzQ, var_A)];
+    for (k, attr) in showmsg.attrs.iter().enumerate() {
+        if let ShownAttr::Committed(Cm) = attr {
+            let var_m = verifier.allocate_scalar(b"m");
+            let var_z = verifier.allocate_scalar(b"z");
+            let var_Cm = verifier.allocate_point(b"Cm", Cm.compress())?;
+            let var_Xi = verifier.allocate_point(b"X", pubkey.X[k + 1].compress())?;
+            verifier.constrain(var_Cm, vec![(var_m, var_P), (var_z, var_A)]);
+            V_terms.push((var_z, var_Xi));
+        }
+    }
+    let var_V = verifier.allocate_point(b"V", Vprime.compress())?;
+    verifier.constrain(var_V, V_terms);
+    verifier.verify_batchable(proof)
+}
+
+// Check one part of a batch of presentations with the same layout,
+// given the error factor of each, with a single randomized multiscalar
+// multiplication. The batch passes or fails as a whole. Callers have
+// checked every showing with check_show_layout.
+pub(crate) fn verify_show_proofs_batch(
+    label: &'static [u8],
+    layout: &[Visibility],
+    showmsgs: &[ShowMessage],
+    part: usize,
+    pubkey: &IssuerPubKey,
+    Vprimes: &[RistrettoPoint],
+) -> Result<(), ProofError> {
+    if showmsgs.is_empty() {
+        return Ok(());
+    }
+    let A: &RistrettoPoint = &CMZ_A;
+    let proofs = showmsgs
+        .iter()
+        .map(|s| s.piCredShow.get(part).cloned())
+        .collect::<Option<Vec<BatchableProof>>>()
+        .ok_or(ProofError::VerificationFailure)?;
+
+    let mut transcripts: Vec<Transcript> = showmsgs.iter().map(|_| show_transcript(label, layout, part)).collect();
+    let mut verifier = BatchVerifier::new(b"CMZ showing proof", showmsgs.len(), transcripts.iter_mut().collect())?;
+    let var_A = verifier.allocate_static_point(b"A", A.compress())?;
+    let var_P = verifier.allocate_instance_point(b"P", showmsgs.iter().map(|s| s.P.compress()).collect())?;
+    let var_negzQ = verifier.allocate_scalar(b"negzQ");
+
+    let mut V_terms = vec![(var_negzQ, var_A)];
+    for (k, vis) in layout.iter().enumerate() {
+        if *vis == Visibility::Hidden {
+            let var_m = verifier.allocate_scalar(b"m");
+            let var_z = verifier.allocate_scalar(b"z");
+            let Cms = showmsgs.iter().map(|s| s.commitment(k + 1).unwrap().compress()).collect();
+            let var_Cm = verifier.allocate_instance_point(b"Cm", Cms)?;
+            let var_Xi = verifier.allocate_static_point(b"X", pubkey.X[k + 1].compress())?;
+            verifier.constrain(var_Cm, vec![(var_m, var_P), (var_z, var_A)]);
+            V_terms.push((var_z, var_Xi));
+        }
+    }
+    let var_V = verifier.allocate_instance_point(b"V", Vprimes.iter().map(|V| V.compress()).collect())?;
+    verifier.constrain(var_V, V_terms);
+    verifier.verify_batchable(&proofs)
+}
+
+// Recompute the "error factor" of one part of a presentation using
+// knowledge of the issuer's private key instead of knowledge of the
+// hidden attributes
+pub(crate) fn error_factor(privkey: &IssuerPrivKey, showmsg: &ShowMessage, part: usize) -> RistrettoPoint {
+    let mut Vprime = privkey.x[0] * showmsg.P - showmsg.CQ[part];
+    for (k, attr) in showmsg.attrs.iter().enumerate() {
+        let i = k + 1;
+        match attr {
+            ShownAttr::Revealed(m) => {
+                Vprime += (privkey.x[i] * m) * showmsg.P;
+            }
+            ShownAttr::Committed(Cm) => {
+                Vprime += privkey.x[i] * Cm;
+            }
+        }
+    }
+    Vprime
+}
+
+impl From<ShowMessage> for VerifiedCredential {
+    fn from(showmsg: ShowMessage) -> VerifiedCredential {
+        VerifiedCredential {
+            P: showmsg.P,
+            attrs: showmsg.attrs,
+        }
+    }
+}
+
+impl Issuer {
+    // Verify a showing of a credential with the given layout.
+    pub fn verify_show(
+        &self,
+        label: &'static [u8],
+        layout: &[Visibility],
+        showmsg: ShowMessage,
+    ) -> Result<VerifiedCredential, ProofError> {
+        if !check_show_layout(layout, &showmsg, self.privkey.x.len()) || showmsg.parts() != 1 {
+            return Err(ProofError::VerificationFailure);
+        }
+        let Vprime = error_factor(&self.privkey, &showmsg, 0);
+        verify_show_proof(label, layout, &showmsg, 0, &self.pubkey, Vprime)?;
+        Ok(showmsg.into())
     }
 
-    impl Issuer {
-        // Verify a showing of an attribute from a user to the issuer
-        // with 5 credentials, of which attributes 3, 4, and 5 are
-        // blinded, and attributes 1 and 2 are revealed.  The issuer
-        // will end up with verified Pedersen commitments Cm3, Cm4, Cm5
-        // to the blinded attributes, so that additional things can be
-        // proved about those attributes in zero knowledge if desired.
-        pub fn verify_blind345_5(
-            &self,
-            showmsg: ShowMessage,
-        ) -> Result<(RistrettoPoint, VerifiedCredential), ProofError> {
-            let A: &RistrettoPoint = &CMZ_A;
-
-            if showmsg.P.is_identity() {
-                return Err(ProofError::VerificationFailure);
+    // Verify a batch of showings with the given layout at once. If the
+    // batch fails, verify the showings one at a time to find the bad
+    // ones.
+    pub fn verify_show_batch(
+        &self,
+        label: &'static [u8],
+        layout: &[Visibility],
+        showmsgs: Vec<ShowMessage>,
+    ) -> Result<Vec<VerifiedCredential>, ProofError> {
+        if !showmsgs.iter().all(|s| check_show_layout(layout, s, self.privkey.x.len()) && s.parts() == 1) {
+            return Err(ProofError::VerificationFailure);
+        }
+        let Vprimes: Vec<RistrettoPoint> = showmsgs.iter().map(|s| error_factor(&self.privkey, s, 0)).collect();
+        verify_show_proofs_batch(label, layout, &showmsgs, 0, &self.pubkey, &Vprimes)?;
+        Ok(showmsgs.into_iter().map(VerifiedCredential::from).collect())
+    }
+}
+
+// Generate a submodule $name for issuing credentials with the given
+// attributes, each marked Hidden or Revealed, and a method $issue_fn on
+// Issuer. request() takes the attributes in the order listed.
+macro_rules! cmz_issue {
+    ($name:ident, $label:expr, $issue_fn:ident, { $($attr:ident : $vis:ident),+ $(,)? }) => {
+        pub mod $name {
+            use curve25519_dalek::scalar::Scalar;
+            use zkp::ProofError;
+
+            use $crate::ggm::{Credential, Issuer, IssuerPubKey, Visibility};
+            use $crate::threshold::IssuerShare;
+            pub use $crate::ggm::{CredentialRequest, CredentialRequestState, CredentialResponse};
+            pub use $crate::threshold::{IssuanceContext, PartialResponse};
+
+            pub const LABEL: &[u8] = $label;
+            pub const ATTRIBUTES: &[&str] = &[$(stringify!($attr)),+];
+            pub const LAYOUT: &[Visibility] = &[$(Visibility::$vis),+];
+
+            pub fn request($($attr: &Scalar),+) -> (CredentialRequest, CredentialRequestState) {
+                $crate::ggm::request(LABEL, LAYOUT, &[$(*$attr),+])
             }
 
-            // Recompute the "error factor" using knowledge of our own
-            // (the issuer's) private key instead of knowledge of the
-            // hidden attributes
-            let Vprime = (self.privkey.x[0]
-                + (self.privkey.x[1] * showmsg.m1))
-                * showmsg.P
-                + self.privkey.x[3] * showmsg.Cm3
-                - showmsg.CQ;
-
-            // Verify the ZKP using Vprime
-            let mut transcript = Transcript::new(b"Blind345 5 showing proof");
-            show::verify_compact(
-                &showmsg.piCredShow,
-                &mut transcript,
-                show::VerifyAssignments {
-                    A: &A.compress(),
-                    P: &showmsg.P.compress(),
-                    Cm3: &showmsg.Cm3.compress(),
-                    V: &Vprime.compress(),
-                    X3: &self.pubkey.X[3].compress(),
-                },
-            )?;
-            Ok((showmsg.P, VerifiedCredential {
-                m1: showmsg.m1,
-                Cm3: showmsg.Cm3,
-            }))
+            impl Issuer {
+                pub fn $issue_fn(&self, req: CredentialRequest) -> Result<CredentialResponse, ProofError> {
+                    self.issue(LABEL, LAYOUT, &req)
+                }
+            }
+
+            pub fn verify(
+                state: CredentialRequestState,
+                resp: CredentialResponse,
+                pubkey: &IssuerPubKey,
+            ) -> Result<Credential, ProofError> {
+                $crate::ggm::verify_issue(LABEL, LAYOUT, state, resp, pubkey)
+            }
+
+            // Two-party issuance (see threshold.rs)
+            pub fn context(req: &CredentialRequest) -> Result<IssuanceContext, ProofError> {
+                $crate::threshold::issuance_context(LABEL, LAYOUT, req)
+            }
+
+            impl IssuerShare {
+                pub fn $issue_fn(&self, req: &CredentialRequest, ctx: &IssuanceContext) -> Result<PartialResponse, ProofError> {
+                    self.issue_share(LABEL, LAYOUT, req, ctx)
+                }
+            }
+
+            pub fn verify_threshold(
+                state: CredentialRequestState,
+                ctx: &IssuanceContext,
+                partials: &[PartialResponse],
+                shares: &[IssuerPubKey],
+            ) -> Result<Credential, ProofError> {
+                $crate::threshold::verify_threshold_issue(LABEL, LAYOUT, state, ctx, partials, shares)
+            }
         }
+    };
+}
+
+// Generate a submodule $name for showing credentials with the given
+// layout, and a method $verify_fn on Issuer to check the showing.
+// verify_batch and verify_share_batch check many showings at once.
+macro_rules! cmz_show {
+    ($name:ident, $label:expr, $verify_fn:ident, { $($attr:ident : $vis:ident),+ $(,)? }) => {
+        pub mod $name {
+            use curve25519_dalek::scalar::Scalar;
+            use zkp::ProofError;
+
+            use $crate::ggm::{Credential, Issuer, IssuerPubKey, Visibility};
+            use $crate::threshold::IssuerShare;
+            pub use $crate::ggm::{ShowMessage, VerifiedCredential};
+
+            pub const LABEL: &[u8] = $label;
+            pub const ATTRIBUTES: &[&str] = &[$(stringify!($attr)),+];
+            pub const LAYOUT: &[Visibility] = &[$(Visibility::$vis),+];
+
+            pub fn show(cred: &Credential, pubkey: &IssuerPubKey) -> (Vec<Scalar>, ShowMessage) {
+                $crate::ggm::show(LABEL, LAYOUT, cred, std::slice::from_ref(pubkey))
+            }
+
+            // Two-party showing (see threshold.rs): one part of the
+            // proof for each server, which checks it with its own share
+            pub fn show_threshold(cred: &Credential, shares: &[IssuerPubKey]) -> (Vec<Scalar>, ShowMessage) {
+                $crate::ggm::show(LABEL, LAYOUT, cred, shares)
+            }
+
+            impl Issuer {
+                pub fn $verify_fn(&self, showmsg: ShowMessage) -> Result<VerifiedCredential, ProofError> {
+                    self.verify_show(LABEL, LAYOUT, showmsg)
+                }
+            }
+
+            pub fn verify_batch(issuer: &Issuer, showmsgs: Vec<ShowMessage>) -> Result<Vec<VerifiedCredential>, ProofError> {
+                issuer.verify_show_batch(LABEL, LAYOUT, showmsgs)
+            }
+
+            impl IssuerShare {
+                pub fn $verify_fn(&self, showmsg: &ShowMessage, part: usize) -> Result<VerifiedCredential, ProofError> {
+                    self.verify_show(LABEL, LAYOUT, showmsg, part)
+                }
+            }
+
+            pub fn verify_share_batch(
+                share: &IssuerShare,
+                showmsgs: Vec<ShowMessage>,
+                part: usize,
+            ) -> Result<Vec<VerifiedCredential>, ProofError> {
+                share.verify_show_batch(LABEL, LAYOUT, showmsgs, part)
+            }
+        }
+    };
+}
+
+// Group credentials: attribute 1 is the member's UID and attribute 3
+// their AID. The leader obtains them with the UID hidden and the AID
+// visible to the issuer (so S1 can check the AID belongs to the group),
+// and members register by revealing the UID and committing to the AID.
+// Attribute 5 is the last validity epoch of the credential (see
+// current_epoch). It is visible at issuance, so S1 can bound the
+// lifetime, and revealed when showing, so S1 can refuse expired
+// credentials; the MAC proves it is the value that was issued.
+cmz_issue! {
+    issue_blind124_5, b"Blind124 5", issue_blind124_5,
+    { m1: Hidden, m2: Hidden, m3: Revealed, m4: Hidden, m5: Revealed }
+}
+
+cmz_show! {
+    show_blind34_5, b"Blind34 5", verify_blind34_5,
+    { m1: Revealed, m2: Revealed, m3: Hidden, m4: Hidden, m5: Revealed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A layout the hand-written protocols never had: a role the issuer
+    // sees, a hidden secret, and an expiry revealed at show time.
+    cmz_issue! {
+        issue_role, b"Role issue", issue_role,
+        { role: Revealed, secret: Hidden, expiry: Revealed }
+    }
+
+    cmz_show! {
+        show_expiry, b"Expiry show", verify_expiry,
+        { role: Hidden, secret: Hidden, expiry: Revealed }
+    }
+
+    #[test]
+    fn group_credential() {
+        let issuer = Issuer::new(5);
+        let one = Scalar::one();
+        let uid = Scalar::random(&mut rand::thread_rng());
+        let aid = Scalar::from(23u64);
+
+        let (req, state) = issue_blind124_5::request(&uid, &one, &aid, &one, &one);
+        assert_eq!(req.revealed(3), Some(aid));
+        assert_eq!(req.revealed(1), None);
+        let resp = issuer.issue_blind124_5(req).unwrap();
+        let cred = issue_blind124_5::verify(state, resp, &issuer.pubkey).unwrap();
+        assert_eq!(cred.m[1], uid);
+
+        let (zs, showmsg) = show_blind34_5::show(&cred, &issuer.pubkey);
+        let verified = issuer.verify_blind34_5(showmsg).unwrap();
+        assert_eq!(verified.revealed(1), Some(uid));
+        let A: &RistrettoPoint = &CMZ_A;
+        assert_eq!(verified.commitment(3), Some(aid * verified.P + zs[3] * A));
+    }
+
+    #[test]
+    fn zero_attribute() {
+        // Group 0's first AID is zero
+        let issuer = Issuer::new(5);
+        let one = Scalar::one();
+        let zero = Scalar::zero();
+        let (req, state) = issue_blind124_5::request(&one, &one, &zero, &one, &one);
+        let resp = issuer.issue_blind124_5(req).unwrap();
+        let cred = issue_blind124_5::verify(state, resp, &issuer.pubkey).unwrap();
+        let (_, showmsg) = show_blind34_5::show(&cred, &issuer.pubkey);
+        assert!(issuer.verify_blind34_5(showmsg).is_ok());
+    }
+
+    #[test]
+    fn custom_layout() {
+        let issuer = Issuer::new(3);
+        let role = Scalar::from(2u64);
+        let secret = Scalar::random(&mut rand::thread_rng());
+        let expiry = Scalar::from(1000u64);
+
+        let (req, state) = issue_role::request(&role, &secret, &expiry);
+        let resp = issuer.issue_role(req).unwrap();
+        let cred = issue_role::verify(state, resp, &issuer.pubkey).unwrap();
+
+        let (_, showmsg) = show_expiry::show(&cred, &issuer.pubkey);
+        assert_eq!(showmsg.revealed(3), Some(expiry));
+        assert!(showmsg.revealed(1).is_none() && showmsg.commitment(1).is_some());
+        assert!(issuer.verify_expiry(showmsg).is_ok());
+        let showmsgs = (0..2).map(|_| show_expiry::show(&cred, &issuer.pubkey).1).collect();
+        assert_eq!(show_expiry::verify_batch(&issuer, showmsgs).unwrap().len(), 2);
+
+        // A credential only verifies under the key that issued it
+        let other = Issuer::new(3);
+        let (_, showmsg) = show_expiry::show(&cred, &issuer.pubkey);
+        assert!(other.verify_expiry(showmsg).is_err());
+    }
+
+    #[test]
+    fn custom_layout_two_party() {
+        use crate::threshold::IssuerShare;
+
+        assert_eq!(issue_role::ATTRIBUTES, &["role", "secret", "expiry"]);
+        assert_eq!(show_expiry::ATTRIBUTES, issue_role::ATTRIBUTES);
+        let s1 = IssuerShare::new(3);
+        let s2 = IssuerShare::new(3);
+        let shares = vec![s1.pubkey.clone(), s2.pubkey.clone()];
+        let role = Scalar::from(2u64);
+        let secret = Scalar::random(&mut rand::thread_rng());
+        let expiry = Scalar::from(1000u64);
+
+        let (req, state) = issue_role::request(&role, &secret, &expiry);
+        let ctx = issue_role::context(&req).unwrap();
+        let partials = vec![s1.issue_role(&req, &ctx).unwrap(), s2.issue_role(&req, &ctx).unwrap()];
+        let cred = issue_role::verify_threshold(state, &ctx, &partials, &shares).unwrap();
+
+        let (_, showmsg) = show_expiry::show_threshold(&cred, &shares);
+        assert_eq!(s1.verify_expiry(&showmsg, 0).unwrap().revealed(3), Some(expiry));
+        assert!(s2.verify_expiry(&showmsg, 1).is_ok());
+        assert!(show_expiry::verify_share_batch(&s2, vec![showmsg], 1).is_ok());
+    }
+
+    #[test]
+    fn layout_mismatch() {
+        let issuer = Issuer::new(5);
+        let one = Scalar::one();
+
+        // A request for one layout is rejected under another
+        let (req, _) = issue_role::request(&one, &one, &one);
+        assert!(issuer.issue_blind124_5(req).is_err());
+
+        let (req, state) = issue_blind124_5::request(&one, &one, &one, &one, &one);
+        let resp = issuer.issue_blind124_5(req).unwrap();
+        let cred = issue_blind124_5::verify(state, resp, &issuer.pubkey).unwrap();
+        let (_, showmsg) = show_blind34_5::show(&cred, &issuer.pubkey);
+        assert!(issuer.verify_expiry(showmsg).is_err());
+    }
+
+    #[test]
+    fn batch_show() {
+        let issuer = Issuer::new(5);
+        let one = Scalar::one();
+        let showings = |issuer: &Issuer, n: u64| -> Vec<ShowMessage> {
+            (0..n)
+                .map(|aid| {
+                    let uid = Scalar::random(&mut rand::thread_rng());
+                    let (req, state) = issue_blind124_5::request(&uid, &one, &Scalar::from(aid), &one, &one);
+                    let resp = issuer.issue_blind124_5(req).unwrap();
+                    let cred = issue_blind124_5::verify(state, resp, &issuer.pubkey).unwrap();
+                    show_blind34_5::show(&cred, &issuer.pubkey).1
+                })
+                .collect()
+        };
+
+        let batch = showings(&issuer, 4);
+        let uids: Vec<Scalar> = batch.iter().map(|s| s.revealed(1).unwrap()).collect();
+        let verified = show_blind34_5::verify_batch(&issuer, batch).unwrap();
+        assert_eq!(verified.iter().map(|v| v.revealed(1).unwrap()).collect::<Vec<_>>(), uids);
+        assert!(show_blind34_5::verify_batch(&issuer, Vec::new()).unwrap().is_empty());
+
+        // One showing of a credential from another issuer spoils the batch
+        let mut batch = showings(&issuer, 3);
+        batch.insert(1, showings(&Issuer::new(5), 1).pop().unwrap());
+        assert!(show_blind34_5::verify_batch(&issuer, batch).is_err());
+
+        // So does a showing with an altered revealed attribute
+        let mut batch = showings(&issuer, 3);
+        batch[2].attrs[0] = ShownAttr::Revealed(one);
+        assert!(show_blind34_5::verify_batch(&issuer, batch).is_err());
     }
 }
@@ -0,0 +1,307 @@
+// Handing a group slot from the group leader to a new member.
+//
+// The leader obtains a credential for every slot during group setup.
+// To give a slot to someone else, the leader seals the slot's
+// credential, the issuer key as attested by both servers, the group
+// number and the group master secret into an Invitation encrypted to
+// the member's public key. The member checks the issuer key against the
+// servers' own attestations, and registers the credential with the
+// servers to get their group token.
+//
+// Encryption is DHIES over Ristretto: an ephemeral key R = rB, a shared
+// point r*pk, HKDF to an AES-128-CTR key and an HMAC-SHA256 key, and
+// encrypt-then-MAC over the bincode encoding of the contents.
+#![allow(non_snake_case)]
+
+use aes::cipher::{generic_array::GenericArray, KeyIvInit, StreamCipher};
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::ristretto::RistrettoBasepointTable;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use data_encoding::BASE32_NOPAD;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use crate::client::{member_token, registration_request};
+use crate::ggm::{Credential, IssuerPubKey, CMZ_B_TABLE};
+use crate::keys::GroupMasterSecret;
+use crate::ps::{GroupToken, GroupTokenPriv};
+use crate::transparency::{fetch_issuer_key, AttestedIssuerKey, KeyPin, TransparencyError};
+use crate::{read_frame, write_frame, GROUP_TOKEN_SIZE};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+const INVITE_KDF_INFO: &[u8] = b"PSA invitation";
+const ENC_KEY_SIZE: usize = 16;
+const MAC_KEY_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum InvitationError {
+    // The text encoding or the ciphertext could not be parsed
+    Malformed,
+    // The MAC did not verify: wrong recipient or tampered invitation
+    BadTag,
+    // The issuer key doesn't match what the servers attest to
+    IssuerKey(TransparencyError),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for InvitationError {
+    fn from(e: std::io::Error) -> InvitationError {
+        InvitationError::Io(e)
+    }
+}
+
+impl From<TransparencyError> for InvitationError {
+    fn from(e: TransparencyError) -> InvitationError {
+        InvitationError::IssuerKey(e)
+    }
+}
+
+// A (prospective) member's long-term encryption key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberKeyPair {
+    sk: Scalar,
+    pub pk: RistrettoPoint,
+}
+
+impl MemberKeyPair {
+    pub fn new() -> MemberKeyPair {
+        let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+        let sk = Scalar::random(&mut rand::thread_rng());
+        let pk = &sk * Btable;
+        MemberKeyPair { sk, pk }
+    }
+}
+
+impl Default for MemberKeyPair {
+    fn default() -> MemberKeyPair {
+        MemberKeyPair::new()
+    }
+}
+
+// Everything a member needs to take over a slot of the group.
+#[derive(Serialize, Deserialize)]
+pub struct InvitationContents {
+    pub group: u32,
+    pub credential: Credential,
+    pub issuer_key: AttestedIssuerKey,
+    pub group_secret: GroupMasterSecret,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    R: CompressedRistretto,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+fn derive_keys(shared: &RistrettoPoint, R: &CompressedRistretto, pk: &RistrettoPoint) -> (Vec<u8>, Vec<u8>) {
+    let mut info = INVITE_KDF_INFO.to_vec();
+    info.extend_from_slice(R.as_bytes());
+    info.extend_from_slice(pk.compress().as_bytes());
+
+    let hk = Hkdf::<Sha256>::new(None, shared.compress().as_bytes());
+    let mut okm = [0u8; ENC_KEY_SIZE + MAC_KEY_SIZE];
+    hk.expand(&info, &mut okm)
+        .expect("valid HKDF output length");
+    (okm[..ENC_KEY_SIZE].to_vec(), okm[ENC_KEY_SIZE..].to_vec())
+}
+
+fn compute_tag(mac_key: &[u8], R: &CompressedRistretto, ciphertext: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(mac_key).expect("HMAC can take key of any size");
+    mac.update(R.as_bytes());
+    mac.update(ciphertext);
+    mac
+}
+
+fn apply_keystream(enc_key: &[u8], data: &mut [u8]) {
+    // Each invitation uses a fresh key, so a fixed IV is fine
+    let iv = [0u8; 16];
+    let mut cipher = Aes128Ctr::new(GenericArray::from_slice(enc_key), &iv.into());
+    cipher.apply_keystream(data);
+}
+
+impl Invitation {
+    // Encrypt the contents to the member's public key.
+    pub fn seal(contents: &InvitationContents, member_pk: &RistrettoPoint) -> Invitation {
+        let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+        let r = Scalar::random(&mut rand::thread_rng());
+        let R = (&r * Btable).compress();
+        let (enc_key, mac_key) = derive_keys(&(r * member_pk), &R, member_pk);
+
+        let mut ciphertext = bincode::serialize(contents).unwrap();
+        apply_keystream(&enc_key, &mut ciphertext);
+        let tag = compute_tag(&mac_key, &R, &ciphertext)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        Invitation { R, ciphertext, tag }
+    }
+
+    pub fn open(&self, keys: &MemberKeyPair) -> Result<InvitationContents, InvitationError> {
+        let R = self.R.decompress().ok_or(InvitationError::Malformed)?;
+        let (enc_key, mac_key) = derive_keys(&(keys.sk * R), &self.R, &keys.pk);
+
+        compute_tag(&mac_key, &self.R, &self.ciphertext)
+            .verify(&self.tag)
+            .map_err(|_| InvitationError::BadTag)?;
+
+        let mut plaintext = self.ciphertext.clone();
+        apply_keystream(&enc_key, &mut plaintext);
+        bincode::deserialize(&plaintext).map_err(|_| InvitationError::Malformed)
+    }
+
+    // Unpadded base32, which only uses [A-Z2-7] and so fits in the
+    // alphanumeric mode of a QR code and survives copy/paste.
+    pub fn to_text(&self) -> String {
+        BASE32_NOPAD.encode(&bincode::serialize(self).unwrap())
+    }
+
+    pub fn from_text(text: &str) -> Result<Invitation, InvitationError> {
+        let bytes = BASE32_NOPAD
+            .decode(text.trim().as_bytes())
+            .map_err(|_| InvitationError::Malformed)?;
+        bincode::deserialize(&bytes).map_err(|_| InvitationError::Malformed)
+    }
+}
+
+// Seal the credential for one slot of the group to a new member.
+pub fn invite(
+    group: u32,
+    credential: Credential,
+    issuer_key: &AttestedIssuerKey,
+    group_secret: &GroupMasterSecret,
+    member_pk: &RistrettoPoint,
+) -> Invitation {
+    let contents = InvitationContents {
+        group,
+        credential,
+        issuer_key: issuer_key.clone(),
+        group_secret: group_secret.clone(),
+    };
+    Invitation::seal(&contents, member_pk)
+}
+
+// Show a credential to both servers in exchange for a group token, and
+// return the token and the commitment openings. S2 checks its part of
+// the presentation first and vouches for it to S1 through redis.
+pub fn register_credential(
+    credential: &Credential,
+    shares: &[IssuerPubKey],
+    mut stream1: &TcpStream,
+    mut stream2: &TcpStream,
+) -> std::io::Result<(Vec<Scalar>, GroupToken)> {
+    let (zs, encoded) = registration_request(credential, shares);
+
+    stream2.write_all(&[3u8])?;
+    write_frame(&mut stream2, &encoded)?;
+    let ack: bool = bincode::deserialize(&read_frame(&mut stream2)?).unwrap_or(false);
+    if !ack {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "S2 refused the credential"));
+    }
+
+    stream1.write_all(&[3u8])?;
+    write_frame(&mut stream1, &encoded)?;
+    let mut buf = [0; GROUP_TOKEN_SIZE];
+    stream1.read_exact(&mut buf)?;
+    let token: GroupToken = bincode::deserialize(&buf)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok((zs, token))
+}
+
+// Member side: open the invitation, check that the servers attest to
+// the issuer key the leader used, and register the credential in
+// exchange for a group token.
+pub fn accept_invitation(
+    invitation: &Invitation,
+    keys: &MemberKeyPair,
+    stream1: &TcpStream,
+    stream2: &TcpStream,
+) -> Result<GroupTokenPriv, InvitationError> {
+    let contents = invitation.open(keys)?;
+    let mut issuer_key = KeyPin::new(&contents.issuer_key)?;
+    issuer_key.update(&fetch_issuer_key(stream1, stream2)?)?;
+    let (zs, token) = register_credential(&contents.credential, issuer_key.shares(), stream1, stream2)?;
+
+    Ok(member_token(contents.group_secret, &contents.credential, &zs, token, issuer_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ggm::{issue_blind124_5, show_blind34_5, Issuer};
+    use crate::signature::SigningKey;
+    use crate::transparency::{IssuerKeyRecord, KeyAttestation};
+
+    // The issuer's key, as the servers would attest to it
+    fn issuer_key(issuer: &Issuer) -> AttestedIssuerKey {
+        let record = IssuerKeyRecord {
+            version: 1,
+            shares: vec![issuer.pubkey.clone()],
+        };
+        AttestedIssuerKey {
+            s1: KeyAttestation::new(1, &SigningKey::new(), record.clone()),
+            s2: KeyAttestation::new(2, &SigningKey::new(), record),
+        }
+    }
+
+    fn slot_credential(issuer: &Issuer, aid: u64) -> Credential {
+        let one = Scalar::one();
+        let uid = Scalar::random(&mut rand::thread_rng());
+        let (req, state) = issue_blind124_5::request(&uid, &one, &Scalar::from(aid), &one, &one);
+        let resp = issuer.issue_blind124_5(req).unwrap();
+        issue_blind124_5::verify(state, resp, &issuer.pubkey).unwrap()
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let issuer = Issuer::new(5);
+        let member = MemberKeyPair::new();
+        let secret = GroupMasterSecret::random();
+        let cred = slot_credential(&issuer, 23);
+        let uid = cred.m[1];
+
+        let inv = invite(2, cred, &issuer_key(&issuer), &secret, &member.pk);
+        let text = inv.to_text();
+        assert!(text.chars().all(|c| c.is_ascii_uppercase() || ('2'..='7').contains(&c)));
+
+        let contents = Invitation::from_text(&text).unwrap().open(&member).unwrap();
+        assert_eq!(contents.group, 2);
+        assert_eq!(contents.credential.m[1], uid);
+        assert_eq!(contents.credential.m[3], Scalar::from(23u64));
+        assert_eq!(contents.group_secret.server_key(1), secret.server_key(1));
+
+        // The opened credential can be shown to the issuer
+        let (_, showmsg) = show_blind34_5::show_threshold(&contents.credential, KeyPin::new(&contents.issuer_key).unwrap().shares());
+        assert!(issuer.verify_blind34_5(showmsg).is_ok());
+    }
+
+    #[test]
+    fn wrong_recipient() {
+        let issuer = Issuer::new(5);
+        let member = MemberKeyPair::new();
+        let other = MemberKeyPair::new();
+        let inv = invite(0, slot_credential(&issuer, 1), &issuer_key(&issuer), &GroupMasterSecret::random(), &member.pk);
+
+        assert!(matches!(inv.open(&other), Err(InvitationError::BadTag)));
+    }
+
+    #[test]
+    fn tampered() {
+        let issuer = Issuer::new(5);
+        let member = MemberKeyPair::new();
+        let mut inv = invite(0, slot_credential(&issuer, 1), &issuer_key(&issuer), &GroupMasterSecret::random(), &member.pk);
+        inv.ciphertext[0] ^= 1;
+
+        assert!(matches!(inv.open(&member), Err(InvitationError::BadTag)));
+        assert!(matches!(Invitation::from_text("not base32!"), Err(InvitationError::Malformed)));
+    }
+}
@@ -0,0 +1,255 @@
+// Key hierarchy for the PRF keys that mask a group's balances.
+//
+// The group leader picks a group master secret and shares it with the
+// members of the group. Everything else is derived from it with HKDF:
+//
+//     master secret
+//       +-- server key (one per server)  = HKDF(master, "server" || id)
+//             +-- epoch key               = HKDF(server key, "epoch" || e)
+//
+// Each server is given only its own server key when the group is
+// created, and derives a fresh epoch key for every settlement epoch, so
+// keys rotate without re-running group setup. Members hold the master
+// secret and can derive the epoch keys of both servers. The leader
+// sends each server its key directly, so neither ever sees the other's.
+//
+// A mask is only safe to use once: S1 sees S2's masked database at every
+// settlement, and two of them under the same epoch key would show how the
+// balances changed in between. Each server keeps a SettleLog and only
+// settles in epochs later than the last one it settled in. Settlement
+// epochs follow the clock (see settle_epoch), and a server refuses
+// epochs too far ahead of its own, so no client can use them all up.
+//
+// When the leader closes a group (see membership.rs) each server removes
+// its key from the registry and clears its share of the group's slots,
+// which are then masked like those of a group not created yet.
+
+use hkdf::Hkdf;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+use crate::mask::MASK_KEY_SIZE;
+use crate::MAX_SETTLE_SKEW;
+
+pub const MASTER_SECRET_SIZE: usize = 32;
+
+const SERVER_KEY_INFO: &[u8] = b"PSA group server key";
+const EPOCH_KEY_INFO: &[u8] = b"PSA group epoch key";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupMasterSecret {
+    secret: [u8; MASTER_SECRET_SIZE],
+}
+
+impl GroupMasterSecret {
+    pub fn random() -> GroupMasterSecret {
+        GroupMasterSecret {
+            secret: rand::thread_rng().gen::<[u8; MASTER_SECRET_SIZE]>(),
+        }
+    }
+
+    // The key handed to server `server` (1 or 2) at group creation.
+    pub fn server_key(&self, server: u8) -> Vec<u8> {
+        let mut info = SERVER_KEY_INFO.to_vec();
+        info.push(server);
+        expand(&self.secret, &info)
+    }
+
+    // The mask keys of (S1, S2) for the given epoch.
+    pub fn epoch_keys(&self, epoch: u64) -> (Vec<u8>, Vec<u8>) {
+        (
+            derive_epoch_key(&self.server_key(1), epoch),
+            derive_epoch_key(&self.server_key(2), epoch),
+        )
+    }
+}
+
+pub fn derive_epoch_key(server_key: &[u8], epoch: u64) -> Vec<u8> {
+    let mut info = EPOCH_KEY_INFO.to_vec();
+    info.extend_from_slice(&epoch.to_be_bytes());
+    expand(server_key, &info)
+}
+
+fn expand(ikm: &[u8], info: &[u8]) -> Vec<u8> {
+    let hk = Hkdf::<Sha256>::new(None, ikm);
+    let mut okm = vec![0u8; MASK_KEY_SIZE];
+    hk.expand(info, &mut okm)
+        .expect("MASK_KEY_SIZE is a valid HKDF output length");
+    okm
+}
+
+// A server's view of the groups' key material, indexed by group number.
+#[derive(Clone, Default)]
+pub struct KeyRegistry {
+    server_keys: HashMap<usize, Vec<u8>>,
+}
+
+impl KeyRegistry {
+    pub fn new() -> KeyRegistry {
+        KeyRegistry {
+            server_keys: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, group: usize, server_key: Vec<u8>) {
+        self.server_keys.insert(group, server_key);
+    }
+
+    // Forget a closed group's key. Returns whether there was one.
+    pub fn remove(&mut self, group: usize) -> bool {
+        self.server_keys.remove(&group).is_some()
+    }
+
+    pub fn contains(&self, group: usize) -> bool {
+        self.server_keys.contains_key(&group)
+    }
+
+    // Mask key of a group for the given epoch, if the group exists.
+    pub fn epoch_key(&self, group: usize, epoch: u64) -> Option<Vec<u8>> {
+        self.server_keys
+            .get(&group)
+            .map(|k| derive_epoch_key(k, epoch))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SettleError {
+    // The epoch is no later than one the server already settled in
+    Stale(u64),
+    // The epoch is too far ahead of the server's clock
+    Early(u64),
+    // The other server refused to settle in this epoch
+    PeerRefused,
+    // The request could not be decoded
+    Malformed,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SettleLog {
+    last: u64,
+}
+
+impl SettleLog {
+    pub fn new() -> SettleLog {
+        SettleLog { last: 0 }
+    }
+
+    // Record a settlement in `epoch`, if it's fresh, when the clock reads
+    // `now`.
+    pub fn accept(&mut self, epoch: u64, now: u64) -> Result<(), SettleError> {
+        if epoch <= self.last {
+            return Err(SettleError::Stale(epoch));
+        }
+        if epoch > now + MAX_SETTLE_SKEW {
+            return Err(SettleError::Early(epoch));
+        }
+        self.last = epoch;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ps::{GroupTokenPriv, ServerData};
+    use crate::FieldElm;
+    use crate::Group;
+    use crate::{MAX_GROUP_NUM, MAX_GROUP_SIZE};
+
+    #[test]
+    fn derivation() {
+        let master = GroupMasterSecret::random();
+        assert_eq!(master.server_key(1), master.server_key(1));
+        assert!(master.server_key(1) != master.server_key(2));
+
+        let (k1, k2) = master.epoch_keys(4);
+        assert!(k1 != k2);
+        assert_eq!(k1, derive_epoch_key(&master.server_key(1), 4));
+        assert!(k1 != master.epoch_keys(5).0);
+    }
+
+    #[test]
+    fn registry() {
+        let master = GroupMasterSecret::random();
+        let mut reg = KeyRegistry::new();
+        reg.register(3, master.server_key(2));
+
+        assert!(reg.contains(3));
+        assert!(!reg.contains(2));
+        assert_eq!(reg.epoch_key(3, 9), Some(master.epoch_keys(9).1));
+        assert_eq!(reg.epoch_key(2, 9), None);
+
+        assert!(reg.remove(3));
+        assert!(!reg.contains(3) && !reg.remove(3));
+    }
+
+    #[test]
+    fn settle_round_trip() {
+        let group = 2;
+        let epoch = 77;
+        let master = GroupMasterSecret::random();
+        let mut reg1 = KeyRegistry::new();
+        let mut reg2 = KeyRegistry::new();
+        reg1.register(group, master.server_key(1));
+        reg2.register(group, master.server_key(2));
+
+        let db: Vec<FieldElm> = (0..MAX_GROUP_NUM * MAX_GROUP_SIZE)
+            .map(|i| FieldElm::from(i as u32))
+            .collect();
+        let zero = vec![FieldElm::from(0u32); db.len()];
+        let enc1 = ServerData::encrypt_db(&db, &reg1, epoch);
+        let enc2 = ServerData::encrypt_db(&zero, &reg2, epoch);
+
+        let mut group_vals = vec![];
+        for i in 0..MAX_GROUP_SIZE {
+            let mut v = enc1[group * MAX_GROUP_SIZE + i].clone();
+            v.add(&enc2[group * MAX_GROUP_SIZE + i]);
+            group_vals.push(v);
+        }
+        let dec = GroupTokenPriv::decrypt_db(group_vals, &master, group as u32, epoch);
+        assert_eq!(dec[..], db[group * MAX_GROUP_SIZE..(group + 1) * MAX_GROUP_SIZE]);
+    }
+
+    #[test]
+    fn settle_log() {
+        let mut log = SettleLog::new();
+        let now = 1000;
+        assert_eq!(log.accept(now, now), Ok(()));
+
+        // Each epoch is settled in once, and only in order
+        assert_eq!(log.accept(now, now), Err(SettleError::Stale(now)));
+        assert_eq!(log.accept(now - 1, now), Err(SettleError::Stale(now - 1)));
+        assert_eq!(log.accept(now + 1, now), Ok(()));
+
+        // A client can't skip the log ahead of the clock
+        let far = now + MAX_SETTLE_SKEW + 1;
+        assert_eq!(log.accept(far, now), Err(SettleError::Early(far)));
+        assert_eq!(log.accept(now + MAX_SETTLE_SKEW, now), Ok(()));
+    }
+
+    #[test]
+    fn closed_group() {
+        let group = 2;
+        let master = GroupMasterSecret::random();
+        let mut reg = KeyRegistry::new();
+        reg.register(group, master.server_key(1));
+        let mut db: Vec<FieldElm> = (0..MAX_GROUP_NUM * MAX_GROUP_SIZE)
+            .map(|i| FieldElm::from(i as u32 + 1))
+            .collect();
+
+        // Once closed, the group's slots look like those of a group that
+        // was never created, and the other groups are untouched
+        ServerData::close_group(&mut db, &mut reg, group);
+        assert!(!reg.contains(group));
+        let zero = vec![FieldElm::from(0u32); db.len()];
+        let enc = ServerData::encrypt_db(&db, &reg, 5);
+        let fresh = ServerData::encrypt_db(&zero, &KeyRegistry::new(), 5);
+        let slots = group * MAX_GROUP_SIZE..(group + 1) * MAX_GROUP_SIZE;
+        assert_eq!(enc[slots.clone()], fresh[slots]);
+        assert_eq!(db[0], FieldElm::from(1u32));
+    }
+}
@@ -8,6 +8,22 @@ pub mod ps;
 pub mod prg;
 pub mod mpc;
 pub mod sketch;
+pub mod mask;
+pub mod keys;
+pub mod invite;
+pub mod membership;
+pub mod ringproof;
+pub mod tokens;
+pub mod threshold;
+pub mod creation;
+pub mod signature;
+pub mod transparency;
+pub mod receipt;
+pub mod client;
+pub mod batch;
+pub mod burst;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 mod field;
 
 #[macro_use]
@@ -16,19 +32,34 @@ use rsa::{RsaPrivateKey, RsaPublicKey};
 use rand_pcg::Pcg64;
 use rand::rngs::OsRng;
 use rsa::rand_core::SeedableRng;
+use std::io::{self, Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use curve25519_dalek::scalar::Scalar;
 
 
-pub use crate::field::FieldElm;
+pub use crate::field::{FieldElm, MODULUS_127, MODULUS_64};
 //pub use crate::rpc::CollectorClient;
 
 pub const MAX_GROUP_SIZE: usize = 10;
 pub const MAX_GROUP_NUM: usize = 50;
 pub const DPF_DOMAIN: usize = 10; // 9 = 2^8 = 256 // about 60 AES evals
 pub const SETTLE_DOMAIN: usize = 8; // 9 = 2^8 = 256 // about 60 AES evals
-pub const SETTLE_SIZE: usize = 233;
-pub const CRED_REQUEST_1: usize = 2648; 
-pub const TRANSACT_REQ_1: usize = 2824 + 136 * 9 - 38 * 1;
-pub const TRANSACT_REQ_2: usize = 2344 - 38 * 1;
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+pub const GROUP_TOKEN_SIZE: usize = 192;
+// Credentials and group tokens are valid up to and including a
+// validity epoch, counted in days since the Unix epoch.
+pub const EPOCH_SECONDS: u64 = 24 * 60 * 60;
+pub const CREDENTIAL_LIFETIME: u64 = 90; // epochs
+pub const TOKEN_LIFETIME: u64 = 7; // epochs
+// Settlement epochs are counted in seconds instead, so a client can
+// pick a fresh one without asking the servers. A server refuses
+// settlement epochs further ahead of its clock than this.
+pub const MAX_SETTLE_SKEW: u64 = 60; // seconds
+// The redis instance the servers and the operator tool share, unless
+// PSA_REDIS_URL says otherwise
+// pub const DEFAULT_REDIS: &str = "redis://127.0.0.1:6379";
+pub const DEFAULT_REDIS: &str = "redis://10.128.0.4:6379";
 
 
 // Additive group, such as (Z_n, +)
@@ -129,6 +160,64 @@ pub fn bits_to_string(bits: &[bool]) -> String {
     out
 }
 
+// Seconds since the Unix epoch. SystemTime::now panics on
+// wasm32-unknown-unknown, so the browser build asks JS for the time.
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn unix_seconds() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+pub fn current_epoch() -> u64 {
+    unix_seconds() / EPOCH_SECONDS
+}
+
+pub fn settle_epoch() -> u64 {
+    unix_seconds()
+}
+
+pub fn redis_url() -> String {
+    std::env::var("PSA_REDIS_URL").unwrap_or_else(|_| DEFAULT_REDIS.to_string())
+}
+
+// Small integer attributes (AIDs, epochs) are stored in credentials as
+// scalars; this recovers them, or returns None if the value is too big.
+pub fn scalar_to_u64(s: &Scalar) -> Option<u64> {
+    let bytes = s.as_bytes();
+    if bytes[8..].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut low = [0u8; 8];
+    low.copy_from_slice(&bytes[..8]);
+    Some(u64::from_le_bytes(low))
+}
+
+// Variable-length messages are sent as a 4-byte big-endian length
+// followed by the payload.
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)
+}
+
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,4 +266,20 @@ mod tests {
         assert_eq!(bitvec.len(), s.len() * 8);
         assert_eq!(s, s2);
     }
+
+    #[test]
+    fn frames() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello").unwrap();
+        write_frame(&mut wire, b"").unwrap();
+        assert_eq!(wire.len(), 4 + 5 + 4);
+
+        let mut r = &wire[..];
+        assert_eq!(read_frame(&mut r).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut r).unwrap(), b"");
+        assert!(read_frame(&mut r).is_err());
+
+        let huge = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        assert!(read_frame(&mut &huge[..]).is_err());
+    }
 }
@@ -0,0 +1,105 @@
+// Masking PRF used to disguise the database before it is settled.
+//
+// Each server adds a mask to every slot of the database, and the
+// members of a group, who hold that group's keys, strip the masks from
+// their own group's balances. A mask is a uniformly random field
+// element: we take 64 bytes of AES-128-CTR keystream and reduce them
+// modulo the group order, so the statistical distance from uniform is
+// negligible (about 2^-259).
+//
+// The keystream for a group is keyed by the group's PRF key, and the
+// CTR nonce is (group, epoch). The block counter for slot i starts at
+// 4*i, so every (group, epoch, slot) triple gets its own disjoint
+// piece of keystream.
+
+use aes::cipher::{
+    generic_array::GenericArray, KeyIvInit, StreamCipher, StreamCipherSeek,
+};
+use curve25519_dalek::scalar::Scalar;
+
+use crate::FieldElm;
+
+type Aes128Ctr32BE = ctr::Ctr32BE<aes::Aes128>;
+
+// PRF keys are AES-128 keys.
+pub const MASK_KEY_SIZE: usize = 16;
+
+// Bytes of keystream consumed per mask (wide reduction).
+const MASK_BYTES: usize = 64;
+
+pub struct MaskStream {
+    stream: Aes128Ctr32BE,
+}
+
+impl MaskStream {
+    // Start the mask stream for slot 0 of `group` in `epoch`.
+    pub fn new(key: &[u8], group: u32, epoch: u64) -> MaskStream {
+        assert_eq!(key.len(), MASK_KEY_SIZE);
+
+        // IV = group || epoch || block counter
+        let mut iv = [0u8; 16];
+        iv[0..4].copy_from_slice(&group.to_be_bytes());
+        iv[4..12].copy_from_slice(&epoch.to_be_bytes());
+
+        MaskStream {
+            stream: Aes128Ctr32BE::new(GenericArray::from_slice(key), &iv.into()),
+        }
+    }
+
+    // Jump to the mask of the given slot within the group.
+    pub fn seek_slot(&mut self, slot: u32) {
+        self.stream.seek((slot as u64) * (MASK_BYTES as u64));
+    }
+
+    pub fn next_mask(&mut self) -> FieldElm {
+        let mut output = [0u8; MASK_BYTES];
+        self.stream.apply_keystream(&mut output);
+        FieldElm {
+            value: Scalar::from_bytes_mod_order_wide(&output),
+        }
+    }
+}
+
+// The mask for a single slot.
+pub fn slot_mask(key: &[u8], group: u32, epoch: u64, slot: u32) -> FieldElm {
+    let mut stream = MaskStream::new(key, group, epoch);
+    stream.seek_slot(slot);
+    stream.next_mask()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; MASK_KEY_SIZE] = [7u8; MASK_KEY_SIZE];
+
+    #[test]
+    fn stream_matches_slot_mask() {
+        let mut stream = MaskStream::new(&KEY, 3, 11);
+        for slot in 0..10 {
+            assert_eq!(stream.next_mask(), slot_mask(&KEY, 3, 11, slot));
+        }
+
+        stream.seek_slot(4);
+        assert_eq!(stream.next_mask(), slot_mask(&KEY, 3, 11, 4));
+    }
+
+    #[test]
+    fn masks_are_separated() {
+        let m = slot_mask(&KEY, 1, 1, 1);
+        assert!(m != slot_mask(&KEY, 1, 1, 2));
+        assert!(m != slot_mask(&KEY, 2, 1, 1));
+        assert!(m != slot_mask(&KEY, 1, 2, 1));
+        assert!(m != slot_mask(&[8u8; MASK_KEY_SIZE], 1, 1, 1));
+    }
+
+    #[test]
+    fn masks_use_full_width() {
+        // A mask built from 16 bytes would always have its top 16 bytes
+        // clear; with wide reduction some of them should be set.
+        let high_set = (0..16)
+            .map(|slot| slot_mask(&KEY, 0, 0, slot).value.to_bytes())
+            .any(|b| b[16..].iter().any(|x| *x != 0));
+        assert!(high_set);
+    }
+}
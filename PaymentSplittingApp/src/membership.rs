@@ -0,0 +1,672 @@
+// Group leadership and issuance of a group's account slots.
+//
+// When a group is created, S1 hands the leader a block of MAX_GROUP_SIZE
+// AIDs and records the leader's public key for the group. Credentials
+// for AIDs in the block are only issued on a SlotRequest signed with
+// the leader's key (see signature.rs). The signature covers the group
+// number and the credential requests, so it cannot be replayed for
+// other AIDs. The leader can request
+// credentials for unused AIDs at any time after setup, and S1 remembers
+// which AIDs have been issued so that no slot is handed out twice. S2
+// keeps a GroupDirectory of its own, with the leader keys registered
+// with it directly (see creation.rs), and checks every SlotRequest
+// again before it adds its share of the MACs, so S1 alone can't get
+// credentials for slots of a group it doesn't lead.
+//
+// The leader removes a member with a RemovalRequest to both servers,
+// which puts the member's UID on their revocation lists so their group
+// token is no longer accepted. Once the member's balance has been
+// settled the leader can also freeze the slot: transactions whose DPFs
+// touch a frozen slot are rejected (see ServerData::frozen_share). Once every member has been
+// settled the leader can close the whole group with a CloseRequest to
+// both servers, which freezes all of its slots for good; the servers
+// then forget the group's keys (see keys.rs).
+//
+// The servers only learn UIDs in group tokens, not which slot they
+// belong to, so the leader has to show that the UID it names is the
+// slot's. Each credential request carries an ElGamal encryption of the
+// member's UID under a key D = d*B of the leader's. The servers keep
+// that UidCiphertext when they issue the slot, and a RemovalRequest
+// proves that the ciphertext decrypts to the named UID under the same d.
+//
+// Each server also files every group token it registers under the
+// member's group and UID (the group is proven at registration, see
+// ringproof.rs). A payment's ring must hold exactly one registered token
+// of every live member of one group, so the payer can't hide among a
+// handful of tokens of their choosing.
+#![allow(non_snake_case)]
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use zkp::CompactProof;
+use zkp::Transcript;
+
+use crate::ggm::issue_blind124_5::{CredentialRequest, CredentialRequestState};
+use crate::ggm::CMZ_B;
+use crate::ps::GroupToken;
+use crate::signature::{self, Signature, SigningKey};
+use crate::MAX_GROUP_SIZE;
+use crate::{current_epoch, scalar_to_u64, CREDENTIAL_LIFETIME};
+
+define_proof! {
+    uid_opening,
+    "PSA removal UID proof",
+    (d),
+    (D, R, E),
+    (B) :
+    D = (d*B),
+    R = (d*E)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MembershipError {
+    UnknownGroup,
+    // The leader's signature did not verify
+    NotLeader,
+    // The AID is not in the group's block
+    NotInGroup(u64),
+    // A credential request's AID is not a slot number at all
+    BadAid,
+    AlreadyIssued(u64),
+    // The AID has not been issued, so there is no member to remove
+    NotIssued(u64),
+    // The UID is not the one the slot's credential was issued for
+    WrongUid(u64),
+    // A payment's ring is not one token of every live member of a group
+    WrongRing,
+    // The requested expiry epoch is in the past or too far ahead
+    BadExpiry(u64),
+    // The request could not be decoded, or a credential request or its
+    // issuance context did not verify
+    BadRequest,
+}
+
+pub type LeaderKey = SigningKey;
+
+// Each kind of leader request is signed under its own context, so that
+// one can't be passed off as another
+const SLOT_CONTEXT: &[u8] = b"PSA slot request";
+const REMOVAL_CONTEXT: &[u8] = b"PSA removal request";
+const CLOSE_CONTEXT: &[u8] = b"PSA close request";
+
+// A leader's request for credentials on AIDs of their group.
+#[derive(Serialize, Deserialize)]
+pub struct SlotRequest {
+    pub group: u32,
+    pub reqs: Vec<CredentialRequest>,
+    sig: Signature,
+}
+
+fn slot_message(group: u32, reqs: &[CredentialRequest]) -> Vec<u8> {
+    let mut msg = group.to_be_bytes().to_vec();
+    msg.extend(bincode::serialize(reqs).unwrap());
+    msg
+}
+
+impl SlotRequest {
+    pub fn new(leader: &LeaderKey, group: u32, reqs: Vec<CredentialRequest>) -> SlotRequest {
+        let sig = leader.sign(SLOT_CONTEXT, &slot_message(group, &reqs));
+        SlotRequest { group, reqs, sig }
+    }
+
+    fn verify(&self, leader_pk: &CompressedRistretto) -> bool {
+        signature::verify(leader_pk, SLOT_CONTEXT, &slot_message(self.group, &self.reqs), &self.sig)
+    }
+}
+
+// The encryption (E, m*B + e*D) of a member's UID m from the credential
+// request for their slot, and the request's key D.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UidCiphertext {
+    D: RistrettoPoint,
+    Enc: (RistrettoPoint, RistrettoPoint),
+}
+
+impl UidCiphertext {
+    // The UID is the request's first attribute, and the first hidden one
+    pub fn of(req: &CredentialRequest) -> Option<UidCiphertext> {
+        req.EncmB.first().map(|Enc| UidCiphertext { D: req.D, Enc: *Enc })
+    }
+}
+
+// What the leader keeps of each credential request to prove a member's
+// UID later.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UidOpening {
+    d: Scalar,
+    ct: UidCiphertext,
+}
+
+impl UidOpening {
+    pub fn of(state: &CredentialRequestState) -> Option<UidOpening> {
+        state.EncmB.first().map(|Enc| UidOpening { d: state.d, ct: UidCiphertext { D: state.D, Enc: *Enc } })
+    }
+}
+
+// A leader's request to remove the member holding `aid`, and optionally
+// to freeze their slot once it has been settled.
+#[derive(Serialize, Deserialize)]
+pub struct RemovalRequest {
+    pub group: u32,
+    pub aid: u64,
+    pub uid: Scalar,
+    pub freeze: bool,
+    sig: Signature,
+    // That the slot's UidCiphertext decrypts to uid
+    piUid: CompactProof,
+}
+
+fn uid_transcript(group: u32, aid: u64) -> Transcript {
+    let mut transcript = Transcript::new(b"PSA removal UID proof");
+    transcript.append_message(b"group", &group.to_be_bytes());
+    transcript.append_message(b"remove aid", &aid.to_be_bytes());
+    transcript
+}
+
+fn removal_message(group: u32, aid: u64, uid: &Scalar, freeze: bool) -> Vec<u8> {
+    let mut msg = group.to_be_bytes().to_vec();
+    msg.extend(aid.to_be_bytes());
+    msg.extend(uid.as_bytes());
+    msg.push(freeze as u8);
+    msg
+}
+
+impl RemovalRequest {
+    pub fn new(leader: &LeaderKey, group: u32, aid: u64, uid: Scalar, opening: &UidOpening, freeze: bool) -> RemovalRequest {
+        let sig = leader.sign(REMOVAL_CONTEXT, &removal_message(group, aid, &uid, freeze));
+        let B: &RistrettoPoint = &CMZ_B;
+        let piUid = uid_opening::prove_compact(
+            &mut uid_transcript(group, aid),
+            uid_opening::ProveAssignments {
+                B,
+                D: &opening.ct.D,
+                R: &(opening.ct.Enc.1 - uid * B),
+                E: &opening.ct.Enc.0,
+                d: &opening.d,
+            },
+        )
+        .0;
+        RemovalRequest { group, aid, uid, freeze, sig, piUid }
+    }
+
+    fn verify(&self, leader_pk: &CompressedRistretto) -> bool {
+        let msg = removal_message(self.group, self.aid, &self.uid, self.freeze);
+        signature::verify(leader_pk, REMOVAL_CONTEXT, &msg, &self.sig)
+    }
+
+    fn verify_uid(&self, ct: &UidCiphertext) -> bool {
+        let B: &RistrettoPoint = &CMZ_B;
+        uid_opening::verify_compact(
+            &self.piUid,
+            &mut uid_transcript(self.group, self.aid),
+            uid_opening::VerifyAssignments {
+                B: &B.compress(),
+                D: &ct.D.compress(),
+                R: &(ct.Enc.1 - self.uid * B).compress(),
+                E: &ct.Enc.0.compress(),
+            },
+        )
+        .is_ok()
+    }
+}
+
+// A leader's request to close their group.
+#[derive(Serialize, Deserialize)]
+pub struct CloseRequest {
+    pub group: u32,
+    sig: Signature,
+}
+
+impl CloseRequest {
+    pub fn new(leader: &LeaderKey, group: u32) -> CloseRequest {
+        let sig = leader.sign(CLOSE_CONTEXT, &group.to_be_bytes());
+        CloseRequest { group, sig }
+    }
+
+    fn verify(&self, leader_pk: &CompressedRistretto) -> bool {
+        signature::verify(leader_pk, CLOSE_CONTEXT, &self.group.to_be_bytes(), &self.sig)
+    }
+}
+
+// UIDs of removed members, whose group tokens are no longer accepted.
+#[derive(Clone, Debug, Default)]
+pub struct RevocationList {
+    uids: HashSet<[u8; 32]>,
+}
+
+impl RevocationList {
+    pub fn revoke(&mut self, uid: &Scalar) {
+        self.uids.insert(uid.to_bytes());
+    }
+
+    pub fn is_revoked(&self, uid: &Scalar) -> bool {
+        self.uids.contains(uid.as_bytes())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct GroupRecord {
+    leader_pk: CompressedRistretto,
+    first_aid: u64,
+    issued: Vec<bool>,
+    frozen: Vec<bool>,
+    // The UID ciphertext of each issued slot's credential request
+    uids: Vec<Option<UidCiphertext>>,
+    // The P, commitment to the AID and expiry of every token registered
+    // with the group, by UID. A member may have registered more than once.
+    tokens: HashMap<[u8; 32], Vec<(CompressedRistretto, CompressedRistretto, u64)>>,
+}
+
+impl GroupRecord {
+    fn has_token(&self, token: &GroupToken) -> bool {
+        self.tokens
+            .get(token.uid.as_bytes())
+            .is_some_and(|ts| ts.iter().any(|(P, cm_aid, _)| *P == token.P && *cm_aid == token.cm_aid))
+    }
+
+    fn slot_of(&self, aid: u64) -> Result<usize, MembershipError> {
+        aid.checked_sub(self.first_aid)
+            .filter(|s| (*s as usize) < MAX_GROUP_SIZE)
+            .map(|s| s as usize)
+            .ok_or(MembershipError::NotInGroup(aid))
+    }
+}
+
+// A server's record of every group's leader, issued AIDs, removed
+// members and frozen slots.
+#[derive(Clone, Debug, Default)]
+pub struct GroupDirectory {
+    groups: HashMap<u32, GroupRecord>,
+    revoked: RevocationList,
+}
+
+impl GroupDirectory {
+    pub fn new() -> GroupDirectory {
+        GroupDirectory {
+            groups: HashMap::new(),
+            revoked: RevocationList::default(),
+        }
+    }
+
+    pub fn revoked(&self) -> &RevocationList {
+        &self.revoked
+    }
+
+    // Database indices of all frozen slots.
+    pub fn frozen_slots(&self) -> Vec<usize> {
+        let mut slots: Vec<usize> = self
+            .groups
+            .values()
+            .flat_map(|r| {
+                (0..MAX_GROUP_SIZE)
+                    .filter(move |s| r.frozen[*s])
+                    .map(move |s| r.first_aid as usize + s)
+            })
+            .collect();
+        slots.sort_unstable();
+        slots
+    }
+
+    pub fn has_group(&self, group: u32) -> bool {
+        self.groups.contains_key(&group)
+    }
+
+    pub fn create_group(&mut self, group: u32, first_aid: u64, leader_pk: CompressedRistretto) {
+        let record = GroupRecord {
+            leader_pk,
+            first_aid,
+            issued: vec![false; MAX_GROUP_SIZE],
+            frozen: vec![false; MAX_GROUP_SIZE],
+            uids: vec![None; MAX_GROUP_SIZE],
+            tokens: HashMap::new(),
+        };
+        self.groups.insert(group, record);
+    }
+
+    // Check that the request comes from the group's leader and only asks
+    // for unused AIDs of the group, then mark those AIDs as issued.
+    pub fn claim_slots(&mut self, req: &SlotRequest) -> Result<(), MembershipError> {
+        let record = self
+            .groups
+            .get_mut(&req.group)
+            .ok_or(MembershipError::UnknownGroup)?;
+        if !req.verify(&record.leader_pk) {
+            return Err(MembershipError::NotLeader);
+        }
+
+        let mut slots = Vec::with_capacity(req.reqs.len());
+        for cred_req in &req.reqs {
+            let aid = cred_req.revealed(3).as_ref().and_then(scalar_to_u64).ok_or(MembershipError::BadAid)?;
+            let slot = record.slot_of(aid)?;
+            if record.issued[slot] || slots.iter().any(|(s, _)| *s == slot) {
+                return Err(MembershipError::AlreadyIssued(aid));
+            }
+            let expiry = cred_req.revealed(5).as_ref().and_then(scalar_to_u64).unwrap_or(0);
+            let now = current_epoch();
+            if expiry < now || expiry > now + CREDENTIAL_LIFETIME {
+                return Err(MembershipError::BadExpiry(expiry));
+            }
+            let ct = UidCiphertext::of(cred_req).ok_or(MembershipError::BadRequest)?;
+            slots.push((slot, ct));
+        }
+        for (slot, ct) in slots {
+            record.issued[slot] = true;
+            record.uids[slot] = Some(ct);
+        }
+        Ok(())
+    }
+
+    // Revoke a member's UID and, if asked, freeze their slot. Frozen
+    // slots stay marked as issued and are never handed out again.
+    pub fn remove_member(&mut self, req: &RemovalRequest) -> Result<(), MembershipError> {
+        let record = self
+            .groups
+            .get_mut(&req.group)
+            .ok_or(MembershipError::UnknownGroup)?;
+        if !req.verify(&record.leader_pk) {
+            return Err(MembershipError::NotLeader);
+        }
+        let slot = record.slot_of(req.aid)?;
+        let ct = match (record.issued[slot], &record.uids[slot]) {
+            (true, Some(ct)) => ct,
+            _ => return Err(MembershipError::NotIssued(req.aid)),
+        };
+        if !req.verify_uid(ct) {
+            return Err(MembershipError::WrongUid(req.aid));
+        }
+
+        self.revoked.revoke(&req.uid);
+        if req.freeze {
+            record.frozen[slot] = true;
+        }
+        Ok(())
+    }
+
+    // Freeze every slot of the group, issued or not, so that no more
+    // credentials are issued for it and no transaction touches it.
+    pub fn close_group(&mut self, req: &CloseRequest) -> Result<(), MembershipError> {
+        let record = self
+            .groups
+            .get_mut(&req.group)
+            .ok_or(MembershipError::UnknownGroup)?;
+        if !req.verify(&record.leader_pk) {
+            return Err(MembershipError::NotLeader);
+        }
+        record.issued = vec![true; MAX_GROUP_SIZE];
+        record.frozen = vec![true; MAX_GROUP_SIZE];
+        Ok(())
+    }
+
+    // File a newly registered token under the group whose block its AID
+    // was proven to be in (see ringproof.rs).
+    pub fn register_token(&mut self, group: u32, token: &GroupToken) -> Result<(), MembershipError> {
+        let record = self.groups.get_mut(&group).ok_or(MembershipError::UnknownGroup)?;
+        record
+            .tokens
+            .entry(token.uid.to_bytes())
+            .or_insert_with(Vec::new)
+            .push((token.P, token.cm_aid, token.epoch));
+        Ok(())
+    }
+
+    // Check that a payment's ring is one registered token of every live
+    // member of a single group, and nothing else, and return the group.
+    // A member is live while they have an unexpired token and have not
+    // been removed. Taking any ring would let the payer shrink their
+    // anonymity set to a few tokens, or pad it with another group's.
+    pub fn check_ring(&self, tokens: &[GroupToken]) -> Result<u32, MembershipError> {
+        let first = tokens.first().ok_or(MembershipError::WrongRing)?;
+        let (group, record) = self
+            .groups
+            .iter()
+            .find(|(_, r)| r.has_token(first))
+            .ok_or(MembershipError::WrongRing)?;
+
+        let mut uids = HashSet::new();
+        for token in tokens {
+            if !record.has_token(token) || self.revoked.is_revoked(&token.uid) || !uids.insert(token.uid.to_bytes()) {
+                return Err(MembershipError::WrongRing);
+            }
+        }
+        let now = current_epoch();
+        let live = record.tokens.iter().filter(|(uid, ts)| {
+            ts.iter().any(|(_, _, epoch)| *epoch >= now) && !self.revoked.uids.contains(*uid)
+        });
+        for (uid, _) in live {
+            if !uids.contains(uid) {
+                return Err(MembershipError::WrongRing);
+            }
+        }
+        Ok(*group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ggm::issue_blind124_5;
+
+    fn cred_reqs_expiring(aids: &[u64], expiry: u64) -> Vec<CredentialRequest> {
+        let one = Scalar::one();
+        let expiry = Scalar::from(expiry);
+        aids.iter()
+            .map(|aid| {
+                let uid = Scalar::random(&mut rand::thread_rng());
+                issue_blind124_5::request(&uid, &one, &Scalar::from(*aid), &one, &expiry).0
+            })
+            .collect()
+    }
+
+    fn cred_reqs(aids: &[u64]) -> Vec<CredentialRequest> {
+        cred_reqs_expiring(aids, current_epoch() + CREDENTIAL_LIFETIME)
+    }
+
+    // A credential request for a member, with their UID and what the
+    // leader keeps to prove it
+    fn member_req(aid: u64) -> (CredentialRequest, Scalar, UidOpening) {
+        let one = Scalar::one();
+        let uid = Scalar::random(&mut rand::thread_rng());
+        let expiry = Scalar::from(current_epoch());
+        let (req, state) = issue_blind124_5::request(&uid, &one, &Scalar::from(aid), &one, &expiry);
+        (req, uid, UidOpening::of(&state).unwrap())
+    }
+
+    #[test]
+    fn leader_claims_slots() {
+        let leader = LeaderKey::new();
+        let mut dir = GroupDirectory::new();
+        dir.create_group(2, 20, leader.pk.compress());
+
+        let setup = SlotRequest::new(&leader, 2, cred_reqs(&[20, 21, 22]));
+        assert_eq!(dir.claim_slots(&setup), Ok(()));
+
+        // A member added later gets an unused AID
+        let later = SlotRequest::new(&leader, 2, cred_reqs(&[23]));
+        assert_eq!(dir.claim_slots(&later), Ok(()));
+
+        let again = SlotRequest::new(&leader, 2, cred_reqs(&[21]));
+        assert_eq!(dir.claim_slots(&again), Err(MembershipError::AlreadyIssued(21)));
+        let dup = SlotRequest::new(&leader, 2, cred_reqs(&[24, 24]));
+        assert_eq!(dir.claim_slots(&dup), Err(MembershipError::AlreadyIssued(24)));
+    }
+
+    #[test]
+    fn expiry_bounds() {
+        let leader = LeaderKey::new();
+        let mut dir = GroupDirectory::new();
+        dir.create_group(2, 20, leader.pk.compress());
+        let now = current_epoch();
+
+        let past = SlotRequest::new(&leader, 2, cred_reqs_expiring(&[20], now - 1));
+        assert_eq!(dir.claim_slots(&past), Err(MembershipError::BadExpiry(now - 1)));
+        let far = now + CREDENTIAL_LIFETIME + 1;
+        let too_long = SlotRequest::new(&leader, 2, cred_reqs_expiring(&[20], far));
+        assert_eq!(dir.claim_slots(&too_long), Err(MembershipError::BadExpiry(far)));
+
+        let ok = SlotRequest::new(&leader, 2, cred_reqs_expiring(&[20], now));
+        assert_eq!(dir.claim_slots(&ok), Ok(()));
+    }
+
+    #[test]
+    fn outsiders_rejected() {
+        let leader = LeaderKey::new();
+        let outsider = LeaderKey::new();
+        let mut dir = GroupDirectory::new();
+        dir.create_group(2, 20, leader.pk.compress());
+        dir.create_group(3, 30, outsider.pk.compress());
+
+        let req = SlotRequest::new(&outsider, 2, cred_reqs(&[24]));
+        assert_eq!(dir.claim_slots(&req), Err(MembershipError::NotLeader));
+
+        // A leader cannot claim AIDs outside their own block
+        let req = SlotRequest::new(&outsider, 3, cred_reqs(&[24]));
+        assert_eq!(dir.claim_slots(&req), Err(MembershipError::NotInGroup(24)));
+        let req = SlotRequest::new(&leader, 7, cred_reqs(&[24]));
+        assert_eq!(dir.claim_slots(&req), Err(MembershipError::UnknownGroup));
+
+        // AIDs too big for a slot number are refused outright
+        let uid = Scalar::random(&mut rand::thread_rng());
+        let one = Scalar::one();
+        let huge = issue_blind124_5::request(&uid, &one, &-one, &one, &Scalar::from(current_epoch())).0;
+        let req = SlotRequest::new(&leader, 2, vec![huge]);
+        assert_eq!(dir.claim_slots(&req), Err(MembershipError::BadAid));
+
+        // The proof is bound to the requests it was made for
+        let mut req = SlotRequest::new(&leader, 2, cred_reqs(&[25]));
+        req.reqs = cred_reqs(&[26]);
+        assert_eq!(dir.claim_slots(&req), Err(MembershipError::NotLeader));
+    }
+
+    #[test]
+    fn s2_checks_for_itself() {
+        // S1 records a leader key of its own for the group, but S2 has
+        // the one the real leader registered
+        let leader = LeaderKey::new();
+        let rogue = LeaderKey::new();
+        let mut s1_dir = GroupDirectory::new();
+        let mut s2_dir = GroupDirectory::new();
+        s1_dir.create_group(2, 20, rogue.pk.compress());
+        s2_dir.create_group(2, 20, leader.pk.compress());
+        assert!(s2_dir.has_group(2) && !s2_dir.has_group(3));
+
+        let req = SlotRequest::new(&rogue, 2, cred_reqs(&[20]));
+        assert_eq!(s1_dir.claim_slots(&req), Ok(()));
+        assert_eq!(s2_dir.claim_slots(&req), Err(MembershipError::NotLeader));
+    }
+
+    #[test]
+    fn remove_and_freeze() {
+        let leader = LeaderKey::new();
+        let outsider = LeaderKey::new();
+        let mut dir = GroupDirectory::new();
+        dir.create_group(2, 20, leader.pk.compress());
+        let (req20, uid20, opening20) = member_req(20);
+        let (req21, uid, opening) = member_req(21);
+        dir.claim_slots(&SlotRequest::new(&leader, 2, vec![req20, req21])).unwrap();
+
+        let req = RemovalRequest::new(&outsider, 2, 21, uid, &opening, false);
+        assert_eq!(dir.remove_member(&req), Err(MembershipError::NotLeader));
+        let req = RemovalRequest::new(&leader, 2, 22, uid, &opening, false);
+        assert_eq!(dir.remove_member(&req), Err(MembershipError::NotIssued(22)));
+        assert!(!dir.revoked().is_revoked(&uid));
+
+        // The UID has to be the one slot 21 was issued for, so the leader
+        // can't revoke another group's member through their own slot
+        let stranger = Scalar::random(&mut rand::thread_rng());
+        let req = RemovalRequest::new(&leader, 2, 21, stranger, &opening, false);
+        assert_eq!(dir.remove_member(&req), Err(MembershipError::WrongUid(21)));
+        let req = RemovalRequest::new(&leader, 2, 21, uid20, &opening20, false);
+        assert_eq!(dir.remove_member(&req), Err(MembershipError::WrongUid(21)));
+        let mut req = RemovalRequest::new(&leader, 2, 21, uid, &opening, false);
+        req.uid = stranger;
+        assert_eq!(dir.remove_member(&req), Err(MembershipError::NotLeader));
+        assert!(!dir.revoked().is_revoked(&stranger));
+
+        // Removal revokes the member right away...
+        dir.remove_member(&RemovalRequest::new(&leader, 2, 21, uid, &opening, false)).unwrap();
+        assert!(dir.revoked().is_revoked(&uid));
+        assert!(dir.frozen_slots().is_empty());
+
+        // ...and the slot is frozen once it has been settled
+        let mut req = RemovalRequest::new(&leader, 2, 21, uid, &opening, false);
+        req.freeze = true;
+        assert_eq!(dir.remove_member(&req), Err(MembershipError::NotLeader));
+        dir.remove_member(&RemovalRequest::new(&leader, 2, 21, uid, &opening, true)).unwrap();
+        assert_eq!(dir.frozen_slots(), vec![21]);
+    }
+
+    #[test]
+    fn close() {
+        let leader = LeaderKey::new();
+        let mut dir = GroupDirectory::new();
+        dir.create_group(2, 20, leader.pk.compress());
+        dir.create_group(3, 30, LeaderKey::new().pk.compress());
+        dir.claim_slots(&SlotRequest::new(&leader, 2, cred_reqs(&[20]))).unwrap();
+
+        assert_eq!(dir.close_group(&CloseRequest::new(&leader, 3)), Err(MembershipError::NotLeader));
+        assert_eq!(dir.close_group(&CloseRequest::new(&leader, 4)), Err(MembershipError::UnknownGroup));
+        // The leader's signature on another kind of request doesn't do
+        let sig = leader.sign(SLOT_CONTEXT, &2u32.to_be_bytes());
+        assert_eq!(dir.close_group(&CloseRequest { group: 2, sig }), Err(MembershipError::NotLeader));
+        dir.close_group(&CloseRequest::new(&leader, 2)).unwrap();
+        assert_eq!(dir.frozen_slots(), (20..30).collect::<Vec<usize>>());
+
+        // Nothing more is issued for a closed group
+        let req = SlotRequest::new(&leader, 2, cred_reqs(&[21]));
+        assert_eq!(dir.claim_slots(&req), Err(MembershipError::AlreadyIssued(21)));
+    }
+
+    fn token(uid: Scalar, epoch: u64) -> GroupToken {
+        let mut rng = rand::thread_rng();
+        GroupToken::new(
+            RistrettoPoint::random(&mut rng).compress(),
+            uid,
+            RistrettoPoint::random(&mut rng).compress(),
+            epoch,
+        )
+    }
+
+    #[test]
+    fn ring_is_the_group() {
+        let mut dir = GroupDirectory::new();
+        dir.create_group(2, 20, LeaderKey::new().pk.compress());
+        dir.create_group(3, 30, LeaderKey::new().pk.compress());
+        let now = current_epoch();
+        let (ua, ub) = (Scalar::random(&mut rand::thread_rng()), Scalar::random(&mut rand::thread_rng()));
+        let (a, b, c) = (token(ua, now), token(ub, now), token(Scalar::one(), now));
+        dir.register_token(2, &a).unwrap();
+        dir.register_token(2, &b).unwrap();
+        dir.register_token(3, &c).unwrap();
+        assert_eq!(dir.register_token(4, &c), Err(MembershipError::UnknownGroup));
+
+        assert_eq!(dir.check_ring(&[b.clone(), a.clone()]), Ok(2));
+        assert_eq!(dir.check_ring(std::slice::from_ref(&c)), Ok(3));
+
+        // Every live member is in the ring, and nothing else is
+        assert_eq!(dir.check_ring(&[]), Err(MembershipError::WrongRing));
+        assert_eq!(dir.check_ring(std::slice::from_ref(&a)), Err(MembershipError::WrongRing));
+        assert_eq!(dir.check_ring(&[a.clone(), b.clone(), c.clone()]), Err(MembershipError::WrongRing));
+        assert_eq!(dir.check_ring(&[a.clone(), a.clone(), b.clone()]), Err(MembershipError::WrongRing));
+        assert_eq!(dir.check_ring(&[a.clone(), token(ub, now)]), Err(MembershipError::WrongRing));
+
+        // A member who registered again may use either token, but not both
+        let b2 = token(ub, now);
+        dir.register_token(2, &b2).unwrap();
+        assert_eq!(dir.check_ring(&[a.clone(), b2.clone()]), Ok(2));
+        assert_eq!(dir.check_ring(&[a.clone(), b.clone(), b2.clone()]), Err(MembershipError::WrongRing));
+
+        // Members whose tokens have all expired drop out
+        dir.register_token(2, &token(Scalar::one(), now - 1)).unwrap();
+        assert_eq!(dir.check_ring(&[a.clone(), b.clone()]), Ok(2));
+
+        // So do removed members
+        dir.revoked.revoke(&ub);
+        assert_eq!(dir.check_ring(std::slice::from_ref(&a)), Ok(2));
+        assert_eq!(dir.check_ring(&[a, b]), Err(MembershipError::WrongRing));
+    }
+}
@@ -100,37 +100,26 @@ where
             rs: Vec::with_capacity(sketch::TRIPLES_PER_LEVEL),
         };
 
-        //      <r,x><r^2,x> - beta^2 =? 0
-        // =============================================
-        out.xs.push(sketch.r_x.clone()); // z1
-        out.ys.push(sketch.r2_x.clone()); // z2
-        let mut c0 = val2_share.clone();
-        c0.negate();
-        out.zs.push(c0); // - beta^2
-        // =============================================
-
-        // 2) Check MAC values are correct.
-        //    For linear query q, vector x, MAC key k
-        //          (<q, kx> + k^2) - k^2 - k*<q,x> == 0?
+        // Each check is a sum of products x_i * y_i + z_i that is zero
+        // for a well-formed key; one Beaver triple per product.
 
-        //   2a) Check that k^2 - k*k = 0
+        // 1) MAC key: k*k - k^2 = 0
         // =============================================
         let mut mac_key2_neg = mac_key2.clone();
         mac_key2_neg.negate();
-
         out.xs.push(mac_key.clone());
         out.ys.push(mac_key.clone());
         out.zs.push(mac_key2_neg);
         // =============================================
-        //   2b) Check k <r,x> - <r, kx> = 0
+        // 2) MAC values: k <r,x> - <r, kx> = 0
         // =============================================
+        let mut sketch_r_kx_neg = sketch.r_kx.clone();
+        sketch_r_kx_neg.negate();
         out.xs.push(sketch.r_x.clone());
         out.ys.push(mac_key.clone());
-        let mut sketch_r_kx_neg = sketch.r_kx.clone();
-        sketch_r_kx_neg.negate(); 
         out.zs.push(sketch_r_kx_neg);
         // =============================================
-        // check value shares are correct
+        // 3) Value shares: beta*beta - beta^2 = 0
         // =============================================
         let mut val2_share_neg = val2_share.clone();
         val2_share_neg.negate();
@@ -138,32 +127,36 @@ where
         out.ys.push(val_share.clone());
         out.zs.push(val2_share_neg);
         // =============================================
-        // check z1^2 - z^2w
+        // 4) One nonzero entry: <r,x>^2 - beta <r^2,x> = 0 and
+        //    <r,x><r^2,x> - beta <r^3,x> = 0, two products each
         // =============================================
+        let mut val_share_neg = val_share.clone();
+        val_share_neg.negate();
         out.xs.push(sketch.r_x.clone());
         out.ys.push(sketch.r_x.clone());
         out.zs.push(T::zero());
-        let mut val_share_neg = val_share.clone();
-        val_share_neg.negate();
         out.xs.push(sketch.r2_x.clone());
-        out.ys.push(val_share_neg);
+        out.ys.push(val_share_neg.clone());
         out.zs.push(T::zero());
-        // =============================================
-        // check z1z2 - z3w
-        // =============================================
         out.xs.push(sketch.r_x.clone());
         out.ys.push(sketch.r2_x.clone());
         out.zs.push(T::zero());
-        let mut val_share_neg = val_share.clone();
-        val_share_neg.negate();
         out.xs.push(sketch.r3_x.clone());
         out.ys.push(val_share_neg);
         out.zs.push(T::zero());
         // =============================================
-
-        out.rs = vec![sketch.rand1.clone(), 
-                    sketch.rand2.clone(), 
-                    sketch.rand3.clone()];
+        debug_assert_eq!(out.xs.len(), sketch::TRIPLES_PER_LEVEL);
+
+        // The checks are summed with public random coefficients, the
+        // powers of rand1, so that errors in two checks can't cancel.
+        // Both products of a check share its coefficient.
+        let mut coeffs = vec![sketch.rand1.clone()];
+        for _ in 1..5 {
+            let mut c = coeffs.last().unwrap().clone();
+            c.mul(&sketch.rand1);
+            coeffs.push(c);
+        }
+        out.rs = [0, 1, 2, 3, 3, 4, 4].iter().map(|&i| coeffs[i].clone()).collect();
 
         out
     }
@@ -233,7 +226,7 @@ where
             term.add_lazy(&self.triples[i].c);
 
             term.add_lazy(&self.zs[i]);
-            // term.mul_lazy(&self.rs[i]);
+            term.mul_lazy(&self.rs[i]);
             out.add_lazy(&term);
         }
 
@@ -1,8 +1,4 @@
-use core::arch::x86_64::{
-    __m128i, _mm_add_epi64, _mm_loadu_si128, _mm_set_epi64x, _mm_storeu_si128,
-};
-
-use aes::{ Aes128, Block, 
+use aes::{ Aes128, 
     cipher::{ BlockEncrypt, KeyInit, KeyIvInit, StreamCipher,
         generic_array::{GenericArray, typenum::{U8}}
     }
@@ -25,9 +21,93 @@ const AES_KEY_SIZE: usize = 16;
 // AES block size in bytes. Always 16 bytes.
 pub const AES_BLOCK_SIZE: usize = 16;
 
+// The FixedKeyPrgStream counter is a 16-byte block read as two
+// little-endian 64-bit lanes; incrementing it adds one to the high lane,
+// wrapping within that lane. On x86_64 it lives in an SSE register. The
+// portable backend keeps the lanes as u64s and gives the same stream; it
+// is used off x86_64 (ARM phones, wasm) and can be forced with the
+// portable_prg feature.
+#[cfg(all(target_arch = "x86_64", any(test, not(feature = "portable_prg"))))]
+mod sse_counter {
+    use aes::Block;
+    use core::arch::x86_64::{
+        __m128i, _mm_add_epi64, _mm_loadu_si128, _mm_set_epi64x, _mm_storeu_si128,
+    };
+
+    use super::AES_BLOCK_SIZE;
+
+    pub type Counter = __m128i;
+
+    // From RustCrypto aesni crate
+    #[inline(always)]
+    pub fn inc_be(v: Counter) -> Counter {
+        unsafe { _mm_add_epi64(v, _mm_set_epi64x(1, 0)) }
+    }
+
+    #[inline(always)]
+    pub fn store(val: Counter, at: &mut [u8]) {
+        debug_assert_eq!(at.len(), AES_BLOCK_SIZE);
+
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            _mm_storeu_si128(at.as_mut_ptr() as *mut __m128i, val)
+        }
+    }
+
+    // Modified from RustCrypto aesni crate
+    #[inline(always)]
+    pub fn load(key: &[u8; 16]) -> Counter {
+        let val = Block::from_slice(key);
+
+        // Safety: `loadu` supports unaligned loads
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            _mm_loadu_si128(val.as_ptr() as *const __m128i)
+        }
+    }
+}
+
+#[cfg(any(test, not(target_arch = "x86_64"), feature = "portable_prg"))]
+mod portable_counter {
+    use std::convert::TryInto;
+
+    use super::AES_BLOCK_SIZE;
+
+    #[derive(Clone, Copy)]
+    pub struct Counter {
+        lo: u64,
+        hi: u64,
+    }
+
+    #[inline(always)]
+    pub fn inc_be(v: Counter) -> Counter {
+        Counter { lo: v.lo, hi: v.hi.wrapping_add(1) }
+    }
+
+    #[inline(always)]
+    pub fn store(val: Counter, at: &mut [u8]) {
+        debug_assert_eq!(at.len(), AES_BLOCK_SIZE);
+        at[..8].copy_from_slice(&val.lo.to_le_bytes());
+        at[8..].copy_from_slice(&val.hi.to_le_bytes());
+    }
+
+    #[inline(always)]
+    pub fn load(key: &[u8; 16]) -> Counter {
+        Counter {
+            lo: u64::from_le_bytes(key[..8].try_into().unwrap()),
+            hi: u64::from_le_bytes(key[8..].try_into().unwrap()),
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "portable_prg")))]
+use sse_counter as counter;
+#[cfg(any(not(target_arch = "x86_64"), feature = "portable_prg"))]
+use portable_counter as counter;
+
 pub struct FixedKeyPrgStream {
     aes: Aes128,
-    ctr: __m128i,
+    ctr: counter::Counter,
     buf: [u8; AES_BLOCK_SIZE * 8],
     have: usize,
     buf_ptr: usize,
@@ -106,6 +186,19 @@ impl PrgSeed {
 
         out
     }
+    // The first n words of the seed's stream, for a leaf that holds
+    // several outputs. Words are packed T::BYTES apiece, so one AES block
+    // yields several, and a wide leaf is read eight blocks at a time.
+    pub fn convert_words<T: PackedWord>(self: &PrgSeed, n: usize) -> Vec<T> {
+        let mut bytes = vec![0u8; n * T::BYTES];
+        FIXED_KEY_STREAM.with(|s_in| {
+            let mut s = s_in.borrow_mut();
+            s.set_key(&self.key);
+            s.fill_bytes(&mut bytes);
+        });
+        bytes.chunks(T::BYTES).map(T::from_bytes).collect()
+    }
+
     pub fn my_convert<T: FromRng + crate::Group>(self: &PrgSeed, target: &bool) -> ConvertOutput<T> {
         let mut out = ConvertOutput {
             seed: PrgSeed::zero(),
@@ -160,6 +253,15 @@ pub trait FromRng {
     }
 }
 
+// Words no wider than an AES block, read straight from PRG output
+// without rejection sampling, so that adjacent words share a block
+pub trait PackedWord: crate::Group {
+    // Bytes of PRG output per word
+    const BYTES: usize;
+
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
 #[derive(Clone)]
 pub struct PrgStream {
     stream: Aes128Ctr128LE,
@@ -216,7 +318,7 @@ impl FixedKeyPrgStream {
     fn new() -> Self {
         let key = GenericArray::from([0u8; AES_KEY_SIZE]);
 
-        let ctr_init = FixedKeyPrgStream::load(&[0; AES_BLOCK_SIZE]);
+        let ctr_init = counter::load(&[0; AES_BLOCK_SIZE]);
         FixedKeyPrgStream {
             aes: Aes128::new(&key),
             ctr: ctr_init,
@@ -228,7 +330,7 @@ impl FixedKeyPrgStream {
     }
 
     fn set_key(&mut self, key: &[u8; 16]) {
-        self.ctr = FixedKeyPrgStream::load(key);
+        self.ctr = counter::load(key);
         self.buf_ptr = AES_BLOCK_SIZE;
         self.have = AES_BLOCK_SIZE;
     }
@@ -237,7 +339,7 @@ impl FixedKeyPrgStream {
         // Only allow skipping a block on a block boundary.
         debug_assert_eq!(self.have % AES_BLOCK_SIZE, 0);
         debug_assert_eq!(self.buf_ptr, AES_BLOCK_SIZE);
-        self.ctr = FixedKeyPrgStream::inc_be(self.ctr);
+        self.ctr = counter::inc_be(self.ctr);
     }
 
     fn refill(&mut self) {
@@ -248,7 +350,7 @@ impl FixedKeyPrgStream {
         self.buf_ptr = 0;
 
         // Write counter into buffer.
-        FixedKeyPrgStream::store(self.ctr, &mut self.buf[0..AES_BLOCK_SIZE]);
+        counter::store(self.ctr, &mut self.buf[0..AES_BLOCK_SIZE]);
 
         let count_bytes = self.buf;
         let mut gen = GenericArray::from_mut_slice(&mut self.buf[0..AES_BLOCK_SIZE]);
@@ -260,7 +362,7 @@ impl FixedKeyPrgStream {
             .zip(count_bytes.iter())
             .for_each(|(x1, x2)| *x1 ^= *x2);
 
-        self.ctr = FixedKeyPrgStream::inc_be(self.ctr);
+        self.ctr = counter::inc_be(self.ctr);
         self.count += AES_BLOCK_SIZE;
     }
 
@@ -274,9 +376,9 @@ impl FixedKeyPrgStream {
         let mut cnts = [[0u8; AES_BLOCK_SIZE]; 8];
         for i in 0..8 {
             // Write counter into buffer
-            FixedKeyPrgStream::store(self.ctr, &mut block8[i]);
-            FixedKeyPrgStream::store(self.ctr, &mut cnts[i]);
-            self.ctr = FixedKeyPrgStream::inc_be(self.ctr);
+            counter::store(self.ctr, &mut block8[i]);
+            counter::store(self.ctr, &mut cnts[i]);
+            self.ctr = counter::inc_be(self.ctr);
         }
 
         self.aes.encrypt_blocks(&mut block8);
@@ -299,34 +401,6 @@ impl FixedKeyPrgStream {
         //println!("Blocks: {:?}", self.buf[1]);
         //println!("Blocks: {:?}", self.buf[2]);
     }
-
-    // From RustCrypto aesni crate
-    #[inline(always)]
-    pub fn inc_be(v: __m128i) -> __m128i {
-        unsafe { _mm_add_epi64(v, _mm_set_epi64x(1, 0)) }
-    }
-
-    #[inline(always)]
-    pub fn store(val: __m128i, at: &mut [u8]) {
-        debug_assert_eq!(at.len(), AES_BLOCK_SIZE);
-
-        #[allow(clippy::cast_ptr_alignment)]
-        unsafe {
-            _mm_storeu_si128(at.as_mut_ptr() as *mut __m128i, val)
-        }
-    }
-
-    // Modified from RustCrypto aesni crate
-    #[inline(always)]
-    pub fn load(key: &[u8; 16]) -> __m128i {
-        let val = Block::from_slice(key);
-
-        // Safety: `loadu` supports unaligned loads
-        #[allow(clippy::cast_ptr_alignment)]
-        unsafe {
-            _mm_loadu_si128(val.as_ptr() as *const __m128i)
-        }
-    }
 }
 
 impl rand::RngCore for FixedKeyPrgStream {
@@ -364,3 +438,83 @@ impl rand::RngCore for FixedKeyPrgStream {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    // Outputs of the SSE backend; the portable backend must match them.
+    // Run with --features portable_prg to check it.
+    #[test]
+    fn known_answers() {
+        let seed = PrgSeed { key: *b"PSA fixed seed 0" };
+        let out = seed.expand();
+        assert_eq!(out.bits, (true, true));
+        assert_eq!(out.seeds.0.key, hex!("38df03647df40e4065fb2e818a17d1e9"));
+        assert_eq!(out.seeds.1.key, hex!("5682fb4e58f7061efacf36c3da3af6b0"));
+        assert_eq!(seed.expand_dir(false, true).seeds.1.key, out.seeds.1.key);
+
+        // A long read goes through refill8, and the counter's high lane
+        // wraps after the first block
+        let mut buf = [0u8; 160];
+        FIXED_KEY_STREAM.with(|s| {
+            let mut s = s.borrow_mut();
+            s.set_key(&[0xff; 16]);
+            s.fill_bytes(&mut buf);
+        });
+        assert_eq!(
+            buf[..],
+            hex!(
+                "c0a47336157aa5f5058cb82dc17299b107f83c1867a01f0aa50e2cdb25c5109e"
+                "d408ceeb4db508343b7c764c3520638e57ae475f726920af5bb06b8106161526"
+                "8fe2f7a06d87b9e977fbc811ef4c99ee6b45b6b53c5399f15980d43a8d828b5d"
+                "5f592ba9d691c9d4fe1458934c7f969d3e0c848d18f018cff4e03e5d8b02af58"
+                "89e466349fec6f938ff8d30d701235315fc787b1a846f65b945aea2043fede19"
+            )[..]
+        );
+    }
+
+    #[test]
+    fn words_share_blocks() {
+        let seed = PrgSeed { key: *b"PSA fixed seed 0" };
+        let words = seed.convert_words::<std::num::Wrapping<u64>>(20);
+        let mut block = [0u8; 16];
+        FIXED_KEY_STREAM.with(|s| {
+            let mut s = s.borrow_mut();
+            s.set_key(&seed.key);
+            s.fill_bytes(&mut block);
+        });
+        assert_eq!(words[0].0.to_le_bytes(), block[..8]);
+        assert_eq!(words[1].0.to_le_bytes(), block[8..]);
+
+        // A prefix of a leaf is the same however many words are drawn
+        assert_eq!(seed.convert_words::<u64>(3)[..], seed.convert_words::<u64>(20)[..3]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn counter_backends_agree() {
+        let keys = [
+            [0u8; 16],
+            *b"PSA fixed seed 0",
+            // The low lane doesn't carry into the high lane
+            hex!("ffffffffffffffff0000000000000000"),
+            // The high lane wraps
+            hex!("0000000000000000feffffffffffffff"),
+        ];
+        for key in keys.iter() {
+            let mut sse = sse_counter::load(key);
+            let mut portable = portable_counter::load(key);
+            for _ in 0..4 {
+                let mut a = [0u8; 16];
+                let mut b = [0u8; 16];
+                sse_counter::store(sse, &mut a);
+                portable_counter::store(portable, &mut b);
+                assert_eq!(a, b);
+                sse = sse_counter::inc_be(sse);
+                portable = portable_counter::inc_be(portable);
+            }
+        }
+    }
+}
@@ -1,9 +1,5 @@
 #![allow(non_snake_case)]
 
-extern crate crypto; 
-
-use crypto::aes::{self, KeySize};
-use crypto::symmetriccipher::SynchronousStreamCipher;
 use curve25519_dalek::scalar::Scalar;
 use curve25519_dalek::ristretto::RistrettoPoint;
 use curve25519_dalek::constants as dalek_constants;
@@ -12,26 +8,35 @@ use ring::error::Unspecified;
 use serde::Deserialize;
 use serde::Serialize;
 use std::net::TcpStream;
-use std::io::{Read,Write};
+use std::io::Write;
 use std::convert::TryInto; 
-use sha2::Sha256;
-use hmac::{Hmac, Mac};
-use std::iter::repeat;
-use zkp::CompactProof;
-use sha2::Sha512;
+use zkp::BatchableProof;
+use zkp::Transcript;
+use sha2::{Digest, Sha256, Sha512};
 use rand::Rng;
-use crate::sketch::SketchDPFKey;
+use crate::sketch::SketchPointDPFKey;
 
 use crate::ggm::*;
 use crate::dpf::*;
 use crate::sketch::*;
 use crate::mpc::*;
+use crate::mask::{MaskStream, MASK_KEY_SIZE};
+use crate::keys::{GroupMasterSecret, KeyRegistry};
+use crate::membership::{CloseRequest, LeaderKey, MembershipError, RemovalRequest, SlotRequest, UidOpening};
+use crate::ringproof::{BlockProof, RingProof};
+use crate::tokens::{TokenKeyring, TokenTag};
+use crate::threshold::{IssuanceContext, IssuerShare, PartialResponse};
+use crate::transparency::KeyPin;
+use crate::batch;
+use crate::burst::Burst;
 use crate::Group;
 use crate::u32_to_bits;
 use crate::FieldElm;
 use crate::MAX_GROUP_SIZE;
 use crate::MAX_GROUP_NUM;
 use crate::DPF_DOMAIN;
+use crate::{read_frame, write_frame};
+use crate::{current_epoch, scalar_to_u64, CREDENTIAL_LIFETIME, TOKEN_LIFETIME};
 
 lazy_static! {
     pub static ref GEN_G: RistrettoPoint =
@@ -44,52 +49,81 @@ pub struct GroupToken {
 	pub P: CompressedRistretto,
 	pub uid: Scalar,
 	pub cm_aid: CompressedRistretto,
-	pub mac_tag: Vec<u8>, 
+	// Last validity epoch of the token, covered by the MAC tags
+	pub epoch: u64,
+	// S1's and S2's MAC tags, each under that server's own key
+	pub tags: [TokenTag; 2],
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GroupTokenPriv {
-	pub prf_keys: (Vec<u8>, Vec<u8>),
+	pub group_secret: GroupMasterSecret,
 	pub token: GroupToken,
 	pub z3: Scalar,
 	pub aid: Scalar,
+	// The issuer public keys of S1 and S2 and the servers' identities,
+	// pinned when the member joined. The keys also identify the
+	// servers the member's transactions are bound to.
+	pub issuer_key: KeyPin,
 }
 
 impl GroupToken {
 
-	pub fn new(P: CompressedRistretto, uid: Scalar, cm_aid: CompressedRistretto, mac_tag: Vec<u8>) -> GroupToken {
-		GroupToken { P, uid, cm_aid, mac_tag }
+	// An untagged token; each server adds its own tag
+	pub fn new(P: CompressedRistretto, uid: Scalar, cm_aid: CompressedRistretto, epoch: u64) -> GroupToken {
+		GroupToken { P, uid, cm_aid, epoch, tags: Default::default() }
+	}
+
+	// The data a server's MAC tag is computed over: every field but the
+	// tags, the server and the version of its key
+	pub fn mac_input(&self, server: usize, key_version: u32) -> Vec<u8> {
+		let mut macinput: Vec<u8> = Vec::new();
+		macinput.extend_from_slice(self.P.as_bytes());
+		macinput.extend_from_slice(&self.uid.to_bytes());
+		macinput.extend_from_slice(self.cm_aid.as_bytes());
+		macinput.extend_from_slice(&self.epoch.to_be_bytes());
+		macinput.push(server as u8);
+		macinput.extend_from_slice(&key_version.to_be_bytes());
+		macinput
 	}
 }
 
 #[derive(Clone, Debug)]
 pub struct ServerData {
-	issuer: Issuer,
+	// S1's issuer key, and the public part of S2's
+	issuer: IssuerShare,
+	peer: IssuerPubKey,
 }
 
 pub struct GpLeaderData {
 	gp_uids: Vec<Scalar>,
 	gp_size: usize,
+	pub leader: LeaderKey,
+	aids: Vec<u64>,
+	issued: usize,
+	// For each slot, what proves its member's UID to S1 on removal
+	uid_openings: Vec<Option<UidOpening>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TransactionData { 
 	pub tokens: Vec<GroupToken>,
 	pub id: u32,
-	pub dpf_src: SketchDPFKey<FieldElm, FieldElm>,
-	pub dpf_dest: SketchDPFKey<FieldElm, FieldElm>,
+	pub dpf_src: SketchPointDPFKey<FieldElm>,
+	pub dpf_dest: SketchPointDPFKey<FieldElm>,
 	pub g_r1: CompressedRistretto, // r1 is the randomness used to create com_a
 	pub r2: Scalar,           // Share of randomness to calculate commitment to x
 	pub r3: Scalar,           // Share of randomness to calculate commitment to i * x
 	pub com_i: CompressedRistretto, 
-	pub triple_proof: CompactProof,
-	pub token_proof: CompactProof,
+	pub triple_proof: BatchableProof,
+	pub token_proof: RingProof,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TransactionDataS2 { 
+	pub tokens: Vec<GroupToken>,
 	pub id: u32,
-	pub dpf_src: SketchDPFKey<FieldElm, FieldElm>,
-	pub dpf_dest: SketchDPFKey<FieldElm, FieldElm>,
+	pub dpf_src: SketchPointDPFKey<FieldElm>,
+	pub dpf_dest: SketchPointDPFKey<FieldElm>,
 	pub g_r1: CompressedRistretto, // r1 is the randomness used to create com_a
 	pub r2: Scalar,           // Share of randomness to calculate commitment to x
 	pub r3: Scalar,           // Share of randomness to calculate commitment to i * x
@@ -98,8 +132,8 @@ pub struct TransactionDataS2 {
 
 #[derive(Serialize, Deserialize)]
 pub struct SettleData {
-	pub dpf_key: DPFKey<FieldElm, FieldElm>,
-	pub r_seed: Vec<u8>,
+	pub dpf_key: PointDPFKey<FieldElm>,
+	pub epoch: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -111,15 +145,103 @@ pub struct TransactionPackage<'a> {
 	pub g_r3: CompressedRistretto,
     pub cshare_s: CorShare<FieldElm>,
     pub cshare_d: CorShare<FieldElm>,
+	// Shares of the DPF outputs summed over frozen slots
+	pub frozen_s: FieldElm,
+	pub frozen_d: FieldElm,
+	// The server's verdict on the group tokens
+	pub accept: bool,
+}
+
+// A registration: the credential presentation, and a proof that the AID
+// it commits to (Cm3) is in the block of the member's group, so that the
+// servers can file the token under the group without learning the slot
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegistrationRequest {
+	pub showmsg: show_blind34_5::ShowMessage,
+	pub group: u32,
+	pub piBlock: BlockProof,
+}
+
+impl RegistrationRequest {
+	// zs are the commitment openings from showing the credential
+	pub fn new(showmsg: show_blind34_5::ShowMessage, zs: &[Scalar], aid: u64) -> RegistrationRequest {
+		let group = (aid / MAX_GROUP_SIZE as u64) as u32;
+		let Cm3 = showmsg.commitment(3).expect("the AID is committed");
+		let piBlock = BlockProof::prove(&mut Transcript::new(b"PSA registration"), &showmsg.P, &Cm3, group, aid, &zs[3]);
+		RegistrationRequest { showmsg, group, piBlock }
+	}
+}
+
+// What S2 leaves for S1 once it has accepted its part of a registration:
+// the show_digest of the message it checked, and the member's token with
+// S2's tag on it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegistrationAck {
+	pub digest: [u8; 32],
+	pub token: GroupToken,
+}
+
+pub fn show_digest(showmsg: &show_blind34_5::ShowMessage) -> [u8; 32] {
+	Sha256::digest(&bincode::serialize(showmsg).unwrap()).into()
+}
+
+// The untagged token for a verified registration credential: P, the UID
+// (m1), the commitment to the AID (Cm3) and the token's expiry epoch.
+// Expired credentials, and AIDs not in the request's group, are refused.
+// The token lives for TOKEN_LIFETIME epochs, but never past the
+// credential's own expiry (m5).
+fn registration_token(ver_cred: &VerifiedCredential, req: &RegistrationRequest) -> Result<GroupToken, Unspecified> {
+	let m1 = ver_cred.revealed(1).ok_or(Unspecified)?;
+	let Cm3 = ver_cred.commitment(3).ok_or(Unspecified)?;
+	req.piBlock.verify(&mut Transcript::new(b"PSA registration"), &ver_cred.P, &Cm3, req.group).map_err(|_| Unspecified)?;
+	let expiry = ver_cred.revealed(5).as_ref().and_then(scalar_to_u64).ok_or(Unspecified)?;
+	let now = current_epoch();
+	if expiry < now {
+		return Err(Unspecified);
+	}
+	Ok(GroupToken::new(ver_cred.P.compress(), m1, Cm3.compress(), expiry.min(now + TOKEN_LIFETIME)))
+}
+
+// S2 checks its part of the presentation (part 1) and tags the member's
+// token. The acknowledgement goes to S1, which adds its own tag.
+pub fn acknowledge_registration(issuer: &IssuerShare, req: &RegistrationRequest, keyring: &TokenKeyring) -> Result<RegistrationAck, Unspecified> {
+	let ver_cred = issuer.verify_blind34_5(&req.showmsg, 1).map_err(|_| Unspecified)?;
+	acknowledge_verified(req, &ver_cred, keyring)
+}
+
+// As acknowledge_registration, for a presentation whose part 1 S2 has
+// already checked, as in a batch (see burst.rs)
+pub fn acknowledge_verified(req: &RegistrationRequest, ver_cred: &VerifiedCredential, keyring: &TokenKeyring) -> Result<RegistrationAck, Unspecified> {
+	if ver_cred.P != req.showmsg.P {
+		return Err(Unspecified);
+	}
+	let mut token = registration_token(ver_cred, req)?;
+	keyring.issue(&mut token);
+	Ok(RegistrationAck { digest: show_digest(&req.showmsg), token })
+}
+
+// Each server checks its part of concurrent registrations' presentations
+// together (see burst.rs): part 0 at S1, part 1 at S2
+pub fn registration_burst(issuer: &IssuerShare, part: usize) -> Burst<ShowMessage, VerifiedCredential> {
+	let (batch_issuer, issuer) = (issuer.clone(), issuer.clone());
+	Burst::new(
+		move |showmsgs: &[ShowMessage]| show_blind34_5::verify_share_batch(&batch_issuer, showmsgs.to_vec(), part).ok(),
+		move |showmsg: &ShowMessage| issuer.verify_blind34_5(showmsg, part).ok(),
+	)
 }
 
 impl ServerData {
 
-	pub fn new(issuer: Issuer) -> ServerData {
-		return ServerData {issuer};
+	pub fn new(issuer: IssuerShare, peer: IssuerPubKey) -> ServerData {
+		return ServerData {issuer, peer};
+	}
+
+	// The issuer public keys of S1 and S2, in that order
+	pub fn issuer_shares(&self) -> Vec<IssuerPubKey> {
+		vec![self.issuer.pubkey.clone(), self.peer.clone()]
 	}
 
-	pub fn setup_new_group(&mut self, start: &usize) -> (Vec<usize>, IssuerPubKey) {
+	pub fn setup_new_group(&mut self, start: &usize) -> Vec<usize> {
 
 		// 1) Allocates M indices for the group by adding M zeros
 		// to the vector database. The new AIDs for the group are 
@@ -129,66 +251,103 @@ impl ServerData {
 			aids.push(*start + i);
 		}
 
-		// Return set of M indices to user
-		return (aids, (self.issuer.pubkey).clone());
+		// Return set of M indices to user. The issuer key comes from
+		// both servers separately (see transparency.rs)
+		return aids;
 	}
 
-	pub fn setup_reg_tokens(&mut self, reqs: Vec<issue_blind124_5::CredentialRequest>) -> Vec<issue_blind124_5::CredentialResponse> {
+	// Fix the context of each credential and compute S1's partial
+	// response. S2 computes its own for the same contexts. A request
+	// that doesn't verify fails the whole batch.
+	pub fn setup_reg_tokens(&mut self, reqs: &[issue_blind124_5::CredentialRequest]) -> Result<Vec<(IssuanceContext, PartialResponse)>, MembershipError> {
 
-		let mut reg_tokens = Vec::<issue_blind124_5::CredentialResponse>::new();
+		let mut reg_tokens = Vec::<(IssuanceContext, PartialResponse)>::new();
 		for req in reqs {
-			let resp = self.issuer.issue_blind124_5(req);
-			reg_tokens.push(resp.unwrap());
+			let ctx = issue_blind124_5::context(req).map_err(|_| MembershipError::BadRequest)?;
+			let resp = self.issuer.issue_blind124_5(req, &ctx).map_err(|_| MembershipError::BadRequest)?;
+			reg_tokens.push((ctx, resp));
 		}
-		return reg_tokens;
+		return Ok(reg_tokens);
 	}
 
-	pub fn register_user(&mut self, reg_token: show_blind345_5::ShowMessage, mac: &Hmac<Sha256>) -> Result<GroupToken, Unspecified> {
-		let result = self.issuer.verify_blind345_5(reg_token);
-		let (P, ver_cred) = result.unwrap();
-
-		// Server produces a MAC tag on UID (ver_cred.m1) and commitment to AID (ver_cred.m3)
-		let mut my_mac = mac.clone();
-		let mut macinput: Vec<u8> = Vec::new();
-		macinput.extend_from_slice(&ver_cred.m1.to_bytes());
-		macinput.extend_from_slice(&(ver_cred.Cm3).compress().to_bytes());
-		let macinput_bytes: &[u8] = &macinput;
-		my_mac.update(macinput_bytes);
-		let result_bytes = (my_mac.finalize()).into_bytes();
+	// S1 checks its own part of the presentation (part 0). S2 checks
+	// part 1 and leaves its serialized RegistrationAck in peer_ack. S1
+	// adds its tag to S2's token once the token's fields match its own,
+	// allowing for S2 having tagged it an epoch earlier.
+	pub fn register_user(&mut self, req: &RegistrationRequest, peer_ack: &[u8], keyring: &TokenKeyring) -> Result<GroupToken, Unspecified> {
+		let ver_cred = self.issuer.verify_blind34_5(&req.showmsg, 0).map_err(|_| Unspecified)?;
+		self.register_verified(req, &ver_cred, peer_ack, keyring)
+	}
 
-		let group_token = GroupToken::new(P.compress(), ver_cred.m1, ver_cred.Cm3.compress(), result_bytes.to_vec());
+	// As register_user, for a presentation whose part 0 S1 has already
+	// checked, as in a batch (see burst.rs)
+	pub fn register_verified(&mut self, req: &RegistrationRequest, ver_cred: &VerifiedCredential, peer_ack: &[u8], keyring: &TokenKeyring) -> Result<GroupToken, Unspecified> {
+		let ack: RegistrationAck = bincode::deserialize(peer_ack).map_err(|_| Unspecified)?;
+		if req.showmsg.parts() != 2 || ack.digest != show_digest(&req.showmsg) || ver_cred.P != req.showmsg.P {
+			return Err(Unspecified);
+		}
+		let ours = registration_token(ver_cred, req)?;
+		let mut group_token = ack.token;
+		if group_token.P != ours.P || group_token.uid != ours.uid || group_token.cm_aid != ours.cm_aid
+			|| group_token.epoch < current_epoch() || group_token.epoch > ours.epoch {
+			return Err(Unspecified);
+		}
+		keyring.issue(&mut group_token);
 		return Ok(group_token);
 	}
 
+	// This server's share of the sum of a DPF's outputs over the frozen
+	// slots. A DPF is a point function, so the shares of both servers add
+	// up to zero unless the transaction touches a frozen slot.
+	pub fn frozen_share(eval: &Vec<FieldElm>, frozen: &[usize]) -> FieldElm {
+		let mut sum = FieldElm::zero();
+		for i in frozen {
+			sum.add(&eval[*i]);
+		}
+		return sum;
+	}
+
+	pub fn untouched_frozen(share1: &FieldElm, share2: &FieldElm) -> bool {
+		let mut sum = share1.clone();
+		sum.add(share2);
+		return sum == FieldElm::zero();
+	}
+
 	// Only to be called once all verifications have been completed. 
 	// We're taking money from the source and giving it to the dest.
-	pub fn transact(db: &mut Vec<FieldElm>, src_vec: &Vec<FieldElm>, dest_vec: &Vec<FieldElm>) {
-		for i in 0..MAX_GROUP_SIZE * MAX_GROUP_NUM {
-			db[i].add(&src_vec[i]);
-			db[i].sub(&dest_vec[i]);
-		}
+	// Works over any field the DPF payload is in.
+	pub fn transact<T: Group + Send + Sync>(db: &mut Vec<T>, src_vec: &Vec<T>, dest_vec: &Vec<T>) {
+		let n = MAX_GROUP_SIZE * MAX_GROUP_NUM;
+		batch::add_sub_assign(&mut db[..n], &src_vec[..n], &dest_vec[..n]);
 	}
-	pub fn encrypt_db(db: &Vec<FieldElm>, key: &Vec<Vec<u8>>, r_seed: Vec<u8>) -> Vec<FieldElm> {
-    	let zero_bytes = [0u8; 16];
+	pub fn encrypt_db(db: &Vec<FieldElm>, keys: &KeyRegistry, epoch: u64) -> Vec<FieldElm> {
 		// Disguise Database for Settling
 		let mut enc_db = db.clone();
 		for j in 0..MAX_GROUP_NUM {
-			// Reset the nonce for every group
-			let mut prf = aes::ctr(KeySize::KeySize128, &key[j], &r_seed);
+			// Every group gets its own mask stream, keyed by this epoch's
+			// key. Slots of groups that don't exist yet hold zeros and are
+			// masked under the all-zero key.
+			let key = keys.epoch_key(j, epoch).unwrap_or(vec![0u8; MASK_KEY_SIZE]);
+			let mut masks = MaskStream::new(&key, j as u32, epoch);
 			for i in 0..MAX_GROUP_SIZE {
-				let mut output: Vec<u8> = repeat(0u8).take(16).collect();
-				prf.process(&zero_bytes, &mut output[..]);
-				output.extend(zero_bytes.clone());
-				let scalar = Scalar::from_bytes_mod_order(output.try_into().unwrap());
-				enc_db[i + (j * MAX_GROUP_SIZE)].add(&FieldElm {value: scalar});
+				enc_db[i + (j * MAX_GROUP_SIZE)].add(&masks.next_mask());
 			}
 		}
 		return enc_db;
 	}
 
-	pub fn settle(enc_db1: &Vec<FieldElm>, enc_db2: &Vec<FieldElm>, keyb: &DPFKey<FieldElm, FieldElm>) -> Vec<FieldElm> {
+	// Forget a closed group: drop our key and clear our share of its
+	// slots.
+	pub fn close_group(db: &mut Vec<FieldElm>, keys: &mut KeyRegistry, group: usize) {
+		keys.remove(group);
+		for i in group * MAX_GROUP_SIZE..(group + 1) * MAX_GROUP_SIZE {
+			db[i] = FieldElm::zero();
+		}
+	}
+
+	pub fn settle(enc_db1: &Vec<FieldElm>, enc_db2: &Vec<FieldElm>, keyb: &PointDPFKey<FieldElm>) -> Vec<FieldElm> {
 		let mut enc_db = Vec::<FieldElm>::new();
-		let evalb = keyb.eval_all_settle();
+		let evalb = keyb.eval_first(MAX_GROUP_NUM);
 		for i in 0..MAX_GROUP_SIZE * MAX_GROUP_NUM {
 			let mut sum = FieldElm::zero();
 			sum.add(&enc_db1[i]);
@@ -218,52 +377,131 @@ impl GpLeaderData {
 		for i in 0..MAX_GROUP_SIZE {
 			gp_uids.push(Scalar::random(&mut rng));
 		}
-		return GpLeaderData {gp_uids, gp_size};
+		return GpLeaderData {gp_uids, gp_size, leader: LeaderKey::new(), aids: Vec::new(), issued: 0, uid_openings: vec![None; MAX_GROUP_SIZE]};
+	}
+
+	// Create credential requests for (UID, AID, s) tuples. Only the first
+	// gp_size AIDs of the block are issued; the rest stay free for
+	// members added later.
+	pub fn group_setup(&mut self, aids: Vec<u64>, stream: &TcpStream, stream2: &TcpStream, shares: &[IssuerPubKey]) -> Result<Vec<Credential>, std::io::Error> {
+
+		self.aids = aids;
+		let count = self.gp_size.min(self.aids.len());
+		let slots: Vec<(Scalar, u64)> = (0..count)
+			.map(|i| (self.gp_uids[i], self.aids[i]))
+			.collect();
+		let creds = self.request_slots(slots, stream, stream2, shares)?;
+		self.issued = count;
+		Ok(creds)
+	}
+
+	// Obtain a credential for the next unused AID of the group, for a
+	// member joining after setup.
+	pub fn add_member(&mut self, stream: &TcpStream, stream2: &TcpStream, shares: &[IssuerPubKey]) -> Result<Credential, std::io::Error> {
+
+		if self.issued >= self.aids.len() {
+			return Err(std::io::Error::other("group is full"));
+		}
+		let slot = (self.gp_uids[self.issued], self.aids[self.issued]);
+		let cred = self.request_slots(vec![slot], stream, stream2, shares)?
+			.pop()
+			.ok_or(std::io::Error::new(std::io::ErrorKind::InvalidData, "no credential issued"))?;
+		self.issued += 1;
+		Ok(cred)
+	}
+
+	// Remove the member holding the given slot of the group. Pass freeze
+	// once their balance has been settled to close the slot for good.
+	// Both servers check the request against their own directory.
+	pub fn remove_member(&self, slot: usize, freeze: bool, stream: &TcpStream, stream2: &TcpStream) -> Result<(), std::io::Error> {
+
+		let opening = match self.uid_openings.get(slot) {
+			Some(Some(opening)) if slot < self.issued => opening,
+			_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "slot has no member")),
+		};
+		let group = (self.aids[0] as usize / MAX_GROUP_SIZE) as u32;
+		let req = RemovalRequest::new(&self.leader, group, self.aids[slot], self.gp_uids[slot], opening, freeze);
+		let req = bincode::serialize(&req).unwrap();
+		for mut s in [stream, stream2] {
+			s.write_all(&[6u8])?;
+			write_frame(&mut s, &req)?;
+			let buf = read_frame(&mut s)?;
+			let result: Result<(), MembershipError> = bincode::deserialize(&buf)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+			result.map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?}", e)))?;
+		}
+		Ok(())
+	}
+
+	// Close the group for good once every member has been settled. Both
+	// servers check the request against their own directory.
+	pub fn close_group(&self, stream: &TcpStream, stream2: &TcpStream) -> Result<(), std::io::Error> {
+
+		let group = (self.aids[0] as usize / MAX_GROUP_SIZE) as u32;
+		let req = bincode::serialize(&CloseRequest::new(&self.leader, group)).unwrap();
+		for mut s in [stream, stream2] {
+			s.write_all(&[8u8])?;
+			write_frame(&mut s, &req)?;
+			let buf = read_frame(&mut s)?;
+			let result: Result<(), MembershipError> = bincode::deserialize(&buf)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+			result.map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?}", e)))?;
+		}
+		Ok(())
 	}
 
-	// Create credential requests for (UID, AID, s) tuples
-	pub fn group_setup(&mut self, aids: Vec<u64>, mut stream: &TcpStream, pk: IssuerPubKey) -> Result<Vec<Credential>, std::io::Error> {
+	// S1 checks the request and answers with the issuance contexts and its
+	// partial responses. S2 checks the request again against its own
+	// directory and answers for the same contexts.
+	fn request_slots(&mut self, slots: Vec<(Scalar, u64)>, mut stream: &TcpStream, mut stream2: &TcpStream, shares: &[IssuerPubKey]) -> Result<Vec<Credential>, std::io::Error> {
 
-		let mut i = 0;
 		let mut reqs = Vec::<issue_blind124_5::CredentialRequest>::new();
 		let mut req_states = Vec::<issue_blind124_5::CredentialRequestState>::new();
 
 		// Not using these, so they can just be one
 		let m2 = Scalar::one();
 		let m4 = Scalar::one();
-		let m5 = Scalar::one();
+		let m5 = Scalar::from(current_epoch() + CREDENTIAL_LIFETIME);
 
-		for aid in aids {
+		for (m1, aid) in slots {
 
-			let m1 = self.gp_uids[i];
 			let m3 = Scalar::from(aid);
 
 			let (req, state) = issue_blind124_5::request(&m1, &m2, &m3, &m4, &m5);
+			self.uid_openings[(aid - self.aids[0]) as usize] = UidOpening::of(&state);
 			reqs.push(req);
 			req_states.push(state);
-
-			i = (i + 1) % self.gp_size;
 		}
-		let mut encoded: Vec<u8> = Vec::new();
-		encoded.push(2u8);
-		encoded.extend(bincode::serialize(&reqs).unwrap());
-		let _ = stream.write(&encoded);
-		let mut buf = [0;3928];
-		let mut bytes_read = 3928;
-		stream.read_exact(&mut buf)?;
-		let resps: Vec<issue_blind124_5::CredentialResponse> = bincode::deserialize(&buf[0..bytes_read]).unwrap(); 
-		
-		// Once we get the Credential Responses:
-		let mut i = 0;
+		let group = (self.aids[0] as usize / MAX_GROUP_SIZE) as u32;
+		let slot_req = SlotRequest::new(&self.leader, group, reqs);
+		stream.write_all(&[2u8])?;
+		write_frame(&mut stream, &bincode::serialize(&slot_req).unwrap())?;
+		let buf = read_frame(&mut stream)?;
+		let resps: Result<Vec<(IssuanceContext, PartialResponse)>, MembershipError> = bincode::deserialize(&buf)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+		let resps = resps.map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?}", e)))?;
+
+		let ctxs: Vec<&IssuanceContext> = resps.iter().map(|(ctx, _)| ctx).collect();
+		stream2.write_all(&[2u8])?;
+		write_frame(&mut stream2, &bincode::serialize(&(&slot_req, ctxs)).unwrap())?;
+		let buf = read_frame(&mut stream2)?;
+		let resps2: Result<Vec<PartialResponse>, MembershipError> = bincode::deserialize(&buf)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+		let resps2 = resps2.map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{:?}", e)))?;
+
+		// Once we get the Credential Responses. Every slot must come back
+		// with a valid credential, or the whole request fails.
+		if resps.len() != req_states.len() || resps2.len() != req_states.len() {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "wrong number of credential responses"));
+		}
 		let mut creds = Vec::<Credential>::new();
-		for resp in resps {
-			let result = issue_blind124_5::verify(req_states[i], resp, &pk);
-			if result.is_ok() {
-				creds.push(result.unwrap());
-			}
-			i += 1;
+		for ((state, (ctx, resp1)), resp2) in req_states.into_iter().zip(resps).zip(resps2) {
+			let partials = [resp1, resp2];
+			let cred = issue_blind124_5::verify_threshold(state, &ctx, &partials, shares)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("credential failed verification: {:?}", e)))?;
+			creds.push(cred);
 		}
-		return Ok(creds);
+		Ok(creds)
 
 	}
 }	
@@ -271,23 +509,114 @@ impl GpLeaderData {
 
 impl GroupTokenPriv {
 
-	pub fn decrypt_db(mut enc_db: Vec<FieldElm>, key1: Vec<u8>, key2: Vec<u8>, r_seed: Vec<u8>) -> Vec<FieldElm> {
-		let zero_bytes = [0u8; 16];
-    		let mut prf1 = aes::ctr(KeySize::KeySize128, &key1, &r_seed);
-    		let mut prf2 = aes::ctr(KeySize::KeySize128, &key2, &r_seed);
+	pub fn decrypt_db(mut enc_db: Vec<FieldElm>, group_secret: &GroupMasterSecret, group: u32, epoch: u64) -> Vec<FieldElm> {
+		let (key1, key2) = group_secret.epoch_keys(epoch);
+		let mut masks1 = MaskStream::new(&key1, group, epoch);
+		let mut masks2 = MaskStream::new(&key2, group, epoch);
 
 		for i in 0..MAX_GROUP_SIZE {
-			let mut output1: Vec<u8> = repeat(0u8).take(16).collect();
-			let mut output2: Vec<u8> = repeat(0u8).take(16).collect();
-			prf1.process(&zero_bytes, &mut output1[..]);
-			prf2.process(&zero_bytes, &mut output2[..]);
-			output1.extend(&zero_bytes);
-			output2.extend(&zero_bytes);
-			let scalar1 = Scalar::from_bytes_mod_order(output1.try_into().unwrap());
-			let scalar2 = Scalar::from_bytes_mod_order(output2.try_into().unwrap());
-			enc_db[i].sub(&FieldElm {value : scalar1});
-			enc_db[i].sub(&FieldElm {value : scalar2});
+			enc_db[i].sub(&masks1.next_mask());
+			enc_db[i].sub(&masks2.next_mask());
 		}
 		return enc_db;
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::GROUP_TOKEN_SIZE;
+
+	fn credential(server: &mut ServerData, s2: &IssuerShare, expiry: u64) -> Credential {
+		let one = Scalar::one();
+		let uid = Scalar::random(&mut rand::thread_rng());
+		let (req, state) = issue_blind124_5::request(&uid, &one, &Scalar::from(7u64), &one, &Scalar::from(expiry));
+		let (ctx, resp1) = server.setup_reg_tokens(&[req.clone()]).unwrap().pop().unwrap();
+		let resp2 = s2.issue_blind124_5(&req, &ctx).unwrap();
+		issue_blind124_5::verify_threshold(state, &ctx, &[resp1, resp2], &server.issuer_shares()).unwrap()
+	}
+
+	fn registration(server: &ServerData, cred: &Credential) -> RegistrationRequest {
+		let (zs, showmsg) = show_blind34_5::show_threshold(cred, &server.issuer_shares());
+		RegistrationRequest::new(showmsg, &zs, 7)
+	}
+
+	fn register(server: &mut ServerData, s2: &IssuerShare, cred: &Credential, keyrings: &[TokenKeyring; 2]) -> Result<GroupToken, Unspecified> {
+		let req = registration(server, cred);
+		let ack = acknowledge_registration(s2, &req, &keyrings[1])?;
+		server.register_user(&req, &bincode::serialize(&ack).unwrap(), &keyrings[0])
+	}
+
+	#[test]
+	fn registration_expiry() {
+		let s2 = IssuerShare::new(5);
+		let mut server = ServerData::new(IssuerShare::new(5), s2.pubkey.clone());
+		let keyrings = [TokenKeyring::new(0), TokenKeyring::new(1)];
+		let now = current_epoch();
+
+		let cred = credential(&mut server, &s2, now + CREDENTIAL_LIFETIME);
+		let token = register(&mut server, &s2, &cred, &keyrings).unwrap();
+		assert_eq!(token.epoch, now + TOKEN_LIFETIME);
+		assert!(keyrings[0].verify(&token).is_ok());
+		assert!(keyrings[1].verify(&token).is_ok());
+		assert_eq!(bincode::serialize(&token).unwrap().len(), GROUP_TOKEN_SIZE);
+
+		// The token never outlives the credential
+		let cred = credential(&mut server, &s2, now);
+		let token = register(&mut server, &s2, &cred, &keyrings).unwrap();
+		assert_eq!(token.epoch, now);
+
+		let cred = credential(&mut server, &s2, now - 1);
+		assert!(register(&mut server, &s2, &cred, &keyrings).is_err());
+	}
+
+	#[test]
+	fn registration_needs_s2() {
+		let s2 = IssuerShare::new(5);
+		let mut server = ServerData::new(IssuerShare::new(5), s2.pubkey.clone());
+		let (keyring, s2_keyring) = (TokenKeyring::new(0), TokenKeyring::new(1));
+		let cred = credential(&mut server, &s2, current_epoch());
+
+		// Without S2's acknowledgement of this very message, S1 refuses
+		let req = registration(&server, &cred);
+		let other = registration(&server, &cred);
+		let ack = acknowledge_registration(&s2, &other, &s2_keyring).unwrap();
+		assert!(server.register_user(&req, &[], &keyring).is_err());
+		assert!(server.register_user(&req, &bincode::serialize(&ack).unwrap(), &keyring).is_err());
+
+		// Nor does it tag a token S2 didn't make for this member
+		let mut ack = acknowledge_registration(&s2, &req, &s2_keyring).unwrap();
+		ack.token.uid = Scalar::one();
+		assert!(server.register_user(&req, &bincode::serialize(&ack).unwrap(), &keyring).is_err());
+		let mut ack = acknowledge_registration(&s2, &req, &s2_keyring).unwrap();
+		ack.token.epoch += 1;
+		assert!(server.register_user(&req, &bincode::serialize(&ack).unwrap(), &keyring).is_err());
+
+		// S2 checks its own part before acknowledging anything
+		let mut forged = req.clone();
+		forged.showmsg.CQ[1] = forged.showmsg.CQ[0];
+		assert!(acknowledge_registration(&s2, &forged, &s2_keyring).is_err());
+		let ack = acknowledge_registration(&s2, &req, &s2_keyring).unwrap();
+		assert!(server.register_user(&req, &bincode::serialize(&ack).unwrap(), &keyring).is_ok());
+	}
+
+	#[test]
+	fn registration_names_the_group() {
+		let s2 = IssuerShare::new(5);
+		let mut server = ServerData::new(IssuerShare::new(5), s2.pubkey.clone());
+		let keyrings = [TokenKeyring::new(0), TokenKeyring::new(1)];
+		let cred = credential(&mut server, &s2, current_epoch());
+
+		// AID 7 is in group 0's block, so neither server files the
+		// token under another group
+		let mut req = registration(&server, &cred);
+		assert_eq!(req.group, 0);
+		req.group = 1;
+		assert!(acknowledge_registration(&s2, &req, &keyrings[1]).is_err());
+		req.group = 0;
+		let ack = acknowledge_registration(&s2, &req, &keyrings[1]).unwrap();
+		req.group = 1;
+		assert!(server.register_user(&req, &bincode::serialize(&ack).unwrap(), &keyrings[0]).is_err());
+	}
+}
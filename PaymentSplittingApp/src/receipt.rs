@@ -0,0 +1,155 @@
+// Signed receipts for transactions.
+//
+// A client used to learn that a payment went through only from a plain
+// reply, which it can't show to anyone else. Now each server answers a
+// transaction with a TransactionReceipt, signed with the identity key it
+// attests the issuer key with (see transparency.rs). The receipt names
+// the transaction's session id, a digest of the DPF key share the server
+// was sent, whether the server applied the transaction, and its database
+// sequence number afterwards. With both servers' receipts (a
+// ReceiptPair), a client can show a group member or an auditor who has
+// pinned the same server identities that a payment was accepted.
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::signature::{self, Signature, SigningKey};
+use crate::sketch::SketchPointDPFKey;
+use crate::transparency::KeyPin;
+use crate::FieldElm;
+
+const RECEIPT_CONTEXT: &[u8] = b"PSA transaction receipt";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReceiptError {
+    // A receipt's signature did not verify under the pinned identity
+    BadSignature,
+    // A receipt is from the wrong server
+    WrongServer,
+    // The receipts are for different transactions
+    Mismatch,
+    // A server did not apply the transaction
+    Rejected,
+}
+
+// Digest of the DPF key share a server was sent for a transaction
+pub fn share_digest(dpf_src: &SketchPointDPFKey<FieldElm>, dpf_dest: &SketchPointDPFKey<FieldElm>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(bincode::serialize(dpf_src).unwrap());
+    hasher.update(bincode::serialize(dpf_dest).unwrap());
+    hasher.finalize().into()
+}
+
+// One server's receipt. server is 1 for S1 and 2 for S2; sequence counts
+// the transactions the server has applied to its database, including
+// this one if it was accepted.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TransactionReceipt {
+    pub server: u8,
+    pub session_id: u32,
+    pub share_digest: [u8; 32],
+    pub accepted: bool,
+    pub sequence: u64,
+    signature: Signature,
+}
+
+fn receipt_message(server: u8, session_id: u32, share_digest: &[u8; 32], accepted: bool, sequence: u64) -> Vec<u8> {
+    bincode::serialize(&(server, session_id, share_digest, accepted, sequence)).unwrap()
+}
+
+impl TransactionReceipt {
+    pub fn new(server: u8, identity: &SigningKey, session_id: u32, share_digest: [u8; 32], accepted: bool, sequence: u64) -> TransactionReceipt {
+        let msg = receipt_message(server, session_id, &share_digest, accepted, sequence);
+        TransactionReceipt {
+            server,
+            session_id,
+            share_digest,
+            accepted,
+            sequence,
+            signature: identity.sign(RECEIPT_CONTEXT, &msg),
+        }
+    }
+
+    fn verify(&self, pin: &KeyPin) -> bool {
+        let msg = receipt_message(self.server, self.session_id, &self.share_digest, self.accepted, self.sequence);
+        let identity = &pin.identities()[self.server as usize - 1];
+        signature::verify(identity, RECEIPT_CONTEXT, &msg, &self.signature)
+    }
+}
+
+// Both servers' receipts for a transaction.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReceiptPair {
+    pub s1: TransactionReceipt,
+    pub s2: TransactionReceipt,
+}
+
+impl ReceiptPair {
+    // Check that both servers signed a receipt for the same session and
+    // applied the transaction
+    pub fn verify(&self, pin: &KeyPin) -> Result<(), ReceiptError> {
+        if self.s1.server != 1 || self.s2.server != 2 {
+            return Err(ReceiptError::WrongServer);
+        }
+        if !self.s1.verify(pin) || !self.s2.verify(pin) {
+            return Err(ReceiptError::BadSignature);
+        }
+        if self.s1.session_id != self.s2.session_id {
+            return Err(ReceiptError::Mismatch);
+        }
+        if !self.s1.accepted || !self.s2.accepted {
+            return Err(ReceiptError::Rejected);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::threshold::IssuerShare;
+    use crate::transparency::{AttestedIssuerKey, IssuerKeyRecord, KeyAttestation};
+
+    fn pin(ids: &[SigningKey; 2]) -> KeyPin {
+        let shares = [IssuerShare::new(5), IssuerShare::new(5)];
+        let record = IssuerKeyRecord {
+            version: 1,
+            shares: shares.iter().map(|s| s.pubkey.clone()).collect(),
+        };
+        KeyPin::new(&AttestedIssuerKey {
+            s1: KeyAttestation::new(1, &ids[0], record.clone()),
+            s2: KeyAttestation::new(2, &ids[1], record),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn receipts() {
+        let ids = [SigningKey::new(), SigningKey::new()];
+        let pin = pin(&ids);
+        let receipts = ReceiptPair {
+            s1: TransactionReceipt::new(1, &ids[0], 7, [1u8; 32], true, 3),
+            s2: TransactionReceipt::new(2, &ids[1], 7, [2u8; 32], true, 3),
+        };
+        assert_eq!(receipts.verify(&pin), Ok(()));
+
+        // A receipt can't be altered or signed by another key
+        let mut altered = receipts.clone();
+        altered.s1.sequence = 4;
+        assert_eq!(altered.verify(&pin), Err(ReceiptError::BadSignature));
+        let mut forged = receipts.clone();
+        forged.s2 = TransactionReceipt::new(2, &SigningKey::new(), 7, [2u8; 32], true, 3);
+        assert_eq!(forged.verify(&pin), Err(ReceiptError::BadSignature));
+        let swapped = ReceiptPair { s1: receipts.s2.clone(), s2: receipts.s1.clone() };
+        assert_eq!(swapped.verify(&pin), Err(ReceiptError::WrongServer));
+
+        // Both receipts must be for the same, accepted transaction
+        let mut other = receipts.clone();
+        other.s2 = TransactionReceipt::new(2, &ids[1], 8, [2u8; 32], true, 3);
+        assert_eq!(other.verify(&pin), Err(ReceiptError::Mismatch));
+        let mut rejected = receipts;
+        rejected.s1 = TransactionReceipt::new(1, &ids[0], 7, [1u8; 32], false, 2);
+        assert_eq!(rejected.verify(&pin), Err(ReceiptError::Rejected));
+    }
+}
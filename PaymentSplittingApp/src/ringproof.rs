@@ -0,0 +1,367 @@
+// One-out-of-n proof that the payer holds one of the group's tokens.
+//
+// A group token k has a reblinded CMZ point P_k and a commitment to the
+// member's AID T_k = aid*P_k + z*G. The payer also commits to the
+// source index C = a*G + r*H. The statement for branch k is
+//
+//     T_k = i*P_k + rt*G   and   C = i*G + rc*H
+//
+// and the proof shows that it holds for some k, without revealing which
+// (Cramer, Damgard and Schoenmakers, "Proofs of Partial Knowledge and
+// Simplified Design of Witness Hiding Protocols", CRYPTO 1994). Every
+// other branch is simulated with a random challenge; the real branch
+// gets the challenge that makes all of them add up to the Fiat-Shamir
+// challenge.
+//
+// The servers only take a ring that is exactly the live tokens of one
+// group, so they need to know each token's group. A BlockProof, sent
+// with the registration, shows the same way that T opens to one of the
+// AIDs a_k of the group's block, with branch k stating T - a_k*P = z*G.
+// It reveals the group, which the ring of every payment shows anyway,
+// but not the slot.
+#![allow(non_snake_case)]
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use serde::Deserialize;
+use serde::Serialize;
+use zkp::ProofError;
+use zkp::Transcript;
+
+use crate::coms::{GEN_G, GEN_H};
+use crate::MAX_GROUP_SIZE;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RingProof {
+    // Per-branch challenges and responses for i, rt and rc
+    c: Vec<Scalar>,
+    zi: Vec<Scalar>,
+    zt: Vec<Scalar>,
+    zc: Vec<Scalar>,
+}
+
+// What the payer knows about the branch that is really theirs.
+pub struct RingWitness {
+    pub index: usize,
+    pub i: Scalar,
+    pub rt: Scalar,
+    pub rc: Scalar,
+}
+
+fn challenge(
+    transcript: &mut Transcript,
+    ring: &[(RistrettoPoint, RistrettoPoint)],
+    C: &RistrettoPoint,
+    commitments: &[(RistrettoPoint, RistrettoPoint)],
+) -> Scalar {
+    transcript.append_message(b"dom-sep", b"Group Token Ring Proof");
+    transcript.append_message(b"C", C.compress().as_bytes());
+    for ((P, T), (A1, A2)) in ring.iter().zip(commitments) {
+        transcript.append_message(b"P", P.compress().as_bytes());
+        transcript.append_message(b"T", T.compress().as_bytes());
+        transcript.append_message(b"A1", A1.compress().as_bytes());
+        transcript.append_message(b"A2", A2.compress().as_bytes());
+    }
+    let mut buf = [0u8; 64];
+    transcript.challenge_bytes(b"challenge", &mut buf);
+    Scalar::from_bytes_mod_order_wide(&buf)
+}
+
+// The commitments a verifier reconstructs for branch k from its
+// challenge and responses.
+fn branch_commitments(
+    P: &RistrettoPoint,
+    T: &RistrettoPoint,
+    C: &RistrettoPoint,
+    c: &Scalar,
+    zi: &Scalar,
+    zt: &Scalar,
+    zc: &Scalar,
+) -> (RistrettoPoint, RistrettoPoint) {
+    let G: &RistrettoPoint = &GEN_G;
+    let H: &RistrettoPoint = &GEN_H;
+    (zi * P + zt * G - c * T, zi * G + zc * H - c * C)
+}
+
+impl RingProof {
+    pub fn prove(
+        transcript: &mut Transcript,
+        ring: &[(RistrettoPoint, RistrettoPoint)],
+        C: &RistrettoPoint,
+        witness: &RingWitness,
+    ) -> RingProof {
+        assert!(witness.index < ring.len());
+        let G: &RistrettoPoint = &GEN_G;
+        let H: &RistrettoPoint = &GEN_H;
+        let mut rng = rand::thread_rng();
+        let n = ring.len();
+
+        let mut c = vec![Scalar::zero(); n];
+        let mut zi = vec![Scalar::zero(); n];
+        let mut zt = vec![Scalar::zero(); n];
+        let mut zc = vec![Scalar::zero(); n];
+        let mut commitments = Vec::with_capacity(n);
+
+        // Nonces for the real branch
+        let si = Scalar::random(&mut rng);
+        let st = Scalar::random(&mut rng);
+        let sc = Scalar::random(&mut rng);
+        for (k, (P, T)) in ring.iter().enumerate() {
+            if k == witness.index {
+                commitments.push((si * P + st * G, si * G + sc * H));
+                continue;
+            }
+            // Simulate the other branches
+            c[k] = Scalar::random(&mut rng);
+            zi[k] = Scalar::random(&mut rng);
+            zt[k] = Scalar::random(&mut rng);
+            zc[k] = Scalar::random(&mut rng);
+            commitments.push(branch_commitments(P, T, C, &c[k], &zi[k], &zt[k], &zc[k]));
+        }
+
+        let total = challenge(transcript, ring, C, &commitments);
+        let j = witness.index;
+        c[j] = total - c.iter().sum::<Scalar>();
+        zi[j] = si + c[j] * witness.i;
+        zt[j] = st + c[j] * witness.rt;
+        zc[j] = sc + c[j] * witness.rc;
+
+        RingProof { c, zi, zt, zc }
+    }
+
+    pub fn verify(
+        &self,
+        transcript: &mut Transcript,
+        ring: &[(RistrettoPoint, RistrettoPoint)],
+        C: &RistrettoPoint,
+    ) -> Result<(), ProofError> {
+        let n = ring.len();
+        if n == 0 || self.c.len() != n || self.zi.len() != n || self.zt.len() != n || self.zc.len() != n {
+            return Err(ProofError::VerificationFailure);
+        }
+        if ring.iter().any(|(P, _)| P.is_identity()) {
+            return Err(ProofError::VerificationFailure);
+        }
+
+        let commitments: Vec<(RistrettoPoint, RistrettoPoint)> = ring
+            .iter()
+            .enumerate()
+            .map(|(k, (P, T))| branch_commitments(P, T, C, &self.c[k], &self.zi[k], &self.zt[k], &self.zc[k]))
+            .collect();
+        let total = challenge(transcript, ring, C, &commitments);
+        if total == self.c.iter().sum::<Scalar>() {
+            Ok(())
+        } else {
+            Err(ProofError::VerificationFailure)
+        }
+    }
+}
+
+// Decompress the (P, T) pairs of a list of group tokens.
+pub fn decompress_ring(
+    points: &[(CompressedRistretto, CompressedRistretto)],
+) -> Option<Vec<(RistrettoPoint, RistrettoPoint)>> {
+    points
+        .iter()
+        .map(|(P, T)| Some((P.decompress()?, T.decompress()?)))
+        .collect()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockProof {
+    // Per-branch challenges and responses for z
+    c: Vec<Scalar>,
+    z: Vec<Scalar>,
+}
+
+// The points T - a_k*P of every AID a_k in the group's block.
+fn block_branches(P: &RistrettoPoint, T: &RistrettoPoint, group: u32) -> Vec<RistrettoPoint> {
+    let first_aid = group as u64 * MAX_GROUP_SIZE as u64;
+    (0..MAX_GROUP_SIZE as u64)
+        .map(|k| T - Scalar::from(first_aid + k) * P)
+        .collect()
+}
+
+fn block_challenge(
+    transcript: &mut Transcript,
+    P: &RistrettoPoint,
+    T: &RistrettoPoint,
+    group: u32,
+    commitments: &[RistrettoPoint],
+) -> Scalar {
+    transcript.append_message(b"dom-sep", b"Group Block Proof");
+    transcript.append_message(b"P", P.compress().as_bytes());
+    transcript.append_message(b"T", T.compress().as_bytes());
+    transcript.append_message(b"group", &group.to_be_bytes());
+    for A in commitments {
+        transcript.append_message(b"A", A.compress().as_bytes());
+    }
+    let mut buf = [0u8; 64];
+    transcript.challenge_bytes(b"challenge", &mut buf);
+    Scalar::from_bytes_mod_order_wide(&buf)
+}
+
+impl BlockProof {
+    // Prove that T = aid*P + z*G for an aid of the group's block.
+    pub fn prove(
+        transcript: &mut Transcript,
+        P: &RistrettoPoint,
+        T: &RistrettoPoint,
+        group: u32,
+        aid: u64,
+        z: &Scalar,
+    ) -> BlockProof {
+        let index = (aid - group as u64 * MAX_GROUP_SIZE as u64) as usize;
+        assert!(index < MAX_GROUP_SIZE);
+        let G: &RistrettoPoint = &GEN_G;
+        let mut rng = rand::thread_rng();
+
+        let mut c = vec![Scalar::zero(); MAX_GROUP_SIZE];
+        let mut zs = vec![Scalar::zero(); MAX_GROUP_SIZE];
+        let mut commitments = Vec::with_capacity(MAX_GROUP_SIZE);
+        let s = Scalar::random(&mut rng);
+        for (k, X) in block_branches(P, T, group).iter().enumerate() {
+            if k == index {
+                commitments.push(s * G);
+                continue;
+            }
+            c[k] = Scalar::random(&mut rng);
+            zs[k] = Scalar::random(&mut rng);
+            commitments.push(zs[k] * G - c[k] * X);
+        }
+
+        let total = block_challenge(transcript, P, T, group, &commitments);
+        c[index] = total - c.iter().sum::<Scalar>();
+        zs[index] = s + c[index] * z;
+
+        BlockProof { c, z: zs }
+    }
+
+    pub fn verify(
+        &self,
+        transcript: &mut Transcript,
+        P: &RistrettoPoint,
+        T: &RistrettoPoint,
+        group: u32,
+    ) -> Result<(), ProofError> {
+        if self.c.len() != MAX_GROUP_SIZE || self.z.len() != MAX_GROUP_SIZE || P.is_identity() {
+            return Err(ProofError::VerificationFailure);
+        }
+        let G: &RistrettoPoint = &GEN_G;
+        let commitments: Vec<RistrettoPoint> = block_branches(P, T, group)
+            .iter()
+            .enumerate()
+            .map(|(k, X)| self.z[k] * G - self.c[k] * X)
+            .collect();
+        let total = block_challenge(transcript, P, T, group, &commitments);
+        if total == self.c.iter().sum::<Scalar>() {
+            Ok(())
+        } else {
+            Err(ProofError::VerificationFailure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Member {
+        P: RistrettoPoint,
+        T: RistrettoPoint,
+        aid: Scalar,
+        z: Scalar,
+    }
+
+    fn member(aid: u64) -> Member {
+        let G: &RistrettoPoint = &GEN_G;
+        let mut rng = rand::thread_rng();
+        let P = RistrettoPoint::random(&mut rng);
+        let aid = Scalar::from(aid);
+        let z = Scalar::random(&mut rng);
+        Member { P, T: aid * P + z * G, aid, z }
+    }
+
+    fn commit(i: &Scalar) -> (RistrettoPoint, Scalar) {
+        let G: &RistrettoPoint = &GEN_G;
+        let H: &RistrettoPoint = &GEN_H;
+        let r = Scalar::random(&mut rand::thread_rng());
+        (i * G + r * H, r)
+    }
+
+    fn ring_of(members: &[Member]) -> Vec<(RistrettoPoint, RistrettoPoint)> {
+        members.iter().map(|m| (m.P, m.T)).collect()
+    }
+
+    #[test]
+    fn every_member_can_prove() {
+        let members: Vec<Member> = (20..25).map(member).collect();
+        let ring = ring_of(&members);
+        for (index, m) in members.iter().enumerate() {
+            let (C, rc) = commit(&m.aid);
+            let witness = RingWitness { index, i: m.aid, rt: m.z, rc };
+            let proof = RingProof::prove(&mut Transcript::new(b"test"), &ring, &C, &witness);
+            assert!(proof.verify(&mut Transcript::new(b"test"), &ring, &C).is_ok());
+
+            // Bound to the commitment and the transcript
+            let (other_C, _) = commit(&m.aid);
+            assert!(proof.verify(&mut Transcript::new(b"test"), &ring, &other_C).is_err());
+            assert!(proof.verify(&mut Transcript::new(b"other"), &ring, &C).is_err());
+        }
+    }
+
+    #[test]
+    fn non_member_cannot_prove() {
+        let members: Vec<Member> = (20..24).map(member).collect();
+        let ring = ring_of(&members);
+        let outsider = member(40);
+
+        // The outsider knows the opening of their own token, which is
+        // not in the ring, and tries to pass it off as branch 0
+        let (C, rc) = commit(&outsider.aid);
+        let witness = RingWitness { index: 0, i: outsider.aid, rt: outsider.z, rc };
+        let proof = RingProof::prove(&mut Transcript::new(b"test"), &ring, &C, &witness);
+        assert!(proof.verify(&mut Transcript::new(b"test"), &ring, &C).is_err());
+
+        // Committing to a member's AID does not help without their z
+        let (C, rc) = commit(&members[1].aid);
+        let witness = RingWitness { index: 1, i: members[1].aid, rt: outsider.z, rc };
+        let proof = RingProof::prove(&mut Transcript::new(b"test"), &ring, &C, &witness);
+        assert!(proof.verify(&mut Transcript::new(b"test"), &ring, &C).is_err());
+    }
+
+    #[test]
+    fn block_membership() {
+        // AIDs 20..30 are group 2's block
+        let m = member(24);
+        let proof = BlockProof::prove(&mut Transcript::new(b"test"), &m.P, &m.T, 2, 24, &m.z);
+        assert!(proof.verify(&mut Transcript::new(b"test"), &m.P, &m.T, 2).is_ok());
+
+        // The proof doesn't carry over to another group or token
+        assert!(proof.verify(&mut Transcript::new(b"test"), &m.P, &m.T, 3).is_err());
+        let other = member(24);
+        assert!(proof.verify(&mut Transcript::new(b"test"), &other.P, &other.T, 2).is_err());
+
+        // A member of group 4 can't pass for one of group 2
+        let outsider = member(41);
+        let proof = BlockProof::prove(&mut Transcript::new(b"test"), &outsider.P, &outsider.T, 2, 21, &outsider.z);
+        assert!(proof.verify(&mut Transcript::new(b"test"), &outsider.P, &outsider.T, 2).is_err());
+    }
+
+    #[test]
+    fn ring_must_match() {
+        let members: Vec<Member> = (20..23).map(member).collect();
+        let ring = ring_of(&members);
+        let (C, rc) = commit(&members[2].aid);
+        let witness = RingWitness { index: 2, i: members[2].aid, rt: members[2].z, rc };
+        let proof = RingProof::prove(&mut Transcript::new(b"test"), &ring, &C, &witness);
+
+        // Dropping the real branch or swapping in another token fails
+        assert!(proof.verify(&mut Transcript::new(b"test"), &ring[..2], &C).is_err());
+        let mut swapped = ring.clone();
+        swapped[2] = (member(22).P, members[2].T);
+        assert!(proof.verify(&mut Transcript::new(b"test"), &swapped, &C).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+// Schnorr signatures for the servers' long-term identity keys.
+//
+// A signature is a proof of knowledge of the secret key whose
+// transcript binds a context label and the message. Clients keep the
+// servers' identity keys (see transparency.rs) and check what the
+// servers sign with them. The operator's creation passes (creation.rs)
+// and the group leaders' requests (membership.rs) are signed the same
+// way, each under a context of its own.
+#![allow(non_snake_case)]
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::ristretto::RistrettoBasepointTable;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use serde::Deserialize;
+use serde::Serialize;
+use zkp::CompactProof;
+use zkp::Transcript;
+
+use crate::ggm::{CMZ_B, CMZ_B_TABLE};
+
+define_proof! {
+    schnorr,
+    "PSA Schnorr signature",
+    (x),
+    (X),
+    (B) :
+    X = (x*B)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Signature(CompactProof);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SigningKey {
+    sk: Scalar,
+    pub pk: RistrettoPoint,
+}
+
+fn sig_transcript(context: &'static [u8], msg: &[u8]) -> Transcript {
+    let mut transcript = Transcript::new(b"PSA Schnorr signature");
+    transcript.append_message(b"context", context);
+    transcript.append_message(b"message", msg);
+    transcript
+}
+
+impl SigningKey {
+    pub fn new() -> SigningKey {
+        let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+        let sk = Scalar::random(&mut rand::thread_rng());
+        let pk = &sk * Btable;
+        SigningKey { sk, pk }
+    }
+
+    // Sign msg for use in the given context, so that a signature made
+    // for one purpose can't be passed off as one for another.
+    pub fn sign(&self, context: &'static [u8], msg: &[u8]) -> Signature {
+        let B: &RistrettoPoint = &CMZ_B;
+        let proof = schnorr::prove_compact(
+            &mut sig_transcript(context, msg),
+            schnorr::ProveAssignments {
+                B,
+                X: &self.pk,
+                x: &self.sk,
+            },
+        )
+        .0;
+        Signature(proof)
+    }
+}
+
+impl Default for SigningKey {
+    fn default() -> SigningKey {
+        SigningKey::new()
+    }
+}
+
+pub fn verify(pk: &CompressedRistretto, context: &'static [u8], msg: &[u8], sig: &Signature) -> bool {
+    let B: &RistrettoPoint = &CMZ_B;
+    schnorr::verify_compact(
+        &sig.0,
+        &mut sig_transcript(context, msg),
+        schnorr::VerifyAssignments {
+            B: &B.compress(),
+            X: pk,
+        },
+    )
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SigningKey::new();
+        let pk = key.pk.compress();
+        let sig = key.sign(b"test", b"message");
+        assert!(verify(&pk, b"test", b"message", &sig));
+        assert!(!verify(&pk, b"test", b"massage", &sig));
+        assert!(!verify(&pk, b"other", b"message", &sig));
+        assert!(!verify(&SigningKey::new().pk.compress(), b"test", b"message", &sig));
+    }
+}
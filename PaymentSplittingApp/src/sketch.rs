@@ -2,6 +2,9 @@ use crate::dpf;
 use crate::mpc;
 
 use serde::{Deserialize, Serialize};
+use std::num::Wrapping;
+
+use crate::{Group, Share};
 
 pub const TRIPLES_PER_LEVEL: usize = 7;
 
@@ -74,6 +77,56 @@ where
     }
 }
 
+// The sketch of the (x, k.x) pairs of a key's full-domain evaluation
+fn sketch<T: crate::Share>(vector_in: &[(T, T)], rand_stream: &mut impl rand::Rng) -> SketchOutput<T> {
+    let mut out: SketchOutput<T> = SketchOutput::zero();
+
+    out.rand1.from_rng(rand_stream);
+    out.rand2.from_rng(rand_stream);
+    out.rand3.from_rng(rand_stream);
+
+    for v in vector_in {
+        // Get r_i from PRG stream
+        let mut sketch_r = T::zero();
+        sketch_r.from_rng(rand_stream);
+
+        // Compute r_i^2
+        let mut sketch_r2 = sketch_r.clone();
+        sketch_r2.mul_lazy(&sketch_r);
+
+        // Compute r_i^3
+        let mut sketch_r3 = sketch_r2.clone();
+        sketch_r3.mul_lazy(&sketch_r);
+
+        // Compute
+        //          <r, x>
+        //          <r^2, x>
+        //          <r, k.x> 
+
+        let (x, kx) = v;
+
+        let mut tmp0 = x.clone();
+        tmp0.mul_lazy(&sketch_r);
+
+        let mut tmp1 = x.clone();
+        tmp1.mul_lazy(&sketch_r2);
+
+        let mut tmp2 = kx.clone();
+        tmp2.mul_lazy(&sketch_r);
+
+        let mut tmp3 = x.clone();
+        tmp3.mul_lazy(&sketch_r3);
+
+        out.r_x.add_lazy(&tmp0);
+        out.r2_x.add_lazy(&tmp1);
+        out.r_kx.add_lazy(&tmp2);
+        out.r3_x.add_lazy(&tmp3);
+    }
+
+    out.reduce();
+    out
+}
+
 impl<T,U> SketchDPFKey<T,U>
 where
     T: crate::Share + std::fmt::Debug + std::cmp::PartialEq,
@@ -157,52 +210,7 @@ where
         vector_in: &[(T, T)],
         rand_stream: &mut impl rand::Rng,
     ) -> SketchOutput<T> {
-        let mut out: SketchOutput<T> = SketchOutput::zero();
-
-        out.rand1.from_rng(rand_stream);
-        out.rand2.from_rng(rand_stream);
-        out.rand3.from_rng(rand_stream);
-
-        for v in vector_in {
-            // Get r_i from PRG stream
-            let mut sketch_r = T::zero();
-            sketch_r.from_rng(rand_stream);
-
-            // Compute r_i^2
-            let mut sketch_r2 = sketch_r.clone();
-            sketch_r2.mul_lazy(&sketch_r);
-
-            // Compute r_i^3
-            let mut sketch_r3 = sketch_r2.clone();
-            sketch_r3.mul_lazy(&sketch_r);
-
-            // Compute
-            //          <r, x>
-            //          <r^2, x>
-            //          <r, k.x> 
-
-            let (x, kx) = v;
-
-            let mut tmp0 = x.clone();
-            tmp0.mul_lazy(&sketch_r);
-
-            let mut tmp1 = x.clone();
-            tmp1.mul_lazy(&sketch_r2);
-
-            let mut tmp2 = kx.clone();
-            tmp2.mul_lazy(&sketch_r);
-
-            let mut tmp3 = x.clone();
-            tmp3.mul_lazy(&sketch_r3);
-
-            out.r_x.add_lazy(&tmp0);
-            out.r2_x.add_lazy(&tmp1);
-            out.r_kx.add_lazy(&tmp2);
-            out.r3_x.add_lazy(&tmp3);
-        }
-
-        out.reduce();
-        out
+        sketch(vector_in, rand_stream)
     }
 
     pub fn sketch_at_last(
@@ -273,18 +281,219 @@ where
     }
 }
 
+/// Standard DPF with the same protection against additive attacks.
+///
+/// The key encodes the pair (x, a.x) for its value x at the point, in
+/// its one output correction word, and is checked with the same sketch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SketchPointDPFKey<T> {
+    pub mac_key: T,
+    pub mac_key2: T,
+    pub val_share: T,
+    pub val2_share: T,
+    pub key: dpf::PointDPFKey<(T, T)>,
+
+    pub triples: Vec<mpc::TripleShare<T>>,
+}
+
+impl<T> SketchPointDPFKey<T>
+where
+    T: crate::Share + std::fmt::Debug + std::cmp::PartialEq,
+{
+    pub fn gen(alpha_bits: &[bool], value: &T) -> [SketchPointDPFKey<T>; 2] {
+        let mac_key = T::random();
+        let (mac_key_sh0, mac_key_sh1) = mac_key.share();
+
+        let mut mac_key2 = mac_key.clone();
+        mac_key2.mul(&mac_key);
+        let (mac_key2_sh0, mac_key2_sh1) = mac_key2.share();
+
+        let (val_share0, val_share1) = value.share();
+        let mut val2 = value.clone();
+        val2.mul(value);
+        let (val2_share0, val2_share1) = val2.share();
+
+        let mut mac_val = value.clone();
+        mac_val.mul(&mac_key);
+        let (dpf_key0, dpf_key1) = dpf::PointDPFKey::gen(alpha_bits, &(value.clone(), mac_val));
+
+        let mut triples0 = vec![];
+        let mut triples1 = vec![];
+        for _i in 0..TRIPLES_PER_LEVEL {
+            let t: [mpc::TripleShare<T>; 2] = mpc::TripleShare::new();
+            triples0.push(t[0].clone());
+            triples1.push(t[1].clone());
+        }
+
+        [
+            SketchPointDPFKey {
+                mac_key: mac_key_sh0,
+                mac_key2: mac_key2_sh0,
+                val_share: val_share0,
+                val2_share: val2_share0,
+                key: dpf_key0,
+                triples: triples0,
+            },
+            SketchPointDPFKey {
+                mac_key: mac_key_sh1,
+                mac_key2: mac_key2_sh1,
+                val_share: val_share1,
+                val2_share: val2_share1,
+                key: dpf_key1,
+                triples: triples1,
+            },
+        ]
+    }
+
+    pub fn sketch_at(
+        &self,
+        vector_in: &[(T, T)],
+        rand_stream: &mut impl rand::Rng,
+    ) -> SketchOutput<T> {
+        sketch(vector_in, rand_stream)
+    }
+
+    pub fn eval(&self, idx: &[bool]) -> T {
+        self.key.eval(idx).0
+    }
+}
+
+/// Standard DPF over the ring Z_2^64, towards keeping the database in
+/// wrapping u64s.
+///
+/// The servers can't do that yet, and no server path uses this key. The
+/// commitments to a transaction's amount and index
+/// (coms::compute_coms_from_dpf) are over FieldElm, and ring shares don't
+/// convert to shares of a scalar: their sum wraps modulo 2^64 and a sum
+/// of scalars doesn't. Tying the ring value to the commitments needs a
+/// conversion the servers run together, which doesn't exist.
+///
+/// The sketch isn't sound over a ring. Z_2^64 has zero divisors, so an
+/// error of 2^63 in one slot drops out of <r, x> for every even r.
+/// Instead the servers check lifted values in the field modulo 2^127 - 1.
+/// Before the second key's negation, the two keys' words at a leaf are
+/// equal exactly where the ring output is zero. Take each word as an
+/// integer below 2^64: their difference L is below 2^64 in absolute
+/// value, so it is zero in the field exactly where the ring output is,
+/// and L = x mod 2^64 elsewhere. The field sketch of (L, a.L) then
+/// shows the ring vector is a point function too. Each leaf carries the
+/// ring output and the MAC a.L, and the MAC key, the value shares and
+/// the triples are in the field, with L at alpha as the value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RingSketchDPFKey {
+    pub mac_key: u128,
+    pub mac_key2: u128,
+    pub val_share: u128,
+    pub val2_share: u128,
+    pub key: dpf::PointDPFKey<(Wrapping<u64>, u128)>,
+
+    pub triples: Vec<mpc::TripleShare<u128>>,
+}
+
+// A word of the ring as an integer in the field
+fn lift(x: Wrapping<u64>) -> u128 {
+    x.0 as u128
+}
+
+impl RingSketchDPFKey {
+    pub fn gen(alpha_bits: &[bool], value: Wrapping<u64>) -> [RingSketchDPFKey; 2] {
+        // L depends on the ring part of the output correction word, so
+        // the MAC goes into the field part once that is fixed
+        let (mut dpf_key0, mut dpf_key1) = dpf::PointDPFKey::gen(alpha_bits, &(value, 0u128));
+        let mut lifted = lift(dpf_key0.eval(alpha_bits).0);
+        lifted.sub(&lift(-dpf_key1.eval(alpha_bits).0));
+
+        let mac_key = u128::random();
+        let (mac_key_sh0, mac_key_sh1) = mac_key.share();
+
+        let mut mac_key2 = mac_key;
+        mac_key2.mul(&mac_key);
+        let (mac_key2_sh0, mac_key2_sh1) = mac_key2.share();
+
+        let (val_share0, val_share1) = lifted.share();
+        let mut val2 = lifted;
+        val2.mul(&lifted);
+        let (val2_share0, val2_share1) = val2.share();
+
+        // Only the key whose bit is set at alpha adds the correction
+        // word there, and the second key's outputs are negated
+        let mut mac_val = lifted;
+        mac_val.mul(&mac_key);
+        if dpf_key1.leaf_bit(alpha_bits) {
+            mac_val.negate();
+        }
+        dpf_key0.cor_word_last.1.add(&mac_val);
+        dpf_key1.cor_word_last.1 = dpf_key0.cor_word_last.1;
+
+        let mut triples0 = vec![];
+        let mut triples1 = vec![];
+        for _i in 0..TRIPLES_PER_LEVEL {
+            let t: [mpc::TripleShare<u128>; 2] = mpc::TripleShare::new();
+            triples0.push(t[0].clone());
+            triples1.push(t[1].clone());
+        }
+
+        [
+            RingSketchDPFKey {
+                mac_key: mac_key_sh0,
+                mac_key2: mac_key2_sh0,
+                val_share: val_share0,
+                val2_share: val2_share0,
+                key: dpf_key0,
+                triples: triples0,
+            },
+            RingSketchDPFKey {
+                mac_key: mac_key_sh1,
+                mac_key2: mac_key2_sh1,
+                val_share: val_share1,
+                val2_share: val2_share1,
+                key: dpf_key1,
+                triples: triples1,
+            },
+        ]
+    }
+
+    // This server's shares of the (L, a.L) pairs, from its outputs
+    pub fn lifted(&self, vector_in: &[(Wrapping<u64>, u128)]) -> Vec<(u128, u128)> {
+        vector_in
+            .iter()
+            .map(|(x, kx)| {
+                if self.key.key_idx() {
+                    let mut l = lift(-*x);
+                    l.negate();
+                    (l, *kx)
+                } else {
+                    (lift(*x), *kx)
+                }
+            })
+            .collect()
+    }
+
+    pub fn sketch_at(
+        &self,
+        vector_in: &[(Wrapping<u64>, u128)],
+        rand_stream: &mut impl rand::Rng,
+    ) -> SketchOutput<u128> {
+        sketch(&self.lifted(vector_in), rand_stream)
+    }
+
+    pub fn eval(&self, idx: &[bool]) -> Wrapping<u64> {
+        self.key.eval(idx).0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use crate::FieldElm;
-    use crate::Group;
 
     #[test]
     fn sketch_add() {
         let a = SketchOutput {
             r_x: FieldElm::from(3),
             r2_x: FieldElm::from(4),
+            r3_x: FieldElm::from(6),
             r_kx: FieldElm::from(5),
 
             rand1: FieldElm::from(0),
@@ -381,4 +590,121 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn point_key_sketch() {
+        let nbits = 6;
+        let alpha = crate::u32_to_bits(nbits, 21);
+        let value = FieldElm::from(20u32);
+        let keys = SketchPointDPFKey::gen(&alpha, &value);
+
+        let mut mac = FieldElm::zero();
+        mac.add(&keys[0].mac_key);
+        mac.add(&keys[1].mac_key);
+
+        // The servers' sketches add up to the sketch of the point
+        // function, which passes the checks MulState makes on them:
+        //   <r, k.x> = k <r, x>   and   <r, x><r^2, x> = beta <r^3, x>
+        let seed = crate::prg::PrgSeed::random();
+        let mut out = SketchOutput::<FieldElm>::zero();
+        for key in &keys {
+            out.add(&key.sketch_at(&key.key.eval_all(), &mut seed.to_rng()));
+        }
+        let mut k_rx = out.r_x.clone();
+        k_rx.mul(&mac);
+        assert_eq!(out.r_kx, k_rx);
+        let mut lhs = out.r_x.clone();
+        lhs.mul(&out.r2_x);
+        let mut rhs = out.r3_x.clone();
+        rhs.mul(&value);
+        assert_eq!(lhs, rhs);
+        assert_ne!(out.r_x, FieldElm::zero());
+
+        let mut y = keys[0].eval(&alpha);
+        y.add(&keys[1].eval(&alpha));
+        assert_eq!(y, value);
+    }
+
+    // Both servers' MulState on their keys, as the server binaries run it
+    fn servers_accept(keys: &[SketchPointDPFKey<FieldElm>; 2], idx: [bool; 2]) -> bool {
+        let seed = crate::prg::PrgSeed::random();
+        let states: Vec<mpc::MulState<FieldElm>> = keys
+            .iter()
+            .zip(idx.iter())
+            .map(|(key, &i)| {
+                let sketch = key.sketch_at(&key.key.eval_all(), &mut seed.to_rng());
+                mpc::MulState::new(i, key.triples.clone(), &key.mac_key, &key.mac_key2, &key.val_share, &key.val2_share, &sketch)
+            })
+            .collect();
+        let cor = mpc::MulState::cor(&states[0].cor_share(), &states[1].cor_share());
+        mpc::MulState::verify(&states[0].out_share(&cor), &states[1].out_share(&cor))
+    }
+
+    #[test]
+    fn mul_state_checks_point_keys() {
+        let alpha = crate::u32_to_bits(6, 21);
+        let keys = SketchPointDPFKey::gen(&alpha, &FieldElm::from(20u32));
+        assert!(servers_accept(&keys, [false, true]));
+        // The d*e term has to come in from exactly one server
+        assert!(!servers_accept(&keys, [false, false]));
+
+        let mut bad = keys.clone();
+        bad[1].key.cor_word_last.0.add(&FieldElm::one());
+        assert!(!servers_accept(&bad, [false, true]));
+        let mut bad = keys.clone();
+        bad[1].key.cor_word_last.1.add(&FieldElm::one());
+        assert!(!servers_accept(&bad, [false, true]));
+    }
+
+    // The checks MulState makes on the servers' summed sketches, as in
+    // point_key_sketch
+    fn ring_sketch_checks(keys: &[RingSketchDPFKey; 2]) -> bool {
+        let seed = crate::prg::PrgSeed::random();
+        let mut out = SketchOutput::<u128>::zero();
+        let (mut mac, mut value) = (0u128, 0u128);
+        for key in keys {
+            out.add(&key.sketch_at(&key.key.eval_all(), &mut seed.to_rng()));
+            mac.add(&key.mac_key);
+            value.add(&key.val_share);
+        }
+        let mut k_rx = out.r_x;
+        k_rx.mul(&mac);
+        let mut lhs = out.r_x;
+        lhs.mul(&out.r2_x);
+        let mut rhs = out.r3_x;
+        rhs.mul(&value);
+        out.r_kx == k_rx && lhs == rhs && out.r_x != 0
+    }
+
+    #[test]
+    fn ring_key_sketch() {
+        let nbits = 8;
+        let alpha = crate::my_u32_to_bits(nbits + 1, 77)[..nbits as usize].to_vec();
+        for value in [Wrapping(20u64), -Wrapping(20u64)].iter() {
+            let keys = RingSketchDPFKey::gen(&alpha, *value);
+            let (all0, all1) = (keys[0].key.eval_all(), keys[1].key.eval_all());
+            for (i, (y0, y1)) in all0.iter().zip(all1.iter()).enumerate() {
+                let expected = if i == 77 { *value } else { Wrapping(0) };
+                assert_eq!(y0.0 + y1.0, expected);
+            }
+            assert_eq!(keys[0].eval(&alpha) + keys[1].eval(&alpha), *value);
+            assert!(ring_sketch_checks(&keys));
+        }
+    }
+
+    #[test]
+    fn ring_sketch_catches_bad_keys() {
+        let alpha = crate::my_u32_to_bits(9, 77)[..8].to_vec();
+
+        // Garbage under one node of the tree
+        let mut keys = RingSketchDPFKey::gen(&alpha, Wrapping(20));
+        keys[0].key.cor_words[3].seed = crate::prg::PrgSeed::random();
+        assert!(!ring_sketch_checks(&keys));
+
+        // The value off by 2^63 on one key only, which a sketch over the
+        // ring misses for every even coefficient
+        let mut keys = RingSketchDPFKey::gen(&alpha, Wrapping(20));
+        keys[1].key.cor_word_last.0 += Wrapping(1 << 63);
+        assert!(!ring_sketch_checks(&keys));
+    }
 }
@@ -0,0 +1,85 @@
+// Both servers in one process, for tests of the client libraries.
+//
+// Built for this crate's own tests and, with the test-util feature, for
+// the wasm and ffi crates'. Only the server-side steps live here; the
+// tests drive the client side through whichever API they cover.
+use curve25519_dalek::scalar::Scalar;
+
+use crate::client::{member_token, registration_request};
+use crate::ggm::{issue_blind124_5, Credential};
+use crate::keys::GroupMasterSecret;
+use crate::ps::{acknowledge_registration, GroupToken, GroupTokenPriv, RegistrationRequest, ServerData, TransactionData, TransactionDataS2};
+use crate::receipt::{share_digest, TransactionReceipt};
+use crate::signature::SigningKey;
+use crate::threshold::IssuerShare;
+use crate::tokens::TokenKeyring;
+use crate::transparency::{AttestedIssuerKey, IssuerKeyRecord, KeyAttestation, KeyPin};
+use crate::current_epoch;
+
+pub struct TestServers {
+    pub s1: ServerData,
+    pub s2: IssuerShare,
+    pub keyrings: [TokenKeyring; 2],
+    pub identities: [SigningKey; 2],
+    pub attested: AttestedIssuerKey,
+}
+
+impl TestServers {
+    pub fn new() -> TestServers {
+        let s2 = IssuerShare::new(5);
+        let s1 = ServerData::new(IssuerShare::new(5), s2.pubkey.clone());
+        let identities = [SigningKey::new(), SigningKey::new()];
+        let record = IssuerKeyRecord { version: 1, shares: s1.issuer_shares() };
+        let attested = AttestedIssuerKey {
+            s1: KeyAttestation::new(1, &identities[0], record.clone()),
+            s2: KeyAttestation::new(2, &identities[1], record),
+        };
+        TestServers { s1, s2, keyrings: [TokenKeyring::new(0), TokenKeyring::new(1)], identities, attested }
+    }
+
+    // A credential for account aid, issued jointly by both servers, the
+    // way a group leader gets one for a new member
+    pub fn credential(&mut self, aid: u64) -> Credential {
+        let one = Scalar::one();
+        let uid = Scalar::random(&mut rand::thread_rng());
+        let expiry = Scalar::from(current_epoch());
+        let (req, state) = issue_blind124_5::request(&uid, &one, &Scalar::from(aid), &one, &expiry);
+        let (ctx, resp1) = self.s1.setup_reg_tokens(std::slice::from_ref(&req)).unwrap().pop().unwrap();
+        let resp2 = self.s2.issue_blind124_5(&req, &ctx).unwrap();
+        issue_blind124_5::verify_threshold(state, &ctx, &[resp1, resp2], &self.s1.issuer_shares()).unwrap()
+    }
+
+    // Opcode 3 at S2 and then S1
+    pub fn register(&mut self, request: &[u8]) -> GroupToken {
+        let request: RegistrationRequest = bincode::deserialize(request).unwrap();
+        let ack = acknowledge_registration(&self.s2, &request, &self.keyrings[1]).unwrap();
+        self.s1.register_user(&request, &bincode::serialize(&ack).unwrap(), &self.keyrings[0]).unwrap()
+    }
+
+    // A registered member for account aid, with the issuer key pinned
+    pub fn member(&mut self, aid: u64, group_secret: &GroupMasterSecret) -> GroupTokenPriv {
+        let cred = self.credential(aid);
+        let (zs, request) = registration_request(&cred, &self.s1.issuer_shares());
+        let token = self.register(&request);
+        let pin = KeyPin::new(&self.attested).unwrap();
+        member_token(group_secret.clone(), &cred, &zs, token, pin)
+    }
+
+    // Opcode 4: both servers sign a receipt for the key shares they got
+    pub fn receipts(&self, td1: &TransactionData, td2: &TransactionDataS2) -> (Vec<u8>, Vec<u8>) {
+        let receipt = |server, id, digest| {
+            let r = TransactionReceipt::new(server, &self.identities[server as usize - 1], id, digest, true, 1);
+            bincode::serialize(&r).unwrap()
+        };
+        (
+            receipt(1, td1.id, share_digest(&td1.dpf_src, &td1.dpf_dest)),
+            receipt(2, td2.id, share_digest(&td2.dpf_src, &td2.dpf_dest)),
+        )
+    }
+}
+
+impl Default for TestServers {
+    fn default() -> TestServers {
+        TestServers::new()
+    }
+}
@@ -0,0 +1,447 @@
+// Two-party issuance of CMZ credentials (see ggm.rs), so that S1 alone
+// can neither mint credentials nor accept presentations.
+//
+// No issuer key is shared between the servers. Each generates an
+// issuer key of its own, independently of the other (an IssuerShare,
+// named for its part in the protocol, not a secret share of anything),
+// and a credential carries a MAC under each server's key.
+//
+// Issuing: the coordinator (S1) checks the request, picks the random
+// point P = b*B of the MACs and multiplies the ciphertexts of the hidden
+// attributes by b, with a proof that it did (the IssuanceContext). Each
+// server then encrypts its own MAC, working on b*EncmB where the
+// single-issuer protocol uses t_i = x_i*b, and proves that it used its
+// key (a PartialResponse). The user checks every proof and decrypts
+// each MAC, keeping them apart: the credential has one MAC part per
+// server key.
+//
+// Showing: the user commits to the hidden attributes once, and proves
+// each part of the MAC separately against those commitments. Each
+// server checks the part for its own key, with nothing from the other
+// server, and only then vouches for the presentation. A presentation
+// S1 makes up needs a MAC under S2's key to get past S2.
+#![allow(non_snake_case)]
+
+use curve25519_dalek::ristretto::RistrettoBasepointTable;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use serde::Deserialize;
+use serde::Serialize;
+use zkp::toolbox::prover::Prover;
+use zkp::toolbox::verifier::Verifier;
+use zkp::toolbox::SchnorrCS;
+use zkp::CompactProof;
+use zkp::ProofError;
+
+use crate::ggm::{check_show_layout, cmz_transcript, error_factor, verify_request, verify_show_proof, verify_show_proofs_batch};
+use crate::ggm::{Credential, CredentialRequest, CredentialRequestState, IssuerPrivKey, IssuerPubKey};
+use crate::ggm::{ShowMessage, VerifiedCredential, Visibility};
+use crate::ggm::{CMZ_A, CMZ_B, CMZ_B_TABLE};
+
+// One server's issuer key, independent of the other server's. pubkey is
+// the public part of this key only.
+#[derive(Clone, Debug)]
+pub struct IssuerShare {
+    privkey: IssuerPrivKey,
+    pub pubkey: IssuerPubKey,
+}
+
+impl IssuerShare {
+    pub fn new(n: u16) -> IssuerShare {
+        let privkey = IssuerPrivKey::new(n);
+        let pubkey = IssuerPubKey::new(&privkey);
+        IssuerShare { privkey, pubkey }
+    }
+}
+
+fn same_size(shares: &[IssuerPubKey], n: usize) -> bool {
+    !shares.is_empty() && shares.iter().all(|pk| pk.X.len() == shares[0].X.len() && pk.X.len() > n)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IssuanceContext {
+    pub P: RistrettoPoint,
+    // b*EncmB for the hidden attributes, in order
+    bEncmB: Vec<(RistrettoPoint, RistrettoPoint)>,
+    piContext: CompactProof,
+}
+
+// Coordinator side: check the request and fix the random point of the
+// MAC for all servers.
+pub fn issuance_context(
+    label: &'static [u8],
+    layout: &[Visibility],
+    req: &CredentialRequest,
+) -> Result<IssuanceContext, ProofError> {
+    verify_request(label, layout, req, layout.len() + 1)?;
+    let B: &RistrettoPoint = &CMZ_B;
+    let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+
+    let b = Scalar::random(&mut rand::thread_rng());
+    let P = &b * Btable;
+    let bEncmB: Vec<(RistrettoPoint, RistrettoPoint)> = req.EncmB.iter().map(|E| (b * E.0, b * E.1)).collect();
+
+    let mut transcript = cmz_transcript(label, b"context", layout);
+    let mut prover = Prover::new(b"CMZ issuing context proof", &mut transcript);
+    let var_B = prover.allocate_point(b"B", *B).0;
+    let var_b = prover.allocate_scalar(b"b", b);
+    let var_P = prover.allocate_point(b"P", P).0;
+    prover.constrain(var_P, vec![(var_b, var_B)]);
+    for (E, bE) in req.EncmB.iter().zip(&bEncmB) {
+        let var_E0 = prover.allocate_point(b"EncmB0", E.0).0;
+        let var_E1 = prover.allocate_point(b"EncmB1", E.1).0;
+        let var_bE0 = prover.allocate_point(b"bEncmB0", bE.0).0;
+        let var_bE1 = prover.allocate_point(b"bEncmB1", bE.1).0;
+        prover.constrain(var_bE0, vec![(var_b, var_E0)]);
+        prover.constrain(var_bE1, vec![(var_b, var_E1)]);
+    }
+    let piContext = prover.prove_compact();
+
+    Ok(IssuanceContext { P, bEncmB, piContext })
+}
+
+fn verify_context(
+    label: &'static [u8],
+    layout: &[Visibility],
+    EncmB: &[(RistrettoPoint, RistrettoPoint)],
+    ctx: &IssuanceContext,
+) -> Result<(), ProofError> {
+    let B: &RistrettoPoint = &CMZ_B;
+    if ctx.P.is_identity() || ctx.bEncmB.len() != EncmB.len() {
+        return Err(ProofError::VerificationFailure);
+    }
+
+    let mut transcript = cmz_transcript(label, b"context", layout);
+    let mut verifier = Verifier::new(b"CMZ issuing context proof", &mut transcript);
+    let var_B = verifier.allocate_point(b"B", B.compress())?;
+    let var_b = verifier.allocate_scalar(b"b");
+    let var_P = verifier.allocate_point(b"P", ctx.P.compress())?;
+    verifier.constrain(var_P, vec![(var_b, var_B)]);
+    for (E, bE) in EncmB.iter().zip(&ctx.bEncmB) {
+        let var_E0 = verifier.allocate_point(b"EncmB0", E.0.compress())?;
+        let var_E1 = verifier.allocate_point(b"EncmB1", E.1.compress())?;
+        let var_bE0 = verifier.allocate_point(b"bEncmB0", bE.0.compress())?;
+        let var_bE1 = verifier.allocate_point(b"bEncmB1", bE.1.compress())?;
+        verifier.constrain(var_bE0, vec![(var_b, var_E0)]);
+        verifier.constrain(var_bE1, vec![(var_b, var_E1)]);
+    }
+    verifier.verify_compact(&ctx.piContext)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PartialResponse {
+    // This server's MAC, encrypted to the request's key D
+    EncQ: (RistrettoPoint, RistrettoPoint),
+    piPartial: CompactProof,
+}
+
+impl IssuerShare {
+    // Encrypt this server's MAC on the request's attributes, at the
+    // point P fixed by the context.
+    pub fn issue_share(
+        &self,
+        label: &'static [u8],
+        layout: &[Visibility],
+        req: &CredentialRequest,
+        ctx: &IssuanceContext,
+    ) -> Result<PartialResponse, ProofError> {
+        verify_request(label, layout, req, self.privkey.x.len())?;
+        verify_context(label, layout, &req.EncmB, ctx)?;
+        let A: &RistrettoPoint = &CMZ_A;
+        let B: &RistrettoPoint = &CMZ_B;
+        let Btable: &RistrettoBasepointTable = &CMZ_B_TABLE;
+        let x = &self.privkey.x;
+
+        let s = Scalar::random(&mut rand::thread_rng());
+        let mut EncQ = (&s * Btable, s * req.D + x[0] * ctx.P);
+
+        let mut transcript = cmz_transcript(label, b"partialissue", layout);
+        let mut prover = Prover::new(b"CMZ partial issuing proof", &mut transcript);
+        let var_A = prover.allocate_point(b"A", *A).0;
+        let var_B = prover.allocate_point(b"B", *B).0;
+        let var_D = prover.allocate_point(b"D", req.D).0;
+        let var_P = prover.allocate_point(b"P", ctx.P).0;
+        let var_x0 = prover.allocate_scalar(b"x0", x[0]);
+        let var_x0tilde = prover.allocate_scalar(b"x0tilde", self.privkey.x0tilde);
+        let var_s = prover.allocate_scalar(b"s", s);
+        let var_X0 = prover.allocate_point(b"X0", self.pubkey.X[0]).0;
+        prover.constrain(var_X0, vec![(var_x0, var_B), (var_x0tilde, var_A)]);
+
+        let mut EncQ0_terms = vec![(var_s, var_B)];
+        let mut EncQ1_terms = vec![(var_s, var_D), (var_x0, var_P)];
+        let mut hidden = ctx.bEncmB.iter();
+        for (k, m) in req.m.iter().enumerate() {
+            let i = k + 1;
+            let var_xi = prover.allocate_scalar(b"x", x[i]);
+            let var_Xi = prover.allocate_point(b"X", self.pubkey.X[i]).0;
+            prover.constrain(var_Xi, vec![(var_xi, var_A)]);
+            match *m {
+                Some(m) if m == Scalar::zero() => {}
+                Some(m) => {
+                    EncQ.1 += (x[i] * m) * ctx.P;
+                    let var_Pi = prover.allocate_point(b"mP", m * ctx.P).0;
+                    EncQ1_terms.push((var_xi, var_Pi));
+                }
+                None => {
+                    let bE = hidden.next().unwrap();
+                    EncQ.0 += x[i] * bE.0;
+                    EncQ.1 += x[i] * bE.1;
+                    let var_bE0 = prover.allocate_point(b"bEncmB0", bE.0).0;
+                    let var_bE1 = prover.allocate_point(b"bEncmB1", bE.1).0;
+                    EncQ0_terms.push((var_xi, var_bE0));
+                    EncQ1_terms.push((var_xi, var_bE1));
+                }
+            }
+        }
+        let var_EncQ0 = prover.allocate_point(b"EncQ0", EncQ.0).0;
+        let var_EncQ1 = prover.allocate_point(b"EncQ1", EncQ.1).0;
+        prover.constrain(var_EncQ0, EncQ0_terms);
+        prover.constrain(var_EncQ1, EncQ1_terms);
+        let piPartial = prover.prove_compact();
+
+        Ok(PartialResponse { EncQ, piPartial })
+    }
+
+    // Check the part of a presentation for this server's key.
+    pub fn verify_show(
+        &self,
+        label: &'static [u8],
+        layout: &[Visibility],
+        showmsg: &ShowMessage,
+        part: usize,
+    ) -> Result<VerifiedCredential, ProofError> {
+        if !check_show_layout(layout, showmsg, self.privkey.x.len()) || part >= showmsg.parts() {
+            return Err(ProofError::VerificationFailure);
+        }
+        let Vprime = error_factor(&self.privkey, showmsg, part);
+        verify_show_proof(label, layout, showmsg, part, &self.pubkey, Vprime)?;
+        Ok(showmsg.clone().into())
+    }
+
+    // Check this server's part of a batch of presentations at once. The
+    // batch passes or fails as a whole.
+    pub fn verify_show_batch(
+        &self,
+        label: &'static [u8],
+        layout: &[Visibility],
+        showmsgs: Vec<ShowMessage>,
+        part: usize,
+    ) -> Result<Vec<VerifiedCredential>, ProofError> {
+        if !showmsgs.iter().all(|s| check_show_layout(layout, s, self.privkey.x.len()) && part < s.parts()) {
+            return Err(ProofError::VerificationFailure);
+        }
+        let Vprimes: Vec<RistrettoPoint> = showmsgs.iter().map(|s| error_factor(&self.privkey, s, part)).collect();
+        verify_show_proofs_batch(label, layout, &showmsgs, part, &self.pubkey, &Vprimes)?;
+        Ok(showmsgs.into_iter().map(VerifiedCredential::from).collect())
+    }
+}
+
+fn verify_partial(
+    label: &'static [u8],
+    layout: &[Visibility],
+    state: &CredentialRequestState,
+    ctx: &IssuanceContext,
+    partial: &PartialResponse,
+    pubkey: &IssuerPubKey,
+) -> Result<(), ProofError> {
+    let A: &RistrettoPoint = &CMZ_A;
+    let B: &RistrettoPoint = &CMZ_B;
+
+    let mut transcript = cmz_transcript(label, b"partialissue", layout);
+    let mut verifier = Verifier::new(b"CMZ partial issuing proof", &mut transcript);
+    let var_A = verifier.allocate_point(b"A", A.compress())?;
+    let var_B = verifier.allocate_point(b"B", B.compress())?;
+    let var_D = verifier.allocate_point(b"D", state.D.compress())?;
+    let var_P = verifier.allocate_point(b"P", ctx.P.compress())?;
+    let var_x0 = verifier.allocate_scalar(b"x0");
+    let var_x0tilde = verifier.allocate_scalar(b"x0tilde");
+    let var_s = verifier.allocate_scalar(b"s");
+    let var_X0 = verifier.allocate_point(b"X0", pubkey.X[0].compress())?;
+    verifier.constrain(var_X0, vec![(var_x0, var_B), (var_x0tilde, var_A)]);
+
+    let mut EncQ0_terms = vec![(var_s, var_B)];
+    let mut EncQ1_terms = vec![(var_s, var_D), (var_x0, var_P)];
+    let mut hidden = ctx.bEncmB.iter();
+    for i in 1..=layout.len() {
+        let var_xi = verifier.allocate_scalar(b"x");
+        let var_Xi = verifier.allocate_point(b"X", pubkey.X[i].compress())?;
+        verifier.constrain(var_Xi, vec![(var_xi, var_A)]);
+        match layout[i - 1] {
+            Visibility::Revealed if state.m[i] == Scalar::zero() => {}
+            Visibility::Revealed => {
+                let var_Pi = verifier.allocate_point(b"mP", (state.m[i] * ctx.P).compress())?;
+                EncQ1_terms.push((var_xi, var_Pi));
+            }
+            Visibility::Hidden => {
+                let bE = hidden.next().ok_or(ProofError::VerificationFailure)?;
+                let var_bE0 = verifier.allocate_point(b"bEncmB0", bE.0.compress())?;
+                let var_bE1 = verifier.allocate_point(b"bEncmB1", bE.1.compress())?;
+                EncQ0_terms.push((var_xi, var_bE0));
+                EncQ1_terms.push((var_xi, var_bE1));
+            }
+        }
+    }
+    let var_EncQ0 = verifier.allocate_point(b"EncQ0", partial.EncQ.0.compress())?;
+    let var_EncQ1 = verifier.allocate_point(b"EncQ1", partial.EncQ.1.compress())?;
+    verifier.constrain(var_EncQ0, EncQ0_terms);
+    verifier.constrain(var_EncQ1, EncQ1_terms);
+    verifier.verify_compact(&partial.piPartial)
+}
+
+// User side: check the context and every server's partial response, and
+// decrypt each server's MAC. shares[j] is the public key of the server
+// that sent partials[j].
+pub fn verify_threshold_issue(
+    label: &'static [u8],
+    layout: &[Visibility],
+    state: CredentialRequestState,
+    ctx: &IssuanceContext,
+    partials: &[PartialResponse],
+    shares: &[IssuerPubKey],
+) -> Result<Credential, ProofError> {
+    if partials.len() != shares.len() || !same_size(shares, layout.len()) {
+        return Err(ProofError::VerificationFailure);
+    }
+    verify_context(label, layout, &state.EncmB, ctx)?;
+
+    let mut Q = Vec::with_capacity(partials.len());
+    for (partial, pubkey) in partials.iter().zip(shares) {
+        verify_partial(label, layout, &state, ctx, partial, pubkey)?;
+        // Decrypt EncQ
+        Q.push(partial.EncQ.1 - (state.d * partial.EncQ.0));
+    }
+
+    Ok(Credential {
+        P: ctx.P,
+        Q,
+        m: state.m,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ggm::{issue_blind124_5, show_blind34_5, ShownAttr};
+
+    fn request(aid: u64) -> (CredentialRequest, CredentialRequestState) {
+        let one = Scalar::one();
+        let uid = Scalar::random(&mut rand::thread_rng());
+        issue_blind124_5::request(&uid, &one, &Scalar::from(aid), &one, &Scalar::from(100u64))
+    }
+
+    fn credential(s1: &IssuerShare, s2: &IssuerShare, aid: u64) -> Credential {
+        let (req, state) = request(aid);
+        let ctx = issue_blind124_5::context(&req).unwrap();
+        let partials = vec![s1.issue_blind124_5(&req, &ctx).unwrap(), s2.issue_blind124_5(&req, &ctx).unwrap()];
+        issue_blind124_5::verify_threshold(state, &ctx, &partials, &[s1.pubkey.clone(), s2.pubkey.clone()]).unwrap()
+    }
+
+    #[test]
+    fn two_party_credential() {
+        let s1 = IssuerShare::new(5);
+        let s2 = IssuerShare::new(5);
+        let shares = vec![s1.pubkey.clone(), s2.pubkey.clone()];
+
+        let cred = credential(&s1, &s2, 23);
+        assert_eq!(cred.m[3], Scalar::from(23u64));
+
+        let (_, showmsg) = show_blind34_5::show_threshold(&cred, &shares);
+        assert_eq!(showmsg.parts(), 2);
+        assert!(s1.verify_blind34_5(&showmsg, 0).is_ok());
+        let verified = s2.verify_blind34_5(&showmsg, 1).unwrap();
+        assert_eq!(verified.revealed(1), Some(cred.m[1]));
+        assert_eq!(verified.revealed(5), Some(Scalar::from(100u64)));
+    }
+
+    #[test]
+    fn batch_show() {
+        let s1 = IssuerShare::new(5);
+        let s2 = IssuerShare::new(5);
+        let shares = vec![s1.pubkey.clone(), s2.pubkey.clone()];
+        let showings = |n: u64| -> Vec<ShowMessage> {
+            (0..n).map(|aid| show_blind34_5::show_threshold(&credential(&s1, &s2, aid), &shares).1).collect()
+        };
+
+        let batch = showings(3);
+        assert_eq!(show_blind34_5::verify_share_batch(&s1, batch.clone(), 0).unwrap().len(), 3);
+        assert_eq!(show_blind34_5::verify_share_batch(&s2, batch.clone(), 1).unwrap().len(), 3);
+        assert!(show_blind34_5::verify_share_batch(&s2, batch.clone(), 0).is_err());
+        assert!(show_blind34_5::verify_share_batch(&s1, batch, 2).is_err());
+
+        // An altered presentation spoils the batch; checking them one
+        // at a time finds it
+        let mut batch = showings(3);
+        batch[1].attrs[0] = ShownAttr::Revealed(Scalar::random(&mut rand::thread_rng()));
+        assert!(show_blind34_5::verify_share_batch(&s2, batch.clone(), 1).is_err());
+        assert!(s2.verify_blind34_5(&batch[0], 1).is_ok());
+        assert!(s2.verify_blind34_5(&batch[1], 1).is_err());
+    }
+
+    #[test]
+    fn neither_server_alone() {
+        let s1 = IssuerShare::new(5);
+        let s2 = IssuerShare::new(5);
+
+        // A credential from S1's key alone has no part for S2 to check
+        let (req, state) = request(23);
+        let ctx = issue_blind124_5::context(&req).unwrap();
+        let partial = s1.issue_blind124_5(&req, &ctx).unwrap();
+        let cred = issue_blind124_5::verify_threshold(state, &ctx, &[partial], std::slice::from_ref(&s1.pubkey)).unwrap();
+        let (_, showmsg) = show_blind34_5::show_threshold(&cred, std::slice::from_ref(&s1.pubkey));
+        assert!(s1.verify_blind34_5(&showmsg, 0).is_ok());
+        assert!(s2.verify_blind34_5(&showmsg, 1).is_err());
+        assert!(s2.verify_blind34_5(&showmsg, 0).is_err());
+    }
+
+    #[test]
+    fn forged_show() {
+        let s1 = IssuerShare::new(5);
+        let s2 = IssuerShare::new(5);
+        let shares = vec![s1.pubkey.clone(), s2.pubkey.clone()];
+
+        // S1 can MAC anything under its own key, and pass that off as
+        // both parts, but S2 refuses the presentation
+        let (req, state) = request(23);
+        let ctx = issue_blind124_5::context(&req).unwrap();
+        let partial = s1.issue_blind124_5(&req, &ctx).unwrap();
+        let own = vec![s1.pubkey.clone(), s1.pubkey.clone()];
+        let forged = issue_blind124_5::verify_threshold(state, &ctx, &[partial.clone(), partial], &own).unwrap();
+        let (_, showmsg) = show_blind34_5::show_threshold(&forged, &own);
+        assert!(s1.verify_blind34_5(&showmsg, 0).is_ok());
+        assert!(s2.verify_blind34_5(&showmsg, 1).is_err());
+
+        // Nor can S1 change what a real presentation reveals
+        let cred = credential(&s1, &s2, 23);
+        let (_, mut showmsg) = show_blind34_5::show_threshold(&cred, &shares);
+        showmsg.attrs[0] = ShownAttr::Revealed(Scalar::random(&mut rand::thread_rng()));
+        assert!(s2.verify_blind34_5(&showmsg, 1).is_err());
+    }
+
+    #[test]
+    fn mismatched_shares() {
+        let s1 = IssuerShare::new(5);
+        let s2 = IssuerShare::new(5);
+        let shares = vec![s1.pubkey.clone(), s2.pubkey.clone()];
+
+        // A partial response for another context is rejected
+        let (req, state) = request(23);
+        let ctx = issue_blind124_5::context(&req).unwrap();
+        let other_ctx = issue_blind124_5::context(&req).unwrap();
+        let partials = vec![s1.issue_blind124_5(&req, &ctx).unwrap(), s2.issue_blind124_5(&req, &other_ctx).unwrap()];
+        assert!(issue_blind124_5::verify_threshold(state, &ctx, &partials, &shares).is_err());
+
+        // So is a partial response under a key that isn't the claimed one
+        let (req, state) = request(23);
+        let ctx = issue_blind124_5::context(&req).unwrap();
+        let partials = vec![s1.issue_blind124_5(&req, &ctx).unwrap(), s1.issue_blind124_5(&req, &ctx).unwrap()];
+        assert!(issue_blind124_5::verify_threshold(state, &ctx, &partials, &shares).is_err());
+
+        // And each part of a presentation only checks under its own key
+        let cred = credential(&s1, &s2, 23);
+        let (_, showmsg) = show_blind34_5::show_threshold(&cred, &shares);
+        assert!(s1.verify_blind34_5(&showmsg, 1).is_err());
+        assert!(s2.verify_blind34_5(&showmsg, 0).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+// Issuing and validating group tokens.
+//
+// A member's GroupToken carries one MAC tag from each server, each under
+// that server's own key. A tag covers every other field of the token
+// (the reblinded point P, the UID, the commitment to the AID and the
+// expiry epoch) as well as the tagging server and its key version, so a
+// token with any field altered is rejected. At registration S2 tags the
+// token first and hands its tag to S1 with its acknowledgement; S1 then
+// adds its own. Each server only checks its own tag on transactions, so
+// neither server can mint a token the other accepts.
+//
+// MAC keys are versioned. Each epoch a server rolls its keyring over:
+// new tokens use a fresh key while tokens under older versions stay
+// valid until their version is retired. Tokens live for at most
+// TOKEN_LIFETIME epochs, so a version is retired that long after it
+// was rotated out.
+#![allow(non_snake_case)]
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+
+use crate::current_epoch;
+use crate::ps::GroupToken;
+use crate::TOKEN_LIFETIME;
+
+pub const TOKEN_KEY_SIZE: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenError {
+    // The token was tagged under a key version this keyring doesn't have
+    UnknownKeyVersion(u32),
+    // Forged or altered token
+    BadTag,
+    // The token's last validity epoch has passed
+    Expired(u64),
+}
+
+// One server's MAC tag on a token
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenTag {
+    pub key_version: u32,
+    pub tag: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenKeyring {
+    // Index of the server holding the keyring, which is also the index
+    // of its tag in a token (0 for S1, 1 for S2)
+    server: usize,
+    current: u32,
+    keys: BTreeMap<u32, [u8; TOKEN_KEY_SIZE]>,
+    // Epoch each old version was rotated out in
+    rotated_out: BTreeMap<u32, u64>,
+}
+
+impl TokenKeyring {
+    pub fn new(server: usize) -> TokenKeyring {
+        assert!(server < 2);
+        let mut keys = BTreeMap::new();
+        keys.insert(0, rand::thread_rng().gen::<[u8; TOKEN_KEY_SIZE]>());
+        TokenKeyring { server, current: 0, keys, rotated_out: BTreeMap::new() }
+    }
+
+    pub fn current_version(&self) -> u32 {
+        self.current
+    }
+
+    // Start tagging new tokens under a fresh key and return its version.
+    pub fn rotate(&mut self) -> u32 {
+        self.rotated_out.insert(self.current, current_epoch());
+        self.current += 1;
+        self.keys.insert(self.current, rand::thread_rng().gen::<[u8; TOKEN_KEY_SIZE]>());
+        self.current
+    }
+
+    // Stop accepting tokens tagged under an old key version. The current
+    // version can't be retired.
+    pub fn retire(&mut self, version: u32) {
+        if version != self.current {
+            self.keys.remove(&version);
+            self.rotated_out.remove(&version);
+        }
+    }
+
+    // Called by the servers once an epoch: rotate to a fresh key and
+    // retire every version no unexpired token can still be tagged under.
+    pub fn rollover(&mut self, now: u64) -> u32 {
+        let stale: Vec<u32> = self.rotated_out.iter()
+            .filter(|(_, &epoch)| epoch + TOKEN_LIFETIME < now)
+            .map(|(&version, _)| version)
+            .collect();
+        for version in stale {
+            self.retire(version);
+        }
+        let version = self.rotate();
+        self.rotated_out.insert(version - 1, now);
+        version
+    }
+
+    fn mac(&self, version: u32) -> Result<Hmac<Sha256>, TokenError> {
+        let key = self.keys.get(&version).ok_or(TokenError::UnknownKeyVersion(version))?;
+        Ok(Hmac::<Sha256>::new_varkey(key).expect("HMAC can take key of any size"))
+    }
+
+    // This server's tag on a token, under the current key. The token's
+    // own tags are ignored.
+    pub fn tag(&self, token: &GroupToken) -> TokenTag {
+        let mut mac = self.mac(self.current).unwrap();
+        mac.update(&token.mac_input(self.server, self.current));
+        TokenTag { key_version: self.current, tag: mac.finalize().into_bytes().to_vec() }
+    }
+
+    // Add this server's tag to a token.
+    pub fn issue(&self, token: &mut GroupToken) {
+        token.tags[self.server] = self.tag(token);
+    }
+
+    // Check this server's tag on a token, and the token's expiry. The
+    // other server's tag is left to the other server.
+    pub fn verify(&self, token: &GroupToken) -> Result<(), TokenError> {
+        let tag = &token.tags[self.server];
+        let mut mac = self.mac(tag.key_version)?;
+        mac.update(&token.mac_input(self.server, tag.key_version));
+        mac.verify(&tag.tag).map_err(|_| TokenError::BadTag)?;
+        if token.epoch < current_epoch() {
+            return Err(TokenError::Expired(token.epoch));
+        }
+        Ok(())
+    }
+}
+
+// Check every token of a transaction.
+pub fn validate_tokens(tokens: &[GroupToken], keyring: &TokenKeyring) -> Result<(), TokenError> {
+    tokens.iter().try_for_each(|token| keyring.verify(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use curve25519_dalek::ristretto::RistrettoPoint;
+    use curve25519_dalek::scalar::Scalar;
+
+    fn untagged(epoch: u64) -> GroupToken {
+        let mut rng = rand::thread_rng();
+        let P = RistrettoPoint::random(&mut rng).compress();
+        let cm_aid = RistrettoPoint::random(&mut rng).compress();
+        GroupToken::new(P, Scalar::random(&mut rng), cm_aid, epoch)
+    }
+
+    // A token tagged by both servers, S2 first as at registration
+    fn token(s1: &TokenKeyring, s2: &TokenKeyring) -> GroupToken {
+        let mut token = untagged(current_epoch() + TOKEN_LIFETIME);
+        s2.issue(&mut token);
+        s1.issue(&mut token);
+        token
+    }
+
+    #[test]
+    fn altered_fields() {
+        let (s1, s2) = (TokenKeyring::new(0), TokenKeyring::new(1));
+        let good = token(&s1, &s2);
+        assert_eq!(s1.verify(&good), Ok(()));
+        assert_eq!(s2.verify(&good), Ok(()));
+
+        let other = token(&s1, &s2);
+        let mut t = good.clone();
+        t.P = other.P;
+        assert_eq!(s1.verify(&t), Err(TokenError::BadTag));
+        assert_eq!(s2.verify(&t), Err(TokenError::BadTag));
+        let mut t = good.clone();
+        t.uid = other.uid;
+        assert_eq!(s1.verify(&t), Err(TokenError::BadTag));
+        let mut t = good.clone();
+        t.cm_aid = other.cm_aid;
+        assert_eq!(s2.verify(&t), Err(TokenError::BadTag));
+        let mut t = good.clone();
+        t.epoch += 1;
+        assert_eq!(s1.verify(&t), Err(TokenError::BadTag));
+        let mut t = good.clone();
+        t.tags[0].tag[0] ^= 1;
+        assert_eq!(s1.verify(&t), Err(TokenError::BadTag));
+
+        // Tokens from another server's keyring are forgeries
+        assert_eq!(s1.verify(&token(&TokenKeyring::new(0), &s2)), Err(TokenError::BadTag));
+        assert_eq!(validate_tokens(&[good, t], &s1), Err(TokenError::BadTag));
+    }
+
+    #[test]
+    fn neither_server_alone() {
+        let (s1, s2) = (TokenKeyring::new(0), TokenKeyring::new(1));
+
+        // A token S1 mints on its own fails S2's check, and the other
+        // way around
+        let mut t = untagged(current_epoch());
+        s1.issue(&mut t);
+        assert_eq!(s1.verify(&t), Ok(()));
+        assert_eq!(s2.verify(&t), Err(TokenError::BadTag));
+        let mut t = untagged(current_epoch());
+        s2.issue(&mut t);
+        assert_eq!(s1.verify(&t), Err(TokenError::BadTag));
+
+        // Nor does copying S1's tag into S2's place help
+        let mut t = token(&s1, &s2);
+        t.tags[1] = t.tags[0].clone();
+        assert_eq!(s2.verify(&t), Err(TokenError::BadTag));
+    }
+
+    #[test]
+    fn expired() {
+        let (s1, s2) = (TokenKeyring::new(0), TokenKeyring::new(1));
+        let mut t = untagged(current_epoch() - 1);
+        s2.issue(&mut t);
+        s1.issue(&mut t);
+        assert_eq!(s1.verify(&t), Err(TokenError::Expired(current_epoch() - 1)));
+        assert_eq!(s2.verify(&t), Err(TokenError::Expired(current_epoch() - 1)));
+    }
+
+    #[test]
+    fn rotation() {
+        let (mut s1, s2) = (TokenKeyring::new(0), TokenKeyring::new(1));
+        let old = token(&s1, &s2);
+        assert_eq!(s1.rotate(), 1);
+        let new = token(&s1, &s2);
+        assert_eq!(new.tags[0].key_version, 1);
+        assert_eq!(new.tags[1].key_version, 0);
+        assert_eq!(validate_tokens(&[old.clone(), new.clone()], &s1), Ok(()));
+
+        // Moving a tag to another version doesn't help
+        let mut t = old.clone();
+        t.tags[0].key_version = 1;
+        assert_eq!(s1.verify(&t), Err(TokenError::BadTag));
+
+        s1.retire(0);
+        s1.retire(1);
+        assert_eq!(s1.verify(&old), Err(TokenError::UnknownKeyVersion(0)));
+        assert_eq!(s1.verify(&new), Ok(()));
+    }
+
+    #[test]
+    fn rollover() {
+        let mut keyring = TokenKeyring::new(1);
+        let now = current_epoch();
+        let old = token(&TokenKeyring::new(0), &keyring);
+
+        // Versions stay valid for as long as their tokens can live
+        assert_eq!(keyring.rollover(now), 1);
+        assert_eq!(keyring.rollover(now + TOKEN_LIFETIME), 2);
+        assert_eq!(keyring.verify(&old), Ok(()));
+
+        // Past that, they're retired, but never the current one
+        assert_eq!(keyring.rollover(now + TOKEN_LIFETIME + 1), 3);
+        assert_eq!(keyring.verify(&old), Err(TokenError::UnknownKeyVersion(0)));
+        assert!(keyring.keys.contains_key(&1));
+        let new = token(&TokenKeyring::new(0), &keyring);
+        assert_eq!(keyring.rollover(now + 10 * TOKEN_LIFETIME), 4);
+        assert_eq!(keyring.keys.keys().copied().collect::<Vec<u32>>(), vec![3, 4]);
+        assert_eq!(keyring.verify(&new), Ok(()));
+    }
+}
@@ -0,0 +1,292 @@
+// Transparency for the issuer public key.
+//
+// Credentials carry a MAC under each of S1's and S2's issuer keys (see
+// threshold.rs), and a client that took the keys from S1 alone could
+// be handed a rogue key, and so be told apart from everyone else. So
+// the public keys are published in a versioned IssuerKeyRecord, which each
+// server signs with its long-term identity key (a KeyAttestation).
+// Clients ask both servers for their attestation over their own
+// connections and only accept a record that both have signed.
+//
+// Each server keeps its identity key on disk across restarts and writes
+// the public part out as text (identity_to_text). The operator hands
+// both public keys out of band with the client, the way the app itself
+// is distributed, and a wallet that has them pins them with
+// KeyPin::with_identities, so it never takes the servers' word for who
+// they are. KeyPin::new trusts the identities on first use instead, for
+// wallets set up from an invitation alone. Later records must be
+// attested by the pinned identities and may only move to a newer
+// version; two different records under the same version are evidence
+// of a misbehaving server. S1 counts versions up from the last record
+// it published, so a restart with fresh issuer keys moves wallets on.
+use curve25519_dalek::ristretto::CompressedRistretto;
+use data_encoding::BASE32_NOPAD;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Write;
+use std::net::TcpStream;
+
+use crate::ggm::IssuerPubKey;
+use crate::signature::{self, Signature, SigningKey};
+use crate::read_frame;
+
+const RECORD_CONTEXT: &[u8] = b"PSA issuer key record";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TransparencyError {
+    // An attestation's signature did not verify
+    BadSignature,
+    // An attestation is from the wrong server or another identity key
+    // than the pinned one
+    WrongServer,
+    // The servers attest to different records
+    Mismatch,
+    // The record is older than the pinned one
+    Rollback(u32),
+    // The record differs from the pinned one of the same version
+    Equivocation(u32),
+}
+
+// The issuer public keys of S1 and S2, in that order. The version goes
+// up whenever the keys change.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IssuerKeyRecord {
+    pub version: u32,
+    pub shares: Vec<IssuerPubKey>,
+}
+
+impl IssuerKeyRecord {
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+// One server's signature on a record. server is 1 for S1 and 2 for S2.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyAttestation {
+    pub server: u8,
+    pub identity: CompressedRistretto,
+    pub record: IssuerKeyRecord,
+    signature: Signature,
+}
+
+fn attestation_message(server: u8, record: &IssuerKeyRecord) -> Vec<u8> {
+    let mut msg = vec![server];
+    msg.extend(record.to_bytes());
+    msg
+}
+
+impl KeyAttestation {
+    pub fn new(server: u8, identity: &SigningKey, record: IssuerKeyRecord) -> KeyAttestation {
+        let signature = identity.sign(RECORD_CONTEXT, &attestation_message(server, &record));
+        KeyAttestation {
+            server,
+            identity: identity.pk.compress(),
+            record,
+            signature,
+        }
+    }
+
+    fn verify(&self) -> bool {
+        signature::verify(&self.identity, RECORD_CONTEXT, &attestation_message(self.server, &self.record), &self.signature)
+    }
+}
+
+// Both servers' attestations of the issuer key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttestedIssuerKey {
+    pub s1: KeyAttestation,
+    pub s2: KeyAttestation,
+}
+
+impl AttestedIssuerKey {
+    // Check that both servers signed the same record
+    pub fn verify(&self) -> Result<&IssuerKeyRecord, TransparencyError> {
+        if self.s1.server != 1 || self.s2.server != 2 {
+            return Err(TransparencyError::WrongServer);
+        }
+        if !self.s1.verify() || !self.s2.verify() {
+            return Err(TransparencyError::BadSignature);
+        }
+        if self.s1.record.to_bytes() != self.s2.record.to_bytes() {
+            return Err(TransparencyError::Mismatch);
+        }
+        Ok(&self.s1.record)
+    }
+
+    fn identities(&self) -> [CompressedRistretto; 2] {
+        [self.s1.identity, self.s2.identity]
+    }
+}
+
+// A server's public identity key as unpadded base32, as for passes
+pub fn identity_to_text(identity: &CompressedRistretto) -> String {
+    BASE32_NOPAD.encode(identity.as_bytes())
+}
+
+pub fn identity_from_text(text: &str) -> Option<CompressedRistretto> {
+    let bytes = BASE32_NOPAD.decode(text.trim().as_bytes()).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let identity = CompressedRistretto::from_slice(&bytes);
+    identity.decompress().map(|_| identity)
+}
+
+// Ask each server for its attestation of the issuer key. The result
+// still has to be checked, with KeyPin::new or KeyPin::update.
+pub fn fetch_issuer_key(mut stream1: &TcpStream, mut stream2: &TcpStream) -> std::io::Result<AttestedIssuerKey> {
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    stream1.write_all(&[7u8])?;
+    let s1 = bincode::deserialize(&read_frame(&mut stream1)?).map_err(invalid)?;
+    stream2.write_all(&[7u8])?;
+    let s2 = bincode::deserialize(&read_frame(&mut stream2)?).map_err(invalid)?;
+    Ok(AttestedIssuerKey { s1, s2 })
+}
+
+// The issuer key and server identities a wallet has accepted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyPin {
+    identities: [CompressedRistretto; 2],
+    record: IssuerKeyRecord,
+}
+
+impl KeyPin {
+    // Pin an attested key on first use
+    pub fn new(key: &AttestedIssuerKey) -> Result<KeyPin, TransparencyError> {
+        let record = key.verify()?.clone();
+        Ok(KeyPin {
+            identities: key.identities(),
+            record,
+        })
+    }
+
+    // Pin an attested key, checking that it was attested by the identity
+    // keys S1 and S2 were known by beforehand
+    pub fn with_identities(identities: &[CompressedRistretto; 2], key: &AttestedIssuerKey) -> Result<KeyPin, TransparencyError> {
+        if key.identities() != *identities {
+            return Err(TransparencyError::WrongServer);
+        }
+        KeyPin::new(key)
+    }
+
+    // Check an attested key against the pin, and move the pin to it if
+    // it is a newer version
+    pub fn update(&mut self, key: &AttestedIssuerKey) -> Result<(), TransparencyError> {
+        if key.identities() != self.identities {
+            return Err(TransparencyError::WrongServer);
+        }
+        let record = key.verify()?;
+        if record.version < self.record.version {
+            return Err(TransparencyError::Rollback(record.version));
+        }
+        if record.version == self.record.version {
+            if record.to_bytes() != self.record.to_bytes() {
+                return Err(TransparencyError::Equivocation(record.version));
+            }
+            return Ok(());
+        }
+        self.record = record.clone();
+        Ok(())
+    }
+
+    pub fn version(&self) -> u32 {
+        self.record.version
+    }
+
+    // The identity keys of S1 and S2, which also sign their receipts
+    pub fn identities(&self) -> &[CompressedRistretto; 2] {
+        &self.identities
+    }
+
+    pub fn shares(&self) -> &[IssuerPubKey] {
+        &self.record.shares
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::threshold::IssuerShare;
+
+    fn record(version: u32, shares: &[IssuerShare]) -> IssuerKeyRecord {
+        IssuerKeyRecord {
+            version,
+            shares: shares.iter().map(|s| s.pubkey.clone()).collect(),
+        }
+    }
+
+    fn attest(ids: &[SigningKey; 2], record: &IssuerKeyRecord) -> AttestedIssuerKey {
+        AttestedIssuerKey {
+            s1: KeyAttestation::new(1, &ids[0], record.clone()),
+            s2: KeyAttestation::new(2, &ids[1], record.clone()),
+        }
+    }
+
+    #[test]
+    fn both_servers_attest() {
+        let ids = [SigningKey::new(), SigningKey::new()];
+        let shares = [IssuerShare::new(5), IssuerShare::new(5)];
+        let good = record(1, &shares);
+        let pin = KeyPin::new(&attest(&ids, &good)).unwrap();
+        assert_eq!(pin.version(), 1);
+        assert_eq!(bincode::serialize(pin.shares()).unwrap(), bincode::serialize(&good.shares).unwrap());
+
+        // S1 alone can't get a rogue key accepted, even under a fresh
+        // identity of its own for S2
+        let rogue = record(1, &[IssuerShare::new(5), shares[1].clone()]);
+        let key = AttestedIssuerKey {
+            s1: KeyAttestation::new(1, &ids[0], rogue.clone()),
+            s2: KeyAttestation::new(2, &ids[1], good.clone()),
+        };
+        assert_eq!(KeyPin::new(&key).err(), Some(TransparencyError::Mismatch));
+        let mut forged = attest(&ids, &good);
+        forged.s2.record = rogue.clone();
+        assert_eq!(KeyPin::new(&forged).err(), Some(TransparencyError::BadSignature));
+        let mut pin2 = pin.clone();
+        assert_eq!(pin2.update(&attest(&[ids[0].clone(), SigningKey::new()], &rogue)), Err(TransparencyError::WrongServer));
+
+        // Attestations must come from the server they claim to
+        let swapped = AttestedIssuerKey {
+            s1: KeyAttestation::new(2, &ids[1], good.clone()),
+            s2: KeyAttestation::new(1, &ids[0], good),
+        };
+        assert_eq!(KeyPin::new(&swapped).err(), Some(TransparencyError::WrongServer));
+    }
+
+    #[test]
+    fn known_identities() {
+        let ids = [SigningKey::new(), SigningKey::new()];
+        let shares = [IssuerShare::new(5), IssuerShare::new(5)];
+        let known = [ids[0].pk.compress(), ids[1].pk.compress()];
+        let texts: Vec<String> = known.iter().map(identity_to_text).collect();
+        let known = [identity_from_text(&texts[0]).unwrap(), identity_from_text(&texts[1]).unwrap()];
+        assert!(KeyPin::with_identities(&known, &attest(&ids, &record(1, &shares))).is_ok());
+        assert!(identity_from_text("not base32!").is_none());
+        assert!(identity_from_text(&BASE32_NOPAD.encode(&[0xff; 32])).is_none());
+
+        // Servers that merely claim to be S1 and S2 are refused
+        let impostors = [SigningKey::new(), SigningKey::new()];
+        let key = attest(&impostors, &record(1, &shares));
+        assert_eq!(KeyPin::with_identities(&known, &key).err(), Some(TransparencyError::WrongServer));
+    }
+
+    #[test]
+    fn versions() {
+        let ids = [SigningKey::new(), SigningKey::new()];
+        let shares = [IssuerShare::new(5), IssuerShare::new(5)];
+        let mut pin = KeyPin::new(&attest(&ids, &record(2, &shares))).unwrap();
+        assert_eq!(pin.update(&attest(&ids, &record(2, &shares))), Ok(()));
+
+        // Both servers signing two keys under one version is caught
+        let other = [IssuerShare::new(5), IssuerShare::new(5)];
+        assert_eq!(pin.update(&attest(&ids, &record(2, &other))), Err(TransparencyError::Equivocation(2)));
+        assert_eq!(pin.update(&attest(&ids, &record(1, &other))), Err(TransparencyError::Rollback(1)));
+
+        // A rotation moves the pin forward
+        assert_eq!(pin.update(&attest(&ids, &record(3, &other))), Ok(()));
+        assert_eq!(pin.version(), 3);
+        assert_eq!(pin.update(&attest(&ids, &record(2, &shares))), Err(TransparencyError::Rollback(2)));
+    }
+}
@@ -0,0 +1,253 @@
+// Client library for browsers, built for wasm32-unknown-unknown.
+//
+// A web wallet can't open TCP connections to the servers, so nothing
+// here does I/O: each step hands back the payload of the frame to send
+// to S1 or S2, and takes the payload of the frame the server answered
+// with. The JavaScript side owns the transport and stores the wallet
+// (Wallet::to_bytes) between sessions.
+//
+//   keys = new MemberKeys()          share keys.public_key() with the leader
+//   reg  = Registration.open(invitation, keys, attestation1, attestation2)
+//          send reg.request() to S2, then S1 (opcode 3)
+//   wallet = reg.finish(token)       token: S1's reply
+//   pay  = wallet.prepare_payment(id, group_tokens, dest, amount)
+//          send pay.s1_request() / pay.s2_request() (opcode 4)
+//          pay.check_receipts(wallet, receipt1, receipt2)
+//   bal  = wallet.request_balances() (opcode 5)
+//          bal.balances(wallet, reply1, reply2)
+use curve25519_dalek::scalar::Scalar;
+use wasm_bindgen::prelude::*;
+
+use payapp::client::{
+    account_index, combine_balances, member_token, prepare_settle, prepare_transaction,
+    receipts_match, registration_request, signed_balance, TransactionError,
+};
+use payapp::ggm::Credential;
+use payapp::invite::{Invitation, InvitationError, MemberKeyPair};
+use payapp::keys::{GroupMasterSecret, SettleError};
+use payapp::ps::{GroupToken, GroupTokenPriv, SettleData, TransactionData, TransactionDataS2};
+use payapp::receipt::{ReceiptError, ReceiptPair};
+use payapp::transparency::{AttestedIssuerKey, KeyPin};
+use payapp::{FieldElm, MAX_GROUP_SIZE};
+
+#[derive(Debug)]
+pub enum ClientError {
+    // A message from the servers or the caller could not be decoded
+    Malformed,
+    Invitation(InvitationError),
+    // The servers' receipts are for another transaction
+    WrongTransaction,
+    Receipt(ReceiptError),
+    // The wallet's token is not among the group's tokens
+    NotInGroup,
+    // A server refused to settle
+    Settle(SettleError),
+}
+
+impl From<InvitationError> for ClientError {
+    fn from(e: InvitationError) -> ClientError {
+        ClientError::Invitation(e)
+    }
+}
+
+impl From<TransactionError> for ClientError {
+    fn from(e: TransactionError) -> ClientError {
+        match e {
+            TransactionError::NotInGroup => ClientError::NotInGroup,
+            TransactionError::BadToken => ClientError::Malformed,
+        }
+    }
+}
+
+impl From<ClientError> for JsValue {
+    fn from(e: ClientError) -> JsValue {
+        JsError::new(&format!("{:?}", e)).into()
+    }
+}
+
+fn decode<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, ClientError> {
+    bincode::deserialize(bytes).map_err(|_| ClientError::Malformed)
+}
+
+// A (prospective) member's encryption key, which invitations are sealed to
+#[wasm_bindgen]
+pub struct MemberKeys {
+    keys: MemberKeyPair,
+}
+
+#[wasm_bindgen]
+impl MemberKeys {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> MemberKeys {
+        MemberKeys { keys: MemberKeyPair::new() }
+    }
+
+    // The compressed public key for the group leader
+    pub fn public_key(&self) -> Vec<u8> {
+        self.keys.pk.compress().to_bytes().to_vec()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self.keys).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MemberKeys, ClientError> {
+        Ok(MemberKeys { keys: decode(bytes)? })
+    }
+}
+
+// An opened invitation, waiting for the servers to issue a group token
+#[wasm_bindgen]
+pub struct Registration {
+    group_secret: GroupMasterSecret,
+    credential: Credential,
+    issuer_key: KeyPin,
+    zs: Vec<Scalar>,
+    request: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Registration {
+    // Open an invitation and check its issuer key against the servers'
+    // current attestations (their replies to opcode 7)
+    pub fn open(invitation: &str, keys: &MemberKeys, attestation1: &[u8], attestation2: &[u8]) -> Result<Registration, ClientError> {
+        let contents = Invitation::from_text(invitation)?.open(&keys.keys)?;
+        let mut issuer_key = KeyPin::new(&contents.issuer_key).map_err(InvitationError::from)?;
+        let current = AttestedIssuerKey {
+            s1: decode(attestation1)?,
+            s2: decode(attestation2)?,
+        };
+        issuer_key.update(&current).map_err(InvitationError::from)?;
+        let (zs, request) = registration_request(&contents.credential, issuer_key.shares());
+        Ok(Registration {
+            group_secret: contents.group_secret,
+            credential: contents.credential,
+            issuer_key,
+            zs,
+            request,
+        })
+    }
+
+    // The registration message, for S2 and then S1
+    pub fn request(&self) -> Vec<u8> {
+        self.request.clone()
+    }
+
+    // Take S1's reply, the group token
+    pub fn finish(self, token: &[u8]) -> Result<Wallet, ClientError> {
+        let token: GroupToken = decode(token)?;
+        Ok(Wallet {
+            token: member_token(self.group_secret, &self.credential, &self.zs, token, self.issuer_key),
+        })
+    }
+}
+
+// A member's slot of a group
+#[wasm_bindgen]
+pub struct Wallet {
+    token: GroupTokenPriv,
+}
+
+#[wasm_bindgen]
+impl Wallet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self.token).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Wallet, ClientError> {
+        Ok(Wallet { token: decode(bytes)? })
+    }
+
+    // The wallet's database slot, which other members pay to
+    pub fn account(&self) -> u32 {
+        account_index(&self.token.aid)
+    }
+
+    pub fn group(&self) -> u32 {
+        self.account() / MAX_GROUP_SIZE as u32
+    }
+
+    // The wallet's group token, for the other members' group_tokens
+    pub fn group_token(&self) -> Vec<u8> {
+        bincode::serialize(&self.token.token).unwrap()
+    }
+
+    // Pay amount to account dest. group_tokens is the bincode encoding
+    // of the group's tokens, this wallet's among them. The servers refuse
+    // a session_id they have seen before, so pick a fresh random one.
+    pub fn prepare_payment(&self, session_id: u32, group_tokens: &[u8], dest: u32, amount: u32) -> Result<Payment, ClientError> {
+        let tokens: Vec<GroupToken> = decode(group_tokens)?;
+        let (s1, s2) = prepare_transaction(session_id, &self.token, &tokens, dest, amount)?;
+        Ok(Payment { s1, s2 })
+    }
+
+    // Ask for the group's balances in a fresh settlement epoch: the
+    // current time in seconds since the Unix epoch, which JS has to pass
+    // in, e.g. Math.floor(Date.now() / 1000)
+    pub fn request_balances(&self, epoch: u64) -> Settlement {
+        let (s1, s2) = prepare_settle(self.group(), epoch);
+        Settlement { s1, s2 }
+    }
+}
+
+#[wasm_bindgen]
+pub struct Payment {
+    s1: TransactionData,
+    s2: TransactionDataS2,
+}
+
+#[wasm_bindgen]
+impl Payment {
+    pub fn s1_request(&self) -> Vec<u8> {
+        bincode::serialize(&self.s1).unwrap()
+    }
+
+    pub fn s2_request(&self) -> Vec<u8> {
+        bincode::serialize(&self.s2).unwrap()
+    }
+
+    // Check that both servers accepted the payment, under the server
+    // identities the wallet pinned
+    pub fn check_receipts(&self, wallet: &Wallet, receipt1: &[u8], receipt2: &[u8]) -> Result<(), ClientError> {
+        let receipts = ReceiptPair {
+            s1: decode(receipt1)?,
+            s2: decode(receipt2)?,
+        };
+        if !receipts_match(&self.s1, &self.s2, &receipts) {
+            return Err(ClientError::WrongTransaction);
+        }
+        receipts.verify(&wallet.token.issuer_key).map_err(ClientError::Receipt)
+    }
+}
+
+#[wasm_bindgen]
+pub struct Settlement {
+    s1: SettleData,
+    s2: SettleData,
+}
+
+#[wasm_bindgen]
+impl Settlement {
+    pub fn s1_request(&self) -> Vec<u8> {
+        bincode::serialize(&self.s1).unwrap()
+    }
+
+    pub fn s2_request(&self) -> Vec<u8> {
+        bincode::serialize(&self.s2).unwrap()
+    }
+
+    // The balance of every slot of the group, from the servers' replies
+    pub fn balances(&self, wallet: &Wallet, reply1: &[u8], reply2: &[u8]) -> Result<Vec<i64>, ClientError> {
+        let bv1: Vec<FieldElm> = decode::<Result<_, SettleError>>(reply1)?.map_err(ClientError::Settle)?;
+        let bv2: Vec<FieldElm> = decode::<Result<_, SettleError>>(reply2)?.map_err(ClientError::Settle)?;
+        if bv1.len() < MAX_GROUP_SIZE || bv2.len() < MAX_GROUP_SIZE {
+            return Err(ClientError::Malformed);
+        }
+        combine_balances(&bv1, &bv2, &wallet.token.group_secret, wallet.group(), self.s1.epoch)
+            .iter()
+            .map(signed_balance)
+            .collect::<Option<Vec<i64>>>()
+            .ok_or(ClientError::Malformed)
+    }
+}
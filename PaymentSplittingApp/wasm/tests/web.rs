@@ -0,0 +1,148 @@
+// Run in a headless runtime with
+//
+//     wasm-pack test --node
+//
+// and natively with cargo test. The servers are simulated in-process,
+// and only the payloads of the frames pass between them and the wallet,
+// as they would through the JavaScript transport.
+use curve25519_dalek::ristretto::CompressedRistretto;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::*;
+
+use payapp::coms::{verify_group_tokens, SessionContext};
+use payapp::invite::invite;
+use payapp::keys::{GroupMasterSecret, KeyRegistry, SettleError};
+use payapp::membership::RevocationList;
+use payapp::ps::{GroupToken, ServerData, SettleData, TransactionData, TransactionDataS2};
+use payapp::test_util::TestServers;
+use payapp::tokens::validate_tokens;
+use payapp::{FieldElm, Group, MAX_GROUP_NUM, MAX_GROUP_SIZE};
+use payapp_wasm::{ClientError, MemberKeys, Payment, Registration, Wallet};
+
+const GROUP: usize = 2;
+
+// The group leader's side: a credential for the slot, sealed to the
+// member's key
+fn invitation(servers: &mut TestServers, slot: u64, group_secret: &GroupMasterSecret, member: &MemberKeys) -> String {
+    let cred = servers.credential((GROUP * MAX_GROUP_SIZE) as u64 + slot);
+    let pk = CompressedRistretto::from_slice(&member.public_key()).decompress().unwrap();
+    invite(GROUP as u32, cred, &servers.attested, group_secret, &pk).to_text()
+}
+
+fn attestations(servers: &TestServers) -> (Vec<u8>, Vec<u8>) {
+    (bincode::serialize(&servers.attested.s1).unwrap(), bincode::serialize(&servers.attested.s2).unwrap())
+}
+
+fn join(servers: &mut TestServers, slot: u64, group_secret: &GroupMasterSecret) -> Wallet {
+    let keys = MemberKeys::new();
+    let invitation = invitation(servers, slot, group_secret, &keys);
+    let (attestation1, attestation2) = attestations(servers);
+    let registration = Registration::open(&invitation, &keys, &attestation1, &attestation2).unwrap();
+    let token = servers.register(&registration.request());
+    registration.finish(&bincode::serialize(&token).unwrap()).unwrap()
+}
+
+// Opcode 4, on the payloads the wallet sends
+fn receipts(servers: &TestServers, payment: &Payment) -> (Vec<u8>, Vec<u8>) {
+    let td1: TransactionData = bincode::deserialize(&payment.s1_request()).unwrap();
+    let td2: TransactionDataS2 = bincode::deserialize(&payment.s2_request()).unwrap();
+    servers.receipts(&td1, &td2)
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn registration() {
+    let mut servers = TestServers::new();
+    let wallet = join(&mut servers, 4, &GroupMasterSecret::random());
+    assert_eq!(wallet.account(), (GROUP * MAX_GROUP_SIZE + 4) as u32);
+    assert_eq!(wallet.group(), GROUP as u32);
+
+    // The wallet survives being stored
+    let stored = Wallet::from_bytes(&wallet.to_bytes()).unwrap();
+    assert_eq!(stored.group_token(), wallet.group_token());
+
+    // An invitation for someone else can't be opened
+    let invitation = invitation(&mut servers, 5, &GroupMasterSecret::random(), &MemberKeys::new());
+    let (attestation1, attestation2) = attestations(&servers);
+    assert!(matches!(
+        Registration::open(&invitation, &MemberKeys::new(), &attestation1, &attestation2),
+        Err(ClientError::Invitation(_))
+    ));
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn payment() {
+    let mut servers = TestServers::new();
+    let group_secret = GroupMasterSecret::random();
+    let payer = join(&mut servers, 1, &group_secret);
+    let payee = join(&mut servers, 2, &group_secret);
+    let tokens: Vec<GroupToken> = [&payer, &payee]
+        .iter()
+        .map(|w| bincode::deserialize(&w.group_token()).unwrap())
+        .collect();
+    let group_tokens = bincode::serialize(&tokens).unwrap();
+
+    let payment = payer.prepare_payment(7, &group_tokens, payee.account(), 12).unwrap();
+
+    // S1 accepts the proof of group membership
+    let td1: TransactionData = bincode::deserialize(&payment.s1_request()).unwrap();
+    let session = SessionContext::new(7, &servers.s1.issuer_shares(), &td1.dpf_src, &td1.dpf_dest, &tokens);
+    assert!(verify_group_tokens(td1.token_proof, tokens.clone(), td1.com_i, &session, &servers.keyrings[0], &RevocationList::default()));
+    assert!(validate_tokens(&tokens, &servers.keyrings[1]).is_ok());
+
+    let (receipt1, receipt2) = receipts(&servers, &payment);
+    assert!(payment.check_receipts(&payer, &receipt1, &receipt2).is_ok());
+
+    // Receipts for another payment, or swapped, are refused
+    let other = payer.prepare_payment(8, &group_tokens, payee.account(), 12).unwrap();
+    let (other1, _) = receipts(&servers, &other);
+    assert!(matches!(payment.check_receipts(&payer, &other1, &receipt2), Err(ClientError::WrongTransaction)));
+    assert!(payment.check_receipts(&payer, &receipt2, &receipt1).is_err());
+
+    // A wallet can only pay if its own token is among the group's
+    let outsider = join(&mut servers, 3, &group_secret);
+    let group_tokens = bincode::serialize(&tokens[1..]).unwrap();
+    assert!(matches!(outsider.prepare_payment(9, &group_tokens, 0, 1), Err(ClientError::NotInGroup)));
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn balances() {
+    let mut servers = TestServers::new();
+    let group_secret = GroupMasterSecret::random();
+    let wallet = join(&mut servers, 0, &group_secret);
+    let mut reg1 = KeyRegistry::new();
+    let mut reg2 = KeyRegistry::new();
+    reg1.register(GROUP, group_secret.server_key(1));
+    reg2.register(GROUP, group_secret.server_key(2));
+
+    // The servers' shares of the database, with the group's first slot
+    // overdrawn
+    let mut db: Vec<FieldElm> = (0..MAX_GROUP_NUM * MAX_GROUP_SIZE).map(|i| FieldElm::from(i as u32)).collect();
+    db[GROUP * MAX_GROUP_SIZE].sub(&FieldElm::from(30u32));
+    let zero = vec![FieldElm::zero(); db.len()];
+
+    // Opcode 5
+    let settlement = wallet.request_balances(1_700_000_000);
+    let d1: SettleData = bincode::deserialize(&settlement.s1_request()).unwrap();
+    let d2: SettleData = bincode::deserialize(&settlement.s2_request()).unwrap();
+    let enc1 = ServerData::encrypt_db(&db, &reg1, d1.epoch);
+    let enc2 = ServerData::encrypt_db(&zero, &reg2, d2.epoch);
+    assert_eq!(d1.epoch, 1_700_000_000);
+    let reply1 = bincode::serialize(&Ok::<_, SettleError>(ServerData::settle(&enc1, &enc2, &d1.dpf_key))).unwrap();
+    let reply2 = bincode::serialize(&Ok::<_, SettleError>(ServerData::settle(&enc2, &enc1, &d2.dpf_key))).unwrap();
+
+    let balances = settlement.balances(&wallet, &reply1, &reply2).unwrap();
+    let expected: Vec<i64> = (0..MAX_GROUP_SIZE).map(|i| (GROUP * MAX_GROUP_SIZE + i) as i64).collect();
+    assert_eq!(balances[0], expected[0] - 30);
+    assert_eq!(balances[1..], expected[1..]);
+
+    // Another group's wallet can't strip the masks
+    let stranger = join(&mut servers, 1, &GroupMasterSecret::random());
+    assert!(matches!(settlement.balances(&stranger, &reply1, &reply2), Err(ClientError::Malformed)));
+
+    // A server that already settled in this epoch refuses
+    let refused = bincode::serialize(&Err::<Vec<FieldElm>, _>(SettleError::Stale(d1.epoch))).unwrap();
+    assert!(matches!(settlement.balances(&wallet, &reply1, &refused), Err(ClientError::Settle(SettleError::Stale(_)))));
+}